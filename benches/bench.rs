@@ -14,6 +14,7 @@ mod me;
 use rav1e::*;
 use rav1e::cdef::cdef_filter_frame;
 use rav1e::context::*;
+use rav1e::cpu_features::{CpuFeatureLevel, CpuKernels};
 use rav1e::partition::*;
 use rav1e::predict::*;
 use rav1e::rdo::rdo_cfl_alpha;
@@ -41,7 +42,7 @@ fn write_b_bench(b: &mut Bencher, tx_size: TxSize, qindex: usize) {
     speed_settings: SpeedSettings::from_preset(10),
     ..Default::default()
   };
-  let sequence = Sequence::new(&Default::default());
+  let sequence = Sequence::new(&Default::default(), None);
   let mut fi = FrameInvariants::<u16>::new(config, sequence);
   let mut w = ec::WriterEncoder::new();
   let mut fc = CDFContext::new(fi.base_q_idx);
@@ -63,7 +64,7 @@ fn write_b_bench(b: &mut Bencher, tx_size: TxSize, qindex: usize) {
     for &mode in RAV1E_INTRA_MODES {
       let sbo = SuperBlockOffset { x: sbx, y: sby };
       for p in 1..3 {
-        ts.qc.update(fi.base_q_idx, tx_size, mode.is_intra(), 8, fi.dc_delta_q[p], fi.ac_delta_q[p]);
+        ts.qc.update(fi.base_q_idx, tx_size, fi.quant_rounding(mode.is_intra()), 8, fi.dc_delta_q[p], fi.ac_delta_q[p]);
         for by in 0..8 {
           for bx in 0..8 {
             // For ex, 8x8 tx should be applied to even numbered (bx,by)
@@ -89,7 +90,8 @@ fn write_b_bench(b: &mut Bencher, tx_size: TxSize, qindex: usize) {
               po,
               false,
               ac,
-              0,
+              IntraParam::None,
+              None,
               RDOType::PixelDistRealRate,
               false
             );
@@ -115,7 +117,7 @@ fn cdef_frame_bench(b: &mut Bencher, width: usize, height: usize) {
     speed_settings: SpeedSettings::from_preset(10),
     ..Default::default()
   };
-  let sequence = Sequence::new(&Default::default());
+  let sequence = Sequence::new(&Default::default(), None);
   let fi = FrameInvariants::<u16>::new(config, sequence);
   let fb = FrameBlocks::new(fi.sb_width * 16, fi.sb_height * 16);
   let mut fs = FrameState::new(&fi);
@@ -123,6 +125,83 @@ fn cdef_frame_bench(b: &mut Bencher, width: usize, height: usize) {
   b.iter(|| cdef_filter_frame(&fi, &mut fs.rec, &fb));
 }
 
+fn pad_frame(c: &mut Criterion) {
+  let w = 1920;
+  let h = 1080;
+  let n = format!("pad_frame({}, {})", w, h);
+  c.bench_function(&n, move |b| pad_frame_bench(b, w, h));
+  // A frame not kept as a reference, padded as every reconstruction was
+  // before and left unpadded as it is now
+  for &pad_all in &[true, false] {
+    let n = format!("finish_non_reference({}, {}, pad_all={})", w, h, pad_all);
+    c.bench_function(&n, move |b| finish_non_reference_bench(b, w, h, pad_all));
+  }
+}
+
+fn pad_frame_bench(b: &mut Bencher, width: usize, height: usize) {
+  let mut frame = Frame::<u16>::new(width, height, ChromaSampling::Cs420);
+
+  b.iter(|| frame.pad(width, height));
+}
+
+fn finish_non_reference_bench(
+  b: &mut Bencher, width: usize, height: usize, pad_all: bool
+) {
+  let config = EncoderConfig { width, height, ..Default::default() };
+  let sequence = Sequence::new(&config, None);
+  let mut fi = FrameInvariants::<u16>::new(config, sequence);
+  fi.refresh_frame_flags = 0;
+  let setup_fi = fi.clone();
+
+  b.iter_with_large_setup(
+    || FrameState::new(&setup_fi),
+    |mut fs| {
+      if pad_all || fi.refresh_frame_flags != 0 {
+        fs.rec.pad(fi.width, fi.height);
+      }
+      update_rec_buffer(&mut fi, fs);
+    }
+  );
+}
+
+fn rec_buffer(c: &mut Criterion) {
+  let w = 3840;
+  let h = 2160;
+  let n = format!("update_rec_buffer({}, {})", w, h);
+  c.bench_function(&n, move |b| update_rec_buffer_bench(b, w, h));
+  // The copy of the reconstruction each frame stored before the reference
+  // slots shared it, for comparison
+  let n = format!("copy_rec_frame({}, {})", w, h);
+  c.bench_function(&n, move |b| copy_rec_frame_bench(b, w, h));
+}
+
+/// Stores a 10-bit reconstruction in every reference slot, as a keyframe
+fn update_rec_buffer_bench(b: &mut Bencher, width: usize, height: usize) {
+  let config = EncoderConfig {
+    width,
+    height,
+    bit_depth: 10,
+    ..Default::default()
+  };
+  let sequence = Sequence::new(&config, None);
+  let mut fi = FrameInvariants::<u16>::new(config, sequence);
+  fi.refresh_frame_flags = ALL_REF_FRAMES_MASK;
+  let setup_fi = fi.clone();
+
+  b.iter_with_large_setup(
+    || FrameState::new(&setup_fi),
+    |fs| {
+      update_rec_buffer(&mut fi, fs);
+    }
+  );
+}
+
+fn copy_rec_frame_bench(b: &mut Bencher, width: usize, height: usize) {
+  let frame = Frame::<u16>::new(width, height, ChromaSampling::Cs420);
+
+  b.iter_with_large_drop(|| frame.clone());
+}
+
 fn cfl_rdo(c: &mut Criterion) {
   for &bsize in &[
     BlockSize::BLOCK_4X4,
@@ -143,12 +222,13 @@ fn cfl_rdo_bench(b: &mut Bencher, bsize: BlockSize) {
     speed_settings: SpeedSettings::from_preset(10),
     ..Default::default()
   };
-  let sequence = Sequence::new(&Default::default());
+  let sequence = Sequence::new(&Default::default(), None);
   let fi = FrameInvariants::<u16>::new(config, sequence);
   let mut fs = FrameState::new(&fi);
   let mut ts = fs.as_tile_state_mut();
   let offset = BlockOffset { x: 1, y: 1 };
-  b.iter(|| rdo_cfl_alpha(&mut ts, offset, bsize, fi.sequence.bit_depth))
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+  b.iter(|| rdo_cfl_alpha(&mut ts, offset, bsize, fi.sequence.bit_depth, cpu))
 }
 
 fn ec_bench(c: &mut Criterion) {
@@ -180,13 +260,15 @@ criterion_group!(intra_prediction, predict::pred_bench,);
 
 criterion_group!(cfl, cfl_rdo);
 criterion_group!(cdef, cdef_frame);
+criterion_group!(pad, pad_frame);
+criterion_group!(references, rec_buffer);
 criterion_group!(write_block, write_b);
 criterion_group!{ name = me;
                   config = Criterion::default().warm_up_time(Duration::new(1,0));
-                  targets = me::get_sad
+                  targets = me::get_sad, me::me_methods
 }
 
 criterion_group!(ec, ec_bench);
 
-criterion_main!(write_block, intra_prediction, cdef, cfl, me, transform, ec);
+criterion_main!(write_block, intra_prediction, cdef, pad, references, cfl, me, transform, ec);
 
@@ -12,8 +12,10 @@ use crate::partition::*;
 use crate::partition::BlockSize::*;
 use crate::plane::*;
 use rand::{ChaChaRng, Rng, SeedableRng};
+use rav1e::cpu_features::{CpuFeatureLevel, CpuKernels};
 use rav1e::me;
-use rav1e::Pixel;
+use rav1e::*;
+use std::sync::Arc;
 
 fn fill_plane<T: Pixel>(ra: &mut ChaChaRng, plane: &mut Plane<T>) {
   let stride = plane.cfg.stride;
@@ -44,10 +46,12 @@ fn run_sad_bench<T: Pixel>(b: &mut Bencher, &(bs, bit_depth): &(BlockSize, usize
 
   let plane_org = input_plane.as_region();
   let plane_ref = rec_plane.as_region();
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
 
   b.iter(|| {
-    let _ =
-      black_box(me::get_sad(&plane_org, &plane_ref, bsw, bsh, bit_depth));
+    let _ = black_box(
+      me::get_sad(&plane_org, &plane_ref, bsw, bsh, bit_depth, cpu)
+    );
   })
 }
 
@@ -111,3 +115,49 @@ pub fn get_sad(c: &mut Criterion) {
 
   c.bench_function_over_inputs("get_sad", bench_get_sad, blocks);
 }
+
+/// A CIF sized clip panning over a smooth texture, a few pixels per frame
+fn panning_frame(ctx: &Context<u8>, frameno: usize) -> Frame<u8> {
+  let mut frame = (*ctx.new_frame()).clone();
+  for (p, plane) in frame.planes.iter_mut().enumerate() {
+    let stride = plane.cfg.stride;
+    let (width, height) = (plane.cfg.width, plane.cfg.height);
+    let shift = (frameno * 3) >> plane.cfg.xdec;
+    for (y, row) in plane.data_origin_mut().chunks_mut(stride).take(height).enumerate() {
+      for (x, v) in row[..width].iter_mut().enumerate() {
+        let (fx, fy) = ((x + shift) as f64, (y + p * 16) as f64);
+        *v = (128.0 + 50.0 * (fx / 9.0).sin() + 50.0 * (fy / 13.0 + fx / 31.0).cos()) as u8;
+      }
+    }
+  }
+  frame
+}
+
+fn bench_me_method(b: &mut Bencher, &method: &MotionSearch) {
+  let mut enc = EncoderConfig::with_speed_preset(6);
+  enc.width = 352;
+  enc.height = 288;
+  enc.low_latency = true;
+  enc.speed_settings.me_method = method;
+  let cfg = Config::new(enc).with_threads(1);
+  let ctx = cfg.new_context::<u8>().unwrap();
+  let frames: Vec<_> =
+    (0..8).map(|i| Arc::new(panning_frame(&ctx, i))).collect();
+
+  b.iter(|| {
+    let mut ctx = cfg.new_context::<u8>().unwrap();
+    for frame in frames.iter() {
+      ctx.send_frame(frame.clone()).unwrap();
+    }
+    ctx.flush();
+    while let Ok(packet) = ctx.receive_packet() {
+      black_box(packet.data);
+    }
+  })
+}
+
+pub fn me_methods(c: &mut Criterion) {
+  let methods = vec![MotionSearch::Diamond, MotionSearch::Hex, MotionSearch::Full];
+
+  c.bench_function_over_inputs("encode_me_method", bench_me_method, methods);
+}
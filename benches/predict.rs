@@ -9,6 +9,7 @@
 
 use criterion::*;
 use rand::{ChaChaRng, Rng, RngCore, SeedableRng};
+use rav1e::cpu_features::{CpuFeatureLevel, CpuKernels};
 use rav1e::partition::BlockSize;
 use rav1e::predict::{Block4x4, Intra};
 use crate::plane::*;
@@ -87,8 +88,15 @@ pub fn intra_dc_4x4(b: &mut Bencher) {
   let mut rng = ChaChaRng::from_seed([0; 32]);
   let (mut block, above, left) = generate_block(&mut rng);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
-    Block4x4::pred_dc(&mut block.as_region_mut(), &above[..4], &left[..4]);
+    Block4x4::pred_dc(
+      &mut block.as_region_mut(),
+      &above[..4],
+      &left[..4],
+      cpu
+    );
   })
 }
 
@@ -96,11 +104,14 @@ pub fn intra_dc_left_4x4(b: &mut Bencher) {
   let mut rng = ChaChaRng::from_seed([0; 32]);
   let (mut block, above, left) = generate_block(&mut rng);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
     Block4x4::pred_dc_left(
       &mut block.as_region_mut(),
       &above[..4],
-      &left[..4]
+      &left[..4],
+      cpu
     );
   })
 }
@@ -109,11 +120,14 @@ pub fn intra_dc_top_4x4(b: &mut Bencher) {
   let mut rng = ChaChaRng::from_seed([0; 32]);
   let (mut block, above, left) = generate_block(&mut rng);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
     Block4x4::pred_dc_top(
       &mut block.as_region_mut(),
       &above[..4],
-      &left[..4]
+      &left[..4],
+      cpu
     );
   })
 }
@@ -122,8 +136,10 @@ pub fn intra_h_4x4(b: &mut Bencher) {
   let mut rng = ChaChaRng::from_seed([0; 32]);
   let (mut block, _above, left) = generate_block(&mut rng);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
-    Block4x4::pred_h(&mut block.as_region_mut(), &left[..4]);
+    Block4x4::pred_h(&mut block.as_region_mut(), &left[..4], cpu);
   })
 }
 
@@ -131,8 +147,10 @@ pub fn intra_v_4x4(b: &mut Bencher) {
   let mut rng = ChaChaRng::from_seed([0; 32]);
   let (mut block, above, _left) = generate_block(&mut rng);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
-    Block4x4::pred_v(&mut block.as_region_mut(), &above[..4]);
+    Block4x4::pred_v(&mut block.as_region_mut(), &above[..4], cpu);
   })
 }
 
@@ -141,12 +159,15 @@ pub fn intra_paeth_4x4(b: &mut Bencher) {
   let (mut block, above, left) = generate_block(&mut rng);
   let above_left = unsafe { *above.as_ptr().offset(-1) };
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
     Block4x4::pred_paeth(
       &mut block.as_region_mut(),
       &above[..4],
       &left[..4],
-      above_left
+      above_left,
+      cpu
     );
   })
 }
@@ -155,11 +176,14 @@ pub fn intra_smooth_4x4(b: &mut Bencher) {
   let mut rng = ChaChaRng::from_seed([0; 32]);
   let (mut block, above, left) = generate_block(&mut rng);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
     Block4x4::pred_smooth(
       &mut block.as_region_mut(),
       &above[..4],
-      &left[..4]
+      &left[..4],
+      cpu
     );
   })
 }
@@ -168,11 +192,14 @@ pub fn intra_smooth_h_4x4(b: &mut Bencher) {
   let mut rng = ChaChaRng::from_seed([0; 32]);
   let (mut block, above, left) = generate_block(&mut rng);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
     Block4x4::pred_smooth_h(
       &mut block.as_region_mut(),
       &above[..4],
-      &left[..4]
+      &left[..4],
+      cpu
     );
   })
 }
@@ -181,11 +208,14 @@ pub fn intra_smooth_v_4x4(b: &mut Bencher) {
   let mut rng = ChaChaRng::from_seed([0; 32]);
   let (mut block, above, left) = generate_block(&mut rng);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
     Block4x4::pred_smooth_v(
       &mut block.as_region_mut(),
       &above[..4],
-      &left[..4]
+      &left[..4],
+      cpu
     );
   })
 }
@@ -196,6 +226,8 @@ pub fn intra_cfl_4x4(b: &mut Bencher) {
   let ac: Vec<i16> = (0..(32 * 32)).map(|_| rng.gen()).collect();
   let alpha = -1 as i16;
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
     Block4x4::pred_cfl(
       &mut block.as_region_mut(),
@@ -203,7 +235,8 @@ pub fn intra_cfl_4x4(b: &mut Bencher) {
       alpha,
       8,
       &above,
-      &left
+      &left,
+      cpu
     );
   })
 }
@@ -213,11 +246,14 @@ pub fn intra_dc_4x4_u8(b: &mut Bencher) {
   let mut edge_buf = UninitializedAlignedArray();
   let (mut block, above, left) = generate_block_u8(&mut rng, &mut edge_buf);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
     Block4x4::pred_dc(
       &mut block.as_region_mut(),
       &above[..4],
-      &left[32 - 4..]
+      &left[32 - 4..],
+      cpu
     );
   })
 }
@@ -227,8 +263,10 @@ pub fn intra_dc_128_4x4_u8(b: &mut Bencher) {
   let mut edge_buf = UninitializedAlignedArray();
   let (mut block, _above, _left) = generate_block_u8(&mut rng, &mut edge_buf);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
-    Block4x4::pred_dc_128(&mut block.as_region_mut(), 8);
+    Block4x4::pred_dc_128(&mut block.as_region_mut(), 8, cpu);
   })
 }
 
@@ -237,11 +275,14 @@ pub fn intra_dc_left_4x4_u8(b: &mut Bencher) {
   let mut edge_buf = UninitializedAlignedArray();
   let (mut block, above, left) = generate_block_u8(&mut rng, &mut edge_buf);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
     Block4x4::pred_dc_left(
       &mut block.as_region_mut(),
       &above[..4],
-      &left[32 - 4..]
+      &left[32 - 4..],
+      cpu
     );
   })
 }
@@ -251,11 +292,14 @@ pub fn intra_dc_top_4x4_u8(b: &mut Bencher) {
   let mut edge_buf = UninitializedAlignedArray();
   let (mut block, above, left) = generate_block_u8(&mut rng, &mut edge_buf);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
     Block4x4::pred_dc_top(
       &mut block.as_region_mut(),
       &above[..4],
-      &left[32 - 4..]
+      &left[32 - 4..],
+      cpu
     );
   })
 }
@@ -265,8 +309,10 @@ pub fn intra_h_4x4_u8(b: &mut Bencher) {
   let mut edge_buf = UninitializedAlignedArray();
   let (mut block, _above, left) = generate_block_u8(&mut rng, &mut edge_buf);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
-    Block4x4::pred_h(&mut block.as_region_mut(), &left[32 - 4..]);
+    Block4x4::pred_h(&mut block.as_region_mut(), &left[32 - 4..], cpu);
   })
 }
 
@@ -275,8 +321,10 @@ pub fn intra_v_4x4_u8(b: &mut Bencher) {
   let mut edge_buf = UninitializedAlignedArray();
   let (mut block, above, _left) = generate_block_u8(&mut rng, &mut edge_buf);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
-    Block4x4::pred_v(&mut block.as_region_mut(), &above[..4]);
+    Block4x4::pred_v(&mut block.as_region_mut(), &above[..4], cpu);
   })
 }
 
@@ -286,12 +334,15 @@ pub fn intra_paeth_4x4_u8(b: &mut Bencher) {
   let (mut block, above, left) = generate_block_u8(&mut rng, &mut edge_buf);
   let above_left = unsafe { *above.as_ptr().offset(-1) };
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
     Block4x4::pred_paeth(
       &mut block.as_region_mut(),
       &above[..4],
       &left[32 - 4..],
-      above_left
+      above_left,
+      cpu
     );
   })
 }
@@ -301,11 +352,14 @@ pub fn intra_smooth_4x4_u8(b: &mut Bencher) {
   let mut edge_buf = UninitializedAlignedArray();
   let (mut block, above, left) = generate_block_u8(&mut rng, &mut edge_buf);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
     Block4x4::pred_smooth(
       &mut block.as_region_mut(),
       &above[..4],
-      &left[32 - 4..]
+      &left[32 - 4..],
+      cpu
     );
   })
 }
@@ -315,11 +369,14 @@ pub fn intra_smooth_h_4x4_u8(b: &mut Bencher) {
   let mut edge_buf = UninitializedAlignedArray();
   let (mut block, above, left) = generate_block_u8(&mut rng, &mut edge_buf);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
     Block4x4::pred_smooth_h(
       &mut block.as_region_mut(),
       &above[..4],
-      &left[32 - 4..]
+      &left[32 - 4..],
+      cpu
     );
   })
 }
@@ -329,11 +386,14 @@ pub fn intra_smooth_v_4x4_u8(b: &mut Bencher) {
   let mut edge_buf = UninitializedAlignedArray();
   let (mut block, above, left) = generate_block_u8(&mut rng, &mut edge_buf);
 
+  let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+
   b.iter(|| {
     Block4x4::pred_smooth_v(
       &mut block.as_region_mut(),
       &above[..4],
-      &left[32 - 4..]
+      &left[32 - 4..],
+      cpu
     );
   })
 }
@@ -0,0 +1,165 @@
+#!/usr/bin/env python3
+#
+# Copyright (c) 2019, The rav1e contributors. All rights reserved
+#
+# This source code is subject to the terms of the BSD 2 Clause License and
+# the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+# was not distributed with this source code in the LICENSE file, you can
+# obtain it at www.aomedia.org/license/software. If the Alliance for Open
+# Media Patent License 1.0 was not distributed with this source code in the
+# PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+"""Encodes an 8-bit 4:2:0 y4m file to IVF through the rav1e C API.
+
+The shared library is built with:
+
+    cargo rustc --release --lib --features capi -- --crate-type cdylib
+
+and the example run with:
+
+    python3 examples/python/encode.py --lib target/release/librav1e.so \\
+        input.y4m output.ivf
+
+The encoder is wrapped in a generator: packets are yielded as soon as
+rav1e_receive_packet has one, and the next frame is read when it returns
+NeedMoreData.
+"""
+
+import argparse
+import ctypes
+import struct
+
+SUCCESS = 0
+NEED_MORE_DATA = 1
+LIMIT_REACHED = 3
+
+
+class RaPacket(ctypes.Structure):
+    # Only the leading fields of RaPacket, the ones of the C API
+    _fields_ = [
+        ("data", ctypes.POINTER(ctypes.c_uint8)),
+        ("len", ctypes.c_size_t),
+        ("input_frameno", ctypes.c_uint64),
+        ("shown", ctypes.c_bool),
+        ("frame_type", ctypes.c_int),
+    ]
+
+
+def load(path):
+    lib = ctypes.CDLL(path)
+    lib.rav1e_config_default.restype = ctypes.c_void_p
+    lib.rav1e_config_unref.argtypes = [ctypes.c_void_p]
+    lib.rav1e_config_set_dimensions.argtypes = [
+        ctypes.c_void_p, ctypes.c_size_t, ctypes.c_size_t]
+    lib.rav1e_config_parse.argtypes = [
+        ctypes.c_void_p, ctypes.c_char_p, ctypes.c_char_p]
+    lib.rav1e_context_new.argtypes = [ctypes.c_void_p]
+    lib.rav1e_context_new.restype = ctypes.c_void_p
+    lib.rav1e_context_unref.argtypes = [ctypes.c_void_p]
+    lib.rav1e_send_frame_planes.argtypes = [
+        ctypes.c_void_p,
+        ctypes.c_char_p, ctypes.c_ssize_t,
+        ctypes.c_char_p, ctypes.c_ssize_t,
+        ctypes.c_char_p, ctypes.c_ssize_t]
+    lib.rav1e_receive_packet.argtypes = [
+        ctypes.c_void_p, ctypes.POINTER(ctypes.POINTER(RaPacket))]
+    lib.rav1e_packet_unref.argtypes = [ctypes.POINTER(RaPacket)]
+    return lib
+
+
+def read_y4m(f):
+    """Returns the width, height and frame rate of a y4m stream, and a
+    generator of its frames as (y, u, v) planes."""
+    header = f.readline().split()
+    if header[0] != b"YUV4MPEG2":
+        raise ValueError("not a y4m file")
+    params = {p[:1]: p[1:] for p in header[1:]}
+    colorspace = params.get(b"C", b"420jpeg")
+    if not colorspace.startswith(b"420") or colorspace.startswith(b"420p"):
+        raise ValueError("only 8-bit 4:2:0 is supported")
+    width, height = int(params[b"W"]), int(params[b"H"])
+    rate = tuple(int(n) for n in params.get(b"F", b"30:1").split(b":"))
+    luma = width * height
+    chroma = ((width + 1) // 2) * ((height + 1) // 2)
+
+    def frames():
+        while f.readline().startswith(b"FRAME"):
+            planes = f.read(luma), f.read(chroma), f.read(chroma)
+            # The C API reads whole planes, a truncated frame is dropped
+            if len(planes[2]) < chroma:
+                break
+            yield planes
+
+    return width, height, rate, frames()
+
+
+def encode(lib, width, height, frames, options):
+    """Yields the packets of the frames, as (frame number, data) pairs."""
+    cfg = lib.rav1e_config_default()
+    if lib.rav1e_config_set_dimensions(cfg, width, height) != 0:
+        raise ValueError("invalid dimensions")
+    for key, value in options:
+        if lib.rav1e_config_parse(cfg, key.encode(), value.encode()) != 0:
+            raise ValueError("invalid option {}={}".format(key, value))
+    ctx = lib.rav1e_context_new(cfg)
+    lib.rav1e_config_unref(cfg)
+    if not ctx:
+        raise ValueError("invalid configuration")
+
+    y_stride, c_stride = width, (width + 1) // 2
+    packet = ctypes.POINTER(RaPacket)()
+
+    def receive():
+        while True:
+            status = lib.rav1e_receive_packet(ctx, ctypes.byref(packet))
+            if status != SUCCESS:
+                break
+            p = packet.contents
+            yield p.input_frameno, ctypes.string_at(p.data, p.len)
+            lib.rav1e_packet_unref(packet)
+        if status not in (NEED_MORE_DATA, LIMIT_REACHED):
+            raise RuntimeError("encoder status {}".format(status))
+
+    try:
+        for y, u, v in frames:
+            status = lib.rav1e_send_frame_planes(
+                ctx, y, y_stride, u, c_stride, v, c_stride)
+            if status != SUCCESS:
+                raise RuntimeError("encoder status {}".format(status))
+            yield from receive()
+        # A null luma plane flushes the encoder
+        lib.rav1e_send_frame_planes(ctx, None, 0, None, 0, None, 0)
+        yield from receive()
+    finally:
+        lib.rav1e_context_unref(ctx)
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("--lib", default="target/release/librav1e.so",
+                        help="path of the rav1e shared library")
+    parser.add_argument("--option", "-o", action="append", default=[],
+                        metavar="KEY=VALUE",
+                        help="encoder option, e.g. speed=10 or qp=100")
+    parser.add_argument("input", help="8-bit 4:2:0 y4m file")
+    parser.add_argument("output", help="IVF file")
+    args = parser.parse_args()
+
+    lib = load(args.lib)
+    options = [o.split("=", 1) for o in args.option]
+    with open(args.input, "rb") as f, open(args.output, "wb") as out:
+        width, height, (num, den), frames = read_y4m(f)
+        out.write(struct.pack("<4sHH4sHHIIII", b"DKIF", 0, 32, b"AV01",
+                              width, height, num, den, 0, 0))
+        count = 0
+        for frameno, data in encode(lib, width, height, frames, options):
+            out.write(struct.pack("<IQ", len(data), frameno))
+            out.write(data)
+            count += 1
+        # Frame count of the header
+        out.seek(24)
+        out.write(struct.pack("<I", count))
+
+
+if __name__ == "__main__":
+    main()
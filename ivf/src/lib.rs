@@ -3,17 +3,22 @@
 
 use bitstream_io::{BitReader, BitWriter, LittleEndian};
 use std::io;
+use std::io::SeekFrom;
 
+/// Offset of the frame count in the header
+const FRAME_COUNT_OFFSET: u64 = 24;
+
+/// Writes the header with a frame count of 0, to be patched with
+/// `update_ivf_frame_count` once the frames are written
 pub fn write_ivf_header(
-  output_file: &mut dyn io::Write, width: usize, height: usize,
-  framerate_num: usize,
-  framerate_den: usize
+  output_file: &mut dyn io::Write, fourcc: &[u8; 4], width: usize,
+  height: usize, framerate_num: usize, framerate_den: usize
 ) {
   let mut bw = BitWriter::endian(output_file, LittleEndian);
   bw.write_bytes(b"DKIF").unwrap();
   bw.write(16, 0).unwrap(); // version
   bw.write(16, 32).unwrap(); // version
-  bw.write_bytes(b"AV01").unwrap();
+  bw.write_bytes(fourcc).unwrap();
   bw.write(16, width as u16).unwrap();
   bw.write(16, height as u16).unwrap();
   bw.write(32, framerate_num as u32).unwrap();
@@ -22,6 +27,17 @@ pub fn write_ivf_header(
   bw.write(32, 0).unwrap();
 }
 
+/// Sets the frame count of the header of a file, leaving its position at
+/// the end
+pub fn update_ivf_frame_count<W: io::Write + io::Seek>(
+  output_file: &mut W, frames: u32
+) -> io::Result<()> {
+  output_file.seek(SeekFrom::Start(FRAME_COUNT_OFFSET))?;
+  output_file.write_all(&frames.to_le_bytes())?;
+  output_file.seek(SeekFrom::End(0))?;
+  Ok(())
+}
+
 pub fn write_ivf_frame(
   output_file: &mut dyn io::Write, pts: u64, data: &[u8]
 ) {
@@ -37,7 +53,8 @@ pub struct Header {
   pub w: u16,
   pub h: u16,
   pub timebase_num: u32,
-  pub timebase_den: u32
+  pub timebase_den: u32,
+  pub frames: u32
 }
 
 pub fn read_header(r: &mut dyn io::Read) -> io::Result<Header> {
@@ -62,10 +79,10 @@ pub fn read_header(r: &mut dyn io::Read) -> io::Result<Header> {
   let timebase_den: u32 = br.read(32)?;
   let timebase_num: u32 = br.read(32)?;
 
-  let _: u32 = br.read(32)?;
+  let frames: u32 = br.read(32)?;
   let _: u32 = br.read(32)?;
 
-  Ok(Header { tag, w, h, timebase_num, timebase_den })
+  Ok(Header { tag, w, h, timebase_num, timebase_den, frames })
 }
 
 pub struct Packet {
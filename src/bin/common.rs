@@ -8,32 +8,91 @@
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
 use crate::{ColorPrimaries, MatrixCoefficients, TransferCharacteristics};
+use crate::muxer::{IvfMuxer, Muxer};
+use crate::stats::EncodeStats;
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand, Shell};
+use rav1e::cpu_features::CpuFeatureLevel;
+use rav1e::grain::{GrainTable, GrainTableSource};
+use rav1e::metrics::weighted_psnr;
 use rav1e::partition::BlockSize;
 use rav1e::*;
 
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::PathBuf;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{fmt, io};
 
 pub struct EncoderIO {
   pub input: Box<dyn Read>,
-  pub output: Box<dyn Write>,
-  pub rec: Option<Box<dyn Write>>
+  pub output: Box<dyn Muxer>,
+  pub rec: Option<Box<dyn Write>>,
+  /// Sidecar of the MD5 of the frames shown
+  pub hash: Option<Box<dyn Write>>,
+  /// Input of the alpha planes and output of the alpha stream, see
+  /// `--alpha`
+  pub alpha: Option<(Box<dyn Read>, Box<dyn Muxer>)>
+}
+
+/// Handling of a change of the size or format of the input, see
+/// `--on-input-change`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputChange {
+  /// Stop with an error, the output ending with the frames before the
+  /// change
+  Error,
+  /// Continue in a new output file, numbered, with a new encoder
+  SplitOutput,
+  /// Scale the frames to the size of the first ones, other changes being
+  /// errors
+  Scale,
 }
 
 pub struct CliOptions {
   pub io: EncoderIO,
+  /// Path of the output, numbered after it for the outputs of
+  /// `InputChange::SplitOutput`
+  pub output_path: String,
+  pub on_input_change: InputChange,
   pub enc: EncoderConfig,
   pub limit: usize,
   pub skip: usize,
+  /// Pattern of the names of numbered image files to read the frames
+  /// from instead of the input, see `--input-pattern`
+  #[cfg(feature = "image")]
+  pub input_pattern: Option<String>,
+  /// Frame rate overriding the one of the input, in frames per second
+  pub frame_rate: Option<Rational>,
+  pub keyint_seconds: Option<f64>,
+  pub sharpen: Option<i32>,
   pub verbose: bool,
   pub threads: usize,
+  /// Interval in frames of the self-checks of the packets
+  pub self_check: Option<u64>,
+  /// Interval between the statistics per frame type printed during the
+  /// encode
+  pub summary_interval: Option<Duration>,
+  /// Path of the JSON report of the encode, see `ProgressInfo::report`
+  pub report: Option<String>,
 }
 
+/// Arguments the input may be omitted with
+#[cfg(feature = "image")]
+const INPUT_ALTERNATIVES: &[&str] = &["FULLHELP", "INPUT_PATTERN"];
+#[cfg(not(feature = "image"))]
+const INPUT_ALTERNATIVES: &[&str] = &["FULLHELP"];
+
 pub fn parse_cli() -> CliOptions {
+  parse_cli_from(std::env::args_os())
+}
+
+/// Parses the options of the command line `args`, the first one being the
+/// name of the program
+pub fn parse_cli_from<I, T>(args: I) -> CliOptions
+where
+  I: IntoIterator<Item = T>,
+  T: Into<std::ffi::OsString> + Clone,
+{
   let mut app = App::new("rav1e")
     .version(env!("CARGO_PKG_VERSION"))
     .about("AV1 video encoder")
@@ -50,11 +109,19 @@ pub fn parse_cli() -> CliOptions {
         .takes_value(true)
         .default_value("0")
     )
+    .arg(
+      Arg::with_name("CPU")
+        .help("Highest instruction set of the SIMD kernels; every level produces the same output. \"auto\" uses what the CPU supports, lowered by the RAV1E_CPU_LIMIT environment variable if set")
+        .long("cpu")
+        .takes_value(true)
+        .possible_values(&["auto", "none", "sse2", "ssse3", "sse4", "avx2"])
+        .default_value("auto")
+    )
     // INPUT/OUTPUT
     .arg(
       Arg::with_name("INPUT")
         .help("Uncompressed YUV4MPEG2 video input")
-        .required_unless("FULLHELP")
+        .required_unless_one(INPUT_ALTERNATIVES)
         .index(1)
     )
     .arg(
@@ -65,6 +132,14 @@ pub fn parse_cli() -> CliOptions {
         .required_unless("FULLHELP")
         .takes_value(true)
     )
+    .arg(
+      Arg::with_name("ON_INPUT_CHANGE")
+        .help("Handling of a y4m input changing size or format at a new stream header, e.g. of concatenated files: stop with an error, continue in a new output file with a new encoder, the file name suffixed with -1, -2..., or scale the frames to the size of the first ones")
+        .long("on-input-change")
+        .takes_value(true)
+        .possible_values(&["error", "split-output", "scale"])
+        .default_value("error")
+    )
     .arg(
       Arg::with_name("STATS_FILE")
         .help("Custom location for first-pass stats file")
@@ -83,7 +158,7 @@ pub fn parse_cli() -> CliOptions {
     )
     .arg(
       Arg::with_name("LIMIT")
-        .help("Maximum number of frames to encode")
+        .help("Maximum number of frames to encode, counted after the skipped ones")
         .short("l")
         .long("limit")
         .takes_value(true)
@@ -96,12 +171,33 @@ pub fn parse_cli() -> CliOptions {
         .takes_value(true)
         .default_value("0")
     )
+    .arg(
+      Arg::with_name("FRAME_RATE")
+        .help("Frame rate of the input as num/den or num, overriding the one in its header")
+        .long("frame-rate")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("INTERNAL_BIT_DEPTH")
+        .help("Bit depth to encode at; 8-bit input is scaled up to it, which reduces banding")
+        .long("internal-bit-depth")
+        .takes_value(true)
+        .possible_values(&["8", "10", "12"])
+    )
     .arg(
       Arg::with_name("QP")
         .help("Quantizer (0-255), smaller values are higher quality [default: 100]")
         .long("quantizer")
         .takes_value(true)
     )
+    .arg(
+      Arg::with_name("CHROMA_QP_OFFSET")
+        .help("Offsets of the Cb and Cr quantizers from the luma one (-64-63), as CB,CR or one value for both;\n\
+            positive values are lower chroma quality")
+        .long("chroma-qp-offset")
+        .takes_value(true)
+        .allow_hyphen_values(true)
+    )
     .arg(
       Arg::with_name("BITRATE")
         .help("Bitrate (kbps)")
@@ -109,6 +205,129 @@ pub fn parse_cli() -> CliOptions {
         .long("bitrate")
         .takes_value(true)
     )
+    .arg(
+      Arg::with_name("MIN_QUANTIZER")
+        .help("Minimum quantizer (0-255) in bitrate mode, keyframes included [default: 0]")
+        .long("min-quantizer")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("MAX_QUANTIZER")
+        .help("Maximum quantizer (1-255) in bitrate mode, keyframes included [default: 255]")
+        .long("max-quantizer")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("MAX_FRAME_SIZE")
+        .help("Maximum size of a single frame in bytes; larger frames are re-encoded with a higher quantizer, then have their tiles spread over tile groups within it")
+        .long("max-frame-size")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("MAX_TILE_GROUP_SIZE")
+        .help("Maximum size of a tile group OBU in bytes; the tiles of a frame are split across several tile groups as needed")
+        .long("max-tile-group-size")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("SEQUENCE_HEADER")
+        .help("Keyframes preceded by the sequence header OBU")
+        .long("sequence-header")
+        .possible_values(&SequenceHeaderPlacement::variants())
+        .default_value("EveryKeyframe")
+        .case_insensitive(true)
+    )
+    .arg(
+      Arg::with_name("DENOISE")
+        .help("Strength of the temporal denoising applied to the input frames before encoding (1-255)")
+        .long("denoise")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("SHARPEN")
+        .help("Sharpen the input frames by the given amount in eighths (1-32), or blur them with a negative amount (-8 to -1)")
+        .long("sharpen")
+        .takes_value(true)
+        .allow_hyphen_values(true)
+    )
+    .arg(
+      Arg::with_name("DISABLE_MODES")
+        .help("Comma separated classes of prediction modes not to use: directional, smooth, paeth, cfl, compound")
+        .long("disable-modes")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("DISABLE_TX")
+        .help("Comma separated transforms not to use: transform type names such as IDTX, non_dct for all types but DCT_DCT, rect for rectangular sizes")
+        .long("disable-tx")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("CDEF")
+        .help("Signal CDEF in the sequence header and search its strengths")
+        .long("cdef")
+        .takes_value(true)
+        .possible_values(&["on", "off"])
+        .default_value("on")
+    )
+    .arg(
+      Arg::with_name("DISABLE_RESTORATION")
+        .help("Leave loop restoration out of the sequence header, without searching its filters")
+        .long("disable-restoration")
+    )
+    .arg(
+      Arg::with_name("DISABLE_FILTER_INTRA")
+        .help("Leave the recursive intra prediction filters out of the sequence header")
+        .long("disable-filter-intra")
+    )
+    .arg(
+      Arg::with_name("ENABLE_TOOLS")
+        .help("Comma separated tools to signal in the sequence header without using them: superres, warped-motion, dual-filter, jnt-comp, ref-frame-mvs")
+        .long("enable-tools")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("REDUCED_TX_SET")
+        .help("Frames limited to the reduced transform set [default: set by the speed]")
+        .long("reduced-tx-set")
+        .takes_value(true)
+        .possible_values(&ReducedTxSet::variants())
+        .case_insensitive(true)
+    )
+    .arg(
+      Arg::with_name("ME_METHOD")
+        .help("Pattern of the full-pel motion search [default: set by the speed]")
+        .long("me-method")
+        .takes_value(true)
+        .possible_values(&MotionSearch::variants())
+        .case_insensitive(true)
+    )
+    .arg(
+      Arg::with_name("ME_RANGE")
+        .help("Largest motion vector component, in pixels [default: set by the speed]")
+        .long("me-range")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("MIN_PARTITION_SIZE")
+        .help("Smallest block size of the partition search, except along the frame edges [default: set by the speed]")
+        .long("min-partition-size")
+        .takes_value(true)
+        .possible_values(&["4x4", "8x8", "16x16", "32x32", "64x64"])
+    )
+    .arg(
+      Arg::with_name("MAX_PARTITION_SIZE")
+        .help("Largest block size coded without splitting")
+        .long("max-partition-size")
+        .takes_value(true)
+        .possible_values(&["8x8", "16x16", "32x32", "64x64"])
+    )
+    .arg(
+      Arg::with_name("FILM_GRAIN_TABLE")
+        .help("Film grain table, in the format of aomenc's --film-grain-table, for the decoder to synthesize grain from")
+        .long("film-grain-table")
+        .takes_value(true)
+    )
     .arg(
       Arg::with_name("SPEED")
         .help("Speed level (0 is best quality, 10 is fastest)\n\
@@ -158,12 +377,51 @@ pub fn parse_cli() -> CliOptions {
         .takes_value(true)
         .default_value("240")
     )
+    .arg(
+      Arg::with_name("KEYFRAME_INTERVAL_SECONDS")
+        .help("Maximum interval between keyframes in seconds, overriding --keyint")
+        .long("keyint-seconds")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("MAX_PIXELS")
+        .help("Largest number of samples per frame accepted, below the limits of the AV1 levels")
+        .long("max-pixels")
+        .takes_value(true)
+    )
     .arg(
       Arg::with_name("LOW_LATENCY")
         .help("Low latency mode; disables frame reordering\n\
             Has a significant speed-to-quality trade-off")
         .long("low_latency")
     )
+    .arg(
+      Arg::with_name("MAX_REFERENCE_FRAMES")
+        .help("Largest number of frames kept for reference, from 1 to 7 [default: 7]\n\
+            Fewer save memory at some cost in compression, below 3 disabling frame reordering")
+        .long("max-reference-frames")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("TEMPORAL_LAYERS")
+        .help("Number of temporal layers, from 1 to 4 [default: 1]\n\
+            Dropping the highest ones halves the frame rate each time")
+        .long("temporal-layers")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("ERROR_RESILIENT")
+        .help("Error resilient mode; no frame depends on the probabilities adapted by the previous ones
+            For delivery over lossy networks, at some cost in compression")
+        .long("error-resilient")
+    )
+    .arg(
+      Arg::with_name("INTRA_REFRESH")
+        .help("Periodic intra refresh period in frames; refreshes the picture with a sweeping column of intra blocks\n\
+            Implies low latency mode; decoders joining mid-stream also need --error-resilient")
+        .long("intra-refresh")
+        .takes_value(true)
+    )
     .arg(
       Arg::with_name("TUNE")
         .help("Quality tuning")
@@ -186,6 +444,23 @@ pub fn parse_cli() -> CliOptions {
         .takes_value(true)
         .default_value("0")
     )
+    .arg(
+      Arg::with_name("TILE_RATE_CONTROL")
+        .help("Give each tile a quantizer matching its complexity")
+        .long("tile-rc")
+    )
+    .arg(
+      Arg::with_name("LARGE_SCALE_TILES")
+        .help("Code for large scale tile decoding, where single tiles are extracted (e.g. light fields): key frames are anchors and the other frames are sent as tile lists")
+        .long("large-scale-tiles")
+    )
+    .arg(
+      Arg::with_name("TILES")
+        .help("Approximate number of tiles, split into columns and rows according to the frame size; overridden by --tile-cols-log2 and --tile-rows-log2. \"single\" for exactly one tile per frame, or \"level\" and a level, e.g. \"level5.1\", for the fewest tiles within that level")
+        .long("tiles")
+        .takes_value(true)
+        .default_value("0")
+    )
     // MASTERING
     .arg(
       Arg::with_name("PIXEL_RANGE")
@@ -195,6 +470,14 @@ pub fn parse_cli() -> CliOptions {
         .default_value("unspecified")
         .case_insensitive(true)
     )
+    .arg(
+      Arg::with_name("INPUT_RANGE")
+        .help("Pixel range of the input, converted from full to limited range if it differs from --range")
+        .long("input-range")
+        .possible_values(&PixelRange::variants())
+        .default_value("unspecified")
+        .case_insensitive(true)
+    )
     .arg(
       Arg::with_name("COLOR_PRIMARIES")
         .help("Color primaries used to describe color parameters")
@@ -240,6 +523,41 @@ pub fn parse_cli() -> CliOptions {
         .long("verbose")
         .short("v")
     )
+    .arg(
+      Arg::with_name("SUMMARY_INTERVAL")
+        .help("Print the statistics per frame type every N seconds, in addition to the end of the encode")
+        .long("summary-interval")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("REPORT")
+        .help("Write a JSON report of the encode: the size, quantizer, PSNR and coefficient bits per plane of each frame, and their totals; numbered like the outputs of --on-input-change split-output")
+        .long("report")
+        .takes_value(true)
+        .value_name("FILE")
+    )
+    .arg(
+      Arg::with_name("DETERMINISTIC")
+        .help("Disable any heuristic that could make the output depend on timing or thread count")
+        .long("deterministic")
+    )
+    .arg(
+      Arg::with_name("SEED")
+        .help("Seed of the pseudo-random choices of the encoder, such as the film grain seeds [default: 0]")
+        .long("seed")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("DRY_RUN")
+        .help("Reconstruct the frames without entropy coding them, for profiling; no bitstream is written")
+        .long("dry-run")
+    )
+    .arg(
+      Arg::with_name("SELF_CHECK")
+        .help("Every N frames, check that the packet parses back as OBUs, that its tiles lie within it and that the reconstruction still has the CRC taken after the loop filters; on failure, the planes of the frame are dumped to the current directory")
+        .long("self-check")
+        .takes_value(true)
+    )
     .arg(
       Arg::with_name("PSNR")
         .help("Calculate and display PSNR metrics")
@@ -251,6 +569,27 @@ pub fn parse_cli() -> CliOptions {
         .short("r")
         .takes_value(true)
     )
+    .arg(
+      Arg::with_name("FRAME_HASH")
+        .help("Outputs the MD5 of each frame shown, as printed by \"aomdec --rawvideo --md5 -o %4\" for the output (with --skip-film-grain when using film grain), numbering the frames of the outputs split on input changes in sequence")
+        .long("frame-hash")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("ALPHA")
+        .help("Y4M file holding the alpha plane of each frame of the input as its luma plane, encoded to --alpha-output as the alpha stream of an AVIF image with transparency. Scene change detection is disabled, for both streams to place their keyframes alike")
+        .long("alpha")
+        .takes_value(true)
+        .requires("ALPHA_OUTPUT")
+        .conflicts_with_all(&["RECONSTRUCTION", "FRAME_HASH", "SELF_CHECK", "PASS"])
+    )
+    .arg(
+      Arg::with_name("ALPHA_OUTPUT")
+        .help("IVF file to write the alpha stream of --alpha to")
+        .long("alpha-output")
+        .takes_value(true)
+        .requires("ALPHA")
+    )
     .arg(
       Arg::with_name("SPEED_TEST")
         .help("Run an encode using default encoding settings, manually adjusting only the settings specified; allows benchmarking settings in isolation")
@@ -273,8 +612,19 @@ pub fn parse_cli() -> CliOptions {
                      .possible_values(&Shell::variants())
                 )
     );
+  #[cfg(feature = "image")]
+  {
+    app = app.arg(
+      Arg::with_name("INPUT_PATTERN")
+        .help("Read the frames from numbered PNG files instead, e.g. \"frame_%06d.png\", from 0 or 1 up to the first missing one. RGB is converted to 4:2:0 with --matrix and --range, BT.709 limited range by default; 16-bit images are encoded at --internal-bit-depth, 10 by default")
+        .long("input-pattern")
+        .takes_value(true)
+        .conflicts_with_all(&["INPUT", "ALPHA"])
+        .requires("FRAME_RATE")
+    );
+  }
 
-  let matches = app.clone().get_matches();
+  let matches = app.clone().get_matches_from(args);
 
   if matches.is_present("FULLHELP") {
     app.print_long_help().unwrap();
@@ -291,29 +641,102 @@ pub fn parse_cli() -> CliOptions {
   }
 
   let io = EncoderIO {
-    input: match matches.value_of("INPUT").unwrap() {
-      "-" => Box::new(io::stdin()) as Box<dyn Read>,
-      f => Box::new(File::open(&f).unwrap()) as Box<dyn Read>
-    },
-    output: match matches.value_of("OUTPUT").unwrap() {
-      "-" => Box::new(io::stdout()) as Box<dyn Write>,
-      f => Box::new(File::create(&f).unwrap()) as Box<dyn Write>
+    input: match matches.value_of("INPUT") {
+      Some("-") => Box::new(io::stdin()) as Box<dyn Read>,
+      Some(f) => Box::new(File::open(&f).unwrap()) as Box<dyn Read>,
+      // The frames are read from image files
+      None => Box::new(io::empty()) as Box<dyn Read>,
     },
+    output: Box::new(IvfMuxer::open(matches.value_of("OUTPUT").unwrap()).unwrap()),
     rec: matches
       .value_of("RECONSTRUCTION")
-      .map(|f| Box::new(File::create(&f).unwrap()) as Box<dyn Write>)
+      .map(|f| Box::new(File::create(&f).unwrap()) as Box<dyn Write>),
+    hash: matches
+      .value_of("FRAME_HASH")
+      .map(|f| Box::new(File::create(&f).unwrap()) as Box<dyn Write>),
+    alpha: matches.value_of("ALPHA").map(|f| {
+      let output = matches.value_of("ALPHA_OUTPUT").unwrap();
+      (
+        Box::new(File::open(&f).unwrap()) as Box<dyn Read>,
+        Box::new(IvfMuxer::open(output).unwrap()) as Box<dyn Muxer>
+      )
+    })
+  };
+
+  let output_path = matches.value_of("OUTPUT").unwrap().to_string();
+  let on_input_change = match matches.value_of("ON_INPUT_CHANGE").unwrap() {
+    "split-output" => {
+      if output_path == "-" {
+        panic!("Splitting the output on input changes needs an output file");
+      }
+      InputChange::SplitOutput
+    }
+    "scale" => InputChange::Scale,
+    _ => InputChange::Error,
   };
 
   CliOptions {
     io,
+    output_path,
+    on_input_change,
     enc: parse_config(&matches),
     limit: matches.value_of("LIMIT").unwrap().parse().unwrap(),
     skip: matches.value_of("SKIP").unwrap().parse().unwrap(),
+    #[cfg(feature = "image")]
+    input_pattern: matches.value_of("INPUT_PATTERN").map(String::from),
+    frame_rate: matches.value_of("FRAME_RATE").map(|rate| {
+      parse_frame_rate(rate)
+        .expect("Frame rate must be given as num/den or num, both positive integers")
+    }),
+    keyint_seconds: matches.value_of("KEYFRAME_INTERVAL_SECONDS").map(|v| {
+      let seconds: f64 = v.parse()
+        .expect("Keyframe interval in seconds must be a number");
+      if seconds.is_nan() || seconds <= 0.0 {
+        panic!("Keyframe interval in seconds must be positive");
+      }
+      seconds
+    }),
+    sharpen: matches.value_of("SHARPEN").map(|amount| {
+      let amount: i32 = amount.parse()
+        .expect("Sharpening amount must be an integer between -8 and 32");
+      if amount < -8 || amount > 32 {
+        panic!("Sharpening amount must be between -8 and 32");
+      }
+      amount
+    }),
     verbose: matches.is_present("VERBOSE"),
     threads,
+    self_check: matches.value_of("SELF_CHECK").map(|interval| {
+      let interval: u64 = interval.parse()
+        .expect("Self-check interval must be an integer");
+      if interval == 0 {
+        panic!("Self-check interval must be greater than 0");
+      }
+      interval
+    }),
+    summary_interval: matches.value_of("SUMMARY_INTERVAL").map(|seconds| {
+      Duration::from_secs(
+        seconds.parse().expect("Summary interval must be an integer")
+      )
+    }),
+    report: matches.value_of("REPORT").map(str::to_string),
   }
 }
 
+/// Parses a frame rate given as `num/den` or `num`
+fn parse_frame_rate(rate: &str) -> Option<Rational> {
+  let mut parts = rate.splitn(2, '/');
+  let num: u64 = parts.next()?.trim().parse().ok()?;
+  let den: u64 = match parts.next() {
+    Some(den) => den.trim().parse().ok()?,
+    None => 1,
+  };
+  if num == 0 || den == 0 {
+    return None;
+  }
+  Some(Rational::new(num, den))
+}
+
 fn parse_config(matches: &ArgMatches<'_>) -> EncoderConfig {
   let maybe_quantizer = matches.value_of("QP").map(|qp| qp.parse().unwrap());
   let maybe_bitrate =
@@ -373,10 +796,11 @@ fn parse_config(matches: &ArgMatches<'_>) -> EncoderConfig {
       .unwrap_or_default();
 
     let mut cfg = EncoderConfig::with_speed_preset(speed);
-    cfg.max_key_frame_interval = min_interval;
+    cfg.min_key_frame_interval = min_interval;
     cfg.max_key_frame_interval = max_interval;
 
     cfg.pixel_range = matches.value_of("PIXEL_RANGE").unwrap().parse().unwrap_or_default();
+    cfg.input_pixel_range = matches.value_of("INPUT_RANGE").unwrap().parse().unwrap_or_default();
     cfg.color_description = if color_primaries == ColorPrimaries::Unspecified &&
       transfer_characteristics == TransferCharacteristics::Unspecified &&
       matrix_coefficients == MatrixCoefficients::Unspecified {
@@ -430,6 +854,82 @@ fn parse_config(matches: &ArgMatches<'_>) -> EncoderConfig {
 
   cfg.quantizer = quantizer;
   cfg.bitrate = bitrate;
+  if let Some(min) = matches.value_of("MIN_QUANTIZER") {
+    cfg.min_quantizer = min.parse().expect("Minimum quantizer must be an integer");
+  }
+  if let Some(max) = matches.value_of("MAX_QUANTIZER") {
+    cfg.max_quantizer = max.parse().expect("Maximum quantizer must be an integer");
+  }
+  cfg.max_frame_size_bytes = matches.value_of("MAX_FRAME_SIZE")
+    .map(|size| size.parse().expect("Maximum frame size must be an integer"));
+  cfg.max_tile_group_size = matches.value_of("MAX_TILE_GROUP_SIZE")
+    .map(|size| size.parse().expect("Maximum tile group size must be an integer"));
+  cfg.sequence_header_placement =
+    matches.value_of("SEQUENCE_HEADER").unwrap().parse().unwrap();
+  cfg.preprocess = matches.value_of("DENOISE").map(|strength| {
+    let strength: u8 = strength.parse()
+      .expect("Denoise strength must be an integer between 1 and 255");
+    if strength == 0 {
+      panic!("Denoise strength must be greater than 0");
+    }
+    Preprocess::Denoise { strength }
+  });
+  if let Some(modes) = matches.value_of("DISABLE_MODES") {
+    cfg.disabled_modes = modes.parse().unwrap_or_else(|e| panic!("{}", e));
+  }
+  if let Some(tx) = matches.value_of("DISABLE_TX") {
+    cfg.disabled_tx = tx.parse().unwrap_or_else(|e| panic!("{}", e));
+  }
+  if matches.value_of("CDEF") == Some("off") {
+    cfg.tools.cdef = false;
+  }
+  cfg.tools.restoration = !matches.is_present("DISABLE_RESTORATION");
+  cfg.tools.filter_intra = !matches.is_present("DISABLE_FILTER_INTRA");
+  if let Some(tools) = matches.value_of("ENABLE_TOOLS") {
+    for tool in tools.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+      match tool {
+        "superres" => cfg.tools.superres = true,
+        "warped-motion" => cfg.tools.warped_motion = true,
+        "dual-filter" => cfg.tools.dual_filter = true,
+        "jnt-comp" => cfg.tools.jnt_comp = true,
+        "ref-frame-mvs" => cfg.tools.ref_frame_mvs = true,
+        _ => panic!("Unknown coding tool: {}", tool),
+      }
+    }
+  }
+  if let Some(reduced) = matches.value_of("REDUCED_TX_SET") {
+    cfg.speed_settings.reduced_tx_set = reduced.parse().unwrap();
+  }
+  if let Some(method) = matches.value_of("ME_METHOD") {
+    cfg.speed_settings.me_method = method.parse().unwrap();
+  }
+  if let Some(range) = matches.value_of("ME_RANGE") {
+    let range: usize = range.parse().unwrap();
+    if range == 0 || range > 2047 {
+      panic!("Motion search range must be between 1-2047");
+    }
+    cfg.speed_settings.me_range = range;
+  }
+  if let Some(size) = matches.value_of("MIN_PARTITION_SIZE") {
+    let size: usize = size.split('x').next().unwrap().parse().unwrap();
+    cfg.speed_settings.min_block_size = BlockSize::from_width_and_height(size, size);
+  }
+  if let Some(size) = matches.value_of("MAX_PARTITION_SIZE") {
+    let size: usize = size.split('x').next().unwrap().parse().unwrap();
+    cfg.speed_settings.max_block_size = BlockSize::from_width_and_height(size, size);
+  }
+  cfg.internal_bit_depth = matches
+    .value_of("INTERNAL_BIT_DEPTH")
+    .map(|d| d.parse().unwrap());
+  cfg.film_grain = matches.value_of("FILM_GRAIN_TABLE").map(|path| {
+    let mut data = String::new();
+    File::open(path)
+      .and_then(|mut file| file.read_to_string(&mut data))
+      .unwrap_or_else(|e| panic!("Cannot read film grain table {}: {}", path, e));
+    let table = GrainTable::parse(&data)
+      .unwrap_or_else(|e| panic!("{}: {}", path, e));
+    GrainTableSource::File(table)
+  });
   cfg.show_psnr = matches.is_present("PSNR");
   cfg.pass = matches.value_of("PASS").map(|pass| pass.parse().unwrap());
   cfg.stats_file = if cfg.pass.is_some() {
@@ -445,9 +945,63 @@ fn parse_config(matches: &ArgMatches<'_>) -> EncoderConfig {
   if cfg.tile_cols_log2 > 6 || cfg.tile_rows_log2 > 6 {
     panic!("Log2 of tile columns and rows may not be greater than 6");
   }
+  if let Some(cpu) = matches.value_of("CPU").filter(|&cpu| cpu != "auto") {
+    cfg.cpu_feature_level = cpu.parse::<CpuFeatureLevel>().unwrap();
+  }
+  cfg.tile_rate_control = matches.is_present("TILE_RATE_CONTROL");
+  cfg.large_scale_tile = matches.is_present("LARGE_SCALE_TILES");
+  cfg.tiles = matches.value_of("TILES").unwrap().parse().unwrap();
+  if let TileConfig::Count(tiles) = cfg.tiles {
+    if tiles > 4096 {
+      panic!("Number of tiles may not be greater than 4096");
+    }
+  }
 
+  if let Some(offsets) = matches.value_of("CHROMA_QP_OFFSET") {
+    let offsets: Vec<i8> = offsets.split(',').map(|offset| {
+      offset.parse().expect("Chroma quantizer offsets must be integers")
+    }).collect();
+    cfg.chroma_qp_offset = match offsets[..] {
+      [offset] => [offset, offset],
+      [cb, cr] => [cb, cr],
+      _ => panic!("Give one chroma quantizer offset, or one for Cb and one for Cr")
+    };
+    if cfg.chroma_qp_offset.iter().any(|&offset| offset < -64 || offset > 63) {
+      panic!("Chroma quantizer offsets must be between -64 and 63");
+    }
+  }
   cfg.low_latency = matches.is_present("LOW_LATENCY");
+  if let Some(max) = matches.value_of("MAX_REFERENCE_FRAMES") {
+    cfg.max_reference_frames =
+      max.parse().expect("Maximum number of reference frames must be an integer");
+  }
+  if let Some(layers) = matches.value_of("TEMPORAL_LAYERS") {
+    cfg.temporal_layers =
+      layers.parse().expect("Number of temporal layers must be an integer");
+  }
+  cfg.error_resilient = matches.is_present("ERROR_RESILIENT");
+  cfg.max_pixels = matches.value_of("MAX_PIXELS").map(|pixels| {
+    pixels.parse().expect("Maximum number of pixels must be an integer")
+  });
+  cfg.intra_refresh_period = matches.value_of("INTRA_REFRESH").map(|period| {
+    let period: u64 = period.parse().expect("Intra refresh period must be an integer");
+    if period == 0 {
+      panic!("Intra refresh period must be greater than 0");
+    }
+    period
+  });
   cfg.train_rdo = train_rdo;
+  cfg.deterministic = matches.is_present("DETERMINISTIC");
+  if let Some(seed) = matches.value_of("SEED") {
+    cfg.seed = seed.parse().expect("Seed must be an integer");
+  }
+  cfg.dry_run = matches.is_present("DRY_RUN");
+  cfg.rec_crc = matches.is_present("SELF_CHECK");
+  // Keyframes placed on scene changes would differ between the color and
+  // alpha streams
+  if matches.is_present("ALPHA") {
+    cfg.speed_settings.no_scene_detection = true;
+  }
   cfg
 }
 
@@ -475,7 +1029,10 @@ fn apply_speed_test_cfg(cfg: &mut EncoderConfig, setting: &str) {
       cfg.speed_settings.fast_deblock = true;
     },
     "reduced_tx_set" => {
-      cfg.speed_settings.reduced_tx_set = true;
+      cfg.speed_settings.reduced_tx_set = ReducedTxSet::On;
+    },
+    "reduced_tx_set_auto" => {
+      cfg.speed_settings.reduced_tx_set = ReducedTxSet::Auto;
     },
     "tx_domain_distortion" => {
       cfg.speed_settings.tx_domain_distortion = true;
@@ -502,34 +1059,81 @@ fn apply_speed_test_cfg(cfg: &mut EncoderConfig, setting: &str) {
       cfg.speed_settings.no_scene_detection = true;
     },
     "diamond_me" => {
-      cfg.speed_settings.diamond_me = true;
+      cfg.speed_settings.me_method = MotionSearch::Diamond;
+    }
+    "hex_me" => {
+      cfg.speed_settings.me_method = MotionSearch::Hex;
+    }
+    "me_range_64" => {
+      cfg.speed_settings.me_range = 64;
+    }
+    "me_downsampled" => {
+      cfg.speed_settings.me_downsampled = true;
     }
     "cdef" => {
       cfg.speed_settings.cdef = true;
     }
+    "fast_cdef" => {
+      cfg.speed_settings.fast_cdef = true;
+    }
+    "rdoq" => {
+      cfg.speed_settings.rdoq = true;
+    }
+    "angle_delta_search" => {
+      cfg.speed_settings.angle_delta_search = true;
+    }
+    "var_tx" => {
+      cfg.speed_settings.var_tx = true;
+    }
+    "partition_heuristic" => {
+      cfg.speed_settings.partition_heuristic = true;
+    }
+    "filter_aware_distortion" => {
+      cfg.speed_settings.filter_aware_distortion = true;
+    }
+    "exact_rate" => {
+      cfg.speed_settings.exact_rate = true;
+    }
+    "skip_zero_residual" => {
+      cfg.speed_settings.skip_zero_residual = true;
+    }
     setting => {
       panic!("Unrecognized speed test setting {}", setting);
     }
   };
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct FrameSummary {
   // Frame size in bytes
   pub size: usize,
-  pub number: u64,
+  // Number of the frame shown in display order
+  pub number: Option<u64>,
   pub frame_type: FrameType,
+  // Base quantizer index
+  pub qp: u8,
+  pub pyramid_level: u64,
+  pub bits: FrameBits,
   // PSNR for Y, U, and V planes
   pub psnr: Option<(f64, f64, f64)>,
+  // Whether the frame was coded in an earlier packet
+  pub show_existing_frame: bool,
+  // Frames coded in the packet but not shown
+  pub hidden_frames: Vec<HiddenFrame>,
 }
 
 impl<T: Pixel> From<Packet<T>> for FrameSummary {
   fn from(packet: Packet<T>) -> Self {
     Self {
       size: packet.data.len(),
-      number: packet.number,
+      number: packet.input_frameno,
       frame_type: packet.frame_type,
+      qp: packet.qp,
+      pyramid_level: packet.pyramid_level,
+      bits: packet.bits,
       psnr: packet.psnr,
+      show_existing_frame: packet.show_existing_frame,
+      hidden_frames: packet.hidden_frames,
     }
   }
 }
@@ -538,10 +1142,15 @@ impl fmt::Display for FrameSummary {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     write!(
       f,
-      "Frame {} - {} - {} bytes{}",
-      self.number,
+      "Frame {} - {} - {} bytes (header {} / coeffs {} / modes {} bits) - qp {} - level {}{}",
+      self.number.map_or("hidden".to_string(), |number| number.to_string()),
       self.frame_type,
       self.size,
+      self.bits.header,
+      self.bits.coefficients,
+      self.bits.modes,
+      self.qp,
+      self.pyramid_level,
       if let Some(psnr) = self.psnr {
         format!(" - PSNR: Y: {:.4}  Cb: {:.4}  Cr: {:.4}", psnr.0, psnr.1, psnr.2)
       } else { String::new() }
@@ -566,10 +1175,19 @@ pub struct ProgressInfo {
   encoded_size: usize,
   // Whether to display PSNR statistics during and at end of encode
   show_psnr: bool,
+  // Statistics per frame type and bitrate of the last seconds
+  stats: EncodeStats,
+  // Interval between two summaries printed during the encode
+  summary_interval: Option<Duration>,
+  // The time the last summary was printed
+  last_summary: Instant,
 }
 
 impl ProgressInfo {
-  pub fn new(frame_rate: Rational, total_frames: Option<usize>, show_psnr: bool) -> Self {
+  pub fn new(
+    frame_rate: Rational, total_frames: Option<usize>, show_psnr: bool,
+    summary_interval: Option<Duration>
+  ) -> Self {
     Self {
       frame_rate,
       total_frames,
@@ -577,14 +1195,29 @@ impl ProgressInfo {
       frame_info: Vec::with_capacity(total_frames.unwrap_or_default()),
       encoded_size: 0,
       show_psnr,
+      stats: EncodeStats::new(frame_rate.num as f64 / frame_rate.den as f64),
+      summary_interval,
+      last_summary: Instant::now(),
     }
   }
 
   pub fn add_frame(&mut self, frame: FrameSummary) {
     self.encoded_size += frame.size;
+    self.stats.add_frame(&frame);
     self.frame_info.push(frame);
   }
 
+  // Returns the statistics per frame type if `summary_interval` elapsed
+  // since they were last returned
+  pub fn periodic_summary(&mut self) -> Option<String> {
+    let interval = self.summary_interval?;
+    if self.last_summary.elapsed() < interval {
+      return None;
+    }
+    self.last_summary = Instant::now();
+    Some(self.stats.to_string())
+  }
+
   pub fn frames_encoded(&self) -> usize {
     self.frame_info.len()
   }
@@ -634,6 +1267,76 @@ impl ProgressInfo {
       .sum()
   }
 
+  // Size in bytes of the coefficients of each plane over all frames
+  pub fn get_plane_coefficients_size(&self) -> [usize; 3] {
+    let mut size = [0; 3];
+    for frame in self.frame_info.iter() {
+      for p in 0..3 {
+        size[p] += frame.bits.plane_coefficients[p];
+      }
+    }
+    [size[0] / 8, size[1] / 8, size[2] / 8]
+  }
+
+  // Mean PSNR of the Y, U and V planes over all frames, if computed
+  fn mean_psnr(&self) -> Option<(f64, f64, f64)> {
+    if !self.show_psnr || self.frame_info.is_empty() {
+      return None;
+    }
+    let n = self.frame_info.len() as f64;
+    let sum = self.frame_info.iter().filter_map(|fi| fi.psnr)
+      .fold((0., 0., 0.), |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2));
+    Some((sum.0 / n, sum.1 / n, sum.2 / n))
+  }
+
+  // The report of `--report`: the frames in output order, and the totals
+  // of the summary
+  pub fn report(&self) -> serde_json::Value {
+    let psnr = |psnr: Option<(f64, f64, f64)>| psnr.map(|(y, u, v)| {
+      serde_json::json!({
+        "y": y, "u": u, "v": v, "weighted": weighted_psnr((y, u, v))
+      })
+    });
+    let frames: Vec<_> = self.frame_info.iter().map(|frame| {
+      serde_json::json!({
+        "number": frame.number,
+        "frame_type": frame.frame_type.to_string(),
+        "size": frame.size,
+        "qp": frame.qp,
+        "pyramid_level": frame.pyramid_level,
+        "show_existing_frame": frame.show_existing_frame,
+        "hidden_frames": frame.hidden_frames.len(),
+        "bits": {
+          "header": frame.bits.header,
+          "coefficients": frame.bits.coefficients,
+          "plane_coefficients": frame.bits.plane_coefficients,
+          "modes": frame.bits.modes
+        },
+        "psnr": psnr(frame.psnr)
+      })
+    }).collect();
+    serde_json::json!({
+      "frames": frames,
+      "summary": {
+        "frames": self.frames_encoded(),
+        "size": self.encoded_size,
+        "bitrate": self.bitrate(),
+        "plane_coefficients_size": self.get_plane_coefficients_size(),
+        "psnr": psnr(self.mean_psnr())
+      }
+    })
+  }
+
+  fn coefficient_summary(&self) -> String {
+    let size = self.get_plane_coefficients_size();
+    let total = self.frame_info.iter().map(|frame| frame.size).sum::<usize>();
+    let percent = |bytes: usize| 100. * bytes as f64 / total.max(1) as f64;
+    format!("\nCoefficients: Y: {} B ({:.1}%)  Cb: {} B ({:.1}%)  Cr: {} B ({:.1}%)",
+            size[0], percent(size[0]),
+            size[1], percent(size[1]),
+            size[2], percent(size[2]))
+  }
+
   pub fn print_summary(&self) -> String {
     let (key, key_size) = (
       self.get_frame_type_count(FrameType::KEY),
@@ -661,21 +1364,13 @@ impl ProgressInfo {
       inter, inter_size.checked_div(inter).unwrap_or(0),
       ionly, ionly_size / key,
       switch, switch_size / key,
-      if self.show_psnr {
-        let psnr_y =
-          self.frame_info.iter().map(|fi| fi.psnr.unwrap().0).sum::<f64>()
-            / self.frame_info.len() as f64;
-        let psnr_u =
-          self.frame_info.iter().map(|fi| fi.psnr.unwrap().1).sum::<f64>()
-            / self.frame_info.len() as f64;
-        let psnr_v =
-          self.frame_info.iter().map(|fi| fi.psnr.unwrap().2).sum::<f64>()
-            / self.frame_info.len() as f64;
-        format!("\nMean PSNR: Y: {:.4}  Cb: {:.4}  Cr: {:.4}  Avg: {:.4}",
+      if let Some((psnr_y, psnr_u, psnr_v)) = self.mean_psnr() {
+        format!("\nMean PSNR: Y: {:.4}  Cb: {:.4}  Cr: {:.4}  Avg: {:.4}  Weighted: {:.4}",
                 psnr_y, psnr_u, psnr_v,
-                (psnr_y + psnr_u + psnr_v) / 3.0)
+                (psnr_y + psnr_u + psnr_v) / 3.0,
+                weighted_psnr((psnr_y, psnr_u, psnr_v)))
       } else { String::new() }
-    )
+    ) + &self.coefficient_summary() + "\n\n" + &self.stats.to_string()
   }
 }
 
@@ -684,21 +1379,23 @@ impl fmt::Display for ProgressInfo {
     if let Some(total_frames) = self.total_frames {
       write!(
         f,
-        "encoded {}/{} frames, {:.3} fps, {:.2} Kb/s, est. size: {:.2} MB, est. time: {:.0} s",
+        "encoded {}/{} frames, {:.3} fps, {:.2} Kb/s (last 5 s: {:.2} Kb/s), est. size: {:.2} MB, est. time: {:.0} s",
         self.frames_encoded(),
         total_frames,
         self.encoding_fps(),
         self.bitrate() as f64 / 1024f64,
+        self.stats.window_bitrate() as f64 / 1024f64,
         self.estimated_size() as f64 / (1024 * 1024) as f64,
         self.estimated_time()
       )
     } else {
       write!(
         f,
-        "encoded {} frames, {:.3} fps, {:.2} Kb/s",
+        "encoded {} frames, {:.3} fps, {:.2} Kb/s (last 5 s: {:.2} Kb/s)",
         self.frames_encoded(),
         self.encoding_fps(),
-        self.bitrate() as f64 / 1024f64
+        self.bitrate() as f64 / 1024f64,
+        self.stats.window_bitrate() as f64 / 1024f64
       )
     }
   }
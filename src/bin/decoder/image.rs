@@ -0,0 +1,329 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Input from a sequence of numbered PNG files, converted from RGB to YUV
+//! 4:2:0.
+
+use crate::decoder::{DecodeError, Decoder, VideoDetails};
+use rav1e::*;
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Conversion of RGB samples to YUV with the coefficients of a matrix,
+/// quantized to a range and bit depth
+#[derive(Clone, Copy, Debug)]
+pub struct RgbToYuv {
+  kr: f64,
+  kb: f64,
+  full_range: bool,
+  bit_depth: usize,
+}
+
+impl RgbToYuv {
+  /// Returns `None` for matrices other than BT.709, BT.601 (`BT470BG` and
+  /// `ST170M`) and BT.2020 with non-constant luminance. An unspecified
+  /// range is limited.
+  pub fn new(
+    matrix: MatrixCoefficients, range: PixelRange, bit_depth: usize
+  ) -> Option<Self> {
+    let (kr, kb) = match matrix {
+      MatrixCoefficients::BT709 => (0.2126, 0.0722),
+      MatrixCoefficients::BT470BG | MatrixCoefficients::ST170M =>
+        (0.299, 0.114),
+      MatrixCoefficients::BT2020NonConstantLuminance => (0.2627, 0.0593),
+      _ => return None,
+    };
+    Some(RgbToYuv { kr, kb, full_range: range == PixelRange::Full, bit_depth })
+  }
+
+  /// Luma in 0-1 and chroma in -0.5-0.5 of RGB samples in 0-1
+  fn ycbcr(&self, [r, g, b]: [f64; 3]) -> (f64, f64, f64) {
+    let y = self.kr * r + (1. - self.kr - self.kb) * g + self.kb * b;
+    (y, (b - y) / (2. * (1. - self.kb)), (r - y) / (2. * (1. - self.kr)))
+  }
+
+  fn quantize(&self, v: f64, scale: f64, offset: f64) -> u16 {
+    let max = ((1 << self.bit_depth) - 1) as f64;
+    (v * scale + offset).round().max(0.).min(max) as u16
+  }
+
+  fn quantize_luma(&self, y: f64) -> u16 {
+    if self.full_range {
+      self.quantize(y, ((1 << self.bit_depth) - 1) as f64, 0.)
+    } else {
+      let shift = (1 << (self.bit_depth - 8)) as f64;
+      self.quantize(y, 219. * shift, 16. * shift)
+    }
+  }
+
+  fn quantize_chroma(&self, c: f64) -> u16 {
+    let offset = (1 << (self.bit_depth - 1)) as f64;
+    if self.full_range {
+      self.quantize(c, ((1 << self.bit_depth) - 1) as f64, offset)
+    } else {
+      self.quantize(c, 224. * (1 << (self.bit_depth - 8)) as f64, offset)
+    }
+  }
+
+  /// Y, U and V of one pixel of RGB samples in 0-1
+  pub fn convert_pixel(&self, rgb: [f64; 3]) -> [u16; 3] {
+    let (y, cb, cr) = self.ycbcr(rgb);
+    [self.quantize_luma(y), self.quantize_chroma(cb), self.quantize_chroma(cr)]
+  }
+
+  /// Converts an image of `width`x`height` RGB pixels with samples up to
+  /// `max` into planes of Y, U and V samples, the chroma of each U and V
+  /// sample averaged over the 2x2 pixels it covers
+  pub fn convert(
+    &self, rgb: &[[u16; 3]], width: usize, height: usize, max: u16
+  ) -> [Vec<u16>; 3] {
+    let (chroma_w, chroma_h) = ((width + 1) / 2, (height + 1) / 2);
+    let mut luma = Vec::with_capacity(width * height);
+    let mut cb_sum = vec![(0., 0.); chroma_w * chroma_h];
+    let mut count = vec![0u8; chroma_w * chroma_h];
+    for (i, pixel) in rgb.iter().enumerate().take(width * height) {
+      let normalized = [
+        f64::from(pixel[0]) / f64::from(max),
+        f64::from(pixel[1]) / f64::from(max),
+        f64::from(pixel[2]) / f64::from(max),
+      ];
+      let (y, cb, cr) = self.ycbcr(normalized);
+      luma.push(self.quantize_luma(y));
+      let c = (i / width / 2) * chroma_w + (i % width) / 2;
+      cb_sum[c].0 += cb;
+      cb_sum[c].1 += cr;
+      count[c] += 1;
+    }
+    let (mut u, mut v) = (Vec::new(), Vec::new());
+    for (&(cb, cr), &n) in cb_sum.iter().zip(count.iter()) {
+      u.push(self.quantize_chroma(cb / f64::from(n)));
+      v.push(self.quantize_chroma(cr / f64::from(n)));
+    }
+    [luma, u, v]
+  }
+}
+
+/// Path of the file numbered `index` from a pattern with one `%d` or
+/// `%0Nd` placeholder, `None` if it has none
+pub fn frame_path(pattern: &str, index: u64) -> Option<String> {
+  let start = pattern.find('%')?;
+  let spec = &pattern[start + 1..];
+  let end = spec.find('d')?;
+  let width = &spec[..end];
+  let number = match width {
+    "" => index.to_string(),
+    w if w.starts_with('0') => {
+      format!("{:01$}", index, w.parse::<usize>().ok()?)
+    }
+    _ => return None,
+  };
+  Some(format!("{}{}{}", &pattern[..start], number, &spec[end + 1..]))
+}
+
+/// Frames read from numbered PNG files, from 0 or 1 on until the first
+/// missing file
+pub struct ImageSequence {
+  pattern: String,
+  /// Number of the next file to read
+  next: u64,
+  /// Whether the images have 16-bit samples rather than 8-bit ones
+  high_bit_depth: bool,
+  conversion: RgbToYuv,
+  details: VideoDetails,
+}
+
+/// An RGB image with samples up to `max`
+struct RgbImage {
+  width: usize,
+  height: usize,
+  max: u16,
+  pixels: Vec<[u16; 3]>,
+}
+
+fn read_png(path: &Path) -> Result<RgbImage, DecodeError> {
+  let file = match File::open(path) {
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+      return Err(DecodeError::EOF)
+    }
+    file => file.map_err(DecodeError::IoError)?,
+  };
+  let mut decoder = png::Decoder::new(file);
+  // Palettes and bit depths below 8 are expanded to 8-bit samples
+  decoder.set_transformations(png::Transformations::EXPAND);
+  let (info, mut reader) =
+    decoder.read_info().map_err(|_| DecodeError::BadInput)?;
+  let mut data = vec![0; info.buffer_size()];
+  reader.next_frame(&mut data).map_err(|_| DecodeError::BadInput)?;
+
+  let (max, bytes) = match info.bit_depth {
+    png::BitDepth::Sixteen => (std::u16::MAX, 2),
+    _ => (std::u8::MAX as u16, 1),
+  };
+  let channels = info.color_type.samples();
+  let sample = |s: &[u8]| {
+    if bytes == 2 { u16::from(s[0]) << 8 | u16::from(s[1]) } else { u16::from(s[0]) }
+  };
+  let (width, height) = (info.width as usize, info.height as usize);
+  let pixels = data.chunks(info.line_size).take(height).flat_map(|row| {
+    row.chunks(channels * bytes).take(width).map(|p| {
+      // Gray is the same on all channels; alpha is ignored
+      if channels < 3 {
+        let v = sample(p);
+        [v, v, v]
+      } else {
+        [sample(p), sample(&p[bytes..]), sample(&p[2 * bytes..])]
+      }
+    }).collect::<Vec<_>>()
+  }).collect();
+  Ok(RgbImage { width, height, max, pixels })
+}
+
+impl ImageSequence {
+  /// Opens the sequence of files named after `pattern`, to convert with
+  /// `matrix` to `range`. 16-bit images are converted at `high_bit_depth`,
+  /// 8-bit ones at 8 bits.
+  pub fn open(
+    pattern: &str, matrix: MatrixCoefficients, range: PixelRange,
+    high_bit_depth: usize, time_base: Rational
+  ) -> Result<Self, DecodeError> {
+    frame_path(pattern, 0).ok_or(DecodeError::ParseError)?;
+    let first = (0..2)
+      .find(|&i| Path::new(&frame_path(pattern, i).unwrap()).exists())
+      .ok_or(DecodeError::EOF)?;
+    let image = read_png(Path::new(&frame_path(pattern, first).unwrap()))?;
+    let bit_depth = if image.max > 255 { high_bit_depth } else { 8 };
+    let conversion = RgbToYuv::new(matrix, range, bit_depth)
+      .ok_or(DecodeError::UnknownColorspace)?;
+    Ok(ImageSequence {
+      pattern: pattern.to_string(),
+      next: first,
+      high_bit_depth: image.max > 255,
+      conversion,
+      details: VideoDetails {
+        width: image.width,
+        height: image.height,
+        bit_depth,
+        chroma_sampling: ChromaSampling::Cs420,
+        chroma_sample_position: ChromaSamplePosition::Unknown,
+        time_base,
+      },
+    })
+  }
+
+  fn next_path(&mut self) -> Result<String, DecodeError> {
+    let path = frame_path(&self.pattern, self.next).unwrap();
+    if !Path::new(&path).exists() {
+      return Err(DecodeError::EOF);
+    }
+    self.next += 1;
+    Ok(path)
+  }
+}
+
+impl Decoder for ImageSequence {
+  fn get_video_details(&self) -> VideoDetails {
+    self.details
+  }
+
+  fn read_frame<T: Pixel>(
+    &mut self, cfg: &VideoDetails
+  ) -> Result<Frame<T>, DecodeError> {
+    let path = self.next_path()?;
+    let image = read_png(Path::new(&path))?;
+    if (image.width, image.height) != (cfg.width, cfg.height)
+      || (image.max > 255) != self.high_bit_depth {
+      return Err(DecodeError::BadInput);
+    }
+    let planes =
+      self.conversion.convert(&image.pixels, image.width, image.height, image.max);
+    let mut f: Frame<T> =
+      Frame::try_new(cfg.width, cfg.height, cfg.chroma_sampling)
+        .map_err(|_| DecodeError::OutOfMemory)?;
+    let bytes = if self.details.bit_depth > 8 { 2 } else { 1 };
+    for (i, (plane, samples)) in f.planes.iter_mut().zip(planes.iter()).enumerate() {
+      let width = if i == 0 { cfg.width } else { (cfg.width + 1) / 2 };
+      let data: Vec<u8> = samples.iter().flat_map(|&s| {
+        let le = [s as u8, (s >> 8) as u8];
+        le[..bytes].to_vec()
+      }).collect();
+      plane.copy_from_raw_u8(&data, width * bytes, bytes);
+    }
+    Ok(f)
+  }
+
+  fn skip_frame(&mut self) -> Result<(), DecodeError> {
+    self.next_path().map(|_| ())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn known_conversions() {
+    let bt709 =
+      RgbToYuv::new(MatrixCoefficients::BT709, PixelRange::Limited, 8).unwrap();
+    assert_eq!(bt709.convert_pixel([1., 1., 1.]), [235, 128, 128]);
+    assert_eq!(bt709.convert_pixel([0., 0., 0.]), [16, 128, 128]);
+    assert_eq!(bt709.convert_pixel([1., 0., 0.]), [63, 102, 240]);
+    assert_eq!(bt709.convert_pixel([0., 1., 0.]), [173, 42, 26]);
+    assert_eq!(bt709.convert_pixel([0., 0., 1.]), [32, 240, 118]);
+
+    let bt601 =
+      RgbToYuv::new(MatrixCoefficients::BT470BG, PixelRange::Limited, 8).unwrap();
+    assert_eq!(bt601.convert_pixel([1., 0., 0.]), [81, 90, 240]);
+    assert_eq!(bt601.convert_pixel([0., 1., 0.]), [145, 54, 34]);
+
+    let full =
+      RgbToYuv::new(MatrixCoefficients::BT709, PixelRange::Full, 8).unwrap();
+    assert_eq!(full.convert_pixel([1., 1., 1.]), [255, 128, 128]);
+    assert_eq!(full.convert_pixel([0., 1., 0.]), [182, 30, 12]);
+
+    let bt709_10 =
+      RgbToYuv::new(MatrixCoefficients::BT709, PixelRange::Limited, 10).unwrap();
+    assert_eq!(bt709_10.convert_pixel([1., 1., 1.]), [940, 512, 512]);
+    assert_eq!(bt709_10.convert_pixel([0., 0., 0.]), [64, 512, 512]);
+    assert_eq!(bt709_10.convert_pixel([1., 0., 0.]), [250, 409, 960]);
+
+    assert!(
+      RgbToYuv::new(MatrixCoefficients::YCgCo, PixelRange::Limited, 8).is_none()
+    );
+  }
+
+  #[test]
+  fn chroma_averaged() {
+    let bt709 =
+      RgbToYuv::new(MatrixCoefficients::BT709, PixelRange::Limited, 8).unwrap();
+    // Red and blue columns, with an odd width and height
+    let rgb: Vec<_> = (0..9).map(|i| {
+      if i % 3 == 1 { [0, 0, 255] } else { [255, 0, 0] }
+    }).collect();
+    let [y, u, v] = bt709.convert(&rgb, 3, 3, 255);
+    assert_eq!(y, vec![63, 32, 63, 63, 32, 63, 63, 32, 63]);
+    // The mean of the chroma of red and blue, then that of red alone
+    let (_, cb_red, cr_red) = bt709.ycbcr([1., 0., 0.]);
+    let (_, cb_blue, cr_blue) = bt709.ycbcr([0., 0., 1.]);
+    let mixed_u = bt709.quantize_chroma((cb_red + cb_blue) / 2.);
+    let mixed_v = bt709.quantize_chroma((cr_red + cr_blue) / 2.);
+    assert_eq!(u, vec![mixed_u, 102, mixed_u, 102]);
+    assert_eq!(v, vec![mixed_v, 240, mixed_v, 240]);
+  }
+
+  #[test]
+  fn frame_paths() {
+    assert_eq!(frame_path("frame_%06d.png", 42).unwrap(), "frame_000042.png");
+    assert_eq!(frame_path("out/%d.png", 1234).unwrap(), "out/1234.png");
+    assert_eq!(frame_path("%03d", 12345).unwrap(), "12345");
+    assert!(frame_path("frame.png", 0).is_none());
+    assert!(frame_path("frame_%6d.png", 0).is_none());
+  }
+}
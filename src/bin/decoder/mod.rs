@@ -1,12 +1,16 @@
-use std::io;
+use std::{fmt, io};
 use rav1e::*;
 
 pub mod y4m;
+#[cfg(feature = "image")]
+pub mod image;
 
 
 pub trait Decoder {
   fn get_video_details(&self) -> VideoDetails;
   fn read_frame<T: Pixel>(&mut self, cfg: &VideoDetails) -> Result<Frame<T>, DecodeError>;
+  /// Reads past the next frame without decoding it when possible
+  fn skip_frame(&mut self) -> Result<(), DecodeError>;
 }
 
 #[derive(Debug)]
@@ -16,6 +20,21 @@ pub enum DecodeError {
   UnknownColorspace,
   ParseError,
   IoError(io::Error),
+  /// The frame could not be allocated
+  OutOfMemory,
+  /// The input continues with other parameters, those of the frames read
+  /// from then on
+  ParametersChanged(VideoDetails),
+}
+
+impl From<io::Error> for DecodeError {
+  fn from(e: io::Error) -> DecodeError {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+      DecodeError::EOF
+    } else {
+      DecodeError::IoError(e)
+    }
+  }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -28,6 +47,34 @@ pub struct VideoDetails {
   pub time_base: Rational,
 }
 
+impl VideoDetails {
+  /// Whether the frames have the same size, format and frame rate
+  pub fn same_parameters(&self, other: &VideoDetails) -> bool {
+    self.width == other.width
+      && self.height == other.height
+      && self.bit_depth == other.bit_depth
+      && self.chroma_sampling == other.chroma_sampling
+      && self.chroma_sample_position == other.chroma_sample_position
+      && self.time_base.num == other.time_base.num
+      && self.time_base.den == other.time_base.den
+  }
+}
+
+impl fmt::Display for VideoDetails {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{}x{} {}-bit {:?} @ {}/{} fps",
+      self.width,
+      self.height,
+      self.bit_depth,
+      self.chroma_sampling,
+      self.time_base.den,
+      self.time_base.num
+    )
+  }
+}
+
 impl Default for VideoDetails {
   fn default() -> Self {
     VideoDetails {
@@ -1,4 +1,5 @@
-use std::io::Read;
+use std::io;
+use std::io::{BufRead, BufReader, Cursor, Read};
 
 use rav1e::Rational;
 use crate::decoder::DecodeError;
@@ -9,31 +10,41 @@ use crate::ChromaSampling;
 use crate::encoder::Frame;
 use rav1e::*;
 
+/// Longest header line read, parameters included
+const MAX_LINE_LEN: usize = 4096;
+
+fn video_details<R: Read>(dec: &y4m::Decoder<'_, R>) -> VideoDetails {
+  let width = dec.get_width();
+  let height = dec.get_height();
+  let color_space = dec.get_colorspace();
+  let bit_depth = color_space.get_bit_depth();
+  let (chroma_sampling, chroma_sample_position) = map_y4m_color_space(color_space);
+  let framerate = dec.get_framerate();
+  let time_base =  Rational::new(framerate.den as u64, framerate.num as u64);
+
+  VideoDetails {
+    width,
+    height,
+    bit_depth,
+    chroma_sampling,
+    chroma_sample_position,
+    time_base,
+  }
+}
+
 impl Decoder for y4m::Decoder<'_, Box<dyn Read>> {
   fn get_video_details(&self) -> VideoDetails {
-    let width = self.get_width();
-    let height = self.get_height();
-    let color_space = self.get_colorspace();
-    let bit_depth = color_space.get_bit_depth();
-    let (chroma_sampling, chroma_sample_position) = map_y4m_color_space(color_space);
-    let framerate = self.get_framerate();
-    let time_base =  Rational::new(framerate.den as u64, framerate.num as u64);
-
-    VideoDetails {
-      width,
-      height,
-      bit_depth,
-      chroma_sampling,
-      chroma_sample_position,
-      time_base,
-    }
+    video_details(self)
   }
 
   fn read_frame<T: Pixel>(&mut self, cfg: &VideoDetails) -> Result<Frame<T>, DecodeError> {
     let bytes = self.get_bytes_per_sample();
     self.read_frame()
-      .map(|frame| {
-        let mut f: Frame<T> = Frame::new(cfg.width, cfg.height, cfg.chroma_sampling);
+      .map_err(Into::into)
+      .and_then(|frame| {
+        let mut f: Frame<T> =
+          Frame::try_new(cfg.width, cfg.height, cfg.chroma_sampling)
+            .map_err(|_| DecodeError::OutOfMemory)?;
 
         let (chroma_period, _) = cfg.chroma_sampling.sampling_period();
 
@@ -48,9 +59,150 @@ impl Decoder for y4m::Decoder<'_, Box<dyn Read>> {
           cfg.width * bytes / chroma_period,
           bytes
         );
-        f
+        Ok(f)
       })
-      .map_err(Into::into)
+  }
+
+  fn skip_frame(&mut self) -> Result<(), DecodeError> {
+    self.read_frame().map(|_| ()).map_err(Into::into)
+  }
+}
+
+/// Reader of y4m streams following each other, e.g. concatenated files,
+/// whose frames are read with the parameters of the last stream header.
+///
+/// A header with the same parameters as the previous one is skipped, while
+/// `read_frame` and `skip_frame` return `DecodeError::ParametersChanged`
+/// once at one with other parameters.
+pub struct Y4mInput {
+  reader: BufReader<Box<dyn Read>>,
+  color_space: y4m::Colorspace,
+  details: VideoDetails,
+  /// Bytes per sample
+  bytes: usize,
+  line: Vec<u8>,
+}
+
+impl Y4mInput {
+  /// Reads the header of the first stream
+  pub fn new(reader: Box<dyn Read>) -> Result<Self, DecodeError> {
+    let mut input = Y4mInput {
+      reader: BufReader::new(reader),
+      color_space: y4m::Colorspace::C420,
+      details: Default::default(),
+      bytes: 1,
+      line: Vec::new(),
+    };
+    if !input.read_line()? {
+      return Err(DecodeError::EOF);
+    }
+    input.parse_header()?;
+    Ok(input)
+  }
+
+  pub fn get_colorspace(&self) -> y4m::Colorspace {
+    self.color_space
+  }
+
+  /// Reads the next line, returning `false` at the end of the input
+  fn read_line(&mut self) -> Result<bool, DecodeError> {
+    self.line.clear();
+    let len = (&mut self.reader)
+      .take(MAX_LINE_LEN as u64)
+      .read_until(b'\n', &mut self.line)?;
+    if len == 0 {
+      return Ok(false);
+    }
+    if self.line.pop() != Some(b'\n') {
+      return Err(DecodeError::ParseError);
+    }
+    Ok(true)
+  }
+
+  /// Parses the stream header in the last line read
+  fn parse_header(&mut self) -> Result<(), DecodeError> {
+    let mut header = Cursor::new([&self.line[..], &b"\n"[..]].concat());
+    let dec = y4m::decode(&mut header)?;
+    self.color_space = dec.get_colorspace();
+    self.details = video_details(&dec);
+    self.bytes = dec.get_bytes_per_sample();
+    Ok(())
+  }
+
+  /// Reads up to the data of the next frame, past the stream headers
+  fn next_frame(&mut self) -> Result<(), DecodeError> {
+    loop {
+      if !self.read_line()? {
+        return Err(DecodeError::EOF);
+      }
+      if self.line.starts_with(b"FRAME")
+        && (self.line.len() == 5 || self.line[5] == b' ')
+      {
+        return Ok(());
+      }
+      if !self.line.starts_with(b"YUV4MPEG2 ") {
+        return Err(DecodeError::ParseError);
+      }
+      let previous = self.details;
+      self.parse_header()?;
+      if !self.details.same_parameters(&previous) {
+        return Err(DecodeError::ParametersChanged(self.details));
+      }
+    }
+  }
+
+  /// Sizes of the luma and chroma planes in samples
+  fn plane_sizes(&self) -> ((usize, usize), (usize, usize)) {
+    let VideoDetails { width, height, .. } = self.details;
+    let chroma = match self.details.chroma_sampling {
+      ChromaSampling::Cs420 => ((width + 1) / 2, (height + 1) / 2),
+      ChromaSampling::Cs422 => ((width + 1) / 2, height),
+      ChromaSampling::Cs444 => (width, height),
+      ChromaSampling::Cs400 => (0, 0),
+    };
+    ((width, height), chroma)
+  }
+}
+
+impl Decoder for Y4mInput {
+  /// Details of the last stream
+  fn get_video_details(&self) -> VideoDetails {
+    self.details
+  }
+
+  /// Reads a frame of the size and format of the last stream, whatever
+  /// `cfg` is
+  fn read_frame<T: Pixel>(&mut self, _cfg: &VideoDetails) -> Result<Frame<T>, DecodeError> {
+    self.next_frame()?;
+    let ((width, height), (chroma_width, chroma_height)) = self.plane_sizes();
+    let bytes = self.bytes;
+    let mut f: Frame<T> =
+      Frame::try_new(width, height, self.details.chroma_sampling)
+        .map_err(|_| DecodeError::OutOfMemory)?;
+
+    let mut data = vec![0; width * height * bytes];
+    self.reader.read_exact(&mut data)?;
+    f.planes[0].copy_from_raw_u8(&data, width * bytes, bytes);
+    if chroma_width > 0 {
+      let mut data = vec![0; chroma_width * chroma_height * bytes];
+      for plane in f.planes[1..].iter_mut() {
+        self.reader.read_exact(&mut data)?;
+        plane.copy_from_raw_u8(&data, chroma_width * bytes, bytes);
+      }
+    }
+    Ok(f)
+  }
+
+  fn skip_frame(&mut self) -> Result<(), DecodeError> {
+    self.next_frame()?;
+    let ((width, height), (chroma_width, chroma_height)) = self.plane_sizes();
+    let len = (width * height + 2 * chroma_width * chroma_height) * self.bytes;
+    let skipped =
+      io::copy(&mut (&mut self.reader).take(len as u64), &mut io::sink())?;
+    if skipped < len as u64 {
+      return Err(DecodeError::EOF);
+    }
+    Ok(())
   }
 }
 
@@ -66,6 +218,41 @@ impl From<y4m::Error> for DecodeError {
   }
 }
 
+/// Returns the colorspace with the sampling of `color_space` at `bit_depth`,
+/// `None` if y4m has none, as for monochrome above 8 bits
+pub fn promote_color_space(
+  color_space: y4m::Colorspace, bit_depth: usize
+) -> Option<y4m::Colorspace> {
+  use y4m::Colorspace::*;
+  match (map_y4m_color_space(color_space).0, bit_depth) {
+    (_, 8) => Some(color_space),
+    (ChromaSampling::Cs420, 10) => Some(C420p10),
+    (ChromaSampling::Cs420, 12) => Some(C420p12),
+    (ChromaSampling::Cs422, 10) => Some(C422p10),
+    (ChromaSampling::Cs422, 12) => Some(C422p12),
+    (ChromaSampling::Cs444, 10) => Some(C444p10),
+    (ChromaSampling::Cs444, 12) => Some(C444p12),
+    _ => None
+  }
+}
+
+/// Returns the 8-bit colorspace of a chroma sampling and sample position
+pub fn y4m_color_space(
+  chroma_sampling: ChromaSampling, chroma_sample_position: ChromaSamplePosition
+) -> y4m::Colorspace {
+  use y4m::Colorspace::*;
+  use crate::ChromaSampling::*;
+  use crate::ChromaSamplePosition::*;
+  match (chroma_sampling, chroma_sample_position) {
+    (Cs400, _) => Cmono,
+    (Cs420, Vertical) => C420mpeg2,
+    (Cs420, Colocated) => C420,
+    (Cs420, _) => C420jpeg,
+    (Cs422, _) => C422,
+    (Cs444, _) => C444,
+  }
+}
+
 pub fn map_y4m_color_space(
   color_space: y4m::Colorspace
 ) -> (ChromaSampling, ChromaSamplePosition) {
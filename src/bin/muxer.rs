@@ -8,12 +8,71 @@
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
 use crate::decoder::VideoDetails;
+use std::fs::File;
+use std::io;
 use std::io::Write;
 use std::slice;
 use rav1e::*;
 
 pub use ivf::*;
 
+/// Writes the packets of the encoder into a container
+pub trait Muxer {
+  fn write_header(&mut self, info: &ContainerInfo);
+  fn write_frame(&mut self, pts: u64, data: &[u8]);
+  fn flush(&mut self) -> io::Result<()>;
+  /// Completes the container once every frame is written
+  fn finalize(&mut self);
+}
+
+pub struct IvfMuxer {
+  output: Box<dyn Write>,
+  /// The output file, whose header gets the frame count when finalized.
+  /// Streams such as stdout keep a count of 0.
+  file: Option<File>,
+  frames: u32,
+}
+
+impl IvfMuxer {
+  /// Opens the file at `path`, or stdout for `-`
+  pub fn open(path: &str) -> io::Result<IvfMuxer> {
+    if path == "-" {
+      return Ok(IvfMuxer { output: Box::new(io::stdout()), file: None, frames: 0 });
+    }
+    let file = File::create(path)?;
+    Ok(IvfMuxer { output: Box::new(file.try_clone()?), file: Some(file), frames: 0 })
+  }
+}
+
+impl Muxer for IvfMuxer {
+  fn write_header(&mut self, info: &ContainerInfo) {
+    write_ivf_header(
+      &mut self.output,
+      &info.fourcc,
+      info.width,
+      info.height,
+      info.time_base.den as usize,
+      info.time_base.num as usize
+    );
+  }
+
+  fn write_frame(&mut self, pts: u64, data: &[u8]) {
+    write_ivf_frame(&mut self.output, pts, data);
+    self.frames += 1;
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.output.flush()
+  }
+
+  fn finalize(&mut self) {
+    self.output.flush().unwrap();
+    if let Some(ref mut file) = self.file {
+      update_ivf_frame_count(file, self.frames).unwrap();
+    }
+  }
+}
+
 pub fn write_y4m_frame<T: Pixel>(y4m_enc: &mut y4m::Encoder<'_, Box<dyn Write>>, rec: &rav1e::Frame<T>, y4m_details: VideoDetails) {
   let pitch_y = if y4m_details.bit_depth > 8 { y4m_details.width * 2 } else { y4m_details.width };
   let chroma_sampling_period = y4m_details.chroma_sampling.sampling_period();
@@ -92,3 +151,14 @@ pub fn write_y4m_frame<T: Pixel>(y4m_enc: &mut y4m::Encoder<'_, Box<dyn Write>>,
   let rec_frame = y4m::Frame::new([&rec_y, &rec_u, &rec_v], None);
   y4m_enc.write_frame(&rec_frame).unwrap();
 }
+
+/// Writes the MD5 of a frame shown as `aomdec --rawvideo --md5 -o %4`
+/// prints it, `number` counting the frames from 1
+pub fn write_frame_hash<T: Pixel>(
+  out: &mut dyn Write, rec: &rav1e::Frame<T>, number: u64,
+  details: VideoDetails
+) {
+  let md5 = rec.md5(details.width, details.height);
+  let hex: String = md5.iter().map(|b| format!("{:02x}", b)).collect();
+  writeln!(out, "{}  {:04}", hex, number).unwrap();
+}
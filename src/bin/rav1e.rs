@@ -15,27 +15,54 @@ extern crate scan_fmt;
 mod common;
 mod decoder;
 mod muxer;
+mod stats;
 use crate::common::*;
 use crate::muxer::*;
 use rav1e::*;
+use rav1e::plane::PlaneConfig;
+use rav1e::prefilter::{AnyFrameFilter, Sharpen};
 
 use std::io;
 use std::io::Write;
 use std::io::Read;
 use std::path::Path;
-use std::sync::Arc;
-use crate::decoder::Decoder;
+use std::sync::{Arc, Mutex};
+use crate::decoder::{DecodeError, Decoder};
 use crate::decoder::VideoDetails;
+use crate::decoder::y4m::{
+  map_y4m_color_space, promote_color_space, y4m_color_space, Y4mInput
+};
+#[cfg(feature = "image")]
+use crate::decoder::image::ImageSequence;
 use std::fs::File;
 use std::io::BufWriter;
 
 struct Source<D: Decoder> {
  limit: usize,
  count: usize,
+ /// Number of frames read before the current encode, the outputs split on
+ /// input changes numbering their frames after the previous ones
+ start: usize,
  input: D,
+ video_info: VideoDetails,
+ /// Details of the input when the encode started
+ input_details: VideoDetails,
+ on_change: InputChange,
+ /// Details the input changed to, ending the encode
+ changed: Option<VideoDetails>,
 }
 
 impl<D: Decoder> Source<D> {
+  fn new(
+    input: D, limit: usize, video_info: VideoDetails, on_change: InputChange
+  ) -> Self {
+    let input_details = input.get_video_details();
+    Source {
+      limit, count: 0, start: 0, input, video_info, input_details, on_change,
+      changed: None
+    }
+  }
+
   fn read_frame<T: Pixel>(&mut self, ctx: &mut Context<T>, video_info: VideoDetails) {
     if self.limit != 0 && self.count == self.limit {
       ctx.flush();
@@ -48,32 +75,154 @@ impl<D: Decoder> Source<D> {
           _ => panic!("unknown input bit depth!")
         }
         self.count += 1;
+        let frame = self.scale_frame(frame, video_info);
         let _ = ctx.send_frame(Some(Arc::new(frame)));
       }
+      Err(DecodeError::OutOfMemory) => {
+        panic!("Failed to allocate an input frame of {}x{}", video_info.width, video_info.height);
+      }
+      Err(DecodeError::ParametersChanged(details)) => {
+        let resized = VideoDetails {
+          width: self.input_details.width,
+          height: self.input_details.height,
+          ..details
+        };
+        if self.on_change == InputChange::Scale
+          && resized.same_parameters(&self.input_details)
+        {
+          self.read_frame(ctx, video_info);
+        } else {
+          self.changed = Some(details);
+          ctx.flush();
+        }
+      }
       _ => {
         ctx.flush();
       }
     };
   }
+
+  /// Scales a frame of the input to the size of the video, if it changed
+  fn scale_frame<T: Pixel>(
+    &self, frame: Frame<T>, video_info: VideoDetails
+  ) -> Frame<T> {
+    let details = self.input.get_video_details();
+    if (details.width, details.height) == (video_info.width, video_info.height) {
+      return frame;
+    }
+    let mut scaled =
+      Frame::new(video_info.width, video_info.height, video_info.chroma_sampling);
+    for (dst, src) in scaled.planes.iter_mut().zip(frame.planes.iter()) {
+      let PlaneConfig { xdec, ydec, .. } = src.cfg;
+      dst.scale_from(
+        (video_info.width + xdec) >> xdec, (video_info.height + ydec) >> ydec,
+        src,
+        (details.width + xdec) >> xdec, (details.height + ydec) >> ydec
+      );
+    }
+    scaled
+  }
+}
+
+/// Path of the output file `number`, the first one being `path`
+fn output_path(path: &str, number: usize) -> String {
+  if number == 0 {
+    return path.to_string();
+  }
+  let path = Path::new(path);
+  let stem = path.file_stem().map_or("".into(), |s| s.to_string_lossy());
+  let name = match path.extension() {
+    Some(ext) => format!("{}-{}.{}", stem, number, ext.to_string_lossy()),
+    None => format!("{}-{}", stem, number),
+  };
+  path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Writes each plane of the frame, within its configured size, to a raw
+/// file named after the frame number, and returns the file names
+fn dump_frame<T: Pixel>(
+  frame: &Frame<T>, number: u64,
+) -> io::Result<Vec<String>> {
+  frame.planes.iter().zip(&["y", "u", "v"]).map(|(plane, name)| {
+    let file_name = format!("rav1e-self-check-{}.{}", number, name);
+    let mut data = Vec::new();
+    let PlaneConfig { width, height, stride, .. } = plane.cfg;
+    for row in plane.data_origin().chunks(stride).take(height) {
+      for &v in &row[..width] {
+        let v = u16::cast_from(v);
+        data.push(v as u8);
+        if std::mem::size_of::<T>() > 1 {
+          data.push((v >> 8) as u8);
+        }
+      }
+    }
+    std::fs::write(&file_name, &data)?;
+    Ok(file_name)
+  }).collect()
 }
 
 // Encode and write a frame.
 // Returns frame information in a `Result`.
 fn process_frame<T: Pixel, D: Decoder>(
   ctx: &mut Context<T>,
-  output_file: &mut dyn Write,
+  output: &mut dyn Muxer,
   source: &mut Source<D>,
   mut y4m_enc: Option<&mut y4m::Encoder<'_, Box<dyn Write>>>,
+  hash: Option<&mut dyn Write>,
+  self_check: Option<u64>,
 ) -> Option<Vec<FrameSummary>> {
-  let y4m_details = source.input.get_video_details();
+  let y4m_details = source.video_info;
   let mut frame_summaries = Vec::new();
   let pkt_wrapped = ctx.receive_packet();
   match pkt_wrapped {
-    Ok(pkt) => {
-      write_ivf_frame(output_file, pkt.number as u64, pkt.data.as_ref());
+    Ok(mut pkt) => {
+      // A temporal unit split to fit within --max-frame-size is muxed
+      // whole, as containers store one per frame. The rest of it is
+      // already coded.
+      while pkt.continued {
+        let next = ctx.receive_packet()
+          .expect("The rest of the temporal unit is queued");
+        let offset = pkt.data.len();
+        let mut data = pkt.data;
+        data.extend_from_slice(&next.data);
+        let mut tiles = pkt.tiles;
+        tiles.extend(next.tiles.iter().map(|tile| TileLocation {
+          offset: offset + tile.offset,
+          ..*tile
+        }));
+        pkt = Packet { data, tiles, ..next };
+      }
+      if let (Some(interval), Some(number)) = (self_check, pkt.input_frameno) {
+        if number % interval == 0 {
+          if let Err(e) = pkt.self_check() {
+            match pkt.rec.as_ref().map(|rec| dump_frame(rec, number)) {
+              Some(Ok(files)) => panic!(
+                "Self-check failed: {}; frame planes dumped to {:?}",
+                e, files
+              ),
+              Some(Err(dump)) => panic!(
+                "Self-check failed: {}; the frame planes could not be dumped: {}",
+                e, dump
+              ),
+              None => panic!("Self-check failed: {}", e),
+            }
+          }
+        }
+      }
+      // Dry runs produce no data
+      if !pkt.data.is_empty() {
+        output.write_frame(pkt.timestamp, pkt.data.as_ref());
+      }
       if let (Some(ref mut y4m_enc_uw), Some(ref rec)) = (y4m_enc.as_mut(), &pkt.rec) {
         write_y4m_frame(y4m_enc_uw, rec, y4m_details);
       }
+      // Packets are in display order, each showing one frame
+      if let (Some(hash), Some(ref rec), Some(number)) =
+        (hash, &pkt.rec, pkt.input_frameno)
+      {
+        let number = source.start as u64 + number + 1;
+        write_frame_hash(hash, rec, number, y4m_details);
+      }
       frame_summaries.push(pkt.into());
     }
     Err(EncoderStatus::NeedMoreData) => {
@@ -88,6 +237,15 @@ fn process_frame<T: Pixel, D: Decoder>(
     Err(EncoderStatus::Failure) => {
       panic!("Failed to encode video");
     }
+    Err(EncoderStatus::OutOfMemory) => {
+      panic!("Failed to allocate the buffers of a frame");
+    }
+    Err(EncoderStatus::InvalidConfig(e)) => {
+      panic!("Invalid encoder configuration: {}", e);
+    }
+    Err(EncoderStatus::Cancelled) => {
+      panic!("The encoding was cancelled");
+    }
   }
   Some(frame_summaries)
 }
@@ -100,28 +258,53 @@ fn write_stats_file<T: Pixel>(ctx: &Context<T>, filename: &Path) -> Result<(), i
 }
 
 fn do_encode<T: Pixel, D: Decoder>(
-  cfg: Config, verbose: bool, mut progress: ProgressInfo,
-  mut err: std::io::StderrLock, mut output: &mut dyn Write,
+  cfg: Config, verbose: bool, self_check: Option<u64>, mut progress: ProgressInfo,
+  mut err: std::io::StderrLock, output: &mut dyn Muxer,
   source: &mut Source<D>,
-  mut y4m_enc: Option<y4m::Encoder<'_, Box<dyn Write>>>
-) {
-  let mut ctx: Context<T> = cfg.new_context();
+  mut y4m_enc: Option<y4m::Encoder<'_, Box<dyn Write>>>,
+  mut hash: Option<&mut dyn Write>
+) -> ProgressInfo {
+  let mut ctx: Context<T> = cfg.new_context()
+    .unwrap_or_else(|e| panic!("Invalid encoder configuration: {}", e));
+  output.write_header(&ctx.container_info());
 
+  if verbose {
+    let (cols, rows) = ctx.tile_split();
+    let _ = writeln!(err, "Using {} tile columns and {} tile rows", cols, rows);
+    let bit_depth = cfg.enc.internal_bit_depth.unwrap_or(cfg.enc.bit_depth);
+    let cpu = ctx.cpu_feature_level();
+    let kernels: Vec<_> = cpu.kernels(bit_depth).iter()
+      .map(|(name, level)| format!("{} {}", name, level))
+      .collect();
+    let _ = writeln!(
+      err, "Using CPU feature level {}, kernels: {}", cpu, kernels.join(", ")
+    );
+  }
 
   while let Some(frame_info) =
-    process_frame(&mut ctx, &mut output, source, y4m_enc.as_mut())
+    process_frame(
+      &mut ctx, output, source, y4m_enc.as_mut(), hash.as_mut().map(|h| &mut **h),
+      self_check
+    )
   {
     for frame in frame_info {
+      let line = if verbose { Some(frame.to_string()) } else { None };
       progress.add_frame(frame);
-      let _ = if verbose {
-        writeln!(err, "{} - {}", frame, progress)
-      } else {
-        write!(err, "\r{}                    ", progress)
+      let _ = match line {
+        Some(line) => writeln!(err, "{} - {}", line, progress),
+        None => write!(err, "\r{}                    ", progress),
       };
     }
+    if let Some(summary) = progress.periodic_summary() {
+      let _ = write!(err, "\n{}\n", summary);
+    }
 
     output.flush().unwrap();
   }
+  output.finalize();
+  if let Some(hash) = hash {
+    hash.flush().unwrap();
+  }
 
   if cfg.enc.pass == Some(1) {
     if let Err(e) =
@@ -131,75 +314,894 @@ fn do_encode<T: Pixel, D: Decoder>(
     }
   }
   let _ = write!(err, "\n{}\n", progress.print_summary());
+  progress
+}
+
+fn write_report(progress: &ProgressInfo, filename: &str) -> Result<(), io::Error> {
+  let writer = BufWriter::new(File::create(filename)?);
+  serde_json::to_writer_pretty(writer, &progress.report())?;
+  Ok(())
+}
+
+/// Opens the images of `--input-pattern`, if given, completing the color
+/// description of the encode with the conversion of the RGB images
+#[cfg(feature = "image")]
+fn image_input(cli: &mut CliOptions) -> Option<ImageSequence> {
+  let pattern = cli.input_pattern.take()?;
+  // The RGB images are converted as signaled, BT.709 limited range unless
+  // given
+  if cli.enc.pixel_range == PixelRange::Unspecified {
+    cli.enc.pixel_range = PixelRange::Limited;
+  }
+  let description = cli.enc.color_description.unwrap_or(ColorDescription {
+    color_primaries: ColorPrimaries::Unspecified,
+    transfer_characteristics: TransferCharacteristics::Unspecified,
+    matrix_coefficients: MatrixCoefficients::Unspecified,
+  });
+  let matrix_coefficients = match description.matrix_coefficients {
+    MatrixCoefficients::Unspecified => MatrixCoefficients::BT709,
+    matrix => matrix,
+  };
+  cli.enc.color_description =
+    Some(ColorDescription { matrix_coefficients, ..description });
+
+  // Required along with the pattern
+  let frame_rate = cli.frame_rate.unwrap();
+  let input = ImageSequence::open(
+    &pattern,
+    matrix_coefficients,
+    cli.enc.pixel_range,
+    cli.enc.internal_bit_depth.unwrap_or(10),
+    Rational::new(frame_rate.den, frame_rate.num)
+  ).unwrap_or_else(|e| {
+    panic!("Failed to read the images {}: {:?}", pattern, e)
+  });
+  Some(input)
 }
 
 fn main() {
   let mut cli = parse_cli();
-  let mut y4m_dec = y4m::decode(&mut cli.io.input).expect("input is not a y4m file");
-  let video_info = y4m_dec.get_video_details();
-  let y4m_enc = match cli.io.rec.as_mut() {
-    Some(rec) => Some(
-      y4m::encode(
-        video_info.width,
-        video_info.height,
-        y4m::Ratio::new(video_info.time_base.den as usize, video_info.time_base.num as usize)
-      ).with_colorspace(y4m_dec.get_colorspace())
-        .write_header(rec)
-        .unwrap()
-    ),
-    None => None
-  };
+  // Warnings only by default, more with e.g. RAV1E_LOG=debug
+  env_logger::Builder::from_env(
+    env_logger::Env::default().filter_or("RAV1E_LOG", "warn")
+  ).init();
 
-  cli.enc.width = video_info.width;
-  cli.enc.height = video_info.height;
-  cli.enc.bit_depth = video_info.bit_depth;
-  cli.enc.chroma_sampling = video_info.chroma_sampling;
-  cli.enc.chroma_sample_position = video_info.chroma_sample_position;
-  cli.enc.time_base = video_info.time_base;
-  let cfg = Config {
-    enc: cli.enc,
-    threads: cli.threads,
-  };
+  if let Some((alpha, alpha_output)) = cli.io.alpha.take() {
+    let input =
+      std::mem::replace(&mut cli.io.input, Box::new(io::empty()) as Box<dyn Read>);
+    let y4m_dec = Y4mInput::new(input).expect("input is not a y4m file");
+    let alpha = Y4mInput::new(alpha).expect("alpha input is not a y4m file");
+    encode_with_alpha(cli, y4m_dec, alpha, alpha_output);
+    return;
+  }
+
+  #[cfg(feature = "image")]
+  {
+    if let Some(input) = image_input(&mut cli) {
+      encode_input(cli, input, y4m::Colorspace::C420);
+      return;
+    }
+  }
+
+  let input =
+    std::mem::replace(&mut cli.io.input, Box::new(io::empty()) as Box<dyn Read>);
+  let y4m_dec = Y4mInput::new(input).expect("input is not a y4m file");
+  let color_space = y4m_dec.get_colorspace();
+  encode_input(cli, y4m_dec, color_space);
+}
+
+/// Encodes the frames of `input`, with the sampling of `color_space` in
+/// the reconstruction
+fn encode_input<D: Decoder>(
+  mut cli: CliOptions, mut input: D, mut color_space: y4m::Colorspace
+) {
+  for _ in 0..cli.skip {
+    // The encode starts with the parameters of its first frame
+    loop {
+      match input.skip_frame() {
+        Err(DecodeError::ParametersChanged(_)) => continue,
+        result => break result.expect("Skipped more frames than in the input"),
+      }
+    }
+  }
 
   let stderr = io::stderr();
-  let mut err = stderr.lock();
+  let mut source = Source::new(
+    input, cli.limit, Default::default(), cli.on_input_change
+  );
+  for segment in 0.. {
+    let input_details = source.input.get_video_details();
+    let mut video_info = input_details;
+    if let Some(frame_rate) = cli.frame_rate {
+      video_info.time_base = Rational::new(frame_rate.den, frame_rate.num);
+    }
+    let mut enc = cli.enc.clone();
+    enc.bit_depth = video_info.bit_depth;
+    // The input is scaled up by the encoder, which outputs frames of the
+    // internal bit depth
+    if let Some(bit_depth) = enc.internal_bit_depth {
+      if bit_depth < video_info.bit_depth {
+        panic!(
+          "Internal bit depth {} is lower than the input bit depth {}",
+          bit_depth, video_info.bit_depth
+        );
+      }
+      video_info.bit_depth = bit_depth;
+    }
+    let sampling =
+      (input_details.chroma_sampling, input_details.chroma_sample_position);
+    if segment > 0 && map_y4m_color_space(color_space) != sampling {
+      color_space = y4m_color_space(sampling.0, sampling.1);
+    }
+    // The reconstruction of each output follows the previous one, as the
+    // streams of the input
+    let y4m_enc = match cli.io.rec.as_mut() {
+      Some(rec) => match promote_color_space(color_space, video_info.bit_depth) {
+        Some(color_space) => Some(
+          y4m::encode(
+            video_info.width,
+            video_info.height,
+            y4m::Ratio::new(video_info.time_base.den as usize, video_info.time_base.num as usize)
+          ).with_colorspace(color_space)
+            .write_header(rec)
+            .unwrap()
+        ),
+        None => {
+          eprintln!(
+            "No {}-bit y4m colorspace for {:?}, not writing the reconstruction",
+            video_info.bit_depth, video_info.chroma_sampling
+          );
+          None
+        }
+      },
+      None => None
+    };
+
+    enc.width = video_info.width;
+    enc.height = video_info.height;
+    enc.chroma_sampling = video_info.chroma_sampling;
+    enc.chroma_sample_position = video_info.chroma_sample_position;
+    enc.time_base = video_info.time_base;
+    if let Some(seconds) = cli.keyint_seconds {
+      enc.set_key_frame_interval_seconds(seconds);
+    }
+    let mut cfg = Config::new(enc).with_threads(cli.threads);
+    cfg.frame_filter = cli.sharpen.map(|amount| {
+      let sharpen = Sharpen::new(amount, video_info.bit_depth);
+      Arc::new(Mutex::new(sharpen)) as Arc<Mutex<dyn AnyFrameFilter>>
+    });
+
+    let mut err = stderr.lock();
+
+    let _ = writeln!(
+      err,
+      "{}x{} @ {}/{} fps",
+      video_info.width,
+      video_info.height,
+      video_info.time_base.den,
+      video_info.time_base.num
+    );
+
+    let progress = ProgressInfo::new(
+      Rational { num: video_info.time_base.den, den: video_info.time_base.num },
+      if cli.limit == 0 { None } else { Some(cli.limit) },
+        cfg.enc.show_psnr,
+        cli.summary_interval
+    );
+
+    source.video_info = video_info;
+    source.input_details = input_details;
+    source.start = source.count;
+
+    let progress = if video_info.bit_depth == 8 {
+      do_encode::<u8, D>(
+        cfg, cli.verbose, cli.self_check, progress, err, &mut *cli.io.output, &mut source, y4m_enc,
+        cli.io.hash.as_mut().map(|h| &mut **h as &mut dyn Write)
+      )
+    } else {
+      do_encode::<u16, D>(
+        cfg, cli.verbose, cli.self_check, progress, err, &mut *cli.io.output, &mut source, y4m_enc,
+        cli.io.hash.as_mut().map(|h| &mut **h as &mut dyn Write)
+      )
+    };
+    if let Some(report) = cli.report.as_ref() {
+      let path = output_path(report, segment);
+      if let Err(e) = write_report(&progress, &path) {
+        let _ = writeln!(stderr.lock(), "Error: Failed to write the report {}: {}", path, e);
+      }
+    }
+
+    let details = match source.changed.take() {
+      Some(details) => details,
+      None => break,
+    };
+    if cli.on_input_change != InputChange::SplitOutput {
+      panic!(
+        "The input changed from {} to {} after {} frames{}",
+        input_details,
+        details,
+        source.count,
+        if cli.on_input_change == InputChange::Scale {
+          ", other than in size"
+        } else {
+          "; see --on-input-change"
+        }
+      );
+    }
+    let path = output_path(&cli.output_path, segment + 1);
+    let _ = writeln!(
+      stderr.lock(),
+      "The input changed to {} after {} frames, continuing in {}",
+      details, source.count, path
+    );
+    cli.io.output = Box::new(
+      IvfMuxer::open(&path)
+        .unwrap_or_else(|e| panic!("Failed to create {}: {}", path, e))
+    );
+  }
+}
+
+/// Encodes the frames of `input` to the output, and their alpha planes,
+/// the luma planes of the frames of `alpha`, to `alpha_output`
+fn encode_with_alpha(
+  mut cli: CliOptions, mut input: Y4mInput, mut alpha: Y4mInput,
+  mut alpha_output: Box<dyn Muxer>
+) {
+  for _ in 0..cli.skip {
+    input.skip_frame().expect("Skipped more frames than in the input");
+    alpha.skip_frame().expect("Skipped more frames than in the alpha input");
+  }
+
+  let mut video_info = input.get_video_details();
+  let alpha_info = alpha.get_video_details();
+  if (alpha_info.width, alpha_info.height, alpha_info.bit_depth)
+    != (video_info.width, video_info.height, video_info.bit_depth)
+  {
+    panic!("The alpha input {} does not match the input {}", alpha_info, video_info);
+  }
+  if let Some(frame_rate) = cli.frame_rate {
+    video_info.time_base = Rational::new(frame_rate.den, frame_rate.num);
+  }
+  let mut enc = cli.enc.clone();
+  enc.bit_depth = video_info.bit_depth;
+  enc.width = video_info.width;
+  enc.height = video_info.height;
+  enc.chroma_sampling = video_info.chroma_sampling;
+  enc.chroma_sample_position = video_info.chroma_sample_position;
+  enc.time_base = video_info.time_base;
+  if let Some(seconds) = cli.keyint_seconds {
+    enc.set_key_frame_interval_seconds(seconds);
+  }
+  let bit_depth = enc.internal_bit_depth.unwrap_or(enc.bit_depth);
+  let mut cfg = Config::new(enc).with_threads(cli.threads);
+  cfg.frame_filter = cli.sharpen.map(|amount| {
+    let sharpen = Sharpen::new(amount, bit_depth);
+    Arc::new(Mutex::new(sharpen)) as Arc<Mutex<dyn AnyFrameFilter>>
+  });
 
+  let stderr = io::stderr();
+  let mut err = stderr.lock();
   let _ = writeln!(
     err,
-    "{}x{} @ {}/{} fps",
+    "{}x{} @ {}/{} fps, with alpha",
     video_info.width,
     video_info.height,
     video_info.time_base.den,
     video_info.time_base.num
   );
-
-  write_ivf_header(
-    &mut cli.io.output,
-    video_info.width,
-    video_info.height,
-    video_info.time_base.den as usize,
-    video_info.time_base.num as usize
-  );
-
-  let progress = ProgressInfo::new(
+  let mut progress = ProgressInfo::new(
     Rational { num: video_info.time_base.den, den: video_info.time_base.num },
     if cli.limit == 0 { None } else { Some(cli.limit) },
-      cfg.enc.show_psnr
+    cfg.enc.show_psnr,
+    cli.summary_interval
   );
 
-  for _ in 0..cli.skip {
-    y4m_dec.read_frame().expect("Skipped more frames than in the input");
-  }
-
-  let mut source = Source { limit: cli.limit, input: y4m_dec, count: 0 };
-
-  if video_info.bit_depth == 8 {
-    do_encode::<u8, y4m::Decoder<'_, Box<dyn Read>>>(
-      cfg, cli.verbose, progress, err, &mut cli.io.output, &mut source, y4m_enc
+  let frames = if bit_depth == 8 {
+    do_encode_alpha::<u8>(
+      cfg, &mut *cli.io.output, &mut *alpha_output, &mut input, &mut alpha,
+      cli.limit
     )
   } else {
-    do_encode::<u16, y4m::Decoder<'_, Box<dyn Read>>>(
-      cfg, cli.verbose, progress, err, &mut cli.io.output, &mut source, y4m_enc
+    do_encode_alpha::<u16>(
+      cfg, &mut *cli.io.output, &mut *alpha_output, &mut input, &mut alpha,
+      cli.limit
     )
+  };
+  for frame in frames {
+    if cli.verbose {
+      let _ = writeln!(err, "{}", frame);
+    }
+    progress.add_frame(frame);
+  }
+  let _ = writeln!(err, "{}", progress.print_summary());
+  if let Some(report) = cli.report.as_ref() {
+    if let Err(e) = write_report(&progress, report) {
+      let _ = writeln!(err, "Error: Failed to write the report {}: {}", report, e);
+    }
+  }
+}
+
+/// Encodes the frames of `input` along with their alpha planes until the
+/// end of the inputs or `limit` frames, returning the frames of the color
+/// stream
+fn do_encode_alpha<T: Pixel>(
+  cfg: Config, output: &mut dyn Muxer, alpha_output: &mut dyn Muxer,
+  input: &mut Y4mInput, alpha: &mut Y4mInput, limit: usize
+) -> Vec<FrameSummary> {
+  let mut ctx: DualContext<T> = cfg.new_dual_context()
+    .unwrap_or_else(|e| panic!("Invalid encoder configuration: {}", e));
+  output.write_header(&ctx.color().container_info());
+  alpha_output.write_header(&ctx.alpha().container_info());
+
+  let video_info = input.get_video_details();
+  let mut frame_summaries = Vec::new();
+  let mut count = 0;
+  loop {
+    // The streams have the same frame types, so their packets come together
+    match (ctx.receive_color_packet(), ctx.receive_alpha_packet()) {
+      (Ok(pkt), Ok(alpha_pkt)) => {
+        // Dry runs produce no data
+        if !pkt.data.is_empty() {
+          output.write_frame(pkt.timestamp, pkt.data.as_ref());
+          alpha_output.write_frame(alpha_pkt.timestamp, alpha_pkt.data.as_ref());
+        }
+        frame_summaries.push(pkt.into());
+      }
+      (Err(EncoderStatus::NeedMoreData), Err(EncoderStatus::NeedMoreData)) => {
+        if limit != 0 && count == limit {
+          ctx.flush();
+          continue;
+        }
+        match (input.read_frame::<T>(&video_info), alpha.read_frame::<T>(&video_info)) {
+          (Ok(frame), Ok(alpha_frame)) => {
+            count += 1;
+            let _ = ctx.send_frame(Some((Arc::new(frame), &alpha_frame.planes[0])));
+          }
+          (Err(DecodeError::EOF), Err(DecodeError::EOF)) => ctx.flush(),
+          (Err(DecodeError::EOF), _) | (_, Err(DecodeError::EOF)) => {
+            panic!("The input and the alpha input have different numbers of frames");
+          }
+          (Err(DecodeError::ParametersChanged(_)), _)
+          | (_, Err(DecodeError::ParametersChanged(_))) => {
+            panic!("The input or the alpha input changed after {} frames", count);
+          }
+          _ => panic!("Failed to read frame {} of the inputs", count),
+        }
+      }
+      (Err(EncoderStatus::LimitReached), Err(EncoderStatus::LimitReached)) => break,
+      (color, alpha) => {
+        panic!("Failed to encode video: {:?}, {:?}", color.err(), alpha.err());
+      }
+    }
+  }
+  output.finalize();
+  alpha_output.finalize();
+  frame_summaries
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::io::Cursor;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  const W: usize = 64;
+  const H: usize = 64;
+
+  fn y4m_input(frames: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    {
+      let mut enc = y4m::encode(W, H, y4m::Ratio::new(30, 1))
+        .with_colorspace(y4m::Colorspace::C420)
+        .write_header(&mut data)
+        .unwrap();
+      let y = vec![128u8; W * H];
+      let uv = vec![128u8; W * H / 4];
+      for _ in 0..frames {
+        enc.write_frame(&y4m::Frame::new([&y, &uv, &uv], None)).unwrap();
+      }
+    }
+    data
+  }
+
+  fn read_u32(data: &[u8]) -> u32 {
+    data.iter().take(4).rev().fold(0, |acc, &b| acc << 8 | u32::from(b))
+  }
+
+  /// Encodes to an IVF file as the command line does, returning the parsed
+  /// IVF header, the parameters of the context and the timestamps of the
+  /// frames
+  fn encode(
+    frames: usize, skip: usize, limit: usize, frame_rate: Option<Rational>
+  ) -> (Header, ContainerInfo, Vec<u64>) {
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+
+    let mut input = Box::new(Cursor::new(y4m_input(frames))) as Box<dyn Read>;
+    let mut y4m_dec = y4m::decode(&mut input).unwrap();
+    let mut video_info = y4m_dec.get_video_details();
+    if let Some(frame_rate) = frame_rate {
+      video_info.time_base = Rational::new(frame_rate.den, frame_rate.num);
+    }
+    for _ in 0..skip {
+      y4m_dec.read_frame().unwrap();
+    }
+
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = W;
+    enc.height = H;
+    enc.time_base = video_info.time_base;
+    let cfg = Config::new(enc);
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+    let info = ctx.container_info();
+
+    let path = std::env::temp_dir().join(format!(
+      "rav1e-test-{}-{}.ivf",
+      std::process::id(),
+      FILES.fetch_add(1, Ordering::SeqCst)
+    ));
+    let mut muxer = IvfMuxer::open(path.to_str().unwrap()).unwrap();
+    muxer.write_header(&info);
+    let mut source =
+      Source::new(y4m_dec, limit, video_info, InputChange::Error);
+    while process_frame(&mut ctx, &mut muxer, &mut source, None, None, None).is_some() {}
+    muxer.finalize();
+    drop(muxer);
+
+    let output = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let header = read_header(&mut Cursor::new(&output)).unwrap();
+    let mut timestamps = Vec::new();
+    let mut pos = 32;
+    while pos < output.len() {
+      let size = read_u32(&output[pos..]) as usize;
+      let pts = u64::from(read_u32(&output[pos + 4..]))
+        | u64::from(read_u32(&output[pos + 8..])) << 32;
+      timestamps.push(pts);
+      pos += 12 + size;
+    }
+    (header, info, timestamps)
+  }
+
+  #[test]
+  fn ivf_header_from_context() {
+    let (header, info, timestamps) =
+      encode(10, 2, 5, Some(Rational::new(25, 1)));
+    assert_eq!(&header.tag, &info.fourcc);
+    assert_eq!((header.w as usize, header.h as usize), (info.width, info.height));
+    assert_eq!(
+      (u64::from(header.timebase_den), u64::from(header.timebase_num)),
+      (info.time_base.den, info.time_base.num)
+    );
+    // Patched once the limit is reached
+    assert_eq!(header.frames as usize, timestamps.len());
+    assert_eq!(timestamps.len(), 5);
+  }
+
+  #[test]
+  fn skip_limit_and_frame_rate() {
+    for &(frames, skip, limit, frame_rate) in &[
+      (10, 0, 0, None),
+      (10, 2, 0, None),
+      (10, 2, 5, None),
+      (10, 0, 5, Some(Rational::new(24000, 1001))),
+      (10, 3, 5, Some(Rational::new(24000, 1001))),
+      (10, 8, 5, Some(Rational::new(25, 1))),
+    ] {
+      let (header, _, timestamps) = encode(frames, skip, limit, frame_rate);
+      let (num, den) = (header.timebase_den, header.timebase_num);
+      let expected_rate = frame_rate.unwrap_or(Rational::new(30, 1));
+      assert_eq!((u64::from(num), u64::from(den)), (expected_rate.num, expected_rate.den));
+
+      // The limit counts the frames submitted after the skipped ones
+      let encoded = if limit == 0 { frames - skip } else { limit.min(frames - skip) };
+      assert_eq!(timestamps.len(), encoded);
+      // Lasting exactly encoded * den / num seconds
+      assert_eq!(timestamps, (0..encoded as u64).collect::<Vec<_>>());
+    }
+  }
+
+  #[test]
+  fn frame_hash_sidecar() {
+    let mut input = Box::new(Cursor::new(y4m_input(12))) as Box<dyn Read>;
+    let y4m_dec = y4m::decode(&mut input).unwrap();
+    let video_info = y4m_dec.get_video_details();
+
+    // Several GOPs, with frames shown after later ones are coded
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = W;
+    enc.height = H;
+    enc.max_key_frame_interval = 5;
+    let cfg = Config::new(enc);
+    let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+    let path = std::env::temp_dir()
+      .join(format!("rav1e-test-hash-{}.ivf", std::process::id()));
+    let mut muxer = IvfMuxer::open(path.to_str().unwrap()).unwrap();
+    let mut source = Source::new(y4m_dec, 0, video_info, InputChange::Error);
+    let mut hash = Vec::new();
+    while process_frame(
+      &mut ctx, &mut muxer, &mut source, None, Some(&mut hash as &mut dyn Write),
+      None
+    ).is_some() {}
+    drop(muxer);
+    std::fs::remove_file(&path).unwrap();
+
+    let hash = String::from_utf8(hash).unwrap();
+    let lines: Vec<&str> = hash.lines().collect();
+    assert_eq!(lines.len(), 12);
+    for (i, line) in lines.iter().enumerate() {
+      let (md5, number) = line.split_at(32);
+      assert!(md5.chars().all(|c| c.is_ascii_hexdigit()));
+      assert_eq!(number, format!("  {:04}", i + 1));
+    }
+  }
+
+  /// A y4m stream of `frames` frames of `width`x`height`, in 4:2:0 of
+  /// `bit_depth`
+  fn y4m_stream(
+    width: usize, height: usize, bit_depth: usize, frames: usize
+  ) -> Vec<u8> {
+    let color_space =
+      promote_color_space(y4m::Colorspace::C420, bit_depth).unwrap();
+    let bytes = if bit_depth > 8 { 2 } else { 1 };
+    let mut data = Vec::new();
+    {
+      let mut enc = y4m::encode(width, height, y4m::Ratio::new(30, 1))
+        .with_colorspace(color_space)
+        .write_header(&mut data)
+        .unwrap();
+      // Little-endian samples for high bit depth
+      let y: Vec<u8> = (0..width * height).flat_map(|i| {
+        let v = (i % 200) as u8;
+        if bytes > 1 { vec![v, 0] } else { vec![v] }
+      }).collect();
+      let uv = vec![0; (width + 1) / 2 * ((height + 1) / 2) * bytes];
+      for _ in 0..frames {
+        enc.write_frame(&y4m::Frame::new([&y, &uv, &uv], None)).unwrap();
+      }
+    }
+    data
+  }
+
+  #[test]
+  fn y4m_input_streams() {
+    let data = [
+      y4m_stream(64, 48, 8, 2),
+      y4m_stream(64, 48, 8, 1),
+      y4m_stream(40, 24, 8, 2),
+    ].concat();
+    let mut input = Y4mInput::new(Box::new(Cursor::new(data))).unwrap();
+    assert_eq!(input.get_video_details().width, 64);
+    // The same parameters continue the input
+    for _ in 0..3 {
+      let frame = input.read_frame::<u8>(&Default::default()).unwrap();
+      assert_eq!(frame.planes[0].p(3, 1), ((64 + 3) % 200) as u8);
+    }
+    match input.read_frame::<u8>(&Default::default()) {
+      Err(DecodeError::ParametersChanged(details)) => {
+        assert_eq!((details.width, details.height), (40, 24));
+      }
+      _ => panic!("expected a change of parameters"),
+    }
+    assert!(input.skip_frame().is_ok());
+    let frame = input.read_frame::<u8>(&Default::default()).unwrap();
+    assert_eq!(frame.planes[0].p(3, 1), ((40 + 3) % 200) as u8);
+    assert!(match input.read_frame::<u8>(&Default::default()) {
+      Err(DecodeError::EOF) => true,
+      _ => false,
+    });
+  }
+
+  struct PacketCounter(usize);
+
+  impl Muxer for PacketCounter {
+    fn write_header(&mut self, _info: &ContainerInfo) {}
+    fn write_frame(&mut self, _pts: u64, _data: &[u8]) {
+      self.0 += 1;
+    }
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+    fn finalize(&mut self) {}
+  }
+
+  /// Encodes from the input as the command line does, until its end or a
+  /// change of its parameters, returning the number of packets and the
+  /// size of the reconstruction
+  fn encode_segment(source: &mut Source<Y4mInput>) -> (usize, usize, usize) {
+    encode_segment_hashed(source, None)
+  }
+
+  fn encode_segment_hashed(
+    source: &mut Source<Y4mInput>, hash: Option<&mut dyn Write>
+  ) -> (usize, usize, usize) {
+    let video_info = source.input.get_video_details();
+    source.video_info = video_info;
+    source.input_details = video_info;
+    source.start = source.count;
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = video_info.width;
+    enc.height = video_info.height;
+    enc.bit_depth = video_info.bit_depth;
+    let cfg = Config::new(enc);
+    let mut ctx: Context<u16> = cfg.new_context().unwrap();
+    let mut muxer = PacketCounter(0);
+    let mut hash = hash;
+    while process_frame(
+      &mut ctx, &mut muxer, source, None, hash.as_mut().map(|h| &mut **h), None
+    ).is_some() {}
+    (muxer.0, video_info.width, video_info.height)
+  }
+
+  #[test]
+  fn input_change_policies() {
+    let data = || [
+      y4m_stream(64, 48, 10, 3),
+      y4m_stream(32, 24, 10, 2),
+      y4m_stream(64, 48, 10, 1),
+    ].concat();
+    let input = || Y4mInput::new(Box::new(Cursor::new(data()))).unwrap();
+    let details = input().get_video_details();
+
+    // The encode ends at the change
+    let mut source = Source::new(input(), 0, details, InputChange::Error);
+    assert_eq!(encode_segment(&mut source), (3, 64, 48));
+    assert_eq!(source.changed.map(|d| (d.width, d.height)), Some((32, 24)));
+
+    // A new encode follows each change
+    let mut source = Source::new(input(), 0, details, InputChange::SplitOutput);
+    let mut segments = vec![encode_segment(&mut source)];
+    while source.changed.take().is_some() {
+      segments.push(encode_segment(&mut source));
+    }
+    assert_eq!(segments, vec![(3, 64, 48), (2, 32, 24), (1, 64, 48)]);
+
+    // The frame hashes number the frames of the split outputs in sequence
+    let mut source = Source::new(input(), 0, details, InputChange::SplitOutput);
+    let mut hash = Vec::new();
+    encode_segment_hashed(&mut source, Some(&mut hash as &mut dyn Write));
+    while source.changed.take().is_some() {
+      encode_segment_hashed(&mut source, Some(&mut hash as &mut dyn Write));
+    }
+    let numbers: Vec<String> = String::from_utf8(hash).unwrap().lines()
+      .map(|line| line[34..].to_string())
+      .collect();
+    assert_eq!(numbers, (1..=6).map(|i| format!("{:04}", i)).collect::<Vec<_>>());
+
+    // Every frame is encoded at the first size
+    let mut source = Source::new(input(), 0, details, InputChange::Scale);
+    assert_eq!(encode_segment(&mut source), (6, 64, 48));
+    assert!(source.changed.is_none());
+
+    // Unless the format changes too
+    let data = [y4m_stream(64, 48, 10, 2), y4m_stream(32, 24, 8, 2)].concat();
+    let input = Y4mInput::new(Box::new(Cursor::new(data))).unwrap();
+    let mut source = Source::new(input, 0, details, InputChange::Scale);
+    assert_eq!(encode_segment(&mut source), (2, 64, 48));
+    assert_eq!(source.changed.map(|d| d.bit_depth), Some(8));
+  }
+
+  #[test]
+  fn split_output_paths() {
+    assert_eq!(output_path("out.ivf", 0), "out.ivf");
+    assert_eq!(output_path("out.ivf", 2), "out-2.ivf");
+    assert_eq!(output_path("dir/out", 1), "dir/out-1");
+  }
+
+  #[test]
+  fn json_report() {
+    let mut progress =
+      ProgressInfo::new(Rational { num: 30, den: 1 }, None, true, None);
+    for &(number, size, psnr) in
+      [(0, 1000, (40., 44., 46.)), (1, 200, (38., 42., 44.))].iter()
+    {
+      let mut bits = FrameBits::default();
+      bits.plane_coefficients = [800 * number as usize + 1600, 800, 0];
+      progress.add_frame(FrameSummary {
+        size,
+        number: Some(number),
+        frame_type: if number == 0 { FrameType::KEY } else { FrameType::INTER },
+        qp: 100,
+        pyramid_level: 0,
+        bits,
+        psnr: Some(psnr),
+        show_existing_frame: false,
+        hidden_frames: Vec::new(),
+      });
+    }
+
+    let report = progress.report();
+    let frames = report["frames"].as_array().unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[1]["number"], 1);
+    assert_eq!(frames[1]["size"], 200);
+    assert_eq!(frames[0]["bits"]["plane_coefficients"][0], 1600);
+    assert_eq!(frames[0]["psnr"]["weighted"], (6. * 40. + 44. + 46.) / 8.);
+
+    let summary = &report["summary"];
+    assert_eq!(summary["frames"], 2);
+    assert_eq!(summary["size"], 1200);
+    assert_eq!(summary["plane_coefficients_size"], serde_json::json!([500, 200, 0]));
+    assert_eq!(summary["psnr"]["y"], 39.);
+    assert_eq!(summary["psnr"]["weighted"], (6. * 39. + 43. + 45.) / 8.);
+  }
+
+  #[test]
+  fn alpha_stream_alongside_color() {
+    let input = || {
+      Y4mInput::new(Box::new(Cursor::new(y4m_stream(W, H, 8, 6)))).unwrap()
+    };
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = W;
+    enc.height = H;
+    enc.max_key_frame_interval = 4;
+    enc.speed_settings.no_scene_detection = true;
+    for &(limit, encoded) in &[(0, 6), (5, 5)] {
+      let mut output = PacketCounter(0);
+      let mut alpha_output = PacketCounter(0);
+      let frames = do_encode_alpha::<u8>(
+        Config::new(enc.clone()), &mut output, &mut alpha_output,
+        &mut input(), &mut input(), limit
+      );
+      assert_eq!(frames.len(), encoded);
+      assert_eq!((output.0, alpha_output.0), (encoded, encoded));
+    }
+  }
+  /// Decodes the frames of an IVF file with libaom, returning the samples
+  /// of the planes of each frame shown
+  #[cfg(all(feature = "image", feature = "decode_test"))]
+  fn decode_ivf(path: &Path, bit_depth: usize) -> Vec<[Vec<u16>; 3]> {
+    use aom_sys::*;
+    use std::{mem, ptr, slice};
+
+    let mut file = File::open(path).unwrap();
+    let header = read_header(&mut file).unwrap();
+    let (width, height) = (header.w as usize, header.h as usize);
+    let mut frames = Vec::new();
+    unsafe {
+      let mut dec: aom_codec_ctx = mem::zeroed();
+      let cfg = aom_codec_dec_cfg_t {
+        threads: 1,
+        w: width as u32,
+        h: height as u32,
+        allow_lowbitdepth: 1,
+        cfg: cfg_options { ext_partition: 1 }
+      };
+      assert_eq!(aom_codec_dec_init_ver(
+        &mut dec, aom_codec_av1_dx(), &cfg, 0, AOM_DECODER_ABI_VERSION as i32
+      ), 0);
+      while let Ok(packet) = read_packet(&mut file) {
+        assert_eq!(aom_codec_decode(
+          &mut dec, packet.data.as_ptr(), packet.data.len(), ptr::null_mut()
+        ), 0);
+        let mut iter: aom_codec_iter_t = ptr::null_mut();
+        loop {
+          let img = aom_codec_get_frame(&mut dec, &mut iter);
+          if img.is_null() {
+            break;
+          }
+          let img = *img;
+          let mut planes = [Vec::new(), Vec::new(), Vec::new()];
+          for (p, plane) in planes.iter_mut().enumerate() {
+            let shift = if p == 0 { 0 } else { 1 };
+            let (w, h) = ((width + shift) >> shift, (height + shift) >> shift);
+            for y in 0..h {
+              let row = img.planes[p].offset(y as isize * img.stride[p] as isize);
+              if bit_depth > 8 {
+                plane.extend_from_slice(slice::from_raw_parts(row as *const u16, w));
+              } else {
+                plane.extend(slice::from_raw_parts(row, w).iter().map(|&v| u16::from(v)));
+              }
+            }
+          }
+          frames.push(planes);
+        }
+      }
+      aom_codec_destroy(&mut dec);
+    }
+    frames
+  }
+
+  /// Encodes numbered PNG files of `depth` through `--input-pattern` with
+  /// the other command line `args`, checking that the decoded frames are
+  /// the conversions of the images to `bit_depth`
+  #[cfg(all(feature = "image", feature = "decode_test"))]
+  fn image_sequence(depth: png::BitDepth, bit_depth: usize, args: &[&str]) {
+    use crate::decoder::image::{frame_path, RgbToYuv};
+
+    let dir = std::env::temp_dir().join(format!(
+      "rav1e-test-images-{}-{}", std::process::id(), bit_depth
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let pattern = dir.join("frame_%06d.png").to_str().unwrap().to_string();
+    let output = dir.join("out.ivf");
+    let max: u16 = match depth {
+      png::BitDepth::Sixteen => 65535,
+      _ => 255,
+    };
+    // Gradients moving from frame to frame, numbered from 1
+    let pixel = |x: usize, y: usize, i: usize| {
+      let scale = usize::from(max) / 255;
+      [(x * 4 * scale) as u16, (y * 4 * scale) as u16, ((x + y + i * 2) * scale) as u16]
+    };
+    let frames = 6;
+    for i in 0..frames {
+      let file = File::create(frame_path(&pattern, i as u64 + 1).unwrap()).unwrap();
+      let mut encoder = png::Encoder::new(file, W as u32, H as u32);
+      encoder.set_color(png::ColorType::RGB);
+      encoder.set_depth(depth);
+      // Big-endian samples for 16-bit images
+      let data: Vec<u8> = (0..W * H).flat_map(|p| {
+        pixel(p % W, p / W, i).iter().flat_map(|&v| {
+          if max > 255 { vec![(v >> 8) as u8, v as u8] } else { vec![v as u8] }
+        }).collect::<Vec<_>>()
+      }).collect();
+      encoder.write_header().unwrap().write_image_data(&data).unwrap();
+    }
+
+    let mut command_line = vec![
+      "rav1e", "--input-pattern", pattern.as_str(), "--frame-rate", "24",
+      "-o", output.to_str().unwrap(), "--speed", "10", "--quantizer", "1",
+    ];
+    command_line.extend_from_slice(args);
+    let mut cli = parse_cli_from(command_line);
+    let input = image_input(&mut cli).unwrap();
+    assert_eq!(input.get_video_details().bit_depth, bit_depth);
+    encode_input(cli, input, y4m::Colorspace::C420);
+
+    let decoded = decode_ivf(&output, bit_depth);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(decoded.len(), frames);
+
+    // The decoded frames are the conversions of the images, within the
+    // rounding of the finest quantizer
+    let conversion = RgbToYuv::new(
+      MatrixCoefficients::BT709, PixelRange::Limited, bit_depth
+    ).unwrap();
+    let tolerance = 3 << (bit_depth - 8);
+    for (i, planes) in decoded.iter().enumerate() {
+      let rgb: Vec<_> = (0..W * H).map(|p| pixel(p % W, p / W, i)).collect();
+      let expected = conversion.convert(&rgb, W, H, max);
+      for (plane, expected) in planes.iter().zip(expected.iter()) {
+        assert_eq!(plane.len(), expected.len());
+        for (&d, &e) in plane.iter().zip(expected.iter()) {
+          assert!(
+            (i32::from(d) - i32::from(e)).abs() <= tolerance,
+            "frame {}: {} != {}", i, d, e
+          );
+        }
+      }
+    }
+  }
+
+  #[cfg(all(feature = "image", feature = "decode_test"))]
+  #[test]
+  fn image_sequence_8bit() {
+    image_sequence(png::BitDepth::Eight, 8, &[]);
+  }
+
+  #[cfg(all(feature = "image", feature = "decode_test"))]
+  #[test]
+  fn image_sequence_16bit_at_10bit() {
+    image_sequence(png::BitDepth::Sixteen, 10, &[]);
+  }
+
+  #[cfg(all(feature = "image", feature = "decode_test"))]
+  #[test]
+  fn image_sequence_16bit_at_12bit() {
+    image_sequence(png::BitDepth::Sixteen, 12, &["--internal-bit-depth", "12"]);
+  }
+
+  #[test]
+  fn promote_monochrome() {
+    use y4m::Colorspace::*;
+    match promote_color_space(Cmono, 8) {
+      Some(Cmono) => {}
+      _ => panic!("8-bit monochrome is not kept")
+    }
+    for &bit_depth in &[10, 12] {
+      assert!(promote_color_space(Cmono, bit_depth).is_none());
+    }
   }
 }
@@ -0,0 +1,299 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Statistics of the frames of an encode per frame type, and bitrate over
+//! the last seconds of video.
+
+use crate::common::FrameSummary;
+use rav1e::*;
+
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Length of the window of `EncodeStats::window_bitrate`, in seconds
+const BITRATE_WINDOW_SECONDS: f64 = 5.0;
+
+/// Frames counted together in the statistics
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameCategory {
+  /// Keyframes
+  Key,
+  /// Inter frames shown as soon as they are coded
+  Inter,
+  /// Frames shown by a later packet than the one coding them, typically
+  /// alternate references. Their size is that of the packet showing them.
+  AltRef,
+  /// Frames coded without being shown
+  Hidden,
+}
+
+const CATEGORIES: [FrameCategory; 4] = [
+  FrameCategory::Key,
+  FrameCategory::Inter,
+  FrameCategory::AltRef,
+  FrameCategory::Hidden,
+];
+
+impl fmt::Display for FrameCategory {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      FrameCategory::Key => "KEY",
+      FrameCategory::Inter => "INTER",
+      FrameCategory::AltRef => "ALTREF",
+      FrameCategory::Hidden => "hidden",
+    };
+    f.pad(name)
+  }
+}
+
+/// Running totals of the frames of a category
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CategoryStats {
+  pub count: usize,
+  /// Size of the frames in bytes
+  pub size: usize,
+  qp_sum: u64,
+  psnr_sum: f64,
+  psnr_count: usize,
+}
+
+impl CategoryStats {
+  fn add(&mut self, size: usize, qp: u8, psnr: Option<(f64, f64, f64)>) {
+    self.count += 1;
+    self.size += size;
+    self.qp_sum += u64::from(qp);
+    if let Some((psnr_y, _, _)) = psnr {
+      self.psnr_sum += psnr_y;
+      self.psnr_count += 1;
+    }
+  }
+
+  pub fn avg_size(&self) -> usize {
+    self.size.checked_div(self.count).unwrap_or(0)
+  }
+
+  pub fn avg_qp(&self) -> f64 {
+    if self.count == 0 {
+      0.
+    } else {
+      self.qp_sum as f64 / self.count as f64
+    }
+  }
+
+  /// Mean luma PSNR of the frames it was computed for
+  pub fn avg_psnr(&self) -> Option<f64> {
+    if self.psnr_count == 0 {
+      None
+    } else {
+      Some(self.psnr_sum / self.psnr_count as f64)
+    }
+  }
+}
+
+/// Bitrate of the last frames, over a fixed duration of video
+#[derive(Clone, Debug)]
+pub struct BitrateWindow {
+  /// Frames per second of the video
+  fps: f64,
+  /// Number of frames in a full window
+  len: usize,
+  sizes: VecDeque<usize>,
+  /// Size in bytes of the frames in `sizes`
+  bytes: usize,
+}
+
+impl BitrateWindow {
+  pub fn new(seconds: f64, fps: f64) -> Self {
+    let len = ((seconds * fps).round() as usize).max(1);
+    BitrateWindow { fps, len, sizes: VecDeque::with_capacity(len), bytes: 0 }
+  }
+
+  /// Adds the size in bytes of the next frame, dropping the oldest one out
+  /// of the window
+  pub fn add(&mut self, size: usize) {
+    if self.sizes.len() == self.len {
+      self.bytes -= self.sizes.pop_front().unwrap();
+    }
+    self.sizes.push_back(size);
+    self.bytes += size;
+  }
+
+  /// Bitrate of the frames in the window, in bits/second
+  pub fn bitrate(&self) -> usize {
+    if self.sizes.is_empty() {
+      return 0;
+    }
+    ((self.bytes * 8) as f64 * self.fps / self.sizes.len() as f64) as usize
+  }
+}
+
+/// Statistics of the frames encoded so far
+#[derive(Clone, Debug)]
+pub struct EncodeStats {
+  categories: [CategoryStats; 4],
+  window: BitrateWindow,
+}
+
+impl EncodeStats {
+  pub fn new(fps: f64) -> Self {
+    EncodeStats {
+      categories: Default::default(),
+      window: BitrateWindow::new(BITRATE_WINDOW_SECONDS, fps),
+    }
+  }
+
+  /// Counts the frame shown by a packet and the hidden frames coded in it
+  pub fn add_frame(&mut self, frame: &FrameSummary) {
+    let mut shown_size = frame.size;
+    for hidden in frame.hidden_frames.iter() {
+      self.categories[FrameCategory::Hidden as usize]
+        .add(hidden.size, hidden.qp, None);
+      shown_size -= hidden.size;
+    }
+    let category = if frame.show_existing_frame {
+      FrameCategory::AltRef
+    } else if frame.frame_type == FrameType::KEY {
+      FrameCategory::Key
+    } else {
+      FrameCategory::Inter
+    };
+    self.categories[category as usize].add(shown_size, frame.qp, frame.psnr);
+    self.window.add(frame.size);
+  }
+
+  pub fn get(&self, category: FrameCategory) -> &CategoryStats {
+    &self.categories[category as usize]
+  }
+
+  /// Bitrate of the last 5 seconds of video, in bits/second
+  pub fn window_bitrate(&self) -> usize {
+    self.window.bitrate()
+  }
+}
+
+impl fmt::Display for EncodeStats {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Type       Count    Avg size   Avg QP   PSNR Y")?;
+    for &category in CATEGORIES.iter() {
+      let stats = self.get(category);
+      write!(
+        f,
+        "\n{:<8} {:>7} {:>9} B {:>8.2}",
+        category,
+        stats.count,
+        stats.avg_size(),
+        stats.avg_qp()
+      )?;
+      if let Some(psnr) = stats.avg_psnr() {
+        write!(f, " {:>8.4}", psnr)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn summary(
+    number: u64, frame_type: FrameType, size: usize, qp: u8,
+    show_existing_frame: bool, hidden_frames: Vec<HiddenFrame>
+  ) -> FrameSummary {
+    FrameSummary {
+      size,
+      number: Some(number),
+      frame_type,
+      qp,
+      pyramid_level: 0,
+      bits: Default::default(),
+      psnr: Some((40. + qp as f64 / 10., 45., 45.)),
+      show_existing_frame,
+      hidden_frames,
+    }
+  }
+
+  fn hidden(number: u64, size: usize, qp: u8) -> HiddenFrame {
+    HiddenFrame {
+      input_frameno: number,
+      coded_order: number,
+      frame_type: FrameType::INTER,
+      size,
+      qp
+    }
+  }
+
+  #[test]
+  fn per_category_averages() {
+    let mut stats = EncodeStats::new(30.);
+    // A keyframe, then two inter frames coded with the alternate reference
+    // of frame 3, shown by the last packet
+    stats.add_frame(&summary(0, FrameType::KEY, 1000, 60, false, vec![]));
+    stats.add_frame(&summary(
+      1, FrameType::INTER, 700, 100, false, vec![hidden(3, 400, 80)]
+    ));
+    stats.add_frame(&summary(2, FrameType::INTER, 100, 120, false, vec![]));
+    stats.add_frame(&summary(3, FrameType::INTER, 10, 80, true, vec![]));
+
+    let key = stats.get(FrameCategory::Key);
+    assert_eq!((key.count, key.avg_size()), (1, 1000));
+    assert_eq!(key.avg_psnr(), Some(46.));
+
+    let inter = stats.get(FrameCategory::Inter);
+    assert_eq!((inter.count, inter.size), (2, 400));
+    assert_eq!(inter.avg_qp(), 110.);
+
+    let altref = stats.get(FrameCategory::AltRef);
+    assert_eq!((altref.count, altref.avg_size()), (1, 10));
+
+    let hidden = stats.get(FrameCategory::Hidden);
+    assert_eq!((hidden.count, hidden.avg_size()), (1, 400));
+    assert_eq!(hidden.avg_qp(), 80.);
+    assert_eq!(hidden.avg_psnr(), None);
+
+    let table = stats.to_string();
+    assert_eq!(table.lines().count(), 5);
+    assert!(table.lines().nth(4).unwrap().starts_with("hidden"));
+  }
+
+  #[test]
+  fn empty_categories() {
+    let stats = EncodeStats::new(30.);
+    for &category in CATEGORIES.iter() {
+      let s = stats.get(category);
+      assert_eq!((s.count, s.avg_size(), s.avg_qp()), (0, 0, 0.));
+    }
+    assert_eq!(stats.window_bitrate(), 0);
+  }
+
+  #[test]
+  fn bitrate_window_slides() {
+    // 2 seconds of 10 fps video
+    let mut window = BitrateWindow::new(2., 10.);
+    for _ in 0..20 {
+      window.add(100);
+    }
+    assert_eq!(window.bitrate(), 8000);
+    // Only the last 20 frames count
+    for _ in 0..10 {
+      window.add(200);
+    }
+    assert_eq!(window.bitrate(), 12000);
+    for _ in 0..20 {
+      window.add(50);
+    }
+    assert_eq!(window.bitrate(), 4000);
+
+    // A partial window is over the frames in it
+    let mut window = BitrateWindow::new(2., 10.);
+    window.add(100);
+    window.add(300);
+    assert_eq!(window.bitrate(), 16000);
+  }
+}
@@ -0,0 +1,521 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! C API, enabled by the `capi` feature.
+//!
+//! A shared library is built with:
+//!
+//! ```text
+//! cargo rustc --release --lib --features capi -- --crate-type cdylib
+//! ```
+//!
+//! The encoder is driven like the Rust `Context`: frames are sent with
+//! `rav1e_send_frame_planes` and packets are fetched with
+//! `rav1e_receive_packet` until it returns `RaEncoderStatus::NeedMoreData`, or
+//! `RaEncoderStatus::LimitReached` once flushed. Neither call blocks waiting
+//! for the other, which maps directly to generators in bindings:
+//!
+//! ```c
+//! RaPacket *pkt;
+//! while (read_frame(y, u, v)) {
+//!   rav1e_send_frame_planes(ctx, y, y_stride, u, u_stride, v, v_stride);
+//!   while (rav1e_receive_packet(ctx, &pkt) == 0) {
+//!     write_packet(pkt->data, pkt->len);
+//!     rav1e_packet_unref(pkt);
+//!   }
+//!   /* 1 (NeedMoreData): the next frame is needed */
+//! }
+//! rav1e_send_frame_planes(ctx, NULL, 0, NULL, 0, NULL, 0);
+//! while (rav1e_receive_packet(ctx, &pkt) == 0) {
+//!   write_packet(pkt->data, pkt->len);
+//!   rav1e_packet_unref(pkt);
+//! }
+//! /* 3 (LimitReached): every packet was output */
+//! ```
+//!
+//! `examples/python/encode.py` wraps this loop in a Python generator with
+//! `ctypes`.
+
+use std::ffi::CStr;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+use libc::{c_char, c_int, ptrdiff_t, size_t};
+
+use crate::api::*;
+use crate::encoder::Frame;
+use crate::util::Pixel;
+
+/// Encoder configuration, created by `rav1e_config_default`
+pub struct RaConfig {
+  cfg: Config,
+}
+
+enum EncContext {
+  U8(Context<u8>),
+  U16(Context<u16>),
+}
+
+/// Encoder context, created by `rav1e_context_new`
+pub struct RaContext {
+  ctx: EncContext,
+  config: EncoderConfig,
+}
+
+/// An encoded packet, to be freed with `rav1e_packet_unref`
+#[repr(C)]
+pub struct RaPacket {
+  pub data: *const u8,
+  pub len: size_t,
+  /// Number of the frame shown in display order, if `shown`
+  pub input_frameno: u64,
+  /// Whether the packet shows a frame, as all packets currently do
+  pub shown: bool,
+  pub frame_type: FrameType,
+  /// Holds the memory `data` points to
+  _owner: Vec<u8>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RaEncoderStatus {
+  Success = 0,
+  NeedMoreData,
+  EnoughData,
+  LimitReached,
+  Failure = -1,
+  InvalidConfig = -2,
+}
+
+impl From<EncoderStatus> for RaEncoderStatus {
+  fn from(status: EncoderStatus) -> Self {
+    match status {
+      EncoderStatus::NeedMoreData => RaEncoderStatus::NeedMoreData,
+      EncoderStatus::EnoughData => RaEncoderStatus::EnoughData,
+      EncoderStatus::LimitReached => RaEncoderStatus::LimitReached,
+      // The C API sets no progress observer to cancel the encoding
+      EncoderStatus::Failure
+      | EncoderStatus::OutOfMemory
+      | EncoderStatus::Cancelled => RaEncoderStatus::Failure,
+      EncoderStatus::InvalidConfig(_) => RaEncoderStatus::InvalidConfig,
+    }
+  }
+}
+
+impl<T> From<Result<T, EncoderStatus>> for RaEncoderStatus {
+  fn from(result: Result<T, EncoderStatus>) -> Self {
+    match result {
+      Ok(_) => RaEncoderStatus::Success,
+      Err(status) => status.into(),
+    }
+  }
+}
+
+/// Returns a configuration with the default settings for 8-bit 4:2:0
+/// 640x480 content, to be freed with `rav1e_config_unref`.
+#[no_mangle]
+pub extern "C" fn rav1e_config_default() -> *mut RaConfig {
+  let mut enc = EncoderConfig::default();
+  enc.width = 640;
+  enc.height = 480;
+  let cfg = Config::new(enc);
+  Box::into_raw(Box::new(RaConfig { cfg }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_config_unref(cfg: *mut RaConfig) {
+  if !cfg.is_null() {
+    drop(Box::from_raw(cfg));
+  }
+}
+
+/// Sets the size of the frames in luma samples. Returns 0 on success and a
+/// negative value if either dimension is 0.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_config_set_dimensions(
+  cfg: *mut RaConfig, width: size_t, height: size_t
+) -> c_int {
+  if width == 0 || height == 0 {
+    return -1;
+  }
+  let enc = &mut (*cfg).cfg.enc;
+  enc.width = width;
+  enc.height = height;
+  0
+}
+
+/// Sets the bit depth of the samples, 8, 10 or 12. Returns 0 on success and
+/// a negative value for other bit depths.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_config_set_bit_depth(
+  cfg: *mut RaConfig, bit_depth: c_int
+) -> c_int {
+  match bit_depth {
+    8 | 10 | 12 => {
+      (*cfg).cfg.enc.bit_depth = bit_depth as usize;
+      0
+    }
+    _ => -1,
+  }
+}
+
+/// Error of `rav1e_config_parse`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ParseError {
+  UnknownKey = -1,
+  InvalidValue = -2,
+}
+
+fn parse_range<T: std::str::FromStr + PartialOrd>(
+  value: &str, min: T, max: T
+) -> Result<T, ParseError> {
+  match value.parse() {
+    Ok(v) if v >= min && v <= max => Ok(v),
+    _ => Err(ParseError::InvalidValue),
+  }
+}
+
+fn config_parse(
+  enc: &mut EncoderConfig, key: &str, value: &str
+) -> Result<(), ParseError> {
+  match key {
+    "speed" => {
+      enc.speed_settings = SpeedSettings::from_preset(parse_range(value, 0, 10)?);
+    }
+    "qp" => {
+      // Lossless coding is not supported
+      enc.quantizer = parse_range(value, 1, 255)?;
+    }
+    "tiles" => {
+      enc.tiles = match value {
+        "single" => TileConfig::Single,
+        _ if value.starts_with("level") => {
+          value.parse().map_err(|_| ParseError::InvalidValue)?
+        }
+        _ => TileConfig::Count(parse_range(value, 0, 4096)?),
+      };
+    }
+    "keyint" => {
+      enc.max_key_frame_interval = parse_range(value, 1, std::u64::MAX)?;
+      enc.min_key_frame_interval =
+        enc.min_key_frame_interval.min(enc.max_key_frame_interval);
+    }
+    "tune" => {
+      enc.tune = value.parse().map_err(|_| ParseError::InvalidValue)?;
+    }
+    _ => return Err(ParseError::UnknownKey),
+  }
+  Ok(())
+}
+
+/// Sets an option of the configuration from its name and value as strings:
+///
+/// - `speed`: speed preset, from 0 to 10
+/// - `qp`: quantizer, from 1 to 255
+/// - `tiles`: approximate number of tiles, from 0 (as set by the tile
+///   columns and rows) to 4096, `single` for exactly one tile per frame, or
+///   `level` and a level, e.g. `level5.1`, for the fewest tiles within it
+/// - `keyint`: maximum interval between two key frames, at least 1
+/// - `tune`: `Psnr` or `Psychovisual`
+///
+/// Returns 0 on success, -1 for an unknown key and -2 for an invalid value.
+/// The configuration is left unchanged on error.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_config_parse(
+  cfg: *mut RaConfig, key: *const c_char, value: *const c_char
+) -> c_int {
+  let key = CStr::from_ptr(key).to_str();
+  let value = CStr::from_ptr(value).to_str();
+  let result = match (key, value) {
+    (Ok(key), Ok(value)) => config_parse(&mut (*cfg).cfg.enc, key, value),
+    (Err(_), _) => Err(ParseError::UnknownKey),
+    (_, Err(_)) => Err(ParseError::InvalidValue),
+  };
+  match result {
+    Ok(()) => 0,
+    Err(e) => e as c_int,
+  }
+}
+
+/// Returns the largest number of frames sent after a frame before
+/// `rav1e_receive_packet` returns the packet showing it, unless the context
+/// is flushed.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_config_max_reorder_latency(
+  cfg: *const RaConfig
+) -> size_t {
+  (*cfg).cfg.max_reorder_latency()
+}
+
+/// Creates an encoder context from a configuration, which may be freed
+/// right after. Returns null if the configuration is invalid.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_context_new(
+  cfg: *const RaConfig
+) -> *mut RaContext {
+  let cfg = &(*cfg).cfg;
+  let ctx = if cfg.enc.internal_bit_depth.unwrap_or(cfg.enc.bit_depth) == 8 {
+    cfg.new_context().map(EncContext::U8)
+  } else {
+    cfg.new_context().map(EncContext::U16)
+  };
+  match ctx {
+    Ok(ctx) => {
+      Box::into_raw(Box::new(RaContext { ctx, config: cfg.enc.clone() }))
+    }
+    Err(_) => ptr::null_mut(),
+  }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_context_unref(ctx: *mut RaContext) {
+  if !ctx.is_null() {
+    drop(Box::from_raw(ctx));
+  }
+}
+
+/// Copies the planes into a new frame of the size of the context, padded
+/// for the encoder. Each plane is `stride` bytes per row, with samples of 1
+/// byte for 8-bit content and of 2 little-endian bytes otherwise.
+unsafe fn frame_from_planes<T: Pixel>(
+  config: &EncoderConfig, planes: &[(*const u8, ptrdiff_t); 3],
+  bytewidth: usize
+) -> Option<Frame<T>> {
+  let mut frame =
+    Frame::new(config.width, config.height, config.chroma_sampling);
+  let chroma_planes = if config.chroma_sampling == ChromaSampling::Cs400 {
+    1
+  } else {
+    3
+  };
+
+  for (plane, &(data, stride)) in
+    frame.planes.iter_mut().zip(planes.iter()).take(chroma_planes)
+  {
+    let row_bytes = plane.cfg.width * bytewidth;
+    if data.is_null() || stride < row_bytes as ptrdiff_t {
+      return None;
+    }
+    let stride = stride as usize;
+    let len = stride * (plane.cfg.height - 1) + row_bytes;
+    plane.copy_from_raw_u8(slice::from_raw_parts(data, len), stride, bytewidth);
+  }
+  frame.pad(config.width, config.height);
+
+  Some(frame)
+}
+
+unsafe fn send_planes<T: Pixel>(
+  ctx: &mut Context<T>, config: &EncoderConfig,
+  planes: &[(*const u8, ptrdiff_t); 3]
+) -> RaEncoderStatus {
+  if planes[0].0.is_null() {
+    ctx.flush();
+    return RaEncoderStatus::Success;
+  }
+  let bytewidth = if config.bit_depth > 8 { 2 } else { 1 };
+  match frame_from_planes(config, planes, bytewidth) {
+    Some(frame) => ctx.send_frame(Arc::new(frame)).into(),
+    None => RaEncoderStatus::Failure,
+  }
+}
+
+/// Sends a frame to the encoder, copied from one pointer and stride in
+/// bytes per plane. The chroma planes are ignored for 4:0:0 content.
+/// A null `y` flushes the encoder: the remaining packets are then output
+/// and no more frames are accepted.
+///
+/// Returns `RaEncoderStatus::Failure` if a plane is missing or its stride
+/// is shorter than a row.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_send_frame_planes(
+  ctx: *mut RaContext, y: *const u8, y_stride: ptrdiff_t, u: *const u8,
+  u_stride: ptrdiff_t, v: *const u8, v_stride: ptrdiff_t
+) -> RaEncoderStatus {
+  let planes = [(y, y_stride), (u, u_stride), (v, v_stride)];
+  let RaContext { ref mut ctx, ref config } = *ctx;
+  match ctx {
+    EncContext::U8(ctx) => send_planes(ctx, config, &planes),
+    EncContext::U16(ctx) => send_planes(ctx, config, &planes),
+  }
+}
+
+fn packet_from<T: Pixel>(packet: Packet<T>) -> *mut RaPacket {
+  let owner = packet.data;
+  Box::into_raw(Box::new(RaPacket {
+    data: owner.as_ptr(),
+    len: owner.len(),
+    input_frameno: packet.input_frameno.unwrap_or(0),
+    shown: packet.shown,
+    frame_type: packet.frame_type,
+    _owner: owner,
+  }))
+}
+
+/// Fetches the next encoded packet into `*packet` and returns
+/// `RaEncoderStatus::Success`, or returns the status of the encoder and
+/// leaves `*packet` untouched.
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_receive_packet(
+  ctx: *mut RaContext, packet: *mut *mut RaPacket
+) -> RaEncoderStatus {
+  let result = match (*ctx).ctx {
+    EncContext::U8(ref mut ctx) => ctx.receive_packet().map(packet_from),
+    EncContext::U16(ref mut ctx) => ctx.receive_packet().map(packet_from),
+  };
+  match result {
+    Ok(p) => {
+      *packet = p;
+      RaEncoderStatus::Success
+    }
+    Err(status) => status.into(),
+  }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rav1e_packet_unref(packet: *mut RaPacket) {
+  if !packet.is_null() {
+    drop(Box::from_raw(packet));
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::ffi::CString;
+
+  unsafe fn encode(bit_depth: c_int, limit: usize) -> Vec<u64> {
+    let (w, h) = (64, 48);
+    let bytewidth = if bit_depth > 8 { 2 } else { 1 };
+    let cfg = rav1e_config_default();
+    assert_eq!(rav1e_config_set_dimensions(cfg, w, h), 0);
+    assert_eq!(rav1e_config_set_bit_depth(cfg, bit_depth), 0);
+    (*cfg).cfg.enc.speed_settings = SpeedSettings::from_preset(10);
+    let ctx = rav1e_context_new(cfg);
+    rav1e_config_unref(cfg);
+    assert!(!ctx.is_null());
+
+    // Rows are padded past the width, as in most frame buffers
+    let y_stride = (w + 16) * bytewidth;
+    let c_stride = (w / 2 + 8) * bytewidth;
+    // Samples wider than 8 bits are little endian, scaled to the bit depth
+    let fill = |value: u16, len: usize| -> Vec<u8> {
+      let value = value << (bit_depth - 8);
+      if bytewidth == 2 {
+        (0..len / 2).flat_map(|_| vec![value as u8, (value >> 8) as u8]).collect()
+      } else {
+        vec![value as u8; len]
+      }
+    };
+    let y = fill(96, y_stride * h);
+    let u = fill(64, c_stride * h / 2);
+    let v = fill(192, c_stride * h / 2);
+
+    let mut frames = Vec::new();
+    for i in 0..=limit {
+      let status = if i < limit {
+        rav1e_send_frame_planes(
+          ctx, y.as_ptr(), y_stride as ptrdiff_t, u.as_ptr(),
+          c_stride as ptrdiff_t, v.as_ptr(), c_stride as ptrdiff_t
+        )
+      } else {
+        rav1e_send_frame_planes(ctx, ptr::null(), 0, ptr::null(), 0, ptr::null(), 0)
+      };
+      assert_eq!(status, RaEncoderStatus::Success);
+
+      loop {
+        let mut packet = ptr::null_mut();
+        match rav1e_receive_packet(ctx, &mut packet) {
+          RaEncoderStatus::Success => {
+            assert!((*packet).len > 0);
+            assert!((*packet).shown);
+            frames.push((*packet).input_frameno);
+            rav1e_packet_unref(packet);
+          }
+          RaEncoderStatus::NeedMoreData | RaEncoderStatus::LimitReached => break,
+          status => panic!("{:?}", status),
+        }
+      }
+    }
+    rav1e_context_unref(ctx);
+    frames
+  }
+
+  #[test]
+  fn send_frame_planes() {
+    for &bit_depth in &[8, 10] {
+      let mut frames = unsafe { encode(bit_depth, 5) };
+      frames.sort();
+      frames.dedup();
+      assert_eq!(frames, vec![0, 1, 2, 3, 4]);
+    }
+  }
+
+  #[test]
+  fn config_parse() {
+    fn parse(cfg: *mut RaConfig, key: &str, value: &str) -> c_int {
+      let key = CString::new(key).unwrap();
+      let value = CString::new(value).unwrap();
+      unsafe { rav1e_config_parse(cfg, key.as_ptr(), value.as_ptr()) }
+    }
+
+    let cfg = rav1e_config_default();
+    assert_eq!(parse(cfg, "speed", "10"), 0);
+    assert_eq!(parse(cfg, "qp", "80"), 0);
+    assert_eq!(parse(cfg, "tiles", "4"), 0);
+    assert_eq!(parse(cfg, "keyint", "6"), 0);
+    assert_eq!(parse(cfg, "tune", "Psnr"), 0);
+    {
+      let enc = unsafe { &(*cfg).cfg.enc };
+      assert_eq!(enc.speed_settings.min_block_size, SpeedSettings::from_preset(10).min_block_size);
+      assert_eq!(enc.quantizer, 80);
+      assert_eq!(enc.tiles, TileConfig::Count(4));
+      assert_eq!(enc.max_key_frame_interval, 6);
+      assert!(enc.min_key_frame_interval <= 6);
+      assert_eq!(enc.tune, Tune::Psnr);
+    }
+
+    assert_eq!(parse(cfg, "speeed", "1"), -1);
+    assert_eq!(parse(cfg, "speed", "11"), -2);
+    assert_eq!(parse(cfg, "qp", "0"), -2);
+    assert_eq!(parse(cfg, "qp", "high"), -2);
+    assert_eq!(parse(cfg, "keyint", "0"), -2);
+    assert_eq!(parse(cfg, "tune", "fast"), -2);
+    assert_eq!(unsafe { (*cfg).cfg.enc.quantizer }, 80);
+    unsafe { rav1e_config_unref(cfg) };
+  }
+
+  #[test]
+  fn max_reorder_latency() {
+    let cfg = rav1e_config_default();
+    let latency = unsafe { rav1e_config_max_reorder_latency(cfg) };
+    assert_eq!(latency, unsafe { (*cfg).cfg.max_reorder_latency() });
+    assert!(latency > 0);
+    unsafe { rav1e_config_unref(cfg) };
+  }
+
+  #[test]
+  fn short_stride_is_rejected() {
+    unsafe {
+      let cfg = rav1e_config_default();
+      rav1e_config_set_dimensions(cfg, 64, 48);
+      let ctx = rav1e_context_new(cfg);
+      rav1e_config_unref(cfg);
+
+      let y = vec![0u8; 64 * 48];
+      let c = vec![0u8; 32 * 24];
+      let status = rav1e_send_frame_planes(
+        ctx, y.as_ptr(), 32, c.as_ptr(), 32, c.as_ptr(), 32
+      );
+      assert_eq!(status, RaEncoderStatus::Failure);
+      rav1e_context_unref(ctx);
+    }
+  }
+}
@@ -427,6 +427,65 @@ pub fn cdef_filter_superblock<T: Pixel>(
   }
 }
 
+/// Luma strength of the CDEF model of `cdef_model_sse`, coded as in
+/// `FrameInvariants::cdef_y_strengths`: a mid-range primary strength with
+/// the weakest secondary strength.
+pub const CDEF_MODEL_Y_STRENGTH: u8 = 3 * 4 + 1;
+
+/// Sum of squared errors of a luma block after a fixed-strength CDEF, as a
+/// cheap model of the distortion left by the in-loop filter. Each 8x8 block
+/// is filtered alone, in its own direction, as the neighboring pixels may
+/// not be reconstructed yet.
+pub fn cdef_model_sse<T: Pixel>(
+  src: &PlaneRegion<'_, T>, rec: &PlaneRegion<'_, T>, w: usize, h: usize,
+  bit_depth: usize, damping: u8
+) -> u64 {
+  assert!(w & 0x7 == 0);
+  assert!(h & 0x7 == 0);
+
+  let coeff_shift = bit_depth - 8;
+  let pri_strength = (CDEF_MODEL_Y_STRENGTH / CDEF_SEC_STRENGTHS) as i32;
+  let sec_strength = (CDEF_MODEL_Y_STRENGTH % CDEF_SEC_STRENGTHS) as i32;
+  let damping = damping as i32 + coeff_shift as i32;
+
+  // The 2-pixel border stays flagged as unavailable
+  let mut padded = Plane::wrap(vec![CDEF_VERY_LARGE; 12 * 12], 12);
+  let mut filtered = [T::cast_from(0u16); 8 * 8];
+  let mut sse: u64 = 0;
+  for by in (0..h).step_by(8) {
+    for bx in (0..w).step_by(8) {
+      {
+        let data = padded.data_origin_mut();
+        for y in 0..8 {
+          for x in 0..8 {
+            data[(y + 2) * 12 + x + 2] = u16::cast_from(rec[by + y][bx + x]);
+          }
+        }
+      }
+      let mut var: i32 = 0;
+      let dir = cdef_find_dir(
+        &padded.slice(PlaneOffset { x: 2, y: 2 }), &mut var, coeff_shift
+      );
+      unsafe {
+        cdef_filter_block(filtered.as_mut_ptr(), 8,
+                          padded.data_origin().as_ptr(), 12,
+                          adjust_strength(pri_strength << coeff_shift, var),
+                          sec_strength << coeff_shift,
+                          if pri_strength != 0 { dir as usize } else { 0 },
+                          damping, 8, 8, coeff_shift as i32);
+      }
+      for y in 0..8 {
+        for x in 0..8 {
+          let d = i32::cast_from(src[by + y][bx + x])
+            - i32::cast_from(filtered[y * 8 + x]);
+          sse += (d * d) as u64;
+        }
+      }
+    }
+  }
+  sse
+}
+
 // Input to this process is the array CurrFrame of reconstructed samples.
 // Output from this process is the array CdefFrame containing deringed samples.
 // The purpose of CDEF is to perform deringing based on the detected direction of blocks.
@@ -544,7 +603,7 @@ mod test {
       speed_settings: SpeedSettings::from_preset(10),
       ..Default::default()
     };
-    let sequence = Sequence::new(&Default::default());
+    let sequence = Sequence::new(&Default::default(), None);
     let fi = FrameInvariants::new(config, sequence);
     (frame, fi)
   }
@@ -619,4 +678,75 @@ mod test {
       }
     }
   }
+
+  fn luma_sse(a: &Frame<u8>, b: &Frame<u8>) -> u64 {
+    let (a, b) = (a.planes[0].as_region(), b.planes[0].as_region());
+    let mut sse = 0;
+    for y in 0..64 {
+      for x in 0..64 {
+        let d = i32::from(a[y][x]) - i32::from(b[y][x]);
+        sse += (d * d) as u64;
+      }
+    }
+    sse
+  }
+
+  #[test]
+  fn cdef_model_tracks_filter() {
+    let config = EncoderConfig { width: 64, height: 64, ..Default::default() };
+    let sequence = Sequence::new(&config, None);
+    let mut fi = FrameInvariants::<u8>::new(config, sequence);
+    fi.cdef_y_strengths[0] = CDEF_MODEL_Y_STRENGTH;
+    let blocks = FrameBlocks::new(fi.w_in_b, fi.h_in_b);
+
+    // Flat areas split by edges, on a gentle slope
+    let mut src = Frame::<u8>::new(64, 64, ChromaSampling::Cs420);
+    let stride = src.planes[0].cfg.stride;
+    for y in 0..64 {
+      for x in 0..64 {
+        let level = if (x / 12 + y / 20) % 2 == 0 { 60 } else { 180 };
+        src.planes[0].data_origin_mut()[y * stride + x] = (level + (x + y) / 4) as u8;
+      }
+    }
+
+    let mut seed: u32 = 1;
+    let mut pairs = Vec::new();
+    for &amplitude in &[2, 4, 6, 8, 12, 16, 24] {
+      let mut rec = src.clone();
+      for v in rec.planes[0].data_origin_mut()[..64 * stride].iter_mut() {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        let noise = ((seed >> 16) as i32 % (2 * amplitude + 1)) - amplitude;
+        *v = (i32::from(*v) + noise).max(0).min(255) as u8;
+      }
+
+      let modeled = cdef_model_sse(
+        &src.planes[0].as_region(), &rec.planes[0].as_region(), 64, 64, 8,
+        fi.cdef_damping
+      );
+      let mut filtered = rec.clone();
+      cdef_filter_frame(&fi, &mut filtered, &blocks);
+      let filtered = luma_sse(&src, &filtered);
+      // Closer to the filtered distortion than the unfiltered one is, where
+      // the filter removes most of the noise
+      if amplitude <= 6 {
+        let unfiltered = luma_sse(&src, &rec);
+        let error = |sse: u64| (sse as i64 - filtered as i64).abs();
+        assert!(
+          error(modeled) < error(unfiltered),
+          "amplitude {}: {} modeled, {} unfiltered, {} filtered",
+          amplitude, modeled, unfiltered, filtered
+        );
+      }
+      pairs.push((modeled as f64, filtered as f64));
+    }
+
+    let n = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = pairs.iter().map(|p| p.1).sum::<f64>() / n;
+    let cov: f64 = pairs.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+    let var_x: f64 = pairs.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+    let var_y: f64 = pairs.iter().map(|p| (p.1 - mean_y).powi(2)).sum();
+    let correlation = cov / (var_x * var_y).sqrt();
+    assert!(correlation > 0.95, "correlation {}", correlation);
+  }
 }
@@ -12,7 +12,7 @@
 #![allow(dead_code)]
 #![allow(non_camel_case_types)]
 
-use crate::ec::Writer;
+use crate::ec::{Writer, WriterCheckpoint, WriterCounter};
 use crate::ec::OD_BITRES;
 use crate::encoder::FrameInvariants;
 use crate::entropymode::*;
@@ -25,6 +25,7 @@ use crate::partition::TxType::*;
 use crate::partition::*;
 use crate::lrf::*;
 use crate::plane::*;
+use crate::predict::IntraEdgeFilterType;
 use crate::scan_order::*;
 use crate::tiling::*;
 use crate::token_cdfs::*;
@@ -755,6 +756,7 @@ pub struct CDFContext {
     [[[[u16; TX_TYPES + 1]; INTRA_MODES]; TX_SIZE_SQR_CONTEXTS]; TX_SETS_INTRA],
   inter_tx_cdf: [[[u16; TX_TYPES + 1]; TX_SIZE_SQR_CONTEXTS]; TX_SETS_INTER],
   tx_size_cdf: [[[u16; MAX_TX_DEPTH + 1 + 1]; TX_SIZE_CONTEXTS]; MAX_TX_CATS],
+  txfm_partition_cdf: [[u16; 3]; TXFM_PARTITION_CONTEXTS],
   skip_cdfs: [[u16; 3]; SKIP_CONTEXTS],
   intra_inter_cdfs: [[u16; 3]; INTRA_INTER_CONTEXTS],
   angle_delta_cdf: [[u16; 2 * MAX_ANGLE_DELTA + 1 + 1]; DIRECTIONAL_MODES],
@@ -767,6 +769,7 @@ pub struct CDFContext {
   drl_cdfs: [[u16; 2 + 1]; DRL_MODE_CONTEXTS],
   compound_mode_cdf: [[u16; INTER_COMPOUND_MODES + 1]; INTER_MODE_CONTEXTS],
   nmv_context: NMVContext,
+  delta_q_cdf: [u16; DELTA_Q_PROBS + 1 + 1],
   deblock_delta_multi_cdf: [[u16; DELTA_LF_PROBS + 1 + 1]; FRAME_LF_COUNT],
   deblock_delta_cdf: [u16; DELTA_LF_PROBS + 1 + 1],
   spatial_segmentation_cdfs: [[u16; 8 + 1]; 3],
@@ -817,6 +820,7 @@ impl CDFContext {
       intra_tx_cdf: default_intra_ext_tx_cdf,
       inter_tx_cdf: default_inter_ext_tx_cdf,
       tx_size_cdf: default_tx_size_cdf,
+      txfm_partition_cdf: default_txfm_partition_cdf,
       skip_cdfs: default_skip_cdfs,
       intra_inter_cdfs: default_intra_inter_cdf,
       angle_delta_cdf: default_angle_delta_cdf,
@@ -829,6 +833,7 @@ impl CDFContext {
       drl_cdfs: default_drl_cdf,
       compound_mode_cdf: default_compound_mode_cdf,
       nmv_context: default_nmv_context,
+      delta_q_cdf: default_delta_q_cdf,
       deblock_delta_multi_cdf: default_delta_lf_multi_cdf,
       deblock_delta_cdf: default_delta_lf_cdf,
       spatial_segmentation_cdfs: default_spatial_pred_seg_tree_cdf,
@@ -900,6 +905,7 @@ impl CDFContext {
     reset_2d!(self.tx_size_cdf[1]);
     reset_2d!(self.tx_size_cdf[2]);
     reset_2d!(self.tx_size_cdf[3]);
+    reset_2d!(self.txfm_partition_cdf);
 
     reset_2d!(self.skip_cdfs);
     reset_2d!(self.intra_inter_cdfs);
@@ -912,6 +918,7 @@ impl CDFContext {
     reset_3d!(self.single_ref_cdfs);
     reset_2d!(self.drl_cdfs);
     reset_2d!(self.compound_mode_cdf);
+    reset_1d!(self.delta_q_cdf);
     reset_2d!(self.deblock_delta_multi_cdf);
     reset_1d!(self.deblock_delta_cdf);
     reset_2d!(self.spatial_segmentation_cdfs);
@@ -1007,6 +1014,8 @@ impl CDFContext {
       self.comp_bwd_ref_cdf.first().unwrap().as_ptr() as usize;
     let comp_bwd_ref_cdf_end =
       comp_bwd_ref_cdf_start + size_of_val(&self.comp_bwd_ref_cdf);
+    let delta_q_cdf_start = self.delta_q_cdf.as_ptr() as usize;
+    let delta_q_cdf_end = delta_q_cdf_start + size_of_val(&self.delta_q_cdf);
     let deblock_delta_multi_cdf_start =
       self.deblock_delta_multi_cdf.first().unwrap().as_ptr() as usize;
     let deblock_delta_multi_cdf_end =
@@ -1101,6 +1110,7 @@ impl CDFContext {
       ("comp_ref_type_cdf", comp_ref_type_cdf_start, comp_ref_type_cdf_end),
       ("comp_ref_cdf", comp_ref_cdf_start, comp_ref_cdf_end),
       ("comp_bwd_ref_cdf", comp_bwd_ref_cdf_start, comp_bwd_ref_cdf_end),
+      ("delta_q_cdf", delta_q_cdf_start, delta_q_cdf_end),
       ("deblock_delta_multi_cdf", deblock_delta_multi_cdf_start, deblock_delta_multi_cdf_end),
       ("deblock_delta_cdf", deblock_delta_cdf_start, deblock_delta_cdf_end),
       ("spatial_segmentation_cdfs", spatial_segmentation_cdfs_start, spatial_segmentation_cdfs_end),
@@ -1122,6 +1132,22 @@ impl CDFContext {
       ("coeff_br_cdf", coeff_br_cdf_start, coeff_br_cdf_end),
     ]
   }
+
+  /// Returns the values of the tables listed by `build_map`, by name, with
+  /// the adaptation counters of each CDF in their last element
+  pub fn tables(&self) -> Vec<(&'static str, &[u16])> {
+    self
+      .build_map()
+      .into_iter()
+      .map(|(name, start, end)| {
+        // The ranges are those of u16 arrays within self
+        let table = unsafe {
+          slice::from_raw_parts(start as *const u16, (end - start) / 2)
+        };
+        (name, table)
+      })
+      .collect()
+  }
 }
 
 impl fmt::Debug for CDFContext {
@@ -1163,6 +1189,90 @@ mod test {
     [ 0, 3, 0, 1, 4, 0, 2, 5 ]
   ];
 
+  #[test]
+  fn coeff_cost_matches_coded_size() {
+    use super::*;
+    use crate::ec::WriterEncoder;
+
+    let mut fb = FrameBlocks::new(16, 16);
+    let mut tb = fb.as_tile_blocks_mut();
+    let mut fc = CDFContext::new(100);
+    let mut cw = ContextWriter::new(&mut fc, BlockContext::new(&mut tb));
+    let bo = BlockOffset { x: 0, y: 0 };
+
+    // Empty, sparse, dense and large coefficients, of intra and inter
+    // blocks, in transforms of several sizes and types
+    let mut seed = 1u32;
+    let mut random = |range: i32| {
+      seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+      (seed >> 16) as i32 % (2 * range + 1) - range
+    };
+    for &(tx_size, bsize, tx_type, pred_mode) in &[
+      (TX_8X8, BLOCK_8X8, DCT_DCT, DC_PRED),
+      (TX_4X4, BLOCK_4X4, ADST_DCT, V_PRED),
+      (TX_16X16, BLOCK_16X16, DCT_DCT, NEWMV),
+      (TX_8X16, BLOCK_8X16, IDTX, NEARESTMV),
+    ] {
+      let area = tx_size.area();
+      let sparse: Vec<i32> = (0..area)
+        .map(|i| match i { 0 => 12, 1 => -3, 9 => -40, 27 => 1, _ => 0 })
+        .collect();
+      let dense: Vec<i32> = (0..area).map(|_| random(3)).collect();
+      let large: Vec<i32> = (0..area).map(|_| random(2000)).collect();
+      for coeffs in &[vec![0; area], sparse, dense, large] {
+        for &plane in [0, 1].iter() {
+          let cost = cw.coeff_cost(
+            plane, bo, coeffs, pred_mode, tx_size, tx_type, bsize, 0, 0, false
+          );
+          // The contexts are left untouched
+          assert_eq!(cost, cw.coeff_cost(
+            plane, bo, coeffs, pred_mode, tx_size, tx_type, bsize, 0, 0, false
+          ));
+
+          let mut w = WriterEncoder::new();
+          let tell = w.tell_frac();
+          let checkpoint = cw.checkpoint();
+          cw.write_coeffs_lv_map(
+            &mut w, plane, bo, coeffs, pred_mode, tx_size, tx_type, bsize, 0,
+            0, false
+          );
+          cw.rollback(&checkpoint);
+          assert_eq!(cost, w.tell_frac() - tell);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn reduced_tx_set_shrinks_tx_type_symbols() {
+    use super::*;
+
+    for &tx_size in &[TX_4X4, TX_8X8, TX_16X16, TX_32X32, TX_8X16, TX_16X4] {
+      for &is_inter in &[false, true] {
+        let full = get_tx_set(tx_size, is_inter, false) as usize;
+        let reduced = get_tx_set(tx_size, is_inter, true) as usize;
+        assert!(num_tx_set[reduced] <= num_tx_set[full]);
+        for tx_type in 0..TX_TYPES {
+          assert!(av1_tx_used[reduced][tx_type] <= av1_tx_used[full][tx_type]);
+        }
+      }
+    }
+
+    let bits = |tx_type: TxType, use_reduced_tx_set: bool| {
+      let mut fb = FrameBlocks::new(16, 16);
+      let mut tb = fb.as_tile_blocks_mut();
+      let mut fc = CDFContext::new(100);
+      let mut cw = ContextWriter::new(&mut fc, BlockContext::new(&mut tb));
+      let mut w = WriterCounter::new();
+      cw.write_tx_type(&mut w, TX_8X8, tx_type, NEWMV, true, use_reduced_tx_set);
+      w.tell_frac()
+    };
+    // The types of the reduced set are also cheaper to code
+    for &tx_type in &[DCT_DCT, IDTX] {
+      assert!(bits(tx_type, true) < bits(tx_type, false));
+    }
+  }
+
   #[test]
   fn cfl_joint_sign() {
     use super::*;
@@ -1213,7 +1323,7 @@ impl SuperBlockOffset {
 
 /// Absolute offset in blocks inside a plane, where a block is defined
 /// to be an N*N square where N = (1 << BLOCK_TO_PLANE_SHIFT).
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct BlockOffset {
   pub x: usize,
   pub y: usize
@@ -1249,6 +1359,16 @@ impl BlockOffset {
     self.y % MAX_MIB_SIZE
   }
 
+  /// Offset of the block just past the bottom-right corner of a block of
+  /// size `bsize` at this offset, i.e. the exclusive end of its area.
+  #[inline]
+  pub fn add_size(self, bsize: BlockSize) -> BlockOffset {
+    BlockOffset {
+      x: self.x + bsize.width_mi(),
+      y: self.y + bsize.height_mi()
+    }
+  }
+
   pub fn with_offset(self, col_offset: isize, row_offset: isize) -> BlockOffset {
     let x = self.x as isize + col_offset;
     let y = self.y as isize + row_offset;
@@ -1265,6 +1385,8 @@ impl BlockOffset {
 #[derive(Clone)]
 pub struct Block {
   pub mode: PredictionMode,
+  /// Chroma mode of the intra blocks carrying chroma, `UVModes` in the spec
+  pub uv_mode: PredictionMode,
   pub partition: PartitionType,
   pub skip: bool,
   pub ref_frames: [RefType; 2],
@@ -1287,6 +1409,7 @@ impl Block {
   pub fn default() -> Block {
     Block {
       mode: PredictionMode::DC_PRED,
+      uv_mode: PredictionMode::DC_PRED,
       partition: PartitionType::PARTITION_NONE,
       skip: false,
       ref_frames: [INTRA_FRAME; 2],
@@ -1376,6 +1499,8 @@ impl IndexMut<BlockOffset> for FrameBlocks {
 #[derive(Clone)]
 pub struct BlockContextCheckpoint {
   cdef_coded: bool,
+  code_deltas: bool,
+  current_qidx: u8,
   above_partition_context: Vec<u8>,
   left_partition_context: [u8; MAX_MIB_SIZE],
   above_tx_context: Vec<u8>,
@@ -1387,6 +1512,8 @@ pub struct BlockContextCheckpoint {
 pub struct BlockContext<'a> {
   pub cdef_coded: bool,
   pub code_deltas: bool,
+  /// Quantizer index of the last coded delta, `CurrentQIndex` in the spec
+  pub current_qidx: u8,
   pub update_seg: bool,
   pub preskip_segid: bool,
   above_partition_context: Vec<u8>,
@@ -1409,6 +1536,7 @@ impl<'a> BlockContext<'a> {
     BlockContext {
       cdef_coded: false,
       code_deltas: false,
+      current_qidx: 0,
       update_seg: false,
       preskip_segid: true,
       above_partition_context: vec![0; aligned_cols],
@@ -1428,6 +1556,8 @@ impl<'a> BlockContext<'a> {
   pub fn checkpoint(&mut self) -> BlockContextCheckpoint {
     BlockContextCheckpoint {
       cdef_coded: self.cdef_coded,
+      code_deltas: self.code_deltas,
+      current_qidx: self.current_qidx,
       above_partition_context: self.above_partition_context.clone(),
       left_partition_context: self.left_partition_context,
       above_tx_context: self.above_tx_context.clone(),
@@ -1439,6 +1569,8 @@ impl<'a> BlockContext<'a> {
 
   pub fn rollback(&mut self, checkpoint: &BlockContextCheckpoint) {
     self.cdef_coded = checkpoint.cdef_coded;
+    self.code_deltas = checkpoint.code_deltas;
+    self.current_qidx = checkpoint.current_qidx;
     self.above_partition_context = checkpoint.above_partition_context.clone();
     self.left_partition_context = checkpoint.left_partition_context;
     self.above_tx_context = checkpoint.above_tx_context.clone();
@@ -1447,6 +1579,54 @@ impl<'a> BlockContext<'a> {
     self.left_coeff_context = checkpoint.left_coeff_context;
   }
 
+  /// Selects the intra edge filter of a block from the modes of its above
+  /// and left neighbours, as `get_filter_type()` in the spec
+  pub fn intra_edge_filter_type(
+    &self, bo: BlockOffset, bsize: BlockSize, plane: usize, xdec: usize,
+    ydec: usize
+  ) -> IntraEdgeFilterType {
+    let is_smooth = |bo: BlockOffset| {
+      let block = &self.blocks[bo.y][bo.x];
+      if plane == 0 {
+        block.mode.is_smooth()
+      } else {
+        !block.is_inter() && block.uv_mode.is_smooth()
+      }
+    };
+
+    let mut avail_u = bo.y > 0;
+    let mut avail_l = bo.x > 0;
+    let mut above = BlockOffset { x: bo.x, y: bo.y.saturating_sub(1) };
+    let mut left = BlockOffset { x: bo.x.saturating_sub(1), y: bo.y };
+    if plane > 0 {
+      // The chroma of odd sized blocks is coded with the last of the pair
+      if ydec != 0 && bsize.height_mi() == 1 {
+        avail_u = bo.y >= 2;
+      }
+      if xdec != 0 && bsize.width_mi() == 1 {
+        avail_l = bo.x >= 2;
+      }
+      if xdec != 0 && bo.x & 1 == 0 {
+        above.x = (above.x + 1).min(self.blocks.cols() - 1);
+      }
+      if ydec != 0 && bo.y & 1 == 1 && avail_u {
+        above.y -= 1;
+      }
+      if xdec != 0 && bo.x & 1 == 1 && avail_l {
+        left.x -= 1;
+      }
+      if ydec != 0 && bo.y & 1 == 0 {
+        left.y = (left.y + 1).min(self.blocks.rows() - 1);
+      }
+    }
+
+    if (avail_u && is_smooth(above)) || (avail_l && is_smooth(left)) {
+      IntraEdgeFilterType::Smooth
+    } else {
+      IntraEdgeFilterType::Regular
+    }
+  }
+
   pub fn set_dc_sign(&mut self, cul_level: &mut u32, dc_val: i32) {
     if dc_val < 0 {
       *cul_level |= 1 << COEFF_CONTEXT_BITS;
@@ -1487,7 +1667,7 @@ impl<'a> BlockContext<'a> {
     let n4_h = bsize.height_mi();
 
     let (tx_w, tx_h) = if skip {
-      (n4_w as u8, n4_h as u8)
+      ((n4_w << MI_SIZE_LOG2) as u8, (n4_h << MI_SIZE_LOG2) as u8)
     } else {
       (tx_size.width() as u8, tx_size.height() as u8)
     };
@@ -1801,18 +1981,16 @@ struct FieldMap {
 }
 
 impl FieldMap {
-  /// Print the field the address belong to
+  /// Log the field the address belong to
   fn lookup(&self, addr: usize) {
     for (name, start, end) in &self.map {
-      // eprintln!("{} {} {} val {}", name, start, end, addr);
       if addr >= *start && addr < *end {
-        println!(" CDF {}", name);
-        println!();
+        trace!(" CDF {}", name);
         return;
       }
     }
 
-    println!("  CDF address not found {}", addr);
+    trace!("  CDF address not found {}", addr);
   }
 }
 
@@ -1845,11 +2023,19 @@ pub fn av1_get_coded_tx_size(tx_size: TxSize) -> TxSize {
 pub struct ContextWriterCheckpoint {
   pub fc: CDFContext,
   pub bc: BlockContextCheckpoint,
+  pub coeff_bits_frac: [u64; PLANES],
 }
 
 pub struct ContextWriter<'a> {
   pub bc: BlockContext<'a>,
   pub fc: &'a mut CDFContext,
+  /// Bits spent on the coefficients of each plane of the blocks encoded so
+  /// far, in 1/8th of a bit
+  pub coeff_bits_frac: [u64; PLANES],
+  /// State of the range coder when the mode decision of the current block
+  /// started, to count exact rates with `SymbolWriterCounter`. Only kept
+  /// with the `exact_rate` speed setting.
+  pub ec_state: Option<WriterCheckpoint>,
   #[cfg(feature = "desync_finder")]
   fc_map: Option<FieldMap> // For debugging purposes
 }
@@ -1861,6 +2047,8 @@ impl<'a> ContextWriter<'a> {
     let mut cw = ContextWriter {
       fc,
       bc,
+      coeff_bits_frac: [0; PLANES],
+      ec_state: None,
       #[cfg(feature = "desync_finder")]
       fc_map: Default::default()
     };
@@ -2066,6 +2254,87 @@ impl<'a> ContextWriter<'a> {
         &mut self.fc.tx_size_cdf[tx_size_cat][tx_size_ctx][..=max_depths+1]);
   }
 
+  fn get_txfm_partition_context(
+    &self, bo: BlockOffset, bsize: BlockSize, tx_size: TxSize
+  ) -> usize {
+    let above = (self.bc.above_tx_context[bo.x] as usize) < tx_size.width();
+    let left =
+      (self.bc.left_tx_context[bo.y_in_sb()] as usize) < tx_size.height();
+    let max_tx_size = max_txsize_rect_lookup[bsize as usize].sqr_up();
+    debug_assert!(max_tx_size >= TX_8X8);
+    let category = (tx_size.sqr_up() != max_tx_size && max_tx_size > TX_8X8)
+      as usize + (TxSize::TX_SIZES - 1 - max_tx_size as usize) * 2;
+    category * 3 + above as usize + left as usize
+  }
+
+  /// Sets the transform size context over the area of `area_tx_size`
+  fn update_txfm_partition_context(
+    &mut self, bo: BlockOffset, tx_size: TxSize, area_tx_size: TxSize
+  ) {
+    let x_end = (bo.x + area_tx_size.width_mi()).min(self.bc.above_tx_context.len());
+    for v in &mut self.bc.above_tx_context[bo.x..x_end] {
+      *v = tx_size.width() as u8;
+    }
+    let y = bo.y_in_sb();
+    let y_end = (y + area_tx_size.height_mi()).min(MAX_MIB_SIZE);
+    for v in &mut self.bc.left_tx_context[y..y_end] {
+      *v = tx_size.height() as u8;
+    }
+  }
+
+  /// Writes the transform partition of a non-skip inter block, as
+  /// `read_var_tx_size` reads it, for every largest transform of the block
+  pub fn write_tx_partition(
+    &mut self, w: &mut dyn Writer, bo: BlockOffset, bsize: BlockSize,
+    tx_partition: TxPartition
+  ) {
+    debug_assert!(bsize.greater_than(BlockSize::BLOCK_4X4));
+
+    let max_tx_size = max_txsize_rect_lookup[bsize as usize];
+    let sub = max_tx_size.sub();
+    for y in (0..bsize.height_mi()).step_by(max_tx_size.height_mi()) {
+      for x in (0..bsize.width_mi()).step_by(max_tx_size.width_mi()) {
+        let tx_bo = bo.with_offset(x as isize, y as isize);
+        if !self.is_inside_tile(tx_bo) ||
+          !self.write_tx_split(w, tx_bo, bsize, max_tx_size, tx_partition.is_split()) {
+          continue;
+        }
+        for (i, (x, y)) in max_tx_size.sub_offsets().into_iter().enumerate() {
+          let sub_bo = tx_bo.with_offset(x as isize, y as isize);
+          if self.is_inside_tile(sub_bo) &&
+            self.write_tx_split(w, sub_bo, bsize, sub, tx_partition.is_sub_split(i)) {
+            // Splits are not signalled past MAX_VARTX_DEPTH
+            self.update_txfm_partition_context(sub_bo, sub.sub(), sub);
+          }
+        }
+      }
+    }
+  }
+
+  fn is_inside_tile(&self, bo: BlockOffset) -> bool {
+    bo.x < self.bc.blocks.cols() && bo.y < self.bc.blocks.rows()
+  }
+
+  /// Writes whether the transform is split, returning whether the
+  /// transforms it gives can be split further
+  fn write_tx_split(
+    &mut self, w: &mut dyn Writer, bo: BlockOffset, bsize: BlockSize,
+    tx_size: TxSize, split: bool
+  ) -> bool {
+    let ctx = self.get_txfm_partition_context(bo, bsize, tx_size);
+    symbol_with_update!(self, w, split as u32, &mut self.fc.txfm_partition_cdf[ctx]);
+
+    if !split {
+      self.update_txfm_partition_context(bo, tx_size, tx_size);
+      false
+    } else if tx_size.sub() == TX_4X4 {
+      self.update_txfm_partition_context(bo, TX_4X4, tx_size);
+      false
+    } else {
+      true
+    }
+  }
+
   pub fn get_cdf_intra_mode_kf(&self, bo: BlockOffset) -> &[u16; INTRA_MODES + 1] {
     static intra_mode_context: [usize; INTRA_MODES] =
       [0, 1, 2, 3, 4, 4, 4, 4, 3, 0, 1, 2, 0];
@@ -2469,7 +2738,7 @@ impl<'a> ContextWriter<'a> {
                                            &mut newmv_count, bsize, is_compound);
       col_match |= found_match;
     }
-    if has_tr(bo, bsize) && bo.y > 0 {
+    if has_tr(TileBlockOffset(bo), bsize) && bo.y > 0 {
       let found_match = self.scan_blk_mbmi(bo.with_offset(target_n4_w as isize, -1), ref_frames, mv_stack,
                                            &mut newmv_count, is_compound);
       row_match |= found_match;
@@ -3238,6 +3507,20 @@ impl<'a> ContextWriter<'a> {
     w.literal(bits, strength_index as u32);
   }
 
+  pub fn write_delta_qindex(&mut self, w: &mut dyn Writer, delta_qindex: i32) {
+    let abs = delta_qindex.abs() as u32;
+    symbol_with_update!(self, w, cmp::min(abs, DELTA_Q_SMALL),
+                        &mut self.fc.delta_q_cdf);
+    if abs >= DELTA_Q_SMALL {
+      let bits = msb(abs as i32 - 1) as u32;
+      w.literal(3, bits - 1);
+      w.literal(bits as u8, abs - (1 << bits) - 1);
+    }
+    if abs > 0 {
+      w.bool(delta_qindex < 0, 16384);
+    }
+  }
+
   pub fn write_block_deblock_deltas(&mut self, w: &mut dyn Writer,
                                     bo: BlockOffset, multi: bool) {
       let block = &self.bc.blocks[bo];
@@ -3728,16 +4011,42 @@ impl<'a> ContextWriter<'a> {
     true
   }
 
+  /// Returns the cost in 1/8 bits of coding the quantized coefficients of a
+  /// transform block with `write_coeffs_lv_map`, given the current entropy
+  /// contexts. The contexts are left untouched.
+  ///
+  /// For luma blocks with coefficients, the cost includes the signaling of
+  /// the transform type.
+  pub fn coeff_cost(
+    &mut self, plane: usize, bo: BlockOffset, coeffs: &[i32],
+    pred_mode: PredictionMode, tx_size: TxSize, tx_type: TxType,
+    plane_bsize: BlockSize, xdec: usize, ydec: usize,
+    use_reduced_tx_set: bool
+  ) -> u32 {
+    let checkpoint = self.checkpoint();
+    let w: &mut dyn Writer = &mut WriterCounter::new();
+    let tell = w.tell_frac();
+    self.write_coeffs_lv_map(
+      w, plane, bo, coeffs, pred_mode, tx_size, tx_type, plane_bsize, xdec,
+      ydec, use_reduced_tx_set
+    );
+    let cost = w.tell_frac() - tell;
+    self.rollback(&checkpoint);
+    cost
+  }
+
   pub fn checkpoint(&mut self) -> ContextWriterCheckpoint {
     ContextWriterCheckpoint {
       fc: *self.fc,
-      bc: self.bc.checkpoint()
+      bc: self.bc.checkpoint(),
+      coeff_bits_frac: self.coeff_bits_frac
     }
   }
 
   pub fn rollback(&mut self, checkpoint: &ContextWriterCheckpoint) {
     *self.fc = checkpoint.fc;
     self.bc.rollback(&checkpoint.bc);
+    self.coeff_bits_frac = checkpoint.coeff_bits_frac;
     #[cfg(feature = "desync_finder")] {
       if self.fc_map.is_some() {
         self.fc_map = Some(FieldMap {
@@ -3775,8 +4084,8 @@ const MV_MAX: usize = ((1 << MV_MAX_BITS) - 1);
 const MV_VALS: usize = ((MV_MAX << 1) + 1);
 
 const MV_IN_USE_BITS: usize = 14;
-const MV_UPP: i32 = (1 << MV_IN_USE_BITS);
-const MV_LOW: i32 = (-(1 << MV_IN_USE_BITS));
+pub(crate) const MV_UPP: i32 = (1 << MV_IN_USE_BITS);
+pub(crate) const MV_LOW: i32 = (-(1 << MV_IN_USE_BITS));
 
 
 #[inline(always)]
@@ -0,0 +1,269 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Instruction set levels of the SIMD kernels, selected per context.
+
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+/// Environment variable lowering the level of every context, for instance
+/// `RAV1E_CPU_LIMIT=sse4`
+pub const CPU_LIMIT_VAR: &str = "RAV1E_CPU_LIMIT";
+
+/// The highest instruction set the kernels of a context may use, each
+/// level including the ones below it. The plain Rust implementations are
+/// used where no kernel of the level is available.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CpuFeatureLevel {
+  /// No SIMD kernel at all
+  NATIVE,
+  SSE2,
+  SSSE3,
+  SSE4_1,
+  AVX2,
+}
+
+impl CpuFeatureLevel {
+  /// Every level, from the lowest
+  pub const ALL: [CpuFeatureLevel; 5] = [
+    CpuFeatureLevel::NATIVE,
+    CpuFeatureLevel::SSE2,
+    CpuFeatureLevel::SSSE3,
+    CpuFeatureLevel::SSE4_1,
+    CpuFeatureLevel::AVX2,
+  ];
+
+  /// The highest level the CPU supports
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  pub fn supported() -> Self {
+    if is_x86_feature_detected!("avx2") {
+      CpuFeatureLevel::AVX2
+    } else if is_x86_feature_detected!("sse4.1") {
+      CpuFeatureLevel::SSE4_1
+    } else if is_x86_feature_detected!("ssse3") {
+      CpuFeatureLevel::SSSE3
+    } else if is_x86_feature_detected!("sse2") {
+      CpuFeatureLevel::SSE2
+    } else {
+      CpuFeatureLevel::NATIVE
+    }
+  }
+
+  /// The highest level the CPU supports
+  #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+  pub fn supported() -> Self {
+    CpuFeatureLevel::NATIVE
+  }
+
+  /// The highest level the CPU supports, lowered to the one set in the
+  /// `RAV1E_CPU_LIMIT` environment variable if any. An invalid value is
+  /// ignored.
+  pub fn detect() -> Self {
+    let limit = env::var(CPU_LIMIT_VAR).ok().and_then(|v| v.parse().ok());
+    let supported = Self::supported();
+    limit.map_or(supported, |limit: Self| limit.min(supported))
+  }
+
+  /// The level of the implementation of `kernel` run at this level, for
+  /// samples wider than 8 bits if `high_bit_depth`. It is never above what
+  /// the CPU supports, whatever the level, so that no instruction is run
+  /// on a CPU lacking it. Resolved once per context into `CpuKernels`,
+  /// which the kernels dispatch on.
+  pub fn select(self, kernel: Kernel, high_bit_depth: bool) -> Self {
+    let level = self.min(Self::supported());
+    kernel.implementations(high_bit_depth).iter().cloned()
+      .find(|&implementation| implementation <= level)
+      .unwrap_or(CpuFeatureLevel::NATIVE)
+  }
+
+  /// Names of the kernel families and the level of the implementation they
+  /// resolve to at this level for the given bit depth, `NATIVE` being the
+  /// plain Rust one
+  pub fn kernels(self, bit_depth: usize) -> Vec<(&'static str, Self)> {
+    Kernel::ALL.iter()
+      .map(|&kernel| (kernel.name(), self.select(kernel, bit_depth > 8)))
+      .collect()
+  }
+}
+
+/// The level of the implementation of every kernel family, resolved from a
+/// `CpuFeatureLevel` when a context is created, so that dispatching a
+/// kernel call is a table lookup rather than a feature detection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuKernels {
+  /// Indexed by kernel, then by whether the samples are wider than 8 bits
+  levels: [[CpuFeatureLevel; 2]; 6],
+}
+
+impl CpuKernels {
+  /// The plain Rust implementation of every kernel
+  pub const NATIVE: CpuKernels =
+    CpuKernels { levels: [[CpuFeatureLevel::NATIVE; 2]; 6] };
+
+  pub fn new(level: CpuFeatureLevel) -> Self {
+    let mut levels = [[CpuFeatureLevel::NATIVE; 2]; 6];
+    for &kernel in Kernel::ALL.iter() {
+      for &high_bit_depth in [false, true].iter() {
+        levels[kernel as usize][high_bit_depth as usize] =
+          level.select(kernel, high_bit_depth);
+      }
+    }
+    CpuKernels { levels }
+  }
+
+  /// The level of the implementation of `kernel` to run, for samples wider
+  /// than 8 bits if `high_bit_depth`
+  #[inline(always)]
+  pub fn select(self, kernel: Kernel, high_bit_depth: bool) -> CpuFeatureLevel {
+    self.levels[kernel as usize][high_bit_depth as usize]
+  }
+}
+
+/// Families of kernels with SIMD implementations
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+  Sad,
+  Mc,
+  Ipred,
+  Cfl,
+  FwdTxfm,
+  InvTxfm,
+}
+
+impl Kernel {
+  pub const ALL: [Kernel; 6] = [
+    Kernel::Sad,
+    Kernel::Mc,
+    Kernel::Ipred,
+    Kernel::Cfl,
+    Kernel::FwdTxfm,
+    Kernel::InvTxfm,
+  ];
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Kernel::Sad => "sad",
+      Kernel::Mc => "mc",
+      Kernel::Ipred => "ipred",
+      Kernel::Cfl => "cfl",
+      Kernel::FwdTxfm => "fwd_txfm",
+      Kernel::InvTxfm => "inv_txfm",
+    }
+  }
+
+  /// The levels of the SIMD implementations built in, from the highest.
+  /// The dispatch of every kernel goes through `CpuKernels`, resolved by
+  /// `CpuFeatureLevel::select` from this list.
+  fn implementations(self, high_bit_depth: bool) -> &'static [CpuFeatureLevel] {
+    use self::CpuFeatureLevel::*;
+    let nasm = cfg!(all(target_arch = "x86_64", feature = "nasm"));
+    let x86 = cfg!(any(target_arch = "x86", target_arch = "x86_64"));
+    let x86_64 = cfg!(target_arch = "x86_64");
+    match (self, high_bit_depth) {
+      (Kernel::Sad, false) if nasm => &[AVX2, SSE2],
+      (Kernel::Sad, true) if nasm => &[SSSE3],
+      (Kernel::Mc, false) | (Kernel::Ipred, false) | (Kernel::InvTxfm, false)
+        if nasm => &[AVX2],
+      (Kernel::Cfl, false) if nasm => &[AVX2, SSSE3],
+      (Kernel::Cfl, _) if x86 => &[SSSE3],
+      (Kernel::FwdTxfm, _) if x86_64 => &[AVX2],
+      _ => &[],
+    }
+  }
+}
+
+impl Default for CpuFeatureLevel {
+  /// The highest level, lowered to what the CPU supports by each context
+  fn default() -> Self {
+    CpuFeatureLevel::AVX2
+  }
+}
+
+impl fmt::Display for CpuFeatureLevel {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      CpuFeatureLevel::NATIVE => "none",
+      CpuFeatureLevel::SSE2 => "sse2",
+      CpuFeatureLevel::SSSE3 => "ssse3",
+      CpuFeatureLevel::SSE4_1 => "sse4",
+      CpuFeatureLevel::AVX2 => "avx2",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+impl FromStr for CpuFeatureLevel {
+  type Err = String;
+
+  /// Parses a level as displayed, in any case
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    CpuFeatureLevel::ALL.iter()
+      .find(|level| level.to_string().eq_ignore_ascii_case(s))
+      .cloned()
+      .ok_or_else(|| format!("Unknown CPU feature level: {}", s))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn parse_levels() {
+    for &level in CpuFeatureLevel::ALL.iter() {
+      assert_eq!(level.to_string().parse(), Ok(level));
+    }
+    assert_eq!("AVX2".parse(), Ok(CpuFeatureLevel::AVX2));
+    assert!("avx512".parse::<CpuFeatureLevel>().is_err());
+  }
+
+  #[test]
+  fn kernels_stay_within_level() {
+    for &level in CpuFeatureLevel::ALL.iter() {
+      for &bit_depth in [8, 10].iter() {
+        for (_, kernel) in level.kernels(bit_depth) {
+          assert!(kernel <= level);
+        }
+      }
+    }
+    assert!(CpuFeatureLevel::NATIVE.kernels(8).iter()
+      .all(|&(_, kernel)| kernel == CpuFeatureLevel::NATIVE));
+  }
+
+  #[test]
+  fn select_stays_within_supported() {
+    let supported = CpuFeatureLevel::supported();
+    for &level in CpuFeatureLevel::ALL.iter() {
+      for &kernel in Kernel::ALL.iter() {
+        for &high_bit_depth in [false, true].iter() {
+          let selected = level.select(kernel, high_bit_depth);
+          assert!(selected <= level && selected <= supported);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn resolved_kernels_match_select() {
+    for &level in CpuFeatureLevel::ALL.iter() {
+      let kernels = CpuKernels::new(level);
+      for &kernel in Kernel::ALL.iter() {
+        for &high_bit_depth in [false, true].iter() {
+          assert_eq!(
+            kernels.select(kernel, high_bit_depth),
+            level.select(kernel, high_bit_depth)
+          );
+        }
+      }
+    }
+    assert_eq!(CpuKernels::new(CpuFeatureLevel::NATIVE), CpuKernels::NATIVE);
+  }
+}
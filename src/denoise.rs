@@ -0,0 +1,202 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Temporal denoising of the input frames, applied before mode decision.
+
+use crate::encoder::Frame;
+use crate::plane::Plane;
+use crate::util::{CastFromPrimitive, Pixel};
+
+/// Log2 of the size of the luma blocks matched between frames
+const BLOCK_SIZE_LOG2: usize = 3;
+/// Full-pel motion search range in luma samples
+const SEARCH_RANGE: isize = 4;
+
+#[inline(always)]
+fn sample<T: Pixel>(plane: &Plane<T>, x: isize, y: isize) -> i32 {
+  let x = x.max(0).min(plane.cfg.width as isize - 1);
+  let y = y.max(0).min(plane.cfg.height as isize - 1);
+  i32::cast_from(plane.p(x as usize, y as usize))
+}
+
+fn block_sad<T: Pixel>(
+  cur: &Plane<T>, reference: &Plane<T>, x: isize, y: isize, w: isize,
+  h: isize, mv: (isize, isize)
+) -> u32 {
+  let mut sad = 0;
+  for j in 0..h {
+    for i in 0..w {
+      let c = sample(cur, x + i, y + j);
+      let r = sample(reference, x + i + mv.0, y + j + mv.1);
+      sad += (c - r).abs() as u32;
+    }
+  }
+  sad
+}
+
+/// Finds the full-pel motion vector of a luma block in `reference`, or
+/// `None` if no match is close enough to be averaged without ghosting.
+fn search_block<T: Pixel>(
+  cur: &Plane<T>, reference: &Plane<T>, x: isize, y: isize, w: isize,
+  h: isize, thresh: i32
+) -> Option<(isize, isize)> {
+  let mut best_sad = block_sad(cur, reference, x, y, w, h, (0, 0));
+  let mut best_mv = (0, 0);
+  for dy in -SEARCH_RANGE..=SEARCH_RANGE {
+    for dx in -SEARCH_RANGE..=SEARCH_RANGE {
+      let sad = block_sad(cur, reference, x, y, w, h, (dx, dy));
+      if sad < best_sad {
+        best_sad = sad;
+        best_mv = (dx, dy);
+      }
+    }
+  }
+
+  if best_sad <= thresh as u32 * (w * h) as u32 {
+    Some(best_mv)
+  } else {
+    None
+  }
+}
+
+/// Returns `cur` averaged with the motion compensated `refs`.
+///
+/// Each reference sample is weighted by how close it is to the current one:
+/// differences of `strength` (scaled to the bit depth) or more are left out,
+/// so edges and details which are not noise are preserved. Blocks without a
+/// good match in a reference frame do not use it at all.
+pub fn temporal_denoise<T: Pixel>(
+  cur: &Frame<T>, refs: &[&Frame<T>], strength: u8, bit_depth: usize
+) -> Frame<T> {
+  let mut out = cur.clone();
+  let thresh = i32::from(strength) << (bit_depth - 8);
+  if thresh == 0 || refs.is_empty() {
+    return out;
+  }
+
+  let luma = &cur.planes[0];
+  let bsize = 1 << BLOCK_SIZE_LOG2;
+  for by in (0..luma.cfg.height).step_by(bsize) {
+    for bx in (0..luma.cfg.width).step_by(bsize) {
+      let w = bsize.min(luma.cfg.width - bx) as isize;
+      let h = bsize.min(luma.cfg.height - by) as isize;
+      let mvs: Vec<_> = refs.iter().map(|reference| {
+        search_block(
+          luma, &reference.planes[0], bx as isize, by as isize, w, h, thresh
+        )
+      }).collect();
+
+      for p in 0..3 {
+        let plane = &cur.planes[p];
+        let (xdec, ydec) = (plane.cfg.xdec, plane.cfg.ydec);
+        let stride = plane.cfg.stride;
+        let px = bx >> xdec;
+        let py = by >> ydec;
+        let pw = (bsize >> xdec).min(plane.cfg.width.saturating_sub(px));
+        let ph = (bsize >> ydec).min(plane.cfg.height.saturating_sub(py));
+        let dst = out.planes[p].data_origin_mut();
+
+        for y in py..py + ph {
+          for x in px..px + pw {
+            let c = sample(plane, x as isize, y as isize);
+            let mut sum = c * thresh;
+            let mut weight = thresh;
+            for (reference, mv) in refs.iter().zip(mvs.iter()) {
+              if let Some((dx, dy)) = *mv {
+                let r = sample(
+                  &reference.planes[p],
+                  x as isize + (dx >> xdec),
+                  y as isize + (dy >> ydec)
+                );
+                let w = (thresh - (r - c).abs()).max(0);
+                sum += w * r;
+                weight += w;
+              }
+            }
+            dst[y * stride + x] = T::cast_from((sum + weight / 2) / weight);
+          }
+        }
+      }
+    }
+  }
+
+  out
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::api::ChromaSampling;
+  use rand::{ChaChaRng, Rng, SeedableRng};
+
+  const W: usize = 64;
+  const H: usize = 64;
+
+  fn clean(x: usize, y: usize) -> u16 {
+    ((x * 3 + y * 2) % 200 + 20) as u16
+  }
+
+  fn noisy_frame(ra: &mut ChaChaRng, bit_depth: usize) -> Frame<u16> {
+    let mut frame = Frame::new(W, H, ChromaSampling::Cs420);
+    for plane in frame.planes.iter_mut() {
+      let stride = plane.cfg.stride;
+      let (width, height) = (plane.cfg.width, plane.cfg.height);
+      for (y, row) in plane.data_origin_mut().chunks_mut(stride).take(height).enumerate() {
+        for (x, v) in row[..width].iter_mut().enumerate() {
+          let noise = ra.gen_range(-4i32, 5);
+          *v = ((i32::from(clean(x, y)) + noise) << (bit_depth - 8)) as u16;
+        }
+      }
+    }
+    frame
+  }
+
+  fn luma_sse(frame: &Frame<u16>, bit_depth: usize) -> u64 {
+    let plane = &frame.planes[0];
+    let mut sse = 0;
+    for y in 0..H {
+      for x in 0..W {
+        let d = i64::from(plane.p(x, y) >> (bit_depth - 8))
+          - i64::from(clean(x, y));
+        sse += (d * d) as u64;
+      }
+    }
+    sse
+  }
+
+  #[test]
+  fn reduces_noise() {
+    for &bit_depth in &[8, 10] {
+      let mut ra = ChaChaRng::from_seed([0; 32]);
+      let prev = noisy_frame(&mut ra, bit_depth);
+      let cur = noisy_frame(&mut ra, bit_depth);
+      let next = noisy_frame(&mut ra, bit_depth);
+
+      let out = temporal_denoise(&cur, &[&prev, &next], 16, bit_depth);
+      assert!(luma_sse(&out, bit_depth) < luma_sse(&cur, bit_depth) / 2);
+    }
+  }
+
+  #[test]
+  fn ignores_unrelated_frames() {
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    let cur = noisy_frame(&mut ra, 8);
+    let mut other = Frame::new(W, H, ChromaSampling::Cs420);
+    for plane in other.planes.iter_mut() {
+      for v in plane.data.iter_mut() {
+        *v = 255;
+      }
+    }
+
+    let out = temporal_denoise(&cur, &[&other], 16, 8);
+    for p in 0..3 {
+      assert_eq!(&out.planes[p].data[..], &cur.planes[p].data[..]);
+    }
+  }
+}
@@ -78,6 +78,9 @@ pub trait Writer {
   fn checkpoint(&mut self) -> WriterCheckpoint;
   /// Restore saved position in coding/recording from a checkpoint
   fn rollback(&mut self, _: &WriterCheckpoint);
+  /// Continue the range coding from the state of the Writer a checkpoint
+  /// was taken from, without its output
+  fn resume(&mut self, _: &WriterCheckpoint);
   /// Add additional bits from rate estimators without coding a real symbol
   fn add_bits_frac(&mut self, bits_frac: u32);
 }
@@ -153,6 +156,21 @@ impl WriterCounter {
   }
 }
 
+/// Constructor for a counting Writer continuing from the range coder
+/// state of another Writer, saved in a checkpoint. The fractional bits it
+/// counts are then exactly those the other Writer would add coding the
+/// same symbols, while a Writer starting from the initial state only gets
+/// them right to within the rounding of the range.
+pub struct SymbolWriterCounter;
+
+impl SymbolWriterCounter {
+  pub fn new(checkpoint: &WriterCheckpoint) -> WriterBase<WriterCounter> {
+    let mut w = WriterCounter::new();
+    w.resume(checkpoint);
+    w
+  }
+}
+
 /// Constructor for a recording Writer
 impl WriterRecorder {
   pub fn new() -> WriterBase<WriterRecorder> {
@@ -420,7 +438,7 @@ impl<S> WriterBase<S> {
       if depth == 0 {
         backtrace::resolve(ip, |symbol| {
           if let Some(name) = symbol.name() {
-            println!("Writing symbol {} from {}", s, name);
+            trace!("Writing symbol {} from {}", s, name);
           }
         });
         false
@@ -789,6 +807,10 @@ where
   fn rollback(&mut self, wc: &WriterCheckpoint) {
     StorageBackend::rollback(self, wc)
   }
+  fn resume(&mut self, wc: &WriterCheckpoint) {
+    self.rng = wc.rng;
+    self.cnt = wc.cnt;
+  }
 }
 
 pub trait BCodeWriter {
@@ -888,6 +910,7 @@ impl<W: io::Write> BCodeWriter for BitWriter<W, BigEndian> {
 #[cfg(test)]
 mod test {
   use super::*;
+  use rand::{ChaChaRng, Rng, SeedableRng};
 
   const WINDOW_SIZE: i16 = 32;
   const LOTS_OF_BITS: i16 = 0x4000;
@@ -1088,4 +1111,43 @@ mod test {
     assert_eq!(r.symbol(&cdf), 2);
     assert_eq!(r.symbol(&cdf), 2);
   }
+
+  /// A random CDF of `n` symbols, down to the least probable ones the coder
+  /// can represent
+  fn random_cdf(ra: &mut ChaChaRng, n: usize) -> Vec<u16> {
+    let mut cdf: Vec<u16> =
+      (0..n - 1).map(|_| ra.gen_range(1, 32768)).collect();
+    cdf.sort_unstable_by(|a, b| b.cmp(a));
+    cdf.push(0);
+    cdf
+  }
+
+  #[test]
+  fn symbol_counter() {
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    let symbols: Vec<(u32, Vec<u16>)> = (0..100_000).map(|_| {
+      let n = ra.gen_range(2, 17);
+      let cdf = random_cdf(&mut ra, n);
+      (ra.gen_range(0, n as u32), cdf)
+    }).collect();
+    let (warmup, rest) = symbols.split_at(1000);
+
+    let mut w = WriterEncoder::new();
+    for (s, cdf) in warmup {
+      w.symbol(*s, cdf);
+    }
+
+    // Counted from the state of the encoder part way through, as for a
+    // superblock, the rest of the symbols add up to the data written
+    let split = i64::from(w.tell_frac());
+    let mut twin = SymbolWriterCounter::new(&Writer::checkpoint(&mut w));
+    let twin_start = twin.tell_frac();
+    for (s, cdf) in rest {
+      w.symbol(*s, cdf);
+      twin.symbol(*s, cdf);
+    }
+    let counted = (split + i64::from(twin.tell_frac() - twin_start)) >> OD_BITRES;
+    let coded = w.done().len() as i64 * 8;
+    assert!((coded - counted).abs() <= rest.len() as i64 / 1000);
+  }
 }
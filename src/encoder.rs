@@ -10,15 +10,20 @@
 use crate::api::*;
 use crate::cdef::*;
 use crate::context::*;
+use crate::cpu_features::CpuKernels;
 use crate::deblock::*;
 use crate::ec::*;
+use crate::grain::FilmGrainParams;
 use crate::lrf::*;
 use crate::mc::*;
 use crate::me::*;
 use crate::partition::*;
 use crate::plane::*;
+use crate::predict::{AngleDelta, IntraEdgeFilterType, IntraParam};
+use crate::progress::EncodeProgress;
 use crate::quantize::*;
 use crate::rate::QuantizerParameters;
+use crate::rate::{select_tile_qi, tile_log_q_offset};
 use crate::rate::FRAME_SUBTYPE_I;
 use crate::rate::FRAME_SUBTYPE_P;
 use crate::rdo::*;
@@ -50,11 +55,26 @@ pub static TEMPORAL_DELIMITER: [u8; 2] = [0x12, 0x00];
 
 const FRAME_MARGIN: usize = 16 + SUBPEL_FILTER_SIZE;
 
+/// Border around the planes of a reference frame, in luma samples, that
+/// motion compensation reads from: the 8-tap filter window of a block of
+/// up to `MAX_SB_SIZE` moved 16 samples past the frame edges, the farthest
+/// motion vectors reach. Frames are allocated with it, and `Frame::pad`
+/// fills it.
+pub const MC_BORDER: usize = MAX_SB_SIZE + FRAME_MARGIN;
+
 impl<T: Pixel> Frame<T> {
   pub fn new(width: usize, height: usize, chroma_sampling: ChromaSampling) -> Self {
+    Self::try_new(width, height, chroma_sampling).unwrap_or_else(|e| e.abort())
+  }
+
+  /// Like `new`, returning an error instead of aborting if the planes
+  /// cannot be allocated
+  pub fn try_new(
+    width: usize, height: usize, chroma_sampling: ChromaSampling
+  ) -> Result<Self, AllocError> {
     let luma_width = width.align_power_of_two(3);
     let luma_height = height.align_power_of_two(3);
-    let luma_padding = MAX_SB_SIZE + FRAME_MARGIN;
+    let luma_padding = MC_BORDER;
 
     let (chroma_sampling_period_x, chroma_sampling_period_y) =
       chroma_sampling.sampling_period();
@@ -65,33 +85,78 @@ impl<T: Pixel> Frame<T> {
     let chroma_decimation_x = chroma_sampling_period_x - 1;
     let chroma_decimation_y = chroma_sampling_period_y - 1;
 
-    Frame {
+    Ok(Frame {
       planes: [
-        Plane::new(
+        Plane::try_new(
           luma_width, luma_height,
           0, 0,
           luma_padding, luma_padding
-        ),
-        Plane::new(
+        )?,
+        Plane::try_new(
           chroma_width, chroma_height,
           chroma_decimation_x, chroma_decimation_y,
           chroma_padding_x, chroma_padding_y
-        ),
-        Plane::new(
+        )?,
+        Plane::try_new(
           chroma_width, chroma_height,
           chroma_decimation_x, chroma_decimation_y,
           chroma_padding_x, chroma_padding_y
-        )
+        )?
       ]
-    }
+    })
   }
 
+  /// Extends the edges of the `w`x`h` picture into the borders of its
+  /// planes, see `Plane::pad`
   pub fn pad(&mut self, w: usize, h: usize) {
     for p in self.planes.iter_mut() {
       p.pad(w, h);
     }
   }
 
+  /// CRC-32 of the samples of the planes within their configured width and
+  /// height, as little-endian bytes for high bit depth
+  pub fn crc(&self) -> u32 {
+    let mut crc = !0;
+    for plane in self.planes.iter() {
+      let PlaneConfig { width, height, stride, .. } = plane.cfg;
+      for row in plane.data_origin().chunks(stride).take(height) {
+        for &v in &row[..width] {
+          let v = u16::cast_from(v);
+          crc = crc32_update(crc, v as u8);
+          if mem::size_of::<T>() > 1 {
+            crc = crc32_update(crc, (v >> 8) as u8);
+          }
+        }
+      }
+    }
+    !crc
+  }
+
+  /// MD5 of the samples of a frame displayed at `width`x`height`, the
+  /// chroma planes rounded up, laid out as `aomdec --rawvideo` writes them:
+  /// little-endian 16-bit samples for high bit depth
+  pub fn md5(&self, width: usize, height: usize) -> [u8; 16] {
+    let mut md5 = Md5::new();
+    let mut row_bytes = Vec::new();
+    for plane in self.planes.iter() {
+      let PlaneConfig { xdec, ydec, stride, .. } = plane.cfg;
+      let (width, height) = ((width + xdec) >> xdec, (height + ydec) >> ydec);
+      for row in plane.data_origin().chunks(stride).take(height) {
+        row_bytes.clear();
+        for &v in &row[..width] {
+          let v = u16::cast_from(v);
+          row_bytes.push(v as u8);
+          if mem::size_of::<T>() > 1 {
+            row_bytes.push((v >> 8) as u8);
+          }
+        }
+        md5.update(&row_bytes);
+      }
+    }
+    md5.finish()
+  }
+
   #[inline(always)]
   pub fn as_tile(&self) -> Tile<'_, T> {
     let PlaneConfig { width, height, .. } = self.planes[0].cfg;
@@ -167,11 +232,78 @@ impl<'a, T: Pixel> Iterator for PixelIter<'a, T> {
 #[derive(Debug, Clone)]
 pub struct ReferenceFrame<T: Pixel> {
   pub order_hint: u32,
-  pub frame: Frame<T>,
+  pub base_q_idx: u8,
+  /// Shared with the packets showing the frame, and never modified once
+  /// stored, so that storing or showing it costs no copy
+  pub frame: Arc<Frame<T>>,
   pub input_hres: Plane<T>,
   pub input_qres: Plane<T>,
   pub cdfs: CDFContext,
   pub frame_mvs: Vec<FrameMotionVectors>,
+  pub intra_refresh: Option<IntraRefresh>,
+  pub film_grain_params: Option<FilmGrainParams>,
+  /// CRC of `frame` as the loop filters left it, with `rec_crc`
+  pub crc: Option<u32>,
+}
+
+/// Number of pixels right of a block that subpel inter prediction may read,
+/// in luma units and including the chroma filter taps for 4:2:0
+const INTRA_REFRESH_MV_MARGIN: usize = 8;
+
+/// Number of pixels left of the right edge of the clean area of a reference
+/// that the loop filters may have computed from the area right of it, in
+/// luma units: 6 modified by the deblocking filter, then 2 more read by CDEF
+/// and 3 by loop restoration, 12 in all, which chroma 4:2:0 doubles to 4, 4
+/// and 6 in luma units, 14 in all
+const INTRA_REFRESH_LOOP_FILTER_MARGIN: usize = 16;
+
+/// Position of a frame within a periodic intra refresh cycle.
+///
+/// Each frame of a cycle intra codes a vertical stripe of superblocks, left
+/// to right, so that a decoder starting at the beginning of a cycle has a
+/// fully correct picture at its end. The area left of the stripe is "clean"
+/// and may only be predicted from the clean area of the references.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IntraRefresh {
+  /// Index of the refresh cycle since the last keyframe
+  pub cycle: u64,
+  /// First column of the intra coded stripe, in luma pixels
+  pub stripe_start: usize,
+  /// End (exclusive) of the intra coded stripe, in luma pixels
+  pub stripe_end: usize,
+}
+
+impl IntraRefresh {
+  /// A keyframe is entirely clean for the frames of the first cycle
+  pub fn key_frame(width: usize) -> Self {
+    IntraRefresh { cycle: 0, stripe_start: 0, stripe_end: width }
+  }
+
+  /// Schedules the stripe of the `idx_in_segment`-th frame after a keyframe
+  pub fn new(
+    period: u64, idx_in_segment: u64, sb_width: usize, sb_size_log2: usize,
+    width: usize
+  ) -> Self {
+    assert!(period > 0 && idx_in_segment > 0);
+    let cycle = (idx_in_segment - 1) / period;
+    let pos = (idx_in_segment - 1) % period;
+    let start = (pos * sb_width as u64 / period) as usize;
+    let end = ((pos + 1) * sb_width as u64 / period) as usize;
+    IntraRefresh {
+      cycle,
+      stripe_start: (start << sb_size_log2).min(width),
+      stripe_end: (end << sb_size_log2).min(width),
+    }
+  }
+
+  /// Width of the area of a reference that is clean for this frame, i.e.
+  /// correctly reconstructed by a decoder that started with this cycle
+  pub fn clean_width(&self, reference: Option<&IntraRefresh>) -> usize {
+    match reference {
+      Some(r) if r.cycle == self.cycle => r.stripe_end,
+      _ => 0
+    }
+  }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -234,6 +366,7 @@ pub struct Sequence {
   // 2 - adaptive
   pub still_picture: bool,               // Video is a single frame still picture
   pub reduced_still_picture_hdr: bool,   // Use reduced header for still picture
+  pub enable_filter_intra: bool,         // enables/disables filter intra
   pub enable_intra_edge_filter: bool,    // enables/disables corner/edge/upsampling
   pub enable_interintra_compound: bool,  // enables/disables interintra_compound
   pub enable_masked_compound: bool,      // enables/disables masked compound
@@ -260,7 +393,7 @@ pub struct Sequence {
   pub operating_point_idc: [u16; MAX_NUM_OPERATING_POINTS],
   pub display_model_info_present_flag: bool,
   pub decoder_model_info_present_flag: bool,
-  pub level: [[usize; 2]; MAX_NUM_OPERATING_POINTS],	// minor, major
+  pub level: [Option<Level>; MAX_NUM_OPERATING_POINTS], // None for level 31
   pub tier: [usize; MAX_NUM_OPERATING_POINTS],  // seq_tier in the spec. One bit: 0
   // or 1.
   pub film_grain_params_present: bool,
@@ -268,7 +401,9 @@ pub struct Sequence {
 }
 
 impl Sequence {
-  pub fn new(config: &EncoderConfig) -> Sequence {
+  /// The sequence of an encode conforming to `level`, `None` for level 31,
+  /// the level without limits. The tier is the lowest allowing the bitrate.
+  pub fn new(config: &EncoderConfig, level: Option<Level>) -> Sequence {
     let width_bits = 32 - (config.width as u32).leading_zeros();
     let height_bits = 32 - (config.height as u32).leading_zeros();
     assert!(width_bits <= 16);
@@ -284,16 +419,30 @@ impl Sequence {
     };
 
     let mut operating_point_idc = [0 as u16; MAX_NUM_OPERATING_POINTS];
-    let mut level = [[1, 2 as usize]; MAX_NUM_OPERATING_POINTS];
+    let mut levels = [Some(Level { major: 2, minor: 1 }); MAX_NUM_OPERATING_POINTS];
     let mut tier = [0 as usize; MAX_NUM_OPERATING_POINTS];
 
     for i in 0..MAX_NUM_OPERATING_POINTS {
       operating_point_idc[i] = 0;
-      level[i][0] = 1;    // minor
-      level[i][1] = 2;    // major
       tier[i] = 0;
     }
 
+    let bitrate = config.bitrate.max(0) as u64;
+    let seq_tier = level
+      .and_then(Level::limits)
+      .and_then(|limits| limits.tier(bitrate))
+      .unwrap_or(Tier::Main);
+    levels[0] = level;
+    tier[0] = (seq_tier == Tier::High) as usize;
+
+    // Operating point i decodes all but the i highest temporal layers
+    let temporal_layers = config.temporal_layers as usize;
+    if temporal_layers > 1 {
+      for i in 0..temporal_layers {
+        operating_point_idc[i] = 1 << 8 | ((1 << (temporal_layers - i)) - 1);
+      }
+    }
+
     Sequence {
       profile,
       num_bits_width: width_bits,
@@ -316,29 +465,38 @@ impl Sequence {
       force_integer_mv: 2,
       still_picture: false,
       reduced_still_picture_hdr: false,
-      enable_intra_edge_filter: false,
+      enable_filter_intra: config.tools.filter_intra,
+      enable_intra_edge_filter: true,
       enable_interintra_compound: false,
       enable_masked_compound: false,
-      enable_dual_filter: false,
+      enable_dual_filter: config.tools.dual_filter,
       enable_order_hint: true,
-      enable_jnt_comp: false,
-      enable_ref_frame_mvs: false,
-      enable_warped_motion: false,
-      enable_superres: false,
-      enable_cdef: config.speed_settings.cdef,
-      enable_restoration: config.chroma_sampling != ChromaSampling::Cs422 &&
-        config.chroma_sampling != ChromaSampling::Cs444, // FIXME: not working yet
-      operating_points_cnt_minus_1: 0,
+      enable_jnt_comp: config.tools.jnt_comp,
+      enable_ref_frame_mvs: config.tools.ref_frame_mvs,
+      enable_warped_motion: config.tools.warped_motion,
+      enable_superres: config.tools.superres,
+      enable_cdef: config.tools.cdef && config.speed_settings.cdef
+        && !config.large_scale_tile,
+      enable_restoration: config.tools.restoration &&
+        config.chroma_sampling != ChromaSampling::Cs422 &&
+        config.chroma_sampling != ChromaSampling::Cs444 && // FIXME: not working yet
+        !config.large_scale_tile,
+      operating_points_cnt_minus_1: temporal_layers - 1,
       operating_point_idc,
       display_model_info_present_flag: false,
       decoder_model_info_present_flag: false,
-      level,
+      level: levels,
       tier,
-      film_grain_params_present: false,
+      film_grain_params_present: config.film_grain.is_some(),
       separate_uv_delta_q: true,
     }
   }
 
+  /// `seq_level_idx` of the first operating point
+  pub fn seq_level_idx(&self) -> u8 {
+    self.level[0].map_or(31, Level::idx)
+  }
+
   pub fn get_relative_dist(&self, a: u32, b: u32) -> i32 {
     let diff = a as i32 - b as i32;
     let m = 1 << self.order_hint_bits_minus_1;
@@ -424,6 +582,51 @@ pub struct FrameState<T: Pixel> {
   pub restoration: RestorationState,
   pub frame_mvs: Vec<FrameMotionVectors>,
   pub t: RDOTracker,
+  pub bits: FrameBits,
+  /// Quantizer index of each superblock of the frame, in raster order, or
+  /// empty to use `base_q_idx` everywhere. Superblocks quantized differently
+  /// from the frame get delta-Q signaled.
+  pub sb_qidx: Vec<u8>,
+  /// Offset (Q57) of the effective log quantizer of the frame from the one
+  /// of `base_q_idx`, when the superblocks have different quantizers
+  pub log_q_offset: i64,
+  /// Location of the coded data of each tile in the output of `encode_frame`
+  pub tiles: Vec<TileLocation>,
+  /// CRC of `rec` once reconstructed and filtered, with `rec_crc`
+  pub rec_crc: Option<u32>,
+}
+
+/// Location of the coded data of a tile, so that it can be extracted from
+/// its packet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileLocation {
+  pub row: usize,
+  pub col: usize,
+  /// Offset of the tile data in bytes, from the start of the packet
+  pub offset: usize,
+  pub size: usize,
+}
+
+/// Breakdown of the bits spent on a frame
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameBits {
+  /// Bits of everything but the tile data: temporal delimiter, sequence
+  /// header, frame header and tile group headers
+  pub header: usize,
+  /// Bits of the quantized coefficients, as counted by the entropy coder
+  pub coefficients: usize,
+  /// Bits of the quantized coefficients of each plane, as counted by the
+  /// entropy coder
+  pub plane_coefficients: [usize; PLANES],
+  /// Remaining bits of the tile data: partitions, modes, motion vectors,
+  /// filter parameters, etc.
+  pub modes: usize,
+}
+
+impl FrameBits {
+  pub fn total(&self) -> usize {
+    self.header + self.coefficients + self.modes
+  }
 }
 
 impl<T: Pixel> FrameState<T> {
@@ -434,18 +637,27 @@ impl<T: Pixel> FrameState<T> {
   }
 
   pub fn new_with_frame(fi: &FrameInvariants<T>, frame: Arc<Frame<T>>) -> Self {
+    Self::try_new_with_frame(fi, frame).unwrap_or_else(|e| e.abort())
+  }
+
+  /// Like `new_with_frame`, returning an error instead of aborting if the
+  /// planes of the reconstruction or of the motion search cannot be
+  /// allocated
+  pub fn try_new_with_frame(
+    fi: &FrameInvariants<T>, frame: Arc<Frame<T>>
+  ) -> Result<Self, AllocError> {
     let rs = RestorationState::new(fi, &frame);
     let luma_width = frame.planes[0].cfg.width;
     let luma_height = frame.planes[0].cfg.height;
     let luma_padding_x = frame.planes[0].cfg.xpad;
     let luma_padding_y = frame.planes[0].cfg.ypad;
 
-    Self {
+    Ok(Self {
       sb_size_log2: fi.sb_size_log2(),
       input: frame,
-      input_hres: Plane::new(luma_width / 2, luma_height / 2, 1, 1, luma_padding_x / 2, luma_padding_y / 2),
-      input_qres: Plane::new(luma_width / 4, luma_height / 4, 2, 2, luma_padding_x / 4, luma_padding_y / 4),
-      rec: Frame::new(luma_width, luma_height, fi.sequence.chroma_sampling),
+      input_hres: Plane::try_new(luma_width / 2, luma_height / 2, 1, 1, luma_padding_x / 2, luma_padding_y / 2)?,
+      input_qres: Plane::try_new(luma_width / 4, luma_height / 4, 2, 2, luma_padding_x / 4, luma_padding_y / 4)?,
+      rec: Frame::try_new(luma_width, luma_height, fi.sequence.chroma_sampling)?,
       cdfs: CDFContext::new(0),
       context_update_tile_id: 0,
       max_tile_size_bytes: 0,
@@ -459,8 +671,13 @@ impl<T: Pixel> FrameState<T> {
         }
         vec
       },
-      t: RDOTracker::new()
-    }
+      t: RDOTracker::new(),
+      bits: Default::default(),
+      sb_qidx: Vec::new(),
+      log_q_offset: 0,
+      tiles: Vec::new(),
+      rec_crc: None,
+    })
   }
 
   #[inline(always)]
@@ -551,6 +768,8 @@ pub struct FrameInvariants<T: Pixel> {
   pub reference_mode: ReferenceMode,
   pub use_prev_frame_mvs: bool,
   pub min_partition_size: BlockSize,
+  /// Largest block coded unsplit, at most the superblock size
+  pub max_partition_size: BlockSize,
   pub globalmv_transformation_type: [GlobalMVMode; INTER_REFS_PER_FRAME],
   pub num_tg: usize,
   pub large_scale_tile: bool,
@@ -573,6 +792,9 @@ pub struct FrameInvariants<T: Pixel> {
   pub cdef_uv_strengths: [u8; 8],
   pub delta_q_present: bool,
   pub config: EncoderConfig,
+  /// Implementations of the kernels for `config.cpu_feature_level`,
+  /// resolved with the first frame of a context and cloned with it
+  pub cpu: CpuKernels,
   pub ref_frames: [u8; INTER_REFS_PER_FRAME],
   pub ref_frame_sign_bias: [bool; INTER_REFS_PER_FRAME],
   pub rec_buffer: ReferenceFramesSet<T>,
@@ -587,8 +809,36 @@ pub struct FrameInvariants<T: Pixel> {
   pub inter_cfg: Option<InterPropsConfig>,
   pub enable_early_exit: bool,
   pub tx_mode_select: bool,
+  pub intra_refresh: Option<IntraRefresh>,
+  /// Film grain synthesis parameters, `None` if no grain is applied
+  pub film_grain_params: Option<FilmGrainParams>,
+  /// Number of the frame pinned in each slot as a long-term reference, see
+  /// `Context::mark_long_term`
+  pub long_term_refs: [Option<u64>; REF_FRAMES],
+  /// Temporal layer of the frame, see `EncoderConfig::temporal_layers`
+  pub temporal_id: u8,
+  /// Motion vectors to code the frame with instead of searching them, see
+  /// `Context::set_external_motion`
+  pub external_motion: Option<Arc<ExternalMotion>>,
 }
 
+/// Largest value of `EncoderConfig::max_reference_frames`, one frame per
+/// reference type
+pub const MAX_REFERENCE_FRAMES: usize = INTER_REFS_PER_FRAME;
+
+/// Reference types an inter frame may predict from, in the order they are
+/// given up by lower `max_reference_frames`: with n, only the first n are
+/// considered, the others pointing at the frame of `LAST_FRAME`.
+pub const REF_TYPE_PRIORITY: [RefType; INTER_REFS_PER_FRAME] = [
+  LAST_FRAME,
+  GOLDEN_FRAME,
+  ALTREF_FRAME,
+  LAST2_FRAME,
+  LAST3_FRAME,
+  BWDREF_FRAME,
+  ALTREF2_FRAME
+];
+
 pub(crate) fn pos_to_lvl(pos: u64, pyramid_depth: u64) -> u64 {
   // Derive level within pyramid for a frame with a given coding order position
   // For example, with a pyramid of depth 2, the 2 least significant bits of the
@@ -608,8 +858,11 @@ impl<T: Pixel> FrameInvariants<T> {
     // with exception that SBs on right or bottom frame borders split down to BLOCK_4X4.
     // At speed = 0, RDO search is exhaustive.
     let min_partition_size = config.speed_settings.min_block_size;
-    let use_reduced_tx_set = config.speed_settings.reduced_tx_set;
-    let use_tx_domain_distortion = config.tune == Tune::Psnr && config.speed_settings.tx_domain_distortion;
+    let max_partition_size = config.speed_settings.max_block_size;
+    let use_reduced_tx_set =
+      config.speed_settings.reduced_tx_set == ReducedTxSet::On;
+    let use_tx_domain_distortion = config.tune == Tune::Psnr && config.speed_settings.tx_domain_distortion
+      && !config.speed_settings.filter_aware_distortion;
     let use_tx_domain_rate = config.speed_settings.tx_domain_rate;
 
     let w_in_b = 2 * config.width.align_power_of_two_and_shift(3); // MiCols, ((width+7)/8)<<3 >> MI_SIZE_LOG2
@@ -636,7 +889,7 @@ impl<T: Pixel> FrameInvariants<T> {
       order_hint: 0,
       show_frame: true,
       showable_frame: true,
-      error_resilient: false,
+      error_resilient: config.error_resilient,
       intra_only: false,
       allow_high_precision_mv: false,
       frame_type: FrameType::KEY,
@@ -646,6 +899,7 @@ impl<T: Pixel> FrameInvariants<T> {
       reference_mode: ReferenceMode::SINGLE,
       use_prev_frame_mvs: false,
       min_partition_size,
+      max_partition_size,
       globalmv_transformation_type: [GlobalMVMode::IDENTITY; INTER_REFS_PER_FRAME],
       num_tg: 1,
       large_scale_tile: false,
@@ -658,7 +912,7 @@ impl<T: Pixel> FrameInvariants<T> {
       use_ref_frame_mvs: false,
       is_filter_switchable: false,
       is_motion_mode_switchable: false, // 0: only the SIMPLE motion mode will be used.
-      disable_frame_end_update_cdf: false,
+      disable_frame_end_update_cdf: config.error_resilient,
       allow_warped_motion: false,
       cdef_damping: 3,
       cdef_bits: 3,
@@ -678,8 +932,14 @@ impl<T: Pixel> FrameInvariants<T> {
       use_tx_domain_rate,
       inter_cfg: None,
       enable_early_exit: true,
+      cpu: CpuKernels::new(config.cpu_feature_level),
       config,
       tx_mode_select : false,
+      intra_refresh: None,
+      film_grain_params: None,
+      long_term_refs: [None; REF_FRAMES],
+      temporal_id: 0,
+      external_motion: None,
     }
   }
 
@@ -698,17 +958,21 @@ impl<T: Pixel> FrameInvariants<T> {
     for i in 0..INTER_REFS_PER_FRAME {
       fi.ref_frames[i] = 0;
     }
+    fi.long_term_refs = [None; REF_FRAMES];
+    fi.temporal_id = 0;
 
     fi.tx_mode_select = fi.config.speed_settings.rdo_tx_decision;
+    fi.intra_refresh =
+      fi.config.intra_refresh_period.map(|_| IntraRefresh::key_frame(fi.width));
 
     fi
   }
 
   fn apply_inter_props_cfg(&mut self, idx_in_segment: u64) {
-    let reorder = !self.config.low_latency;
-    let multiref = reorder || self.config.speed_settings.multiref;
-
-    let pyramid_depth = if reorder { 2 } else { 0 };
+    let pyramid_depth = self.config.pyramid_depth();
+    let reorder = pyramid_depth > 0;
+    let multiref = self.config.multiref();
+    let base_slots = self.config.base_slots();
     let group_src_len = 1 << pyramid_depth;
     let group_len = group_src_len + if reorder { pyramid_depth } else { 0 };
 
@@ -718,6 +982,7 @@ impl<T: Pixel> FrameInvariants<T> {
     self.inter_cfg = Some(InterPropsConfig {
       reorder,
       multiref,
+      base_slots,
       pyramid_depth,
       group_src_len,
       group_len,
@@ -736,8 +1001,9 @@ impl<T: Pixel> FrameInvariants<T> {
     let mut fi = previous_fi.clone();
     fi.frame_type = FrameType::INTER;
     fi.intra_only = false;
+    fi.error_resilient = fi.config.error_resilient;
     fi.apply_inter_props_cfg(idx_in_segment);
-    fi.tx_mode_select = false;
+    fi.tx_mode_select = fi.config.speed_settings.var_tx;
     let inter_cfg = fi.inter_cfg.unwrap();
 
     fi.order_hint =
@@ -772,10 +1038,11 @@ impl<T: Pixel> FrameInvariants<T> {
       pos_to_lvl(inter_cfg.idx_in_group - inter_cfg.pyramid_depth + 1, inter_cfg.pyramid_depth)
     };
 
-    // Frames with lvl == 0 are stored in slots 0..4 and frames with higher values
-    // of lvl in slots 4..8
+    // Frames with lvl == 0 are stored in slots 0..base_slots and frames with
+    // higher values of lvl in slots 4..8
+    let base_slots = inter_cfg.base_slots as u32;
     let slot_idx = if lvl == 0 {
-      (fi.order_hint >> inter_cfg.pyramid_depth) % 4 as u32
+      (fi.order_hint >> inter_cfg.pyramid_depth) % base_slots
     } else {
       3 + lvl as u32
     };
@@ -789,11 +1056,17 @@ impl<T: Pixel> FrameInvariants<T> {
     } else {
       1 << slot_idx
     };
+    // The levels of the pyramid double as temporal layers, the highest
+    // ones merged into the top layer
+    let temporal_layers = fi.config.temporal_layers as u64;
+    fi.temporal_id = lvl.min(temporal_layers - 1) as u8;
 
+    let max_refs = fi.config.max_reference_frames;
+    let allowed = |ref_type: RefType| REF_TYPE_PRIORITY[..max_refs].contains(&ref_type);
     let second_ref_frame = if !inter_cfg.multiref {
       LAST_FRAME // make second_ref_frame match first
     } else if !inter_cfg.reorder || inter_cfg.idx_in_group == 0 {
-      LAST2_FRAME
+      if allowed(LAST2_FRAME) { LAST2_FRAME } else { GOLDEN_FRAME }
     } else {
       ALTREF_FRAME
     };
@@ -809,21 +1082,21 @@ impl<T: Pixel> FrameInvariants<T> {
     for i in 0..INTER_REFS_PER_FRAME {
       fi.ref_frames[i] = if lvl == 0 {
         if i == second_ref_frame.to_index() {
-          (slot_idx + 4 - 2) as u8 % 4
+          ((slot_idx + 2 * base_slots - 2) % base_slots) as u8
         } else {
-          (slot_idx + 4 - 1) as u8 % 4
+          ((slot_idx + base_slots - 1) % base_slots) as u8
         }
       } else if i == second_ref_frame.to_index() {
         let oh = fi.order_hint + (inter_cfg.group_src_len as u32 >> lvl);
         let lvl2 = pos_to_lvl(oh as u64, inter_cfg.pyramid_depth);
         if lvl2 == 0 {
-          ((oh >> inter_cfg.pyramid_depth) % 4) as u8
+          ((oh >> inter_cfg.pyramid_depth) % base_slots) as u8
         } else {
           3 + lvl2 as u8
         }
       } else if i == ref_in_previous_group.to_index() {
         if lvl == 0 {
-          (slot_idx + 4 - 1) as u8 % 4
+          ((slot_idx + base_slots - 1) % base_slots) as u8
         } else {
           slot_idx as u8
         }
@@ -831,39 +1104,216 @@ impl<T: Pixel> FrameInvariants<T> {
         let oh = fi.order_hint - (inter_cfg.group_src_len as u32 >> lvl);
         let lvl1 = pos_to_lvl(oh as u64, inter_cfg.pyramid_depth);
         if lvl1 == 0 {
-          ((oh >> inter_cfg.pyramid_depth) % 4) as u8
+          ((oh >> inter_cfg.pyramid_depth) % base_slots) as u8
         } else {
           3 + lvl1 as u8
         }
       }
     }
 
+    if !inter_cfg.reorder && temporal_layers > 1 {
+      // Without reordering, the frames are spread over the temporal layers
+      // the same way, stored like the frames of the pyramid, and predict
+      // from the latest frames of their layer and the ones below only
+      let depth = temporal_layers - 1;
+      let slot_of = |number: u64| {
+        let lvl = pos_to_lvl(number, depth);
+        if lvl == 0 {
+          ((number >> depth) % base_slots as u64) as u8
+        } else {
+          3 + lvl as u8
+        }
+      };
+      let number = fi.order_hint as u64;
+      let lvl = pos_to_lvl(number, depth);
+      let last = slot_of((number - 1) >> (depth - lvl) << (depth - lvl));
+      let base = slot_of((number - 1) >> depth << depth);
+      let second = if last == base {
+        ((base as u32 + base_slots - 1) % base_slots) as u8
+      } else {
+        base
+      };
+      for i in 0..INTER_REFS_PER_FRAME {
+        fi.ref_frames[i] =
+          if i == second_ref_frame.to_index() { second } else { last };
+      }
+      fi.refresh_frame_flags = 1 << slot_of(number);
+      fi.temporal_id = lvl as u8;
+    }
+
+    if max_refs < MAX_REFERENCE_FRAMES {
+      // The reference types given up point at the frame of LAST_FRAME, which
+      // mode decision considers first, skipping the slots already searched
+      let last = fi.ref_frames[LAST_FRAME.to_index()];
+      for ref_type in REF_TYPE_PRIORITY[max_refs..].iter() {
+        fi.ref_frames[ref_type.to_index()] = last;
+      }
+      // No frame references the slots unused by the pyramid: the frame
+      // replaces them as well, so that they do not keep older frames alive
+      if fi.refresh_frame_flags != 0 {
+        let upper_levels = inter_cfg.pyramid_depth.max(temporal_layers - 1);
+        let used_slots = ((1 << base_slots) - 1) | (((1 << upper_levels) - 1) << 4);
+        fi.refresh_frame_flags |=
+          ALL_REF_FRAMES_MASK & !used_slots & !fi.long_term_slots();
+      }
+    }
+
     fi.reference_mode = if inter_cfg.multiref && inter_cfg.reorder && inter_cfg.idx_in_group != 0 {
       ReferenceMode::SELECT
     } else {
       ReferenceMode::SINGLE
     };
+    if fi.config.large_scale_tile {
+      // Camera frames only predict from the anchor frame, the last key
+      // frame, and are never referenced themselves
+      fi.refresh_frame_flags = 0;
+      for i in 0..INTER_REFS_PER_FRAME {
+        fi.ref_frames[i] = 0;
+      }
+      fi.primary_ref_frame = LAST_FRAME.to_index() as u32;
+      fi.reference_mode = ReferenceMode::SINGLE;
+    }
+    if fi.error_resilient {
+      fi.primary_ref_frame = PRIMARY_REF_NONE;
+    }
     fi.number = number;
     fi.me_range_scale = (inter_cfg.group_src_len >> lvl) as u8;
+    fi.intra_refresh = fi.config.intra_refresh_period.map(|period| {
+      IntraRefresh::new(
+        period,
+        number - segment_start_frame,
+        fi.sb_width,
+        fi.sb_size_log2(),
+        fi.width
+      )
+    });
     (fi, true)
   }
 
+  /// Turns the inter frame into a switch frame, predicting from the
+  /// previous frame only and refreshing every reference slot
+  pub(crate) fn set_switch_frame(&mut self) {
+    self.frame_type = FrameType::SWITCH;
+    self.error_resilient = true;
+    self.primary_ref_frame = PRIMARY_REF_NONE;
+    self.refresh_frame_flags = ALL_REF_FRAMES_MASK;
+    let last = self.ref_frames[LAST_FRAME.to_index()];
+    for i in 0..INTER_REFS_PER_FRAME {
+      self.ref_frames[i] = last;
+    }
+    self.reference_mode = ReferenceMode::SINGLE;
+    self.long_term_refs = [None; REF_FRAMES];
+    // Replacing every slot, it belongs to the base layer
+    self.temporal_id = 0;
+  }
+
+  /// The extension header of the OBUs of the frame, `None` when the
+  /// sequence has a single operating point
+  pub fn obu_extension(&self) -> Option<ObuExtension> {
+    if self.sequence.operating_points_cnt_minus_1 == 0 {
+      return None;
+    }
+    Some(ObuExtension { temporal_id: self.temporal_id, spatial_id: 0 })
+  }
+
+  /// Mask of the slots holding long-term references
+  pub(crate) fn long_term_slots(&self) -> u32 {
+    self.long_term_refs.iter().enumerate()
+      .filter(|(_, number)| number.is_some())
+      .fold(0, |mask, (slot, _)| mask | 1 << slot)
+  }
+
+  /// Stores the frame in a slot of its own, above those of the frames of
+  /// the pyramid, and keeps it there as a long-term reference. Returns
+  /// false if every such slot is already taken.
+  pub(crate) fn pin_long_term(&mut self) -> bool {
+    let first_slot = self.config.base_slots() as usize;
+    let layer_slots = 4..4 + self.config.temporal_layer_slots() as usize;
+    let free = (first_slot..REF_FRAMES).rev().find(|&slot| {
+      self.long_term_refs[slot].is_none() && !layer_slots.contains(&slot)
+    });
+    match free {
+      Some(slot) => {
+        self.long_term_refs[slot] = Some(self.number);
+        self.refresh_frame_flags |= 1 << slot;
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Makes the inter frame predict from the latest long-term reference
+  /// only, including its probabilities, and replace every other reference,
+  /// so that it decodes the same whatever frames were lost since the
+  /// long-term reference. Does nothing if no frame is pinned.
+  pub(crate) fn set_long_term_recovery(&mut self) {
+    let latest = self.long_term_refs.iter().enumerate()
+      .filter_map(|(slot, number)| number.map(|number| (number, slot)))
+      .max();
+    if let Some((_, slot)) = latest {
+      for i in 0..INTER_REFS_PER_FRAME {
+        self.ref_frames[i] = slot as u8;
+      }
+      if !self.error_resilient {
+        self.primary_ref_frame = LAST_FRAME.to_index() as u32;
+      }
+      self.reference_mode = ReferenceMode::SINGLE;
+      self.refresh_frame_flags = ALL_REF_FRAMES_MASK & !self.long_term_slots();
+      self.temporal_id = 0;
+    }
+  }
+
+  /// Level of the frame in the reordering pyramid, 0 being the frames coded
+  /// first and referenced the most
+  pub fn pyramid_level(&self) -> u64 {
+    if self.frame_type == FrameType::KEY {
+      return 0;
+    }
+    let inter_cfg = self.inter_cfg.unwrap();
+    if !inter_cfg.reorder {
+      0
+    } else if inter_cfg.idx_in_group < inter_cfg.pyramid_depth {
+      inter_cfg.idx_in_group
+    } else {
+      pos_to_lvl(
+        inter_cfg.idx_in_group - inter_cfg.pyramid_depth + 1,
+        inter_cfg.pyramid_depth
+      )
+    }
+  }
+
   pub fn get_frame_subtype(&self) -> usize {
     if self.frame_type == FrameType::KEY {
       FRAME_SUBTYPE_I
     } else {
-      let inter_cfg = self.inter_cfg.unwrap();
-      let lvl = if !inter_cfg.reorder {
-        0
-      } else if inter_cfg.idx_in_group < inter_cfg.pyramid_depth {
-        inter_cfg.idx_in_group
-      } else {
-        pos_to_lvl(
-          inter_cfg.idx_in_group - inter_cfg.pyramid_depth + 1,
-          inter_cfg.pyramid_depth
-        )
-      };
-      FRAME_SUBTYPE_P + (lvl as usize)
+      FRAME_SUBTYPE_P + (self.pyramid_level() as usize)
+    }
+  }
+
+  /// Whether no other frame predicts from this one: the inter frames that
+  /// refresh no slot, and the top level of the reordering pyramid
+  pub fn is_discardable(&self) -> bool {
+    if self.intra_only {
+      return false;
+    }
+    let inter_cfg = self.inter_cfg.unwrap();
+    self.refresh_frame_flags == 0
+      || (inter_cfg.reorder
+        && self.pyramid_level() == inter_cfg.pyramid_depth)
+  }
+
+  /// Rounding offset of the quantizer for the intra or inter blocks of the
+  /// frame, in 1/64 of the quantizer, see `EncoderConfig::quant_rounding`
+  pub fn quant_rounding(&self, is_intra: bool) -> u8 {
+    let rounding = &self.config.quant_rounding;
+    if self.intra_only {
+      rounding.key
+    } else if is_intra {
+      INTRA_ROUNDING
+    } else if self.is_discardable() {
+      rounding.inter_nonref
+    } else {
+      rounding.inter_ref
     }
   }
 
@@ -883,11 +1333,80 @@ impl<T: Pixel> FrameInvariants<T> {
       self.dc_delta_q[pi] = (qps.dc_qi[pi] as i32 - base_q_idx) as i8;
       self.ac_delta_q[pi] = (qps.ac_qi[pi] as i32 - base_q_idx) as i8;
     }
+    for pi in 1..3 {
+      let offset = self.config.chroma_qp_offset[pi - 1] as i32;
+      if offset != 0 {
+        // The delta-Q of the frame header are coded on 7 bits
+        let delta = |delta_q: i8| (delta_q as i32 + offset).max(-64).min(63) as i8;
+        self.dc_delta_q[pi] = delta(self.dc_delta_q[pi]);
+        self.ac_delta_q[pi] = delta(self.ac_delta_q[pi]);
+      }
+    }
     self.lambda =
       qps.lambda * ((1 << (2 * (self.sequence.bit_depth - 8))) as f64);
     self.me_lambda = self.lambda.sqrt();
   }
 
+  /// Whether periodic intra refresh forces a block starting at luma column
+  /// `frame_x` of the frame, `blk_w` pixels wide, to be intra coded
+  pub fn intra_refresh_forces_intra(&self, frame_x: usize, blk_w: usize) -> bool {
+    match self.intra_refresh {
+      Some(ir) => frame_x < ir.stripe_end && frame_x + blk_w > ir.stripe_start,
+      None => false
+    }
+  }
+
+  /// Whether periodic intra refresh keeps a block starting at luma column
+  /// `frame_x` of the frame, `blk_w`x`blk_h` pixels, from the intra modes
+  /// predicting from above right, which may read the area right of the
+  /// stripe, not refreshed yet
+  pub fn intra_refresh_forbids_above_right(
+    &self, frame_x: usize, blk_w: usize, blk_h: usize
+  ) -> bool {
+    match self.intra_refresh {
+      Some(ir) => ir.stripe_end < self.width && frame_x < ir.stripe_end
+        && frame_x + blk_w + blk_h > ir.stripe_end,
+      None => false
+    }
+  }
+
+  /// Returns the largest horizontal motion vector component (in 1/8 pel)
+  /// allowed by periodic intra refresh for a block starting at luma column
+  /// `frame_x` of the frame and predicted from `ref_frame`, or None if the
+  /// block is not restricted. Unless the whole reference is clean, the loop
+  /// filters have mixed the area right of its clean area into its last
+  /// columns, which are left out.
+  pub fn intra_refresh_mv_col_max(
+    &self, frame_x: usize, blk_w: usize, ref_frame: RefType
+  ) -> Option<isize> {
+    let ir = self.intra_refresh?;
+    if frame_x + blk_w > ir.stripe_start {
+      return None;
+    }
+    let slot = self.ref_frames[ref_frame.to_index()] as usize;
+    let reference = self.rec_buffer.frames[slot]
+      .as_ref()
+      .and_then(|rec| rec.intra_refresh.as_ref());
+    let clean_width = match ir.clean_width(reference) {
+      w if w >= self.width => w,
+      w => w.saturating_sub(INTRA_REFRESH_LOOP_FILTER_MARGIN),
+    } as isize;
+    Some(
+      (clean_width - (frame_x + blk_w + INTRA_REFRESH_MV_MARGIN) as isize) * 8
+    )
+  }
+
+  /// Precision of the motion vectors coded in the frame
+  pub fn mv_precision(&self) -> MvSubpelPrecision {
+    if self.force_integer_mv != 0 {
+      MvSubpelPrecision::MV_SUBPEL_NONE
+    } else if self.allow_high_precision_mv {
+      MvSubpelPrecision::MV_SUBPEL_HIGH_PRECISION
+    } else {
+      MvSubpelPrecision::MV_SUBPEL_LOW_PRECISION
+    }
+  }
+
   #[inline(always)]
   pub fn sb_size_log2(&self) -> usize {
     self.sequence.sb_size_log2()
@@ -909,6 +1428,8 @@ impl<T: Pixel> fmt::Display for FrameInvariants<T> {
 pub struct InterPropsConfig {
   pub reorder: bool,
   pub multiref: bool,
+  /// Number of slots the frames of the lowest pyramid level rotate in
+  pub base_slots: u64,
   pub pyramid_depth: u64,
   /// number of source frames in group
   pub group_src_len: u64,
@@ -926,39 +1447,47 @@ pub fn write_temporal_delimiter(
   Ok(())
 }
 
+/// Writes the sequence header OBU, with its size field
+pub fn write_sequence_header<T: Pixel>(
+  packet: &mut dyn io::Write, fi: &mut FrameInvariants<T>
+) -> io::Result<()> {
+  let obu_extension = None;
+
+  let mut buf1 = Vec::new();
+  let mut buf2 = Vec::new();
+  {
+    let mut bw2 = BitWriter::endian(&mut buf2, BigEndian);
+    bw2.write_sequence_header_obu(fi)?;
+    bw2.write_bit(true)?; // trailing bit
+    bw2.byte_align()?;
+  }
+
+  {
+    let mut bw1 = BitWriter::endian(&mut buf1, BigEndian);
+    bw1.write_obu_header(ObuType::OBU_SEQUENCE_HEADER, obu_extension)?;
+    bw1.write_uleb128(buf2.len() as u64)?;
+  }
+  packet.write_all(&buf1)?;
+  packet.write_all(&buf2)?;
+  Ok(())
+}
+
 fn write_obus<T: Pixel>(
   packet: &mut dyn io::Write, fi: &mut FrameInvariants<T>, fs: &FrameState<T>
 ) -> io::Result<()> {
-  let obu_extension = 0 as u32;
+  let obu_extension = fi.obu_extension();
 
   let mut buf1 = Vec::new();
 
-  // write sequence header obu if KEY_FRAME, preceded by 4-byte size
   if fi.frame_type == FrameType::KEY {
-    let mut buf2 = Vec::new();
-    {
-      let mut bw2 = BitWriter::endian(&mut buf2, BigEndian);
-      bw2.write_sequence_header_obu(fi)?;
-      bw2.write_bit(true)?; // trailing bit
-      bw2.byte_align()?;
-    }
-
-    {
-      let mut bw1 = BitWriter::endian(&mut buf1, BigEndian);
-      bw1.write_obu_header(ObuType::OBU_SEQUENCE_HEADER, obu_extension)?;
-    }
-    packet.write_all(&buf1).unwrap();
-    buf1.clear();
-
-    {
-      let mut bw1 = BitWriter::endian(&mut buf1, BigEndian);
-      bw1.write_uleb128(buf2.len() as u64)?;
+    let with_sequence_header = match fi.config.sequence_header_placement {
+      SequenceHeaderPlacement::EveryKeyframe => true,
+      SequenceHeaderPlacement::FirstOnly => fi.number == 0,
+      SequenceHeaderPlacement::Never => false,
+    };
+    if with_sequence_header {
+      write_sequence_header(packet, fi)?;
     }
-    packet.write_all(&buf1).unwrap();
-    buf1.clear();
-
-    packet.write_all(&buf2).unwrap();
-    buf2.clear();
 
     if fi.sequence.content_light.is_some() {
       let mut bw1 = BitWriter::endian(&mut buf1, BigEndian);
@@ -1019,8 +1548,8 @@ fn diff<T: Pixel>(
     }
 }
 
-fn get_qidx<T: Pixel>(fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, cw: &ContextWriter, tile_bo: BlockOffset) -> u8 {
-  let mut qidx = fi.base_q_idx;
+pub(crate) fn get_qidx<T: Pixel>(fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, cw: &ContextWriter, tile_bo: BlockOffset) -> u8 {
+  let mut qidx = ts.sb_qidx(tile_bo.sb_offset()).unwrap_or(fi.base_q_idx);
   let sidx = cw.bc.blocks[tile_bo].segmentation_idx as usize;
   if ts.segmentation.features[sidx][SegLvl::SEG_LVL_ALT_Q as usize] {
     let delta = ts.segmentation.data[sidx][SegLvl::SEG_LVL_ALT_Q as usize];
@@ -1036,44 +1565,38 @@ pub fn encode_tx_block<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>, cw: &mut ContextWriter,
   w: &mut dyn Writer, p: usize, tile_bo: BlockOffset, mode: PredictionMode,
   tx_size: TxSize, tx_type: TxType, plane_bsize: BlockSize, po: PlaneOffset,
-  skip: bool, ac: &[i16], alpha: i16, rdo_type: RDOType, for_rdo_use: bool
+  skip: bool, ac: &[i16], intra_param: IntraParam,
+  edge_filter: Option<IntraEdgeFilterType>, rdo_type: RDOType,
+  for_rdo_use: bool
 ) -> (bool, i64) {
   let qidx = get_qidx(fi, ts, cw, tile_bo);
   let PlaneConfig { xdec, ydec, .. } = ts.input.planes[p].cfg;
   let tile_rect = ts.tile_rect().decimated(xdec, ydec);
-  let rec = &mut ts.rec.planes[p];
   let area = Area::BlockStartingAt { bo: tile_bo };
 
   assert!(tx_size.sqr() <= TxSize::TX_32X32 || tx_type == TxType::DCT_DCT);
 
   if mode.is_intra() {
+    let rec = &mut ts.rec.planes[p];
     let bit_depth = fi.sequence.bit_depth;
-    let edge_buf = get_intra_edges(&rec.as_const(), po, tx_size, bit_depth, Some(mode));
-    mode.predict_intra(tile_rect, &mut rec.subregion_mut(area), tx_size, bit_depth, &ac, alpha, &edge_buf);
+    let edge_buf = get_intra_edges(&rec.as_const(), TilePlaneOffset(po), tx_size, bit_depth, Some(mode), intra_param);
+    mode.predict_intra(
+      tile_rect, &mut rec.subregion_mut(area), tx_size, bit_depth, &ac,
+      intra_param, &edge_buf, edge_filter, fi.cpu
+    );
   }
 
   if skip { return (false, -1); }
 
-  let mut residual_storage: AlignedArray<[i16; 64 * 64]> = UninitializedAlignedArray();
   let mut coeffs_storage: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
   let mut qcoeffs_storage: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
   let mut rcoeffs_storage: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
-  let residual = &mut residual_storage.array[..tx_size.area()];
   let coeffs = &mut coeffs_storage.array[..tx_size.area()];
   let qcoeffs = &mut qcoeffs_storage.array[..tx_size.area()];
   let rcoeffs = &mut rcoeffs_storage.array[..tx_size.area()];
 
-  diff(
-    residual,
-    &ts.input_tile.planes[p].subregion(area),
-    &rec.subregion(area),
-    tx_size.width(),
-    tx_size.height());
-
-  forward_transform(residual, coeffs, tx_size.width(), tx_size, tx_type, fi.sequence.bit_depth);
-
-  let coded_tx_size = av1_get_coded_tx_size(tx_size).area();
-  ts.qc.quantize(coeffs, qcoeffs, coded_tx_size);
+  quantize_tx_block(fi, ts, cw, p, tile_bo, mode, tx_size, tx_type, plane_bsize,
+                    qidx, coeffs, qcoeffs);
 
   let tell_coeffs = w.tell_frac();
   let has_coeff = if !for_rdo_use || rdo_type.needs_coeff_rate() {
@@ -1083,13 +1606,16 @@ pub fn encode_tx_block<T: Pixel>(
     true
   };
   let cost_coeffs = w.tell_frac() - tell_coeffs;
+  if !for_rdo_use {
+    cw.coeff_bits_frac[p] += cost_coeffs as u64;
+  }
   // Reconstruct
   dequantize(qidx, qcoeffs, rcoeffs, tx_size, fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p]);
 
   let mut tx_dist: i64 = -1;
 
   if !fi.use_tx_domain_distortion || !for_rdo_use {
-    inverse_transform_add(rcoeffs, &mut rec.subregion_mut(area), tx_size, tx_type, fi.sequence.bit_depth);
+    inverse_transform_add(rcoeffs, &mut ts.rec.planes[p].subregion_mut(area), tx_size, tx_type, fi.sequence.bit_depth, fi.cpu);
   }
   if rdo_type.needs_tx_dist() {
     // Store tx-domain distortion of this block
@@ -1105,18 +1631,53 @@ pub fn encode_tx_block<T: Pixel>(
     let tx_dist_scale_rounding_offset = 1 << (tx_dist_scale_bits - 1);
     tx_dist = (tx_dist + tx_dist_scale_rounding_offset) >> tx_dist_scale_bits;
   }
+  // The rate table is indexed by the 8-bit distortion: the quantizers of a
+  // qindex scale with the samples, so the same qindex codes the same
+  // levels at a distortion 4 times larger per extra bit of depth.
+  let rate_dist = (tx_dist as u64) >> (2 * (fi.sequence.bit_depth - 8));
   if fi.config.train_rdo {
-    ts.rdo.add_rate(fi.base_q_idx, tx_size, tx_dist as u64, cost_coeffs as u64);
+    ts.rdo.add_rate(fi.base_q_idx, tx_size, rate_dist, cost_coeffs as u64);
   }
 
   if rdo_type == RDOType::TxDistEstRate {
     // look up rate and distortion in table
-    let estimated_rate = estimate_rate(fi.base_q_idx, tx_size, tx_dist as u64);
+    let estimated_rate = estimate_rate(fi.base_q_idx, tx_size, rate_dist);
     w.add_bits_frac(estimated_rate as u32);
   }
   (has_coeff, tx_dist)
 }
 
+/// Transforms and quantizes the residual of a transform block, whose
+/// prediction is already in the reconstruction.
+fn quantize_tx_block<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>, cw: &mut ContextWriter,
+  p: usize, tile_bo: BlockOffset, mode: PredictionMode, tx_size: TxSize,
+  tx_type: TxType, plane_bsize: BlockSize, qidx: u8, coeffs: &mut [i32],
+  qcoeffs: &mut [i32]
+) {
+  let PlaneConfig { xdec, ydec, .. } = ts.input.planes[p].cfg;
+  let area = Area::BlockStartingAt { bo: tile_bo };
+
+  let mut residual_storage: AlignedArray<[i16; 64 * 64]> = UninitializedAlignedArray();
+  let residual = &mut residual_storage.array[..tx_size.area()];
+
+  diff(
+    residual,
+    &ts.input_tile.planes[p].subregion(area),
+    &ts.rec.planes[p].subregion(area),
+    tx_size.width(),
+    tx_size.height());
+
+  forward_transform(residual, coeffs, tx_size.width(), tx_size, tx_type, fi.sequence.bit_depth, fi.cpu);
+
+  let coded_tx_size = av1_get_coded_tx_size(tx_size).area();
+  ts.qc.quantize(coeffs, qcoeffs, coded_tx_size);
+  if fi.config.speed_settings.rdoq {
+    rdoq(fi, cw, p, tile_bo, mode, tx_size, tx_type, plane_bsize, xdec, ydec,
+         qidx, coeffs, qcoeffs);
+  }
+}
+
 pub fn motion_compensate<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>, cw: &mut ContextWriter,
   luma_mode: PredictionMode, ref_frames: [RefType; 2], mvs: [MotionVector; 2],
@@ -1138,61 +1699,42 @@ pub fn motion_compensate<T: Pixel>(
     let rec = &mut ts.rec.planes[p];
     let po = tile_bo.plane_offset(&rec.plane_cfg);
     let &PlaneConfig { xdec, ydec, .. } = rec.plane_cfg;
-    let tile_rect = luma_tile_rect.decimated(xdec, ydec);
+    let frame_po = TilePlaneOffset(po).to_frame(&luma_tile_rect, xdec, ydec);
 
     let area = Area::BlockStartingAt { bo: tile_bo };
-    if p > 0 && bsize < BlockSize::BLOCK_8X8 {
-      let mut some_use_intra = false;
-      if bsize == BlockSize::BLOCK_4X4 || bsize == BlockSize::BLOCK_4X8 {
-        some_use_intra |= cw.bc.blocks[tile_bo.with_offset(-1,0)].mode.is_intra(); };
-      if !some_use_intra && bsize == BlockSize::BLOCK_4X4 || bsize == BlockSize::BLOCK_8X4 {
-        some_use_intra |= cw.bc.blocks[tile_bo.with_offset(0,-1)].mode.is_intra(); };
-      if !some_use_intra && bsize == BlockSize::BLOCK_4X4 {
-        some_use_intra |= cw.bc.blocks[tile_bo.with_offset(-1,-1)].mode.is_intra(); };
+    if p > 0 && bsize.is_sub8x8(xdec, ydec) {
+      // The chroma block also covers the luma blocks above and to the left,
+      // each predicted with its own motion unless any of them is intra
+      let (offset_x, offset_y) = bsize.sub8x8_offset(xdec, ydec);
+      let some_use_intra = (offset_y..=0).any(|r| (offset_x..=0).any(|c| {
+        (r, c) != (0, 0) && !cw.bc.blocks[tile_bo.with_offset(c, r)].is_inter()
+      }));
 
       if some_use_intra {
-        luma_mode.predict_inter(fi, tile_rect, p, po, &mut rec.subregion_mut(area), plane_bsize.width(),
+        luma_mode.predict_inter(fi, p, frame_po, &mut rec.subregion_mut(area), plane_bsize.width(),
                                 plane_bsize.height(), ref_frames, mvs);
       } else {
-        assert!(u_xdec == 1 && u_ydec == 1);
-        // TODO: these are absolutely only valid for 4:2:0
-        if bsize == BlockSize::BLOCK_4X4 {
-          let mv0 = cw.bc.blocks[tile_bo.with_offset(-1,-1)].mv;
-          let rf0 = cw.bc.blocks[tile_bo.with_offset(-1,-1)].ref_frames;
-          let mv1 = cw.bc.blocks[tile_bo.with_offset(0,-1)].mv;
-          let rf1 = cw.bc.blocks[tile_bo.with_offset(0,-1)].ref_frames;
-          let po1 = PlaneOffset { x: po.x+2, y: po.y };
-          let area1 = Area::StartingAt { x: po1.x, y: po1.y };
-          let mv2 = cw.bc.blocks[tile_bo.with_offset(-1,0)].mv;
-          let rf2 = cw.bc.blocks[tile_bo.with_offset(-1,0)].ref_frames;
-          let po2 = PlaneOffset { x: po.x, y: po.y+2 };
-          let area2 = Area::StartingAt { x: po2.x, y: po2.y };
-          let po3 = PlaneOffset { x: po.x+2, y: po.y+2 };
-          let area3 = Area::StartingAt { x: po3.x, y: po3.y };
-          luma_mode.predict_inter(fi, tile_rect, p, po, &mut rec.subregion_mut(area), 2, 2, rf0, mv0);
-          luma_mode.predict_inter(fi, tile_rect, p, po1, &mut rec.subregion_mut(area1), 2, 2, rf1, mv1);
-          luma_mode.predict_inter(fi, tile_rect, p, po2, &mut rec.subregion_mut(area2), 2, 2, rf2, mv2);
-          luma_mode.predict_inter(fi, tile_rect, p, po3, &mut rec.subregion_mut(area3), 2, 2, ref_frames, mvs);
-        }
-        if bsize == BlockSize::BLOCK_8X4 {
-          let mv1 = cw.bc.blocks[tile_bo.with_offset(0,-1)].mv;
-          let rf1 = cw.bc.blocks[tile_bo.with_offset(0,-1)].ref_frames;
-          luma_mode.predict_inter(fi, tile_rect, p, po, &mut rec.subregion_mut(area), 4, 2, rf1, mv1);
-          let po3 = PlaneOffset { x: po.x, y: po.y+2 };
-          let area3 = Area::StartingAt { x: po3.x, y: po3.y };
-          luma_mode.predict_inter(fi, tile_rect, p, po3, &mut rec.subregion_mut(area3), 4, 2, ref_frames, mvs);
-        }
-        if bsize == BlockSize::BLOCK_4X8 {
-          let mv2 = cw.bc.blocks[tile_bo.with_offset(-1,0)].mv;
-          let rf2 = cw.bc.blocks[tile_bo.with_offset(-1,0)].ref_frames;
-          luma_mode.predict_inter(fi, tile_rect, p, po, &mut rec.subregion_mut(area), 2, 4, rf2, mv2);
-          let po3 = PlaneOffset { x: po.x+2, y: po.y };
-          let area3 = Area::StartingAt { x: po3.x, y: po3.y };
-          luma_mode.predict_inter(fi, tile_rect, p, po3, &mut rec.subregion_mut(area3), 2, 4, ref_frames, mvs);
+        let pred_w = bsize.width() >> xdec;
+        let pred_h = bsize.height() >> ydec;
+        for (r, y) in (0..plane_bsize.height()).step_by(pred_h).enumerate() {
+          for (c, x) in (0..plane_bsize.width()).step_by(pred_w).enumerate() {
+            let (cand_x, cand_y) = (offset_x + c as isize, offset_y + r as isize);
+            let (cand_ref_frames, cand_mvs) = if (cand_x, cand_y) == (0, 0) {
+              (ref_frames, mvs)
+            } else {
+              let b = &cw.bc.blocks[tile_bo.with_offset(cand_x, cand_y)];
+              (b.ref_frames, b.mv)
+            };
+            let pred_po = TilePlaneOffset(PlaneOffset { x: po.x + x as isize, y: po.y + y as isize });
+            let pred_area = Area::StartingAt { x: pred_po.0.x, y: pred_po.0.y };
+            luma_mode.predict_inter(fi, p, pred_po.to_frame(&luma_tile_rect, xdec, ydec),
+                                    &mut rec.subregion_mut(pred_area), pred_w,
+                                    pred_h, cand_ref_frames, cand_mvs);
+          }
         }
       }
     } else {
-      luma_mode.predict_inter(fi, tile_rect, p, po, &mut rec.subregion_mut(area), plane_bsize.width(),
+      luma_mode.predict_inter(fi, p, frame_po, &mut rec.subregion_mut(area), plane_bsize.width(),
                               plane_bsize.height(), ref_frames, mvs);
     }
   }
@@ -1204,8 +1746,9 @@ pub fn save_block_motion<T: Pixel>(
    ref_frame: usize, mv: MotionVector,
 ) {
   let tile_mvs = &mut ts.mvs[ref_frame];
-  let tile_bo_x_end = (tile_bo.x + bsize.width_mi()).min(ts.mi_width);
-  let tile_bo_y_end = (tile_bo.y + bsize.height_mi()).min(ts.mi_height);
+  let tile_bo_end = tile_bo.add_size(bsize);
+  let tile_bo_x_end = tile_bo_end.x.min(ts.mi_width);
+  let tile_bo_y_end = tile_bo_end.y.min(ts.mi_height);
   for mi_y in tile_bo.y..tile_bo_y_end {
     for mi_x in tile_bo.x..tile_bo_x_end {
       tile_mvs[mi_y][mi_x] = mv;
@@ -1238,8 +1781,8 @@ pub fn encode_block_b<T: Pixel>(
   luma_mode: PredictionMode, chroma_mode: PredictionMode,
   ref_frames: [RefType; 2], mvs: [MotionVector; 2],
   bsize: BlockSize, tile_bo: BlockOffset, skip: bool,
-  cfl: CFLParams, tx_size: TxSize, tx_type: TxType,
-  mode_context: usize, mv_stack: &[CandidateMV],
+  cfl: CFLParams, angle_delta: AngleDelta, tx_size: TxSize, tx_type: TxType,
+  tx_partition: TxPartition, mode_context: usize, mv_stack: &[CandidateMV],
   rdo_type: RDOType, for_rdo_use: bool
 ) -> i64 {
   let is_inter = !luma_mode.is_intra();
@@ -1255,17 +1798,36 @@ pub fn encode_block_b<T: Pixel>(
   }
   cw.bc.blocks.set_block_size(tile_bo, bsize);
   cw.bc.blocks.set_mode(tile_bo, bsize, luma_mode);
+  if !is_inter {
+    let uv_mode = if has_chroma(tile_bo, bsize, xdec, ydec) {
+      chroma_mode
+    } else {
+      PredictionMode::DC_PRED
+    };
+    cw.bc.blocks.set_uv_mode(tile_bo, bsize, uv_mode);
+  }
   cw.bc.blocks.set_tx_size(tile_bo, bsize, tx_size);
+  debug_assert!(tx_partition == TxPartition::NONE || (is_inter && !skip));
+  if tx_partition.is_split() {
+    for (x, y, sub_tx_size) in tx_partition.tx_blocks(tx_size) {
+      let tx_bo = tile_bo.with_offset(x as isize, y as isize);
+      cw.bc.blocks.set_tx_size(tx_bo, sub_tx_size.block_size(), sub_tx_size);
+    }
+  }
   cw.bc.blocks.set_ref_frames(tile_bo, bsize, ref_frames);
   cw.bc.blocks.set_motion_vectors(tile_bo, bsize, mvs);
 
-  //write_q_deltas();
+  if cw.bc.code_deltas && fi.delta_q_present && (bsize < sb_size || !skip) {
+    let qidx = ts.sb_qidx(tile_bo.sb_offset()).unwrap_or(fi.base_q_idx);
+    cw.write_delta_qindex(w, qidx as i32 - cw.bc.current_qidx as i32);
+    cw.bc.current_qidx = qidx;
+  }
   if cw.bc.code_deltas && ts.deblock.block_deltas_enabled && (bsize < sb_size || !skip) {
     cw.write_block_deblock_deltas(w, tile_bo, ts.deblock.block_delta_multi);
   }
   cw.bc.code_deltas = false;
 
-  if fi.frame_type == FrameType::INTER {
+  if fi.frame_type.has_inter() {
     cw.write_is_inter(w, tile_bo, is_inter);
     if is_inter {
       cw.fill_neighbours_ref_counts(tile_bo);
@@ -1299,13 +1861,7 @@ pub fn encode_block_b<T: Pixel>(
         [MotionVector::default(); 2]
       };
 
-      let mv_precision = if fi.force_integer_mv != 0 {
-        MvSubpelPrecision::MV_SUBPEL_NONE
-      } else if fi.allow_high_precision_mv {
-        MvSubpelPrecision::MV_SUBPEL_HIGH_PRECISION
-      } else {
-        MvSubpelPrecision::MV_SUBPEL_LOW_PRECISION
-      };
+      let mv_precision = fi.mv_precision();
 
       if luma_mode == PredictionMode::NEWMV ||
         luma_mode == PredictionMode::NEW_NEWMV ||
@@ -1356,7 +1912,7 @@ pub fn encode_block_b<T: Pixel>(
 
   if !is_inter {
     if luma_mode.is_directional() && bsize >= BlockSize::BLOCK_8X8 {
-      cw.write_angle_delta(w, 0, luma_mode);
+      cw.write_angle_delta(w, angle_delta.y, luma_mode);
     }
     if has_chroma(tile_bo, bsize, xdec, ydec) {
       cw.write_intra_uv_mode(w, chroma_mode, luma_mode, bsize);
@@ -1365,11 +1921,12 @@ pub fn encode_block_b<T: Pixel>(
         cw.write_cfl_alphas(w, cfl);
       }
       if chroma_mode.is_directional() && bsize >= BlockSize::BLOCK_8X8 {
-        cw.write_angle_delta(w, 0, chroma_mode);
+        cw.write_angle_delta(w, angle_delta.uv, chroma_mode);
       }
     }
     // TODO: Extra condition related to palette mode, see `read_filter_intra_mode_info` in decodemv.c
-    if luma_mode == PredictionMode::DC_PRED && bsize.width() <= 32 && bsize.height() <= 32 {
+    if fi.sequence.enable_filter_intra && luma_mode == PredictionMode::DC_PRED
+      && bsize.width() <= 32 && bsize.height() <= 32 {
       cw.write_use_filter_intra(w,false, bsize); // Always turn off FILTER_INTRA
     }
   }
@@ -1381,9 +1938,9 @@ pub fn encode_block_b<T: Pixel>(
       if !is_inter {
         cw.write_tx_size_intra(w, tile_bo, bsize, tx_size);
         cw.bc.update_tx_size_context(tile_bo, bsize, tx_size, false);
-      } /*else {  // TODO (yushin): write_tx_size_inter(), i.e. var-tx
-
-      }*/
+      } else {
+        cw.write_tx_partition(w, tile_bo, bsize, tx_partition);
+      }
     } else {
       cw.bc.update_tx_size_context(tile_bo, bsize, tx_size, is_inter && skip);
     }
@@ -1391,9 +1948,9 @@ pub fn encode_block_b<T: Pixel>(
 
   if is_inter {
     motion_compensate(fi, ts, cw, luma_mode, ref_frames, mvs, bsize, tile_bo, false);
-    write_tx_tree(fi, ts, cw, w, luma_mode, tile_bo, bsize, tx_size, tx_type, skip, false, rdo_type, for_rdo_use)
+    write_tx_tree(fi, ts, cw, w, luma_mode, tile_bo, bsize, tx_size, tx_type, tx_partition, skip, false, rdo_type, for_rdo_use)
   } else {
-    write_tx_blocks(fi, ts, cw, w, luma_mode, chroma_mode, tile_bo, bsize, tx_size, tx_type, skip, cfl, false, rdo_type, for_rdo_use)
+    write_tx_blocks(fi, ts, cw, w, luma_mode, chroma_mode, angle_delta, tile_bo, bsize, tx_size, tx_type, skip, cfl, false, rdo_type, for_rdo_use)
   }
 }
 
@@ -1442,7 +1999,8 @@ pub fn luma_ac<T: Pixel>(
 pub fn write_tx_blocks<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
   cw: &mut ContextWriter, w: &mut dyn Writer,
-  luma_mode: PredictionMode, chroma_mode: PredictionMode, tile_bo: BlockOffset,
+  luma_mode: PredictionMode, chroma_mode: PredictionMode,
+  angle_delta: AngleDelta, tile_bo: BlockOffset,
   bsize: BlockSize, tx_size: TxSize, tx_type: TxType, skip: bool,
   cfl: CFLParams, luma_only: bool, rdo_type: RDOType, for_rdo_use: bool
 ) -> i64 {
@@ -1454,8 +2012,17 @@ pub fn write_tx_blocks<T: Pixel>(
   let mut ac: AlignedArray<[i16; 32 * 32]> = UninitializedAlignedArray();
   let mut tx_dist: i64 = 0;
   let do_chroma = has_chroma(tile_bo, bsize, xdec, ydec);
+  let edge_filter = |cw: &ContextWriter, p: usize| {
+    if fi.sequence.enable_intra_edge_filter {
+      let (xdec, ydec) = if p == 0 { (0, 0) } else { (xdec, ydec) };
+      Some(cw.bc.intra_edge_filter_type(tile_bo, bsize, p, xdec, ydec))
+    } else {
+      None
+    }
+  };
 
-  ts.qc.update(qidx, tx_size, luma_mode.is_intra(), fi.sequence.bit_depth, fi.dc_delta_q[0], 0);
+  ts.qc.update(qidx, tx_size, fi.quant_rounding(luma_mode.is_intra()), fi.sequence.bit_depth, fi.dc_delta_q[0], 0);
+  let luma_edge_filter = edge_filter(cw, 0);
 
   for by in 0..bh {
     for bx in 0..bw {
@@ -1468,7 +2035,8 @@ pub fn write_tx_blocks<T: Pixel>(
       let (_, dist) =
         encode_tx_block(
           fi, ts, cw, w, 0, tx_bo, luma_mode, tx_size, tx_type, bsize, po,
-          skip, &ac.array, 0, rdo_type, for_rdo_use
+          skip, &ac.array, IntraParam::AngleDelta(angle_delta.y),
+          luma_edge_filter, rdo_type, for_rdo_use
         );
       assert!(!fi.use_tx_domain_distortion || !for_rdo_use || skip || dist >= 0);
       tx_dist += dist;
@@ -1509,10 +2077,15 @@ pub fn write_tx_blocks<T: Pixel>(
     } else {
       uv_intra_mode_to_tx_type_context(chroma_mode)
     };
+    let chroma_edge_filter = edge_filter(cw, 1);
 
     for p in 1..3 {
-      ts.qc.update(fi.base_q_idx, uv_tx_size, true, fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p]);
-      let alpha = cfl.alpha(p - 1);
+      ts.qc.update(qidx, uv_tx_size, fi.quant_rounding(true), fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p]);
+      let intra_param = if chroma_mode.is_cfl() {
+        IntraParam::Alpha(cfl.alpha(p - 1))
+      } else {
+        IntraParam::AngleDelta(angle_delta.uv)
+      };
       for by in 0..bh_uv {
         for bx in 0..bw_uv {
           let tx_bo =
@@ -1528,7 +2101,8 @@ pub fn write_tx_blocks<T: Pixel>(
           po.y += (by * uv_tx_size.height()) as isize;
           let (_, dist) =
             encode_tx_block(fi, ts, cw, w, p, tx_bo, chroma_mode, uv_tx_size, uv_tx_type,
-                            plane_bsize, po, skip, &ac.array, alpha, rdo_type, for_rdo_use);
+                            plane_bsize, po, skip, &ac.array, intra_param,
+                            chroma_edge_filter, rdo_type, for_rdo_use);
           assert!(!fi.use_tx_domain_distortion || !for_rdo_use || skip || dist >= 0);
           tx_dist += dist;
         }
@@ -1539,13 +2113,14 @@ pub fn write_tx_blocks<T: Pixel>(
   tx_dist
 }
 
-// FIXME: For now, assume tx_mode is LARGEST_TX, so var-tx is not implemented yet,
-// which means only one tx block exist for a inter mode partition.
+/// Codes the residual of an inter block, with the luma transforms of
+/// `tx_partition`. Blocks larger than 64x64 are not supported yet, and only
+/// code a single transform of each plane.
 pub fn write_tx_tree<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>, cw: &mut ContextWriter, w: &mut dyn Writer,
   luma_mode: PredictionMode, tile_bo: BlockOffset,
-  bsize: BlockSize, tx_size: TxSize, tx_type: TxType, skip: bool,
-  luma_only: bool, rdo_type: RDOType, for_rdo_use: bool
+  bsize: BlockSize, tx_size: TxSize, tx_type: TxType, tx_partition: TxPartition,
+  skip: bool, luma_only: bool, rdo_type: RDOType, for_rdo_use: bool
 ) -> i64 {
   let bw = bsize.width_mi() / tx_size.width_mi();
   let bh = bsize.height_mi() / tx_size.height_mi();
@@ -1554,15 +2129,23 @@ pub fn write_tx_tree<T: Pixel>(
   let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
   let ac = &[0i16; 0];
   let mut tx_dist: i64 = 0;
+  // Chroma follows the type of the first luma transform, when it is coded
+  let mut first_has_coeff = None;
 
-  ts.qc.update(qidx, tx_size, luma_mode.is_intra(), fi.sequence.bit_depth, fi.dc_delta_q[0], 0);
+  for (x, y, sub_tx_size) in tx_partition.tx_blocks(tx_size) {
+    let tx_bo = tile_bo.with_offset(x as isize, y as isize);
+    ts.qc.update(qidx, sub_tx_size, fi.quant_rounding(luma_mode.is_intra()), fi.sequence.bit_depth, fi.dc_delta_q[0], 0);
 
-  let po = tile_bo.plane_offset(&ts.input.planes[0].cfg);
-  let (has_coeff, dist) = encode_tx_block(
-    fi, ts, cw, w, 0, tile_bo, luma_mode, tx_size, tx_type, bsize, po, skip, ac, 0, rdo_type, for_rdo_use
-  );
-  assert!(!fi.use_tx_domain_distortion || !for_rdo_use || skip || dist >= 0);
-  tx_dist += dist;
+    let po = tx_bo.plane_offset(&ts.input.planes[0].cfg);
+    let (has_coeff, dist) = encode_tx_block(
+      fi, ts, cw, w, 0, tx_bo, luma_mode, sub_tx_size, tx_type, bsize, po,
+      skip, ac, IntraParam::None, None, rdo_type, for_rdo_use
+    );
+    assert!(!fi.use_tx_domain_distortion || !for_rdo_use || skip || dist >= 0);
+    tx_dist += dist;
+    first_has_coeff.get_or_insert(has_coeff);
+  }
+  let has_coeff = first_has_coeff.unwrap_or(false);
 
   if luma_only { return tx_dist };
 
@@ -1589,10 +2172,16 @@ pub fn write_tx_tree<T: Pixel>(
       debug_assert!(bw_uv == 1, "bw_uv = {}, bh_uv = {}", bw_uv, bh_uv);
       debug_assert!(bh_uv == 1, "bw_uv = {}, bh_uv = {}", bw_uv, bh_uv);
     }*/
-    let uv_tx_type = if has_coeff {tx_type} else {TxType::DCT_DCT}; // if inter mode, uv_tx_type == tx_type
+    let uv_tx_set = get_tx_set(uv_tx_size, true, fi.use_reduced_tx_set);
+    // if inter mode, uv_tx_type == tx_type when the chroma transform set has it
+    let uv_tx_type = if has_coeff && av1_tx_used[uv_tx_set as usize][tx_type as usize] != 0 {
+      tx_type
+    } else {
+      TxType::DCT_DCT
+    };
 
     for p in 1..3 {
-      ts.qc.update(qidx, uv_tx_size, false, fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p]);
+      ts.qc.update(qidx, uv_tx_size, fi.quant_rounding(false), fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p]);
       let tx_bo = BlockOffset {
         x: tile_bo.x  - ((bw * tx_size.width_mi() == 1) as usize),
         y: tile_bo.y  - ((bh * tx_size.height_mi() == 1) as usize)
@@ -1601,7 +2190,8 @@ pub fn write_tx_tree<T: Pixel>(
       let po = tile_bo.plane_offset(&ts.input.planes[p].cfg);
       let (_, dist) =
         encode_tx_block(fi, ts, cw, w, p, tx_bo, luma_mode, uv_tx_size, uv_tx_type,
-                        plane_bsize, po, skip, ac, 0, rdo_type, for_rdo_use);
+                        plane_bsize, po, skip, ac, IntraParam::None, None, rdo_type,
+                        for_rdo_use);
       assert!(!fi.use_tx_domain_distortion || !for_rdo_use || skip || dist >= 0);
       tx_dist += dist;
     }
@@ -1610,6 +2200,74 @@ pub fn write_tx_tree<T: Pixel>(
   tx_dist
 }
 
+/// Returns whether all the quantized coefficients of a block are zero, for
+/// every plane, in which case coding it as skip leaves its reconstruction
+/// unchanged.
+pub fn should_skip(coeffs_all_planes: &[&[i32]]) -> bool {
+  coeffs_all_planes.iter().all(|coeffs| coeffs.iter().all(|&c| c == 0))
+}
+
+/// Predicts an inter block and quantizes its residual as `write_tx_tree`
+/// would code it, without coding anything. Returns whether the block should
+/// be coded as skip instead.
+fn inter_residual_is_zero<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>, cw: &mut ContextWriter,
+  luma_mode: PredictionMode, ref_frames: [RefType; 2], mvs: [MotionVector; 2],
+  bsize: BlockSize, tile_bo: BlockOffset, tx_size: TxSize, tx_type: TxType,
+  tx_partition: TxPartition
+) -> bool {
+  motion_compensate(fi, ts, cw, luma_mode, ref_frames, mvs, bsize, tile_bo, false);
+
+  let qidx = get_qidx(fi, ts, cw, tile_bo);
+  let mut coeffs_storage: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
+  let mut qcoeffs_storage: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
+
+  for (x, y, sub_tx_size) in tx_partition.tx_blocks(tx_size) {
+    let tx_bo = tile_bo.with_offset(x as isize, y as isize);
+    let area = sub_tx_size.area();
+    ts.qc.update(qidx, sub_tx_size, fi.quant_rounding(false), fi.sequence.bit_depth, fi.dc_delta_q[0], 0);
+    quantize_tx_block(fi, ts, cw, 0, tx_bo, luma_mode, sub_tx_size, tx_type, bsize,
+                      qidx, &mut coeffs_storage.array[..area],
+                      &mut qcoeffs_storage.array[..area]);
+    if !should_skip(&[&qcoeffs_storage.array[..area]]) {
+      return false;
+    }
+  }
+
+  // Without luma coefficients, chroma is coded with DCT_DCT
+  let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
+  let uv_tx_size = bsize.largest_uv_tx_size(xdec, ydec);
+  let mut bw_uv = bsize.width_mi() >> xdec;
+  let mut bh_uv = bsize.height_mi() >> ydec;
+  if (bw_uv == 0 || bh_uv == 0) && has_chroma(tile_bo, bsize, xdec, ydec) {
+    bw_uv = 1;
+    bh_uv = 1;
+  }
+  if bw_uv / uv_tx_size.width_mi() == 0 || bh_uv / uv_tx_size.height_mi() == 0 {
+    return true;
+  }
+
+  let plane_bsize = get_plane_block_size(bsize, xdec, ydec);
+  let tx_bo = BlockOffset {
+    x: tile_bo.x - ((bsize.width_mi() == 1) as usize),
+    y: tile_bo.y - ((bsize.height_mi() == 1) as usize)
+  };
+  let area = uv_tx_size.area();
+  let mut cr_qcoeffs_storage: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
+  for p in 1..3 {
+    // The Cb coefficients stay in qcoeffs_storage
+    let qcoeffs = if p == 1 {
+      &mut qcoeffs_storage.array[..area]
+    } else {
+      &mut cr_qcoeffs_storage.array[..area]
+    };
+    ts.qc.update(qidx, uv_tx_size, fi.quant_rounding(false), fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p]);
+    quantize_tx_block(fi, ts, cw, p, tx_bo, luma_mode, uv_tx_size, TxType::DCT_DCT,
+                      plane_bsize, qidx, &mut coeffs_storage.array[..area], qcoeffs);
+  }
+  should_skip(&[&qcoeffs_storage.array[..area], &cr_qcoeffs_storage.array[..area]])
+}
+
 pub fn encode_block_with_modes<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
   cw: &mut ContextWriter, w_pre_cdef: &mut dyn Writer, w_post_cdef: &mut dyn Writer,
@@ -1619,24 +2277,42 @@ pub fn encode_block_with_modes<T: Pixel>(
   let (mode_luma, mode_chroma) =
     (mode_decision.pred_mode_luma, mode_decision.pred_mode_chroma);
   let cfl = mode_decision.pred_cfl_params;
+  let angle_delta = mode_decision.angle_delta;
   let ref_frames = mode_decision.ref_frames;
   let mvs = mode_decision.mvs;
-  let skip = mode_decision.skip;
+  let mut skip = mode_decision.skip;
   let mut cdef_coded = cw.bc.cdef_coded;
-  let (tx_size, tx_type) = (mode_decision.tx_size, mode_decision.tx_type);
+  let (mut tx_size, mut tx_type) = (mode_decision.tx_size, mode_decision.tx_type);
+  let mut tx_partition = mode_decision.tx_partition;
+
+  debug_assert!((tx_size, tx_type, tx_partition) ==
+                rdo_tx_size_type(fi, ts, cw, bsize, tile_bo, mode_luma, angle_delta, ref_frames, mvs, skip));
 
-  debug_assert!((tx_size, tx_type) ==
-                rdo_tx_size_type(fi, ts, cw, bsize, tile_bo, mode_luma, ref_frames, mvs, skip));
+  if !skip && !mode_luma.is_intra() &&
+    fi.config.speed_settings.skip_zero_residual &&
+    inter_residual_is_zero(fi, ts, cw, mode_luma, ref_frames, mvs, bsize, tile_bo,
+                           tx_size, tx_type, tx_partition) {
+    skip = true;
+    let tx = rdo_tx_size_type(fi, ts, cw, bsize, tile_bo, mode_luma, angle_delta, ref_frames, mvs, skip);
+    tx_size = tx.0;
+    tx_type = tx.1;
+    tx_partition = tx.2;
+  }
 
   let mut mv_stack = Vec::new();
   let is_compound = ref_frames[1] != NONE_FRAME;
   let mode_context = cw.find_mvrefs(tile_bo, ref_frames, &mut mv_stack, bsize, fi, is_compound);
 
+  let pre_cdef = !cdef_coded;
   cdef_coded = encode_block_a(&fi.sequence, ts, cw, if cdef_coded  {w_post_cdef} else {w_pre_cdef},
                               bsize, tile_bo, skip);
+  if pre_cdef && cdef_coded {
+    continue_ec_state(fi, w_pre_cdef, w_post_cdef);
+  }
   encode_block_b(fi, ts, cw, if cdef_coded  {w_post_cdef} else {w_pre_cdef},
                  mode_luma, mode_chroma, ref_frames, mvs, bsize, tile_bo, skip, cfl,
-                 tx_size, tx_type, mode_context, &mv_stack, rdo_type, false);
+                 angle_delta, tx_size, tx_type, tx_partition, mode_context, &mv_stack,
+                 rdo_type, false);
 }
 
 fn encode_partition_bottomup<T: Pixel>(
@@ -1665,7 +2341,7 @@ fn encode_partition_bottomup<T: Pixel>(
   // Always split if the current partition is too large
   let must_split = (tile_bo.x + bsw as usize > ts.mi_width ||
                     tile_bo.y + bsh as usize > ts.mi_height ||
-                    bsize.greater_than(BlockSize::BLOCK_64X64)) && is_square;
+                    bsize.greater_than(fi.max_partition_size)) && is_square;
 
   // must_split overrides the minimum partition size when applicable
   let can_split = (bsize > fi.min_partition_size && is_square) || must_split;
@@ -1676,8 +2352,22 @@ fn encode_partition_bottomup<T: Pixel>(
   let w_pre_checkpoint = w_pre_cdef.checkpoint();
   let w_post_checkpoint = w_post_cdef.checkpoint();
 
+  // Kept on the stack, as every block searched has its candidates
+  let mut candidates = [PartitionType::PARTITION_INVALID; EXT_PARTITION_TYPES];
+  let count = candidates.iter_mut()
+    .zip(valid_partitions(bsize))
+    .fold(0, |count, (candidate, partition)| {
+      *candidate = partition;
+      count + 1
+    });
+  let partitions = &mut candidates[..count];
+  if fi.config.speed_settings.partition_heuristic && can_split && !must_split {
+    PartitionHeuristic::new(&cw.bc.blocks.as_const(), tile_bo)
+      .rank(bsize, partitions);
+  }
+
   // Code the whole block
-  if !must_split {
+  if !must_split && partitions.contains(&PartitionType::PARTITION_NONE) {
     let cost = if bsize.gte(BlockSize::BLOCK_8X8) && is_square {
       let w: &mut dyn Writer = if cw.bc.cdef_coded {w_post_cdef} else {w_pre_cdef};
       let tell = w.tell_frac();
@@ -1694,6 +2384,7 @@ fn encode_partition_bottomup<T: Pixel>(
     };
     let spmvs = &mut pmvs[pmv_idx];
 
+    save_ec_state(fi, cw, w_pre_cdef, w_post_cdef);
     let mode_decision = rdo_mode_decision(fi, ts, cw, bsize, tile_bo, spmvs);
 
     if !mode_decision.pred_mode_luma.is_intra() {
@@ -1720,10 +2411,13 @@ fn encode_partition_bottomup<T: Pixel>(
   if can_split {
     debug_assert!(is_square);
 
-    for &partition in RAV1E_PARTITION_TYPES {
+    for &partition in partitions.iter().filter(|p| RAV1E_PARTITION_TYPES.contains(p)) {
       if partition == PartitionType::PARTITION_NONE { continue; }
       if fi.sequence.chroma_sampling == ChromaSampling::Cs422 &&
         partition == PartitionType::PARTITION_VERT { continue; }
+      // Rectangular blocks only code rectangular transforms
+      if fi.config.disabled_tx.rect && (partition == PartitionType::PARTITION_HORZ ||
+        partition == PartitionType::PARTITION_VERT) { continue; }
 
       if must_split {
         let cbw = (ts.mi_width - tile_bo.x).min(bsw); // clipped block width, i.e. having effective pixels
@@ -1870,7 +2564,7 @@ fn encode_partition_topdown<T: Pixel>(
   // Always split if the current partition is too large
   let must_split = (tile_bo.x + bsw as usize > ts.mi_width ||
                     tile_bo.y + bsh as usize > ts.mi_height ||
-                    bsize.greater_than(BlockSize::BLOCK_64X64)) && is_square;
+                    bsize.greater_than(fi.max_partition_size)) && is_square;
 
   let mut rdo_output = block_output.clone().unwrap_or(RDOOutput {
     part_type: PartitionType::PARTITION_INVALID,
@@ -1887,6 +2581,11 @@ fn encode_partition_topdown<T: Pixel>(
     if cbw == bsw/2 && cbh == bsh &&
       fi.sequence.chroma_sampling != ChromaSampling::Cs422 { split_vert = true; }
     if cbh == bsh/2 && cbw == bsw { split_horz = true; }
+    // Rectangular blocks only code rectangular transforms
+    if fi.config.disabled_tx.rect {
+      split_vert = false;
+      split_horz = false;
+    }
   }
 
   if must_split && (!split_vert && !split_horz) {
@@ -1904,6 +2603,10 @@ fn encode_partition_topdown<T: Pixel>(
       //partition_types.append(&mut RAV1E_PARTITION_TYPES.to_vec());
       partition_types.push(PartitionType::PARTITION_NONE);
       partition_types.push(PartitionType::PARTITION_SPLIT);
+      if fi.config.speed_settings.partition_heuristic {
+        PartitionHeuristic::new(&cw.bc.blocks.as_const(), tile_bo)
+          .rank(bsize, &mut partition_types);
+      }
     }
     rdo_output = rdo_partition_decision(fi, ts, cw,
                                         w_pre_cdef, w_post_cdef, bsize, tile_bo, &rdo_output, pmvs, &partition_types, rdo_type);
@@ -1937,6 +2640,7 @@ fn encode_partition_topdown<T: Pixel>(
         let spmvs = &mut pmvs[pmv_idx];
 
         // Make a prediction mode decision for blocks encoded with no rdo_partition_decision call (e.g. edges)
+        save_ec_state(fi, cw, w_pre_cdef, w_post_cdef);
         rdo_mode_decision(fi, ts, cw, bsize, tile_bo, spmvs)
       };
 
@@ -1944,7 +2648,8 @@ fn encode_partition_topdown<T: Pixel>(
       let mut mode_chroma = part_decision.pred_mode_chroma;
 
       let cfl = part_decision.pred_cfl_params;
-      let skip = part_decision.skip;
+      let angle_delta = part_decision.angle_delta;
+      let mut skip = part_decision.skip;
       let ref_frames = part_decision.ref_frames;
       let mvs = part_decision.mvs;
       let mut cdef_coded = cw.bc.cdef_coded;
@@ -1952,8 +2657,8 @@ fn encode_partition_topdown<T: Pixel>(
       // NOTE: Cannot avoid calling rdo_tx_size_type() here again,
       // because, with top-down partition RDO, the neighnoring contexts
       // of current partition can change, i.e. neighboring partitions can split down more.
-      let (tx_size, tx_type) =
-        rdo_tx_size_type(fi, ts, cw, bsize, tile_bo, mode_luma, ref_frames, mvs, skip);
+      let (mut tx_size, mut tx_type, mut tx_partition) =
+        rdo_tx_size_type(fi, ts, cw, bsize, tile_bo, mode_luma, angle_delta, ref_frames, mvs, skip);
 
       let mut mv_stack = Vec::new();
       let is_compound = ref_frames[1] != NONE_FRAME;
@@ -2006,12 +2711,28 @@ fn encode_partition_topdown<T: Pixel>(
         );
       }
 
+      if !skip && !mode_luma.is_intra() &&
+        fi.config.speed_settings.skip_zero_residual &&
+        inter_residual_is_zero(fi, ts, cw, mode_luma, ref_frames, mvs, bsize, tile_bo,
+                               tx_size, tx_type, tx_partition) {
+        skip = true;
+        let tx = rdo_tx_size_type(fi, ts, cw, bsize, tile_bo, mode_luma, angle_delta, ref_frames, mvs, skip);
+        tx_size = tx.0;
+        tx_type = tx.1;
+        tx_partition = tx.2;
+      }
+
       // FIXME: every final block that has gone through the RDO decision process is encoded twice
+      let pre_cdef = !cdef_coded;
       cdef_coded = encode_block_a(&fi.sequence, ts, cw, if cdef_coded  {w_post_cdef} else {w_pre_cdef},
                                   bsize, tile_bo, skip);
+      if pre_cdef && cdef_coded {
+        continue_ec_state(fi, w_pre_cdef, w_post_cdef);
+      }
       encode_block_b(fi, ts, cw, if cdef_coded  {w_post_cdef} else {w_pre_cdef},
                      mode_luma, mode_chroma, ref_frames, mvs, bsize, tile_bo, skip, cfl,
-                     tx_size, tx_type, mode_context, &mv_stack, RDOType::PixelDistRealRate, false);
+                     angle_delta, tx_size, tx_type, tx_partition, mode_context, &mv_stack,
+                     RDOType::PixelDistRealRate, false);
     },
     PARTITION_SPLIT |
     PARTITION_HORZ |
@@ -2067,7 +2788,7 @@ fn encode_partition_topdown<T: Pixel>(
 #[inline(always)]
 fn build_coarse_pmvs<T: Pixel>(fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>) -> Vec<[Option<MotionVector>; REF_FRAMES]> {
   assert!(!fi.sequence.use_128x128_superblock);
-  if ts.mi_width >= 16 && ts.mi_height >= 16 {
+  if fi.external_motion.is_none() && ts.mi_width >= 16 && ts.mi_height >= 16 {
     let mut frame_pmvs = Vec::with_capacity(ts.sb_width * ts.sb_height);
     for sby in 0..ts.sb_height {
       for sbx in 0..ts.sb_width {
@@ -2085,12 +2806,13 @@ fn build_coarse_pmvs<T: Pixel>(fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>
     }
     frame_pmvs
   } else {
-    // the block use for motion estimation would be smaller than the whole image
+    // the block use for motion estimation would be smaller than the whole
+    // image, or the motion is not searched
     vec![[None; REF_FRAMES]; ts.sb_width * ts.sb_height]
   }
 }
 
-fn get_initial_cdfcontext<T: Pixel>(fi: &FrameInvariants<T>) -> CDFContext {
+pub(crate) fn get_initial_cdfcontext<T: Pixel>(fi: &FrameInvariants<T>) -> CDFContext {
   let cdf = if fi.primary_ref_frame == PRIMARY_REF_NONE {
     None
   } else {
@@ -2103,26 +2825,165 @@ fn get_initial_cdfcontext<T: Pixel>(fi: &FrameInvariants<T>) -> CDFContext {
   cdf.unwrap_or_else(|| CDFContext::new(fi.base_q_idx))
 }
 
-fn encode_tile_group<T: Pixel>(fi: &FrameInvariants<T>, fs: &mut FrameState<T>) -> Vec<u8> {
+/// Estimates the cost of coding each tile of a frame, returned together with
+/// the tile area in pixels.
+///
+/// Each 8x8 luma block costs the smaller of its sum of absolute deviations
+/// from its mean and, for inter frames, its sum of absolute differences with
+/// the co-located block of the LAST reference.
+fn tile_complexity<T: Pixel>(
+  fi: &FrameInvariants<T>, fs: &FrameState<T>
+) -> Vec<(u64, usize)> {
+  let ti = &fi.tiling;
+  let input = &fs.input.planes[0];
+  let reference = if fi.intra_only {
+    None
+  } else {
+    fi.rec_buffer.frames[fi.ref_frames[0] as usize].as_ref()
+      .map(|rec| &rec.frame.planes[0])
+  };
+  let tile_width = ti.tile_width_sb << ti.sb_size_log2;
+  let tile_height = ti.tile_height_sb << ti.sb_size_log2;
+
+  let mut complexity = Vec::with_capacity(ti.tile_count());
+  for tile_row in 0..ti.rows {
+    for tile_col in 0..ti.cols {
+      let x0 = tile_col * tile_width;
+      let y0 = tile_row * tile_height;
+      let x1 = (x0 + tile_width).min(ti.frame_width);
+      let y1 = (y0 + tile_height).min(ti.frame_height);
+
+      let mut cost = 0u64;
+      for by in (y0..y1).step_by(8) {
+        for bx in (x0..x1).step_by(8) {
+          let rows = by..(by + 8).min(y1);
+          let cols = bx..(bx + 8).min(x1);
+          let n = (rows.len() * cols.len()) as i32;
+          let sum: i32 = rows.clone()
+            .flat_map(|y| cols.clone().map(move |x| (x, y)))
+            .map(|(x, y)| i32::cast_from(input.p(x, y)))
+            .sum();
+          let mean = (sum + n / 2) / n;
+          let mut block_cost: u64 = rows.clone()
+            .flat_map(|y| cols.clone().map(move |x| (x, y)))
+            .map(|(x, y)| (i32::cast_from(input.p(x, y)) - mean).abs() as u64)
+            .sum();
+          if let Some(reference) = reference {
+            let sad: u64 = rows.clone()
+              .flat_map(|y| cols.clone().map(move |x| (x, y)))
+              .map(|(x, y)| {
+                (i32::cast_from(input.p(x, y))
+                  - i32::cast_from(reference.p(x, y))).abs() as u64
+              })
+              .sum();
+            block_cost = block_cost.min(sad);
+          }
+          cost += block_cost;
+        }
+      }
+      complexity.push((cost, (x1 - x0) * (y1 - y0)));
+    }
+  }
+  complexity
+}
+
+/// Whether most of the residual of the frame is expected to quantize to
+/// zero, the choice of transform types of the full set then gaining less
+/// than the cost of signalling them
+fn is_simple_for_reduced_tx_set<T: Pixel>(
+  fi: &FrameInvariants<T>, fs: &FrameState<T>
+) -> bool {
+  let (cost, area) = tile_complexity(fi, fs).iter()
+    .fold((0, 0), |(cost, area), &(c, a)| (cost + c, area + a as u64));
+  let ac_quant = ac_q(fi.base_q_idx, 0, fi.sequence.bit_depth) as u64;
+  // The transforms scale the residual up by 8
+  cost * 8 < ac_quant * area
+}
+
+/// Gives each superblock of the frame the quantizer index of its tile
+fn sb_qidx_from_tiles(ti: &TilingInfo, tile_qidx: &[u8]) -> Vec<u8> {
+  let sb_cols = ti.frame_width.align_power_of_two_and_shift(ti.sb_size_log2);
+  let sb_rows = ti.frame_height.align_power_of_two_and_shift(ti.sb_size_log2);
+  (0..sb_rows).flat_map(|sby| (0..sb_cols).map(move |sbx| {
+    let tile_row = sby / ti.tile_height_sb;
+    let tile_col = sbx / ti.tile_width_sb;
+    tile_qidx[tile_row * ti.cols + tile_col]
+  })).collect()
+}
+
+/// Splits the quantizer indices of the superblocks of the frame by tile
+fn tile_sb_qidx(ti: &TilingInfo, sb_qidx: &[u8]) -> Vec<Vec<u8>> {
+  let sb_cols = ti.frame_width.align_power_of_two_and_shift(ti.sb_size_log2);
+  let sb_rows = ti.frame_height.align_power_of_two_and_shift(ti.sb_size_log2);
+  let mut tiles = vec![Vec::new(); ti.tile_count()];
+  for sby in 0..sb_rows {
+    for sbx in 0..sb_cols {
+      let tile_row = sby / ti.tile_height_sb;
+      let tile_col = sbx / ti.tile_width_sb;
+      tiles[tile_row * ti.cols + tile_col].push(sb_qidx[sby * sb_cols + sbx]);
+    }
+  }
+  tiles
+}
+
+/// Encodes the tiles of the frame and applies the loop filters, returning
+/// the coded data of each tile in raster order, or `None` if the `progress`
+/// observer cancelled the encoding.
+fn encode_tile_group<T: Pixel>(
+  fi: &FrameInvariants<T>, fs: &mut FrameState<T>,
+  progress: Option<&dyn EncodeProgress>
+) -> Option<Vec<Vec<u8>>> {
   let mut blocks = FrameBlocks::new(fi.w_in_b, fi.h_in_b);
   let ti = &fi.tiling;
 
   let initial_cdf = get_initial_cdfcontext(fi);
   let mut cdfs = vec![initial_cdf; ti.tile_count()];
 
-  let (raw_tiles, rdo_trackers): (Vec<_>, Vec<_>) = ti
+  let (tiles, rdo_trackers): (Vec<_>, Vec<_>) = ti
     .tile_iter_mut(fs, &mut blocks)
     .zip(cdfs.iter_mut())
     .collect::<Vec<_>>()
     .into_par_iter()
     .map(|(mut ctx, cdf)| {
-      let raw = encode_tile(fi, &mut ctx.ts, cdf, &mut ctx.tb);
-      (raw, ctx.ts.rdo)
+      let tile = encode_tile(fi, &mut ctx.ts, cdf, &mut ctx.tb, progress);
+      (tile, ctx.ts.rdo)
     })
     .unzip();
+  let (raw_tiles, coeff_bits_frac): (Vec<_>, Vec<_>) =
+    tiles.into_iter().collect::<Option<Vec<_>>>()?.into_iter().unzip();
+
+  let tile_bits = raw_tiles.iter().map(Vec::len).sum::<usize>() * 8;
+  for p in 0..PLANES {
+    fs.bits.plane_coefficients[p] = (coeff_bits_frac.iter()
+      .map(|tile| tile[p]).sum::<u64>() >> OD_BITRES) as usize;
+  }
+  let coeff_bits =
+    (coeff_bits_frac.iter().flatten().sum::<u64>() >> OD_BITRES) as usize;
+  fs.bits.coefficients = coeff_bits.min(tile_bits);
+  fs.bits.modes = tile_bits - fs.bits.coefficients;
+  fs.log_q_offset = if fi.delta_q_present && !fi.config.dry_run {
+    let tile_bytes: Vec<_> = raw_tiles.iter().map(Vec::len).collect();
+    tile_log_q_offset(
+      fi.base_q_idx, fi.sequence.bit_depth, &tile_sb_qidx(ti, &fs.sb_qidx),
+      &tile_bytes
+    )
+  } else {
+    0
+  };
 
-  /* TODO: Don't apply if lossless */
-  deblock_filter_optimize(fi, fs, &blocks);
+  // As in VP9, and unlike HEVC with its loop_filter_across_tiles_enabled_flag,
+  // the loop filters cannot be disabled at tile edges: decoders always filter
+  // across them, so the loop filters run on the whole frame once every tile
+  // is reconstructed.
+
+  // Large scale tiles are decoded alone, without their neighbors to filter
+  // across the edges
+  if fi.config.large_scale_tile {
+    fs.deblock.levels = [0; 4];
+  } else {
+    /* TODO: Don't apply if lossless */
+    deblock_filter_optimize(fi, fs, &blocks);
+  }
   if fs.deblock.levels[0] != 0 || fs.deblock.levels[1] != 0 {
     deblock_filter_frame(fs, &blocks, fi.sequence.bit_depth);
   }
@@ -2141,7 +3002,7 @@ fn encode_tile_group<T: Pixel>(fi: &FrameInvariants<T>, fs: &mut FrameState<T>)
   }
 
   if fi.config.train_rdo {
-    eprintln!("train rdo");
+    debug!("train rdo");
     for rdo_tracker in &rdo_trackers {
       fs.t.merge_in(&rdo_tracker);
     }
@@ -2162,53 +3023,173 @@ fn encode_tile_group<T: Pixel>(fi: &FrameInvariants<T>, fs: &mut FrameState<T>)
     .max_by_key(|&(_, len)| len)
     .unwrap();
 
-  // use the biggest tile (in bytes) for CDF update
+  // use the biggest tile (in bytes) for CDF update, unless the frame keeps
+  // its initial CDFs
   fs.context_update_tile_id = idx_max;
-  fs.cdfs = cdfs[idx_max];
+  fs.cdfs = if fi.disable_frame_end_update_cdf {
+    initial_cdf
+  } else {
+    cdfs[idx_max]
+  };
   fs.cdfs.reset_counts();
 
   let max_tile_size_bytes = ((max_len as u32).ilog() + 7) / 8;
   debug_assert!(max_tile_size_bytes > 0 && max_tile_size_bytes <= 4);
   fs.max_tile_size_bytes = max_tile_size_bytes;
 
-  build_raw_tile_group(ti, &raw_tiles, max_tile_size_bytes)
+  Some(raw_tiles)
+}
+
+/// Size in bytes of an OBU with a 1-byte header and the given payload size
+fn obu_size(payload_len: usize) -> usize {
+  let mut leb128_len = 1;
+  while payload_len >> (7 * leb128_len) != 0 {
+    leb128_len += 1;
+  }
+  1 + leb128_len + payload_len
+}
+
+/// Packs the coded tiles into tile groups. All the tiles go into a single
+/// tile group, unless its OBU would be larger than `max_obu_size`: the tiles
+/// are then greedily distributed over as many tile groups as needed.
+fn build_tile_groups(
+  ti: &TilingInfo, raw_tiles: &[Vec<u8>], max_tile_size_bytes: u32,
+  max_obu_size: Option<usize>
+) -> Vec<RawTileGroup> {
+  let single =
+    build_raw_tile_group(ti, raw_tiles, 0, max_tile_size_bytes, false);
+  let max_obu_size = match max_obu_size {
+    Some(size) if raw_tiles.len() > 1 && obu_size(single.data.len()) > size => size,
+    _ => return vec![single]
+  };
+
+  let mut tile_groups = Vec::new();
+  let mut start = 0;
+  while start < raw_tiles.len() {
+    // A tile group always holds at least one tile, even if it is too big
+    let mut end = start + 1;
+    let mut tile_group = build_raw_tile_group(
+      ti, &raw_tiles[start..end], start, max_tile_size_bytes, true
+    );
+    while end < raw_tiles.len() {
+      let candidate = build_raw_tile_group(
+        ti, &raw_tiles[start..=end], start, max_tile_size_bytes, true
+      );
+      if obu_size(candidate.data.len()) > max_obu_size {
+        break;
+      }
+      tile_group = candidate;
+      end += 1;
+    }
+    tile_groups.push(tile_group);
+    start = end;
+  }
+  tile_groups
+}
+
+/// Payload of a tile group OBU
+struct RawTileGroup {
+  data: Vec<u8>,
+  /// Offset of the coded data of each tile of the group in `data`
+  tile_offsets: Vec<usize>,
 }
 
-fn build_raw_tile_group(ti: &TilingInfo, raw_tiles: &[Vec<u8>], max_tile_size_bytes: u32) -> Vec<u8> {
+fn build_raw_tile_group(
+  ti: &TilingInfo, raw_tiles: &[Vec<u8>], tg_start: usize,
+  max_tile_size_bytes: u32, tile_start_and_end_present: bool
+) -> RawTileGroup {
   // <https://aomediacodec.github.io/av1-spec/#general-tile-group-obu-syntax>
   let mut raw = Vec::new();
-  let mut bw = BitWriter::endian(&mut raw, BigEndian);
-  if ti.cols * ti.rows > 1 {
-    // tile_start_and_end_present_flag
-    bw.write_bit(false).unwrap();
+  {
+    let mut bw = BitWriter::endian(&mut raw, BigEndian);
+    if ti.cols * ti.rows > 1 {
+      bw.write_bit(tile_start_and_end_present).unwrap();
+      if tile_start_and_end_present {
+        let tile_bits = (ti.tile_cols_log2 + ti.tile_rows_log2) as u32;
+        let tg_end = tg_start + raw_tiles.len() - 1;
+        bw.write(tile_bits, tg_start as u32).unwrap();
+        bw.write(tile_bits, tg_end as u32).unwrap();
+      }
+    }
+    bw.byte_align().unwrap();
   }
-  bw.byte_align().unwrap();
+  let mut tile_offsets = Vec::with_capacity(raw_tiles.len());
   for (i, raw_tile) in raw_tiles.iter().enumerate() {
     let last = raw_tiles.len() - 1;
     if i != last {
+      // tile_size_minus_1, little-endian
       let tile_size_minus_1 = raw_tile.len() - 1;
-      bw.write_le(max_tile_size_bytes, tile_size_minus_1 as u64).unwrap();
+      for b in 0..max_tile_size_bytes {
+        raw.push((tile_size_minus_1 >> (8 * b)) as u8);
+      }
     }
-    bw.write_bytes(&raw_tile).unwrap();
+    tile_offsets.push(raw.len());
+    raw.extend_from_slice(raw_tile);
   }
-  raw
+  RawTileGroup { data: raw, tile_offsets }
 }
 
+/// Maximum number of tiles in a tile list OBU
+const MAX_TILE_LIST_TILES: usize = 512;
+
+/// Packs the coded tiles of a camera frame into tile list OBU payloads, for
+/// large scale tile decoding. Each tile list holds whole rows of tiles,
+/// which make up its output frame, and refers to the tiles of the camera
+/// frame at the same positions, predicted from anchor frame 0. Returns
+/// `None` if a tile is larger than the 64 KiB its entry can signal.
+fn build_tile_lists(
+  ti: &TilingInfo, raw_tiles: &[Vec<u8>]
+) -> Option<Vec<RawTileGroup>> {
+  // <https://aomediacodec.github.io/av1-spec/#tile-list-obu-syntax>
+  if raw_tiles.iter().any(|raw_tile| raw_tile.len() > 1 << 16) {
+    return None;
+  }
+  let rows_per_list = (MAX_TILE_LIST_TILES / ti.cols).min(ti.rows);
+  Some((0..ti.rows).step_by(rows_per_list).map(|first_row| {
+    let rows = rows_per_list.min(ti.rows - first_row);
+    let mut raw = Vec::new();
+    raw.push((ti.cols - 1) as u8); // output_frame_width_in_tiles_minus_1
+    raw.push((rows - 1) as u8); // output_frame_height_in_tiles_minus_1
+    raw.extend_from_slice(&((rows * ti.cols - 1) as u16).to_be_bytes());
+
+    let mut tile_offsets = Vec::with_capacity(rows * ti.cols);
+    for row in first_row..first_row + rows {
+      for col in 0..ti.cols {
+        let raw_tile = &raw_tiles[row * ti.cols + col];
+        raw.push(0); // anchor_frame_idx
+        raw.push(row as u8); // anchor_tile_row
+        raw.push(col as u8); // anchor_tile_col
+        raw.extend_from_slice(&((raw_tile.len() - 1) as u16).to_be_bytes());
+        tile_offsets.push(raw.len());
+        raw.extend_from_slice(raw_tile);
+      }
+    }
+    RawTileGroup { data: raw, tile_offsets }
+  }).collect())
+}
+
+/// Encodes the superblocks of a tile, returning its coded data and the bits
+/// of the coefficients of each plane, or `None` as soon as the `progress`
+/// observer cancels the encoding.
 fn encode_tile<'a, T: Pixel>(
   fi: &FrameInvariants<T>,
   ts: &mut TileStateMut<'_, T>,
   fc: &'a mut CDFContext,
   blocks: &'a mut TileBlocksMut<'a>,
-) -> Vec<u8> {
+  progress: Option<&dyn EncodeProgress>,
+) -> Option<(Vec<u8>, [u64; PLANES])> {
   let mut w = WriterEncoder::new();
+  // A dry run only counts the symbols written to the tile
+  let mut w_dry = WriterCounter::new();
 
-  let estimate_motion_ss2 = if fi.config.speed_settings.diamond_me {
-    crate::me::DiamondSearch::estimate_motion_ss2
-  } else {
-    crate::me::FullSearch::estimate_motion_ss2
+  let estimate_motion_ss2 = match fi.config.speed_settings.me_method {
+    MotionSearch::Diamond => crate::me::DiamondSearch::estimate_motion_ss2,
+    MotionSearch::Hex => crate::me::HexSearch::estimate_motion_ss2,
+    MotionSearch::Full => crate::me::FullSearch::estimate_motion_ss2
   };
 
-  let bc = BlockContext::new(blocks);
+  let mut bc = BlockContext::new(blocks);
+  bc.current_qidx = fi.base_q_idx;
   // For now, restoration unit size is locked to superblock size.
   let mut cw = ContextWriter::new(fc, bc);
 
@@ -2219,8 +3200,18 @@ fn encode_tile<'a, T: Pixel>(
     cw.bc.reset_left_contexts();
 
     for sbx in 0..ts.sb_width {
+      if progress.map_or(false, |progress| progress.is_cancelled()) {
+        return None;
+      }
       let mut w_pre_cdef = WriterRecorder::new();
       let mut w_post_cdef = WriterRecorder::new();
+      if fi.config.speed_settings.exact_rate {
+        // The superblock is coded after the symbols coded so far, and after
+        // its own loop restoration parameters which are still unknown. The
+        // symbols after its CDEF index continue from those before it.
+        let checkpoint = Writer::checkpoint(&mut w);
+        w_pre_cdef.resume(&checkpoint);
+      }
       let tile_sbo = SuperBlockOffset { x: sbx, y: sby };
       let tile_bo = tile_sbo.block_offset(0, 0);
       cw.bc.cdef_coded = false;
@@ -2228,7 +3219,7 @@ fn encode_tile<'a, T: Pixel>(
 
       // Do subsampled ME
       let mut pmvs: [[Option<MotionVector>; REF_FRAMES]; 5] = [[None; REF_FRAMES]; 5];
-      if ts.mi_width >= 8 && ts.mi_height >= 8 {
+      if fi.external_motion.is_none() && ts.mi_width >= 8 && ts.mi_height >= 8 {
         for i in 0..INTER_REFS_PER_FRAME {
           let r = fi.ref_frames[i] as usize;
           if pmvs[0][r].is_none() {
@@ -2300,12 +3291,20 @@ fn encode_tile<'a, T: Pixel>(
 
       // CDEF has to be decided before loop restoration, but coded after.
       // loop restoration must be decided last but coded before anything else.
-      if cw.bc.cdef_coded || fi.sequence.enable_restoration {
-        rdo_loop_decision(tile_sbo, fi, ts, &mut cw, &mut w);
+      {
+        let w: &mut dyn Writer = if fi.config.dry_run { &mut w_dry } else { &mut w };
+        if cw.bc.cdef_coded || fi.sequence.enable_restoration {
+          rdo_loop_decision(tile_sbo, fi, ts, &mut cw, w);
+        }
+
+        if fi.sequence.enable_restoration {
+          cw.write_lrf(w, fi, &mut ts.restoration, tile_sbo);
+        }
       }
 
-      if fi.sequence.enable_restoration {
-        cw.write_lrf(&mut w, fi, &mut ts.restoration, tile_sbo);
+      // The recorded block symbols are never coded in a dry run
+      if fi.config.dry_run {
+        continue;
       }
 
       // Once loop restoration is coded, we can replay the initial block bits
@@ -2321,7 +3320,8 @@ fn encode_tile<'a, T: Pixel>(
     }
   }
 
-  w.done()
+  let data = if fi.config.dry_run { Vec::new() } else { w.done() };
+  Some((data, cw.coeff_bits_frac))
 }
 
 #[allow(unused)]
@@ -2336,6 +3336,109 @@ fn write_tile_group_header(tile_start_and_end_present_flag: bool) ->
     buf.clone()
   }
 
+/// Typical size in bits of the temporal unit, OBU and frame headers, added
+/// to the estimate of the tile data by `estimate_frame_bits`
+const HEADER_BITS_ESTIMATE: u32 = 160;
+
+/// Estimates the size in bits of `frame` coded at the quantizers of `fi`,
+/// cheaply enough to steer the rate control before the frame is coded.
+///
+/// Each 8x8 block of each plane is predicted from its mean or, in inter
+/// frames, from the co-located block of the LAST reference when it is
+/// closer. The DCT of the residual is quantized and costed with
+/// `ContextWriter::coeff_cost` in default contexts. Mode decision, motion
+/// and the adaptation of the probabilities are ignored, so this is a
+/// predictor of the coded size rather than a bound.
+pub fn estimate_frame_bits<T: Pixel>(
+  fi: &FrameInvariants<T>, frame: &Frame<T>
+) -> u32 {
+  let bit_depth = fi.sequence.bit_depth;
+  let reference = if fi.frame_type.has_inter() {
+    fi.rec_buffer.frames[fi.ref_frames[LAST_FRAME.to_index()] as usize]
+      .as_ref()
+  } else {
+    None
+  };
+
+  let mut blocks = FrameBlocks::new(fi.w_in_b, fi.h_in_b);
+  let mut tb = blocks.as_tile_blocks_mut();
+  let mut fc = CDFContext::new(fi.base_q_idx);
+  let mut cw = ContextWriter::new(&mut fc, BlockContext::new(&mut tb));
+  let mut qc = QuantizationContext::default();
+
+  let mut bits_frac: u64 = 0;
+  for (p, plane) in frame.planes.iter().enumerate() {
+    let PlaneConfig { width, height, xdec, ydec, .. } = plane.cfg;
+    if width == 0 || height == 0 {
+      continue;
+    }
+    qc.update(
+      fi.base_q_idx, TxSize::TX_8X8, fi.quant_rounding(reference.is_none()),
+      bit_depth,
+      fi.dc_delta_q[p], fi.ac_delta_q[p]
+    );
+    // Samples past the edges repeat the last column and row
+    let sample = |plane: &Plane<T>, x: usize, y: usize| {
+      i16::cast_from(plane.p(x.min(width - 1), y.min(height - 1)))
+    };
+
+    for y in (0..height).step_by(8) {
+      for x in (0..width).step_by(8) {
+        let mut residual = [0i16; 64];
+        for i in 0..8 {
+          for j in 0..8 {
+            residual[i * 8 + j] = sample(plane, x + j, y + i);
+          }
+        }
+        let sum: i32 = residual.iter().map(|&v| i32::from(v)).sum();
+        let mean = ((sum + 32) >> 6) as i16;
+        let intra_sad: i32 =
+          residual.iter().map(|&v| i32::from(v - mean).abs()).sum();
+        let inter = reference.map(|rf| {
+          let mut diff = [0i16; 64];
+          for i in 0..8 {
+            for j in 0..8 {
+              diff[i * 8 + j] = residual[i * 8 + j]
+                - sample(&rf.frame.planes[p], x + j, y + i);
+            }
+          }
+          diff
+        });
+        match inter {
+          Some(diff)
+            if diff.iter().map(|&v| i32::from(v).abs()).sum::<i32>()
+              < intra_sad =>
+          {
+            residual = diff;
+          }
+          _ => {
+            for v in residual.iter_mut() {
+              *v -= mean;
+            }
+          }
+        }
+
+        let mut coeffs = [0i32; 64];
+        let mut qcoeffs = [0i32; 64];
+        forward_transform(
+          &residual, &mut coeffs, 8, TxSize::TX_8X8, TxType::DCT_DCT, bit_depth,
+          fi.cpu
+        );
+        qc.quantize(&coeffs, &mut qcoeffs, 64);
+
+        let bo = BlockOffset { x: (x << xdec) >> 2, y: (y << ydec) >> 2 };
+        bits_frac += u64::from(cw.coeff_cost(
+          p, bo, &qcoeffs, PredictionMode::DC_PRED, TxSize::TX_8X8,
+          TxType::DCT_DCT, BlockSize::BLOCK_8X8, xdec, ydec,
+          fi.use_reduced_tx_set
+        ));
+      }
+    }
+  }
+
+  (bits_frac >> OD_BITRES) as u32 + HEADER_BITS_ESTIMATE
+}
+
 // Write a packet containing only the placeholder that tells the decoder
 // to present the already decoded frame present at `frame_to_show_map_idx`
 //
@@ -2346,19 +3449,28 @@ pub fn encode_show_existing_frame<T: Pixel>(
   debug_assert!(fi.show_existing_frame);
   let mut packet = Vec::new();
 
-  write_obus(&mut packet, fi, fs).unwrap();
-  let map_idx = fi.frame_to_show_map_idx as usize;
-  if let Some(ref rec) = fi.rec_buffer.frames[map_idx] {
-    for p in 0..3 {
-      fs.rec.planes[p].data.copy_from_slice(&rec.frame.planes[p].data);
-    }
+  if !fi.config.dry_run {
+    write_obus(&mut packet, fi, fs).unwrap();
   }
   packet
 }
 
+/// Why `encode_frame` did not code a frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EncodeFrameError {
+  /// The `progress` observer cancelled the encoding
+  Cancelled,
+  /// A tile of a camera frame of `large_scale_tile` coded to more than the
+  /// 64 KiB a tile list can hold
+  TileTooLarge,
+}
+
+/// Encodes the frame, returning its OBUs. On error, `fs` is left partially
+/// coded and has to be discarded.
 pub fn encode_frame<T: Pixel>(
-  fi: &mut FrameInvariants<T>, fs: &mut FrameState<T>
-) -> Vec<u8> {
+  fi: &mut FrameInvariants<T>, fs: &mut FrameState<T>,
+  progress: Option<&dyn EncodeProgress>
+) -> Result<Vec<u8>, EncodeFrameError> {
   debug_assert!(!fi.show_existing_frame);
   let mut packet = Vec::new();
   if !fi.intra_only {
@@ -2383,37 +3495,138 @@ pub fn encode_frame<T: Pixel>(
 
   segmentation_optimize(fi, fs);
 
-  let tile_group = encode_tile_group(fi, fs);
+  if fi.config.tile_rate_control && fi.tiling.tile_count() > 1 {
+    let tile_qidx = select_tile_qi(
+      fi.base_q_idx, fi.sequence.bit_depth, &tile_complexity(fi, fs)
+    );
+    fs.sb_qidx = sb_qidx_from_tiles(&fi.tiling, &tile_qidx);
+  }
+  fi.use_reduced_tx_set = match fi.config.speed_settings.reduced_tx_set {
+    ReducedTxSet::Off => false,
+    ReducedTxSet::On => true,
+    ReducedTxSet::Auto => is_simple_for_reduced_tx_set(fi, fs)
+  };
+  // The decoder clamps the quantizer index of superblocks to at least 1
+  debug_assert!(fs.sb_qidx.iter().all(|&qidx| qidx > 0));
+  fi.delta_q_present = fs.sb_qidx.iter().any(|&qidx| qidx != fi.base_q_idx);
+
+  let raw_tiles = encode_tile_group(fi, fs, progress)
+    .ok_or(EncodeFrameError::Cancelled)?;
+  // Taken as soon as the loop filters are done, before the frame is padded
+  // and stored, then compared with the frame the packet shows
+  fs.rec_crc = if fi.config.rec_crc { Some(fs.rec.crc()) } else { None };
+
+  if fi.config.dry_run {
+    fs.tiles.clear();
+    return Ok(packet);
+  }
+
+  // Camera frames of large scale tile streams carry their tiles in tile
+  // lists, to be decoded on their own
+  let (obu_type, tile_groups) = if fi.config.large_scale_tile && !fi.intra_only {
+    let tile_lists = build_tile_lists(&fi.tiling, &raw_tiles)
+      .ok_or(EncodeFrameError::TileTooLarge)?;
+    (ObuType::OBU_TILE_LIST, tile_lists)
+  } else {
+    let tile_groups = build_tile_groups(
+      &fi.tiling, &raw_tiles, fs.max_tile_size_bytes,
+      fi.config.max_tile_group_size
+    );
+    (ObuType::OBU_TILE_GROUP, tile_groups)
+  };
 
   write_obus(&mut packet, fi, fs).unwrap();
+  write_tile_groups(&mut packet, fi, fs, obu_type, tile_groups, &raw_tiles);
+  Ok(packet)
+}
+
+/// Writes the tile group or tile list OBUs of a frame after its headers in
+/// `packet`, recording the location of its tiles
+fn write_tile_groups<T: Pixel>(
+  packet: &mut Vec<u8>, fi: &FrameInvariants<T>, fs: &mut FrameState<T>,
+  obu_type: ObuType, tile_groups: Vec<RawTileGroup>, raw_tiles: &[Vec<u8>]
+) {
   let mut buf1 = Vec::new();
-  {
-    let mut bw1 = BitWriter::endian(&mut buf1, BigEndian);
-    bw1.write_obu_header(ObuType::OBU_TILE_GROUP, 0).unwrap();
-  }
-  packet.write_all(&buf1).unwrap();
-  buf1.clear();
+  let mut tiles = raw_tiles.iter().enumerate();
+  fs.tiles.clear();
+  for tile_group in tile_groups {
+    {
+      let mut bw1 = BitWriter::endian(&mut buf1, BigEndian);
+      bw1.write_obu_header(obu_type, fi.obu_extension()).unwrap();
+    }
+    packet.write_all(&buf1).unwrap();
+    buf1.clear();
 
-  {
-    let mut bw1 = BitWriter::endian(&mut buf1, BigEndian);
-    bw1.write_uleb128(tile_group.len() as u64).unwrap();
+    {
+      let mut bw1 = BitWriter::endian(&mut buf1, BigEndian);
+      bw1.write_uleb128(tile_group.data.len() as u64).unwrap();
+    }
+    packet.write_all(&buf1).unwrap();
+    buf1.clear();
+
+    for (&offset, (idx, raw_tile)) in tile_group.tile_offsets.iter().zip(&mut tiles) {
+      fs.tiles.push(TileLocation {
+        row: idx / fi.tiling.cols,
+        col: idx % fi.tiling.cols,
+        offset: packet.len() + offset,
+        size: raw_tile.len(),
+      });
+    }
+    packet.write_all(&tile_group.data).unwrap();
   }
-  packet.write_all(&buf1).unwrap();
-  buf1.clear();
+  fs.bits.header = packet.len() * 8 - fs.bits.coefficients - fs.bits.modes;
+}
 
-  packet.write_all(&tile_group).unwrap();
+/// Spreads the tiles of a coded frame over tile groups of at most
+/// `max_obu_size` bytes, so that its packet can be split between them.
+/// Returns the frame as it is if it has a single tile or carries its tiles
+/// in tile lists.
+pub(crate) fn split_tile_groups<T: Pixel>(
+  fi: &FrameInvariants<T>, fs: &mut FrameState<T>, data: Vec<u8>,
+  max_obu_size: usize
+) -> Vec<u8> {
+  if fi.tiling.tile_count() < 2
+    || (fi.config.large_scale_tile && !fi.intra_only)
+  {
+    return data;
+  }
+  let raw_tiles: Vec<Vec<u8>> = fs.tiles.iter()
+    .map(|tile| data[tile.offset..tile.offset + tile.size].to_vec())
+    .collect();
+  let headers_len: usize = ObuIter::new(&data)
+    .take_while(|obu| obu.obu_type != ObuType::OBU_TILE_GROUP)
+    .map(|obu| obu.data.len())
+    .sum();
+  let max_obu_size = fi.config.max_tile_group_size
+    .map_or(max_obu_size, |size| size.min(max_obu_size));
+  let tile_groups = build_tile_groups(
+    &fi.tiling, &raw_tiles, fs.max_tile_size_bytes, Some(max_obu_size)
+  );
+  let mut packet = data;
+  packet.truncate(headers_len);
+  write_tile_groups(
+    &mut packet, fi, fs, ObuType::OBU_TILE_GROUP, tile_groups, &raw_tiles
+  );
   packet
 }
 
-pub fn update_rec_buffer<T: Pixel>(fi: &mut FrameInvariants<T>, fs: FrameState<T>) {
+/// Stores the reconstruction in the reference slots refreshed by the frame,
+/// returning it so that it remains available even if no slot is refreshed.
+pub fn update_rec_buffer<T: Pixel>(
+  fi: &mut FrameInvariants<T>, fs: FrameState<T>
+) -> Arc<ReferenceFrame<T>> {
   let rfs = Arc::new(
     ReferenceFrame {
       order_hint: fi.order_hint,
-      frame: fs.rec,
+      base_q_idx: fi.base_q_idx,
+      frame: Arc::new(fs.rec),
       input_hres: fs.input_hres,
       input_qres: fs.input_qres,
       cdfs: fs.cdfs,
       frame_mvs: fs.frame_mvs,
+      intra_refresh: fi.intra_refresh,
+      film_grain_params: fi.film_grain_params,
+      crc: fs.rec_crc,
     }
   );
   for i in 0..(REF_FRAMES as usize) {
@@ -2422,6 +3635,7 @@ pub fn update_rec_buffer<T: Pixel>(fi: &mut FrameInvariants<T>, fs: FrameState<T
       fi.rec_buffer.deblock[i] = fs.deblock;
     }
   }
+  rfs
 }
 
 #[cfg(test)]
@@ -2433,4 +3647,407 @@ mod test {
     assert_eq!(RAV1E_PARTITION_TYPES[RAV1E_PARTITION_TYPES.len() - 1],
                PartitionType::PARTITION_SPLIT);
   }
+
+  #[test]
+  fn frame_crc() {
+    let crc = |bytes: &[u8]| !bytes.iter().fold(!0, |crc, &b| crc32_update(crc, b));
+    assert_eq!(crc(b"123456789"), 0xCBF4_3926);
+
+    let mut frame = Frame::<u16>::new(8, 8, ChromaSampling::Cs420);
+    let samples = 8 * 8 + 2 * 4 * 4;
+    assert_eq!(frame.crc(), crc(&vec![0; 2 * samples]));
+    // Samples outside of the planes are left out
+    frame.planes[0].data[0] = 1;
+    assert_eq!(frame.crc(), crc(&vec![0; 2 * samples]));
+    frame.planes[0].data_origin_mut()[0] = 0x102;
+    let mut bytes = vec![0; 2 * samples];
+    bytes[0] = 2;
+    bytes[1] = 1;
+    assert_eq!(frame.crc(), crc(&bytes));
+  }
+
+  #[test]
+  fn frame_mc_border() {
+    for &(w, h) in &[(64, 64), (99, 31)] {
+      for &cs in &[ChromaSampling::Cs420, ChromaSampling::Cs422, ChromaSampling::Cs444] {
+        let frame = Frame::<u8>::new(w, h, cs);
+        for plane in frame.planes.iter() {
+          assert!(plane.border(w, h) >= MC_BORDER);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn frame_md5() {
+    let md5 = |bytes: &[u8]| {
+      let mut md5 = Md5::new();
+      md5.update(bytes);
+      md5.finish()
+    };
+
+    // Displayed at an odd size, the chroma planes rounded up and the
+    // samples past the displayed ones left out
+    let samples = 9 * 7 + 2 * 5 * 4;
+    let mut frame = Frame::<u8>::new(9, 7, ChromaSampling::Cs420);
+    frame.planes[0].data_origin_mut()[9] = 1;
+    assert_eq!(frame.md5(9, 7), md5(&vec![0; samples]));
+    frame.planes[2].data_origin_mut()[frame.planes[2].cfg.stride] = 3;
+    let mut bytes = vec![0; samples];
+    bytes[9 * 7 + 5 * 4 + 5] = 3;
+    assert_eq!(frame.md5(9, 7), md5(&bytes));
+
+    let mut frame = Frame::<u16>::new(9, 7, ChromaSampling::Cs420);
+    frame.planes[0].data_origin_mut()[1] = 0x3ff;
+    let mut bytes = vec![0; 2 * samples];
+    bytes[2] = 0xff;
+    bytes[3] = 3;
+    assert_eq!(frame.md5(9, 7), md5(&bytes));
+  }
+
+  #[test]
+  fn skip_needs_all_planes_zero() {
+    let zero = [0i32; 16];
+    let mut dc = [0i32; 16];
+    dc[0] = -1;
+    assert!(should_skip(&[&zero, &zero, &zero]));
+    assert!(should_skip(&[&zero, &[], &[]]));
+    assert!(!should_skip(&[&zero, &zero, &dc]));
+    assert!(!should_skip(&[&dc, &zero, &zero]));
+  }
+
+  /// Whether a 16x16 block without motion over a flat reference has no
+  /// quantized residual, its input planes being brighter by `offsets`
+  fn zero_residual(offsets: [u8; 3]) -> bool {
+    let mut config = EncoderConfig::default();
+    config.width = 64;
+    config.height = 64;
+    let seq = Sequence::new(&config, None);
+    let mut fi = FrameInvariants::<u8>::new(config, seq);
+
+    let flat = |offsets: [u8; 3]| {
+      let mut frame = Frame::new(64, 64, ChromaSampling::Cs420);
+      for (plane, &offset) in frame.planes.iter_mut().zip(offsets.iter()) {
+        for v in plane.data.iter_mut() {
+          *v = 100 + offset;
+        }
+      }
+      frame
+    };
+    fi.rec_buffer.frames[0] = Some(Arc::new(ReferenceFrame {
+      order_hint: 0,
+      base_q_idx: fi.base_q_idx,
+      frame: Arc::new(flat([0; 3])),
+      input_hres: Plane::new(32, 32, 1, 1, 0, 0),
+      input_qres: Plane::new(16, 16, 2, 2, 0, 0),
+      cdfs: CDFContext::new(fi.base_q_idx),
+      frame_mvs: Vec::new(),
+      intra_refresh: None,
+      film_grain_params: None,
+      crc: None
+    }));
+    fi.ref_frames = [0; INTER_REFS_PER_FRAME];
+
+    let mut fs = FrameState::new_with_frame(&fi, Arc::new(flat(offsets)));
+    let mut ts = fs.as_tile_state_mut();
+    let mut fb = FrameBlocks::new(fi.w_in_b, fi.h_in_b);
+    let mut tb = fb.as_tile_blocks_mut();
+    let mut fc = CDFContext::new(fi.base_q_idx);
+    let mut cw = ContextWriter::new(&mut fc, BlockContext::new(&mut tb));
+
+    inter_residual_is_zero(
+      &fi, &mut ts, &mut cw, PredictionMode::NEWMV, [LAST_FRAME, NONE_FRAME],
+      [MotionVector::default(); 2], BlockSize::BLOCK_16X16,
+      BlockOffset { x: 4, y: 4 }, TxSize::TX_16X16, TxType::DCT_DCT,
+      TxPartition::NONE
+    )
+  }
+
+  #[test]
+  fn zero_residual_in_every_plane() {
+    assert!(zero_residual([0, 0, 0]));
+    // A residual in any plane, chroma ones included, is coded
+    assert!(!zero_residual([40, 0, 0]));
+    assert!(!zero_residual([0, 40, 0]));
+    assert!(!zero_residual([0, 0, 40]));
+  }
+
+  #[test]
+  fn frame_bits_estimate() {
+    let mut config = EncoderConfig::default();
+    config.width = 64;
+    config.height = 64;
+    let seq = Sequence::new(&config, None);
+    let mut fi = FrameInvariants::<u8>::new(config, seq);
+
+    let flat = Frame::new(64, 64, ChromaSampling::Cs420);
+    let mut textured = flat.clone();
+    for plane in textured.planes.iter_mut() {
+      for (i, v) in plane.data.iter_mut().enumerate() {
+        *v = ((i * 37) ^ (i >> 3)) as u8;
+      }
+    }
+
+    fi.base_q_idx = 120;
+    let flat_bits = estimate_frame_bits(&fi, &flat);
+    let coarse_bits = estimate_frame_bits(&fi, &textured);
+    fi.base_q_idx = 30;
+    let fine_bits = estimate_frame_bits(&fi, &textured);
+    assert!(flat_bits >= HEADER_BITS_ESTIMATE);
+    assert!(flat_bits < coarse_bits);
+    assert!(coarse_bits < fine_bits);
+  }
+
+  #[test]
+  fn chroma_qp_offset() {
+    let mut config = EncoderConfig::default();
+    config.width = 64;
+    config.height = 64;
+    config.chroma_qp_offset = [20, -70];
+    let seq = Sequence::new(&config, None);
+    let mut fi = FrameInvariants::<u8>::new(config, seq);
+
+    let qps = QuantizerParameters {
+      log_base_q: 0,
+      log_target_q: 0,
+      dc_qi: [100, 90, 95],
+      ac_qi: [100, 90, 95],
+      lambda: 1.,
+      clamped: std::cmp::Ordering::Equal
+    };
+    fi.set_quantizers(&qps);
+    assert_eq!(fi.base_q_idx, 100);
+    assert_eq!((fi.dc_delta_q[0], fi.ac_delta_q[0]), (0, 0));
+    assert_eq!((fi.dc_delta_q[1], fi.ac_delta_q[1]), (10, 10));
+    assert_eq!((fi.dc_delta_q[2], fi.ac_delta_q[2]), (-64, -64));
+  }
+
+  /// Chroma of a 4x4 block whose 4:2:0 chroma also covers its left, above
+  /// and above-left neighbours, each moving 1 chroma pixel left or right
+  fn sub8x8_chroma(left_is_intra: bool) -> Vec<Vec<u8>> {
+    let mut config = EncoderConfig::default();
+    config.width = 64;
+    config.height = 64;
+    let seq = Sequence::new(&config, None);
+    let mut fi = FrameInvariants::<u8>::new(config, seq);
+
+    // Reference chroma samples only depend on their column
+    let mut frame = Frame::new(64, 64, ChromaSampling::Cs420);
+    for plane in frame.planes.iter_mut() {
+      let stride = plane.cfg.stride;
+      let xorigin = plane.cfg.xorigin;
+      for row in plane.data.chunks_mut(stride) {
+        for (x, v) in row.iter_mut().enumerate() {
+          *v = (4 * (x as isize - xorigin as isize) + 64) as u8;
+        }
+      }
+    }
+    fi.rec_buffer.frames[0] = Some(Arc::new(ReferenceFrame {
+      order_hint: 0,
+      base_q_idx: fi.base_q_idx,
+      frame: Arc::new(frame),
+      input_hres: Plane::new(32, 32, 1, 1, 0, 0),
+      input_qres: Plane::new(16, 16, 2, 2, 0, 0),
+      cdfs: CDFContext::new(fi.base_q_idx),
+      frame_mvs: Vec::new(),
+      intra_refresh: None,
+      film_grain_params: None,
+      crc: None
+    }));
+    fi.ref_frames = [0; INTER_REFS_PER_FRAME];
+
+    let mut fs = FrameState::new(&fi);
+    let mut ts = fs.as_tile_state_mut();
+    let mut fb = FrameBlocks::new(fi.w_in_b, fi.h_in_b);
+    let mut tb = fb.as_tile_blocks_mut();
+    let mut fc = CDFContext::new(fi.base_q_idx);
+    let mut cw = ContextWriter::new(&mut fc, BlockContext::new(&mut tb));
+
+    let mv = |col: i16| [MotionVector { row: 0, col }, MotionVector::default()];
+    let ref_frames = [LAST_FRAME, NONE_FRAME];
+    // Opposite motion in the neighbours, 2 luma pixels each way
+    for &(x, y, col) in &[(4, 4, 16), (5, 4, -16), (4, 5, -16)] {
+      let block = &mut cw.bc.blocks[BlockOffset { x, y }];
+      block.mode = PredictionMode::NEWMV;
+      block.ref_frames = ref_frames;
+      block.mv = mv(col);
+    }
+    if left_is_intra {
+      cw.bc.blocks[BlockOffset { x: 4, y: 5 }].mode = PredictionMode::DC_PRED;
+    }
+
+    motion_compensate(
+      &fi, &mut ts, &mut cw, PredictionMode::NEWMV, ref_frames, mv(16),
+      BlockSize::BLOCK_4X4, BlockOffset { x: 5, y: 5 }, false
+    );
+
+    let rec = &ts.rec.planes[1];
+    (8..12).map(|y| rec[y][8..12].to_vec()).collect()
+  }
+
+  #[test]
+  fn sub8x8_chroma_uses_each_luma_mv() {
+    let col = |x: isize| (4 * x + 64) as u8;
+    let chroma = sub8x8_chroma(false);
+    for (y, row) in chroma.iter().enumerate() {
+      let expected: Vec<u8> = (8..12).map(|x| {
+        // Each 2x2 quadrant follows the motion of its luma block
+        let moves_right = (x < 10) == (y < 2);
+        col(if moves_right { x + 1 } else { x - 1 })
+      }).collect();
+      assert_eq!(row, &expected);
+    }
+
+    // Any intra block among them leaves the chroma to the motion of the
+    // last block
+    let chroma = sub8x8_chroma(true);
+    for row in chroma.iter() {
+      let expected: Vec<u8> = (8..12).map(|x| col(x + 1)).collect();
+      assert_eq!(row, &expected);
+    }
+  }
+
+  #[test]
+  fn intra_refresh_sweeps_frame_once_per_cycle() {
+    // 10 superblock columns, the last one partial
+    let width = 9 * 64 + 20;
+    let period = 4;
+    let mut clean_width = 0;
+    for idx_in_segment in 1..=2 * period {
+      let ir = IntraRefresh::new(period, idx_in_segment, 10, 6, width);
+      assert_eq!(ir.cycle, (idx_in_segment - 1) / period);
+      if (idx_in_segment - 1) % period == 0 {
+        clean_width = 0;
+      }
+      // Stripes are contiguous and superblock aligned
+      assert_eq!(ir.stripe_start, clean_width);
+      assert!(ir.stripe_end >= ir.stripe_start);
+      assert!(ir.stripe_end == width || ir.stripe_end % 64 == 0);
+      clean_width = ir.stripe_end;
+      if idx_in_segment % period == 0 {
+        assert_eq!(clean_width, width);
+      }
+    }
+  }
+
+  #[test]
+  fn intra_refresh_clean_width() {
+    let ir = IntraRefresh::new(4, 7, 10, 6, 640);
+    let same_cycle = IntraRefresh::new(4, 6, 10, 6, 640);
+    let previous_cycle = IntraRefresh::new(4, 4, 10, 6, 640);
+    assert_eq!(ir.clean_width(Some(&same_cycle)), same_cycle.stripe_end);
+    assert_eq!(ir.clean_width(Some(&previous_cycle)), 0);
+    assert_eq!(ir.clean_width(None), 0);
+    // The keyframe is clean for the whole first cycle
+    let first = IntraRefresh::new(4, 1, 10, 6, 640);
+    assert_eq!(first.clean_width(Some(&IntraRefresh::key_frame(640))), 640);
+  }
+
+  #[test]
+  fn complex_tiles_get_finer_quantizers() {
+    let mut config = EncoderConfig::default();
+    config.width = 128;
+    config.height = 64;
+    config.tile_cols_log2 = 1;
+    config.tile_rate_control = true;
+    let seq = Sequence::new(&config, None);
+    let fi = FrameInvariants::<u8>::new(config, seq);
+
+    // A flat left tile and a textured right tile
+    let mut frame = Frame::new(128, 64, ChromaSampling::Cs420);
+    let plane = &mut frame.planes[0];
+    let stride = plane.cfg.stride;
+    for (y, row) in plane.data_origin_mut().chunks_mut(stride).take(64).enumerate() {
+      for (x, v) in row[..128].iter_mut().enumerate() {
+        *v = if x < 64 { 128 } else { ((x * 37 + y * 101) % 251) as u8 };
+      }
+    }
+    let fs = FrameState::new_with_frame(&fi, Arc::new(frame));
+
+    let complexity = tile_complexity(&fi, &fs);
+    assert_eq!(complexity, vec![(0, 64 * 64), (complexity[1].0, 64 * 64)]);
+    assert!(complexity[1].0 > 0);
+    let qi = select_tile_qi(100, 8, &complexity);
+    assert!(qi[1] < 100 && 100 < qi[0]);
+  }
+
+  #[test]
+  fn moving_tiles_get_finer_quantizers() {
+    let mut config = EncoderConfig::default();
+    config.width = 128;
+    config.height = 64;
+    config.tile_cols_log2 = 1;
+    config.tile_rate_control = true;
+    let seq = Sequence::new(&config, None);
+    let mut fi = FrameInvariants::<u8>::new(config, seq);
+
+    // The same texture in both tiles, static on the left and moving by
+    // 3 pixels on the right
+    let texture = |x: usize, y: usize| ((x * 37 + y * 101) % 251) as u8;
+    let fill = |frame: &mut Frame<u8>, shift: usize| {
+      let plane = &mut frame.planes[0];
+      let stride = plane.cfg.stride;
+      for (y, row) in plane.data_origin_mut().chunks_mut(stride).take(64).enumerate() {
+        for (x, v) in row[..128].iter_mut().enumerate() {
+          *v = texture(if x < 64 { x } else { x + shift }, y);
+        }
+      }
+    };
+    let mut reference = Frame::new(128, 64, ChromaSampling::Cs420);
+    fill(&mut reference, 0);
+    let mut frame = Frame::new(128, 64, ChromaSampling::Cs420);
+    fill(&mut frame, 3);
+
+    fi.rec_buffer.frames[0] = Some(Arc::new(ReferenceFrame {
+      order_hint: 0,
+      base_q_idx: fi.base_q_idx,
+      frame: Arc::new(reference),
+      input_hres: Plane::new(64, 32, 1, 1, 0, 0),
+      input_qres: Plane::new(32, 16, 2, 2, 0, 0),
+      cdfs: CDFContext::new(fi.base_q_idx),
+      frame_mvs: Vec::new(),
+      intra_refresh: None,
+      film_grain_params: None,
+      crc: None
+    }));
+    fi.ref_frames = [0; INTER_REFS_PER_FRAME];
+    fi.frame_type = FrameType::INTER;
+    fi.intra_only = false;
+    let fs = FrameState::new_with_frame(&fi, Arc::new(frame));
+
+    let complexity = tile_complexity(&fi, &fs);
+    assert_eq!(complexity, vec![(0, 64 * 64), (complexity[1].0, 64 * 64)]);
+    assert!(complexity[1].0 > 0);
+    let qi = select_tile_qi(100, 8, &complexity);
+    assert!(qi[1] < 100 && 100 < qi[0]);
+  }
+
+  #[test]
+  fn sb_qidx_by_tile() {
+    // 5x3 superblocks in 2x2 tiles of 3x2 superblocks, the last ones partial
+    let ti = TilingInfo::new(6, 5 * 64 - 8, 3 * 64, 1, 1);
+    assert_eq!((ti.cols, ti.rows), (2, 2));
+    let sb_qidx = sb_qidx_from_tiles(&ti, &[10, 20, 30, 40]);
+    assert_eq!(sb_qidx, vec![
+      10, 10, 10, 20, 20,
+      10, 10, 10, 20, 20,
+      30, 30, 30, 40, 40,
+    ]);
+    assert_eq!(tile_sb_qidx(&ti, &sb_qidx), vec![
+      vec![10; 6], vec![20; 4], vec![30; 3], vec![40; 2]
+    ]);
+  }
+  #[test]
+  fn tile_lists_hold_tiles_up_to_64k() {
+    let ti = TilingInfo::new(6, 128, 128, 1, 1);
+    let mut raw_tiles = vec![vec![0u8; 1 << 16]; 4];
+    let tile_lists = build_tile_lists(&ti, &raw_tiles).unwrap();
+    assert_eq!(tile_lists.len(), 1);
+    // tile_data_size_minus_1 of the last entry
+    let offset = tile_lists[0].tile_offsets[3];
+    assert_eq!(&tile_lists[0].data[offset - 2..offset], &[0xff, 0xff]);
+
+    raw_tiles[3].push(0);
+    assert!(build_tile_lists(&ti, &raw_tiles).is_none());
+  }
 }
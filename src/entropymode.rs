@@ -31,6 +31,7 @@ const TX_SIZE_LUMA_MIN: usize = TxSize::TX_4X4 as usize;
 const TX_SIZE_CTX_MIN: usize = (TX_SIZE_LUMA_MIN + 1);
 pub const MAX_TX_CATS: usize = (TxSize::TX_SIZES - TX_SIZE_CTX_MIN);
 pub const MAX_TX_DEPTH: usize = 2;
+pub const MAX_VARTX_DEPTH: usize = 2;
 
 // LUTS ---------------------
 
@@ -0,0 +1,407 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Estimation of the global motion of a frame relative to a reference,
+//! fitted by RANSAC to the motion vectors of its blocks.
+
+use crate::context::MI_SIZE;
+use crate::me::FrameMotionVectors;
+use crate::partition::{GlobalMVMode, RefType};
+
+use rand::{ChaChaRng, Rng, SeedableRng};
+
+/// Fractional bits of the parameters of a warp model
+pub const WARPEDMODEL_PREC_BITS: usize = 16;
+
+/// Spacing of the blocks whose motion vectors are sampled, in 4x4 blocks
+const SAMPLE_STEP: usize = 2;
+/// Number of random minimal sets of samples tried per model
+const RANSAC_TRIALS: usize = 64;
+/// Largest distance, in pels, between the motion of an inlier sample and
+/// the one of the model
+const INLIER_THRESHOLD: f64 = 1.0;
+/// Smallest fraction of the samples the model must follow to be used
+const MIN_INLIER_RATIO: f64 = 0.5;
+/// A model is preferred to the more complex ones if it follows at least
+/// this fraction of the inliers of the best of them
+const SIMPLER_MODEL_RATIO: f64 = 0.95;
+
+/// Global motion of a frame relative to a reference, mapping the sample at
+/// (x, y) of the frame to
+/// (`params[2] * x + params[3] * y + params[0]`,
+/// `params[4] * x + params[5] * y + params[1]`) in the reference, the
+/// parameters being in units of `1 << WARPEDMODEL_PREC_BITS`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WarpModel {
+  pub mode: GlobalMVMode,
+  pub params: [i32; 6],
+}
+
+impl Default for WarpModel {
+  fn default() -> Self {
+    let one = 1 << WARPEDMODEL_PREC_BITS;
+    WarpModel { mode: GlobalMVMode::IDENTITY, params: [0, 0, one, 0, 0, one] }
+  }
+}
+
+/// Motion of the center of a block, relative to the center of the frame,
+/// in pels
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+  x: f64,
+  y: f64,
+  dx: f64,
+  dy: f64,
+}
+
+/// Parameters of a model in the layout of `WarpModel::params`, in pels
+type Params = [f64; 6];
+
+/// Number of samples determining a model of type `mode`
+fn min_samples(mode: GlobalMVMode) -> usize {
+  match mode {
+    GlobalMVMode::IDENTITY => 0,
+    GlobalMVMode::TRANSLATION => 1,
+    GlobalMVMode::ROTZOOM => 2,
+    GlobalMVMode::AFFINE => 3,
+  }
+}
+
+fn project(p: &Params, s: &Sample) -> (f64, f64) {
+  (p[2] * s.x + p[3] * s.y + p[0], p[4] * s.x + p[5] * s.y + p[1])
+}
+
+fn is_inlier(p: &Params, s: &Sample) -> bool {
+  let (x, y) = project(p, s);
+  let (ex, ey) = (x - s.x - s.dx, y - s.y - s.dy);
+  ex * ex + ey * ey <= INLIER_THRESHOLD * INLIER_THRESHOLD
+}
+
+/// Solves the first `n` unknowns of the linear least squares problem whose
+/// equations are `rows`, returning `None` if they are underdetermined
+fn least_squares(n: usize, rows: &[([f64; 4], f64)]) -> Option<[f64; 4]> {
+  // Normal equations, augmented with their right-hand side
+  let mut m = [[0f64; 5]; 4];
+  for (coeffs, value) in rows.iter() {
+    for i in 0..n {
+      for j in 0..n {
+        m[i][j] += coeffs[i] * coeffs[j];
+      }
+      m[i][4] += coeffs[i] * value;
+    }
+  }
+
+  // Gaussian elimination with partial pivoting
+  for col in 0..n {
+    let pivot = (col..n)
+      .max_by(|&a, &b| m[a][col].abs().partial_cmp(&m[b][col].abs()).unwrap())
+      .unwrap();
+    if m[pivot][col].abs() < 1e-9 {
+      return None;
+    }
+    m.swap(col, pivot);
+    for row in 0..n {
+      if row != col {
+        let f = m[row][col] / m[col][col];
+        for k in col..5 {
+          m[row][k] -= f * m[col][k];
+        }
+      }
+    }
+  }
+
+  let mut x = [0f64; 4];
+  for (i, v) in x.iter_mut().enumerate().take(n) {
+    *v = m[i][4] / m[i][i];
+  }
+  Some(x)
+}
+
+/// Fits a model of type `mode` to the samples in the least squares sense
+fn fit(mode: GlobalMVMode, samples: &[Sample]) -> Option<Params> {
+  match mode {
+    GlobalMVMode::IDENTITY => Some([0., 0., 1., 0., 0., 1.]),
+    GlobalMVMode::TRANSLATION => {
+      if samples.is_empty() {
+        return None;
+      }
+      let n = samples.len() as f64;
+      let dx = samples.iter().map(|s| s.dx).sum::<f64>() / n;
+      let dy = samples.iter().map(|s| s.dy).sum::<f64>() / n;
+      Some([dx, dy, 1., 0., 0., 1.])
+    }
+    GlobalMVMode::ROTZOOM => {
+      // x' = a * x + b * y + tx, y' = -b * x + a * y + ty
+      let rows: Vec<_> = samples
+        .iter()
+        .flat_map(|s| {
+          vec![
+            ([s.x, s.y, 1., 0.], s.x + s.dx),
+            ([s.y, -s.x, 0., 1.], s.y + s.dy),
+          ]
+        })
+        .collect();
+      let [a, b, tx, ty] = least_squares(4, &rows)?;
+      Some([tx, ty, a, b, -b, a])
+    }
+    GlobalMVMode::AFFINE => {
+      let fit_row = |target: &dyn Fn(&Sample) -> f64| {
+        let rows: Vec<_> =
+          samples.iter().map(|s| ([s.x, s.y, 1., 0.], target(s))).collect();
+        least_squares(3, &rows)
+      };
+      let [a, b, tx, _] = fit_row(&|s| s.x + s.dx)?;
+      let [c, d, ty, _] = fit_row(&|s| s.y + s.dy)?;
+      Some([tx, ty, a, b, c, d])
+    }
+  }
+}
+
+/// Fits a model of type `mode` by RANSAC, returning it refitted to its
+/// inliers along with their number
+fn ransac(
+  mode: GlobalMVMode, samples: &[Sample], rng: &mut ChaChaRng
+) -> Option<(Params, usize)> {
+  let k = min_samples(mode);
+  if samples.len() < k.max(1) {
+    return None;
+  }
+
+  let mut best: Option<(Params, usize)> = None;
+  for _ in 0..RANSAC_TRIALS {
+    let mut picked: Vec<usize> = Vec::with_capacity(k);
+    while picked.len() < k {
+      let i = rng.gen_range(0, samples.len());
+      if !picked.contains(&i) {
+        picked.push(i);
+      }
+    }
+    let subset: Vec<Sample> = picked.iter().map(|&i| samples[i]).collect();
+    if let Some(p) = fit(mode, &subset) {
+      let inliers = samples.iter().filter(|s| is_inlier(&p, s)).count();
+      if best.map_or(true, |(_, n)| inliers > n) {
+        best = Some((p, inliers));
+      }
+    }
+  }
+
+  let (p, _) = best?;
+  let inliers: Vec<Sample> =
+    samples.iter().filter(|s| is_inlier(&p, s)).cloned().collect();
+  let refined = fit(mode, &inliers).unwrap_or(p);
+  let count = samples.iter().filter(|s| is_inlier(&refined, s)).count();
+  Some((refined, count))
+}
+
+/// Estimates the global motion of a frame relative to `ref_frame` from
+/// the motion vectors of its blocks, `frame_mvs` holding those of each
+/// reference.
+///
+/// The simplest model followed by nearly as many blocks as the most complex
+/// one is returned, or the identity if fewer than half of the blocks follow
+/// any. The models are not limited to the parameters the bitstream can code.
+///
+/// The random samples are drawn from `seed`, `EncoderConfig::seed` in the
+/// encoder, for the estimate to be reproducible.
+pub fn estimate_global_motion(
+  frame_mvs: &[FrameMotionVectors], ref_frame: RefType, seed: u64
+) -> WarpModel {
+  let mvs = &frame_mvs[ref_frame.to_index()];
+  let cx = (mvs.cols * MI_SIZE) as f64 / 2.;
+  let cy = (mvs.rows * MI_SIZE) as f64 / 2.;
+  let mut samples = Vec::new();
+  for by in (0..mvs.rows).step_by(SAMPLE_STEP) {
+    for bx in (0..mvs.cols).step_by(SAMPLE_STEP) {
+      let mv = mvs[by][bx];
+      samples.push(Sample {
+        x: (bx * MI_SIZE + MI_SIZE / 2) as f64 - cx,
+        y: (by * MI_SIZE + MI_SIZE / 2) as f64 - cy,
+        dx: mv.col as f64 / 8.,
+        dy: mv.row as f64 / 8.,
+      });
+    }
+  }
+
+  let mut rng_seed = [0; 32];
+  rng_seed[..8].copy_from_slice(&seed.to_le_bytes());
+  let mut rng = ChaChaRng::from_seed(rng_seed);
+  let fits: Vec<(GlobalMVMode, Params, usize)> = [
+    GlobalMVMode::TRANSLATION,
+    GlobalMVMode::ROTZOOM,
+    GlobalMVMode::AFFINE,
+  ]
+  .iter()
+  .filter_map(|&mode| {
+    ransac(mode, &samples, &mut rng).map(|(p, inliers)| (mode, p, inliers))
+  })
+  .collect();
+
+  let most_inliers = fits.iter().map(|&(_, _, n)| n).max().unwrap_or(0);
+  if (most_inliers as f64) < MIN_INLIER_RATIO * samples.len() as f64 {
+    return WarpModel::default();
+  }
+  let &(mode, p, _) = fits
+    .iter()
+    .find(|&&(_, _, n)| n as f64 >= SIMPLER_MODEL_RATIO * most_inliers as f64)
+    .unwrap();
+
+  // Back from coordinates relative to the center of the frame
+  let params = [
+    p[0] + cx - p[2] * cx - p[3] * cy,
+    p[1] + cy - p[4] * cx - p[5] * cy,
+    p[2],
+    p[3],
+    p[4],
+    p[5],
+  ];
+  let mut model = WarpModel { mode, params: [0; 6] };
+  for (q, &v) in model.params.iter_mut().zip(params.iter()) {
+    *q = (v * (1 << WARPEDMODEL_PREC_BITS) as f64).round() as i32;
+  }
+  if mode == GlobalMVMode::TRANSLATION && model.params[..2] == [0, 0] {
+    return WarpModel::default();
+  }
+  model
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::partition::MotionVector;
+  use crate::partition::RefType::*;
+
+  const COLS: usize = 40;
+  const ROWS: usize = 30;
+
+  /// Motion vectors of the blocks of a 160x120 frame following `params`,
+  /// given in pels relative to the top-left corner, for LAST_FRAME
+  fn model_mvs(params: Params) -> Vec<FrameMotionVectors> {
+    let mut frame_mvs = vec![FrameMotionVectors::new(COLS, ROWS); 7];
+    let mvs = &mut frame_mvs[LAST_FRAME.to_index()];
+    for by in 0..ROWS {
+      for bx in 0..COLS {
+        let x = (bx * MI_SIZE + MI_SIZE / 2) as f64;
+        let y = (by * MI_SIZE + MI_SIZE / 2) as f64;
+        let s = Sample { x, y, dx: 0., dy: 0. };
+        let (px, py) = project(&params, &s);
+        mvs[by][bx] = MotionVector {
+          row: ((py - y) * 8.).round() as i16,
+          col: ((px - x) * 8.).round() as i16,
+        };
+      }
+    }
+    frame_mvs
+  }
+
+  fn add_outliers(frame_mvs: &mut [FrameMotionVectors]) {
+    let mvs = &mut frame_mvs[LAST_FRAME.to_index()];
+    for by in (0..ROWS).step_by(SAMPLE_STEP) {
+      for bx in (0..COLS).step_by(SAMPLE_STEP * 5) {
+        mvs[by][bx] = MotionVector { row: 40, col: -56 };
+      }
+    }
+  }
+
+  fn assert_close(model: WarpModel, params: Params) {
+    let one = (1 << WARPEDMODEL_PREC_BITS) as f64;
+    for i in 0..6 {
+      // An eighth of a pel for the translation, 0.2% for the matrix
+      let tolerance = if i < 2 { one / 8. } else { one / 500. };
+      let expected = params[i] * one;
+      assert!(
+        (model.params[i] as f64 - expected).abs() <= tolerance,
+        "parameter {} of {:?}, expected {:?}", i, model, params
+      );
+    }
+  }
+
+  #[test]
+  fn still_frame() {
+    let frame_mvs = model_mvs([0., 0., 1., 0., 0., 1.]);
+    assert_eq!(
+      estimate_global_motion(&frame_mvs, LAST_FRAME, 0),
+      WarpModel::default()
+    );
+  }
+
+  #[test]
+  fn pan() {
+    let mut frame_mvs = model_mvs([2., -3., 1., 0., 0., 1.]);
+    add_outliers(&mut frame_mvs);
+    let one = 1 << WARPEDMODEL_PREC_BITS;
+    assert_eq!(
+      estimate_global_motion(&frame_mvs, LAST_FRAME, 0),
+      WarpModel {
+        mode: GlobalMVMode::TRANSLATION,
+        params: [2 * one, -3 * one, one, 0, 0, one]
+      }
+    );
+  }
+
+  #[test]
+  fn any_seed() {
+    let params = [-2., 1.5, 1.03, 0.02, -0.02, 1.03];
+    let mut frame_mvs = model_mvs(params);
+    add_outliers(&mut frame_mvs);
+    for &seed in &[1, 42, std::u64::MAX] {
+      let model = estimate_global_motion(&frame_mvs, LAST_FRAME, seed);
+      assert_eq!(model.mode, GlobalMVMode::ROTZOOM);
+      assert_close(model, params);
+      assert_eq!(estimate_global_motion(&frame_mvs, LAST_FRAME, seed), model);
+    }
+  }
+
+  #[test]
+  fn zoom_and_rotation() {
+    let params = [-2., 1.5, 1.03, 0.02, -0.02, 1.03];
+    let mut frame_mvs = model_mvs(params);
+    add_outliers(&mut frame_mvs);
+    let model = estimate_global_motion(&frame_mvs, LAST_FRAME, 0);
+    assert_eq!(model.mode, GlobalMVMode::ROTZOOM);
+    assert_close(model, params);
+  }
+
+  #[test]
+  fn shear() {
+    let params = [1., 0.5, 1., 0.06, 0., 1.];
+    let mut frame_mvs = model_mvs(params);
+    add_outliers(&mut frame_mvs);
+    let model = estimate_global_motion(&frame_mvs, LAST_FRAME, 0);
+    assert_eq!(model.mode, GlobalMVMode::AFFINE);
+    assert_close(model, params);
+  }
+
+  #[test]
+  fn only_for_the_reference() {
+    let frame_mvs = model_mvs([2., -3., 1., 0., 0., 1.]);
+    assert_eq!(
+      estimate_global_motion(&frame_mvs, GOLDEN_FRAME, 0),
+      WarpModel::default()
+    );
+  }
+
+  #[test]
+  fn random_motion() {
+    let mut rng = ChaChaRng::from_seed([1; 32]);
+    let mut frame_mvs = vec![FrameMotionVectors::new(COLS, ROWS); 7];
+    let mvs = &mut frame_mvs[LAST_FRAME.to_index()];
+    for by in 0..ROWS {
+      for bx in 0..COLS {
+        mvs[by][bx] = MotionVector {
+          row: rng.gen_range(-64, 64),
+          col: rng.gen_range(-64, 64),
+        };
+      }
+    }
+    assert_eq!(
+      estimate_global_motion(&frame_mvs, LAST_FRAME, 0),
+      WarpModel::default()
+    );
+  }
+}
@@ -0,0 +1,419 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Film grain synthesis parameters, read from grain tables in the text
+//! format of aomenc's `--film-grain-table`.
+
+use std::fmt;
+
+/// Number of ticks per second of the timestamps of grain tables
+pub const GRAIN_TABLE_TICKS_PER_SECOND: u64 = 10_000_000;
+
+/// Film grain synthesis parameters of a frame, as signaled in its header
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FilmGrainParams {
+  pub random_seed: u16,
+  pub num_y_points: usize,
+  /// Piecewise linear scaling function of the luma grain, as pairs of
+  /// sample value and scaling, with increasing sample values
+  pub scaling_points_y: [[u8; 2]; 14],
+  pub chroma_scaling_from_luma: bool,
+  pub num_cb_points: usize,
+  pub scaling_points_cb: [[u8; 2]; 10],
+  pub num_cr_points: usize,
+  pub scaling_points_cr: [[u8; 2]; 10],
+  /// Shift of the scaling functions, from 8 to 11
+  pub scaling_shift: u8,
+  /// Number of rows and columns of the auto-regressive filter, up to 3
+  pub ar_coeff_lag: usize,
+  pub ar_coeffs_y: [i8; 24],
+  pub ar_coeffs_cb: [i8; 25],
+  pub ar_coeffs_cr: [i8; 25],
+  /// Shift of the auto-regressive coefficients, from 6 to 9
+  pub ar_coeff_shift: u8,
+  pub grain_scale_shift: u8,
+  pub cb_mult: u8,
+  pub cb_luma_mult: u8,
+  pub cb_offset: u16,
+  pub cr_mult: u8,
+  pub cr_luma_mult: u8,
+  pub cr_offset: u16,
+  pub overlap_flag: bool,
+  pub clip_to_restricted_range: bool,
+}
+
+impl FilmGrainParams {
+  /// Whether both parameter sets are the same but for their random seed,
+  /// so that one can be signaled by reference to the other
+  pub fn same_as(&self, other: &FilmGrainParams) -> bool {
+    FilmGrainParams { random_seed: 0, ..*self }
+      == FilmGrainParams { random_seed: 0, ..*other }
+  }
+
+  /// Mixes the seed of the encoder into the random seed of the grain,
+  /// which is left unchanged by seed 0 and never becomes 0
+  pub fn reseed(&mut self, seed: u64) {
+    // Spread every bit of the seed over the top 16 bits
+    let mixed = (seed ^ (seed >> 32)).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    self.random_seed ^= (mixed >> 48) as u16;
+    if self.random_seed == 0 {
+      self.random_seed = 7391;
+    }
+  }
+
+  /// Number of auto-regressive coefficients of the luma grain
+  pub fn num_pos_luma(&self) -> usize {
+    2 * self.ar_coeff_lag * (self.ar_coeff_lag + 1)
+  }
+}
+
+/// Film grain parameters applying to the frames of a time range
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrainTableSegment {
+  /// Start of the range, in ticks of `GRAIN_TABLE_TICKS_PER_SECOND`
+  pub start_time: u64,
+  /// End of the range, excluded
+  pub end_time: u64,
+  /// Parameters of the first frame of the range, whose random seed is
+  /// advanced for each frame, or `None` if no grain is applied
+  pub params: Option<FilmGrainParams>,
+}
+
+/// Film grain parameters of a whole sequence
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GrainTable {
+  pub segments: Vec<GrainTableSegment>,
+}
+
+/// Where the film grain parameters of the frames come from
+#[derive(Clone, Debug, PartialEq)]
+pub enum GrainTableSource {
+  /// A grain table file, parsed with `GrainTable::parse`
+  File(GrainTable),
+}
+
+impl GrainTableSource {
+  /// Returns the parameters of the frame displayed at `time`, in ticks of
+  /// `GRAIN_TABLE_TICKS_PER_SECOND`, or `None` if no grain is applied.
+  ///
+  /// The random seed of the segment is advanced by 3381 for each frame, as
+  /// aomenc does, so that the grain differs from frame to frame.
+  pub fn params_at(
+    &self, time: u64, frame_number: u64
+  ) -> Option<FilmGrainParams> {
+    let GrainTableSource::File(ref table) = *self;
+    let segment = table
+      .segments
+      .iter()
+      .find(|s| s.start_time <= time && time < s.end_time)?;
+    let mut params = segment.params?;
+    params.random_seed = params
+      .random_seed
+      .wrapping_add((frame_number as u16).wrapping_mul(3381));
+    if params.random_seed == 0 {
+      params.random_seed = 7391;
+    }
+    Some(params)
+  }
+}
+
+/// Error in a grain table, with the line it was found on
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrainTableError {
+  pub line: usize,
+  pub message: String,
+}
+
+impl fmt::Display for GrainTableError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Invalid grain table at line {}: {}", self.line, self.message)
+  }
+}
+
+impl std::error::Error for GrainTableError {}
+
+/// Whitespace separated tokens of a grain table, with their line numbers
+struct Tokens<'a> {
+  tokens: Box<dyn Iterator<Item = (usize, &'a str)> + 'a>,
+  line: usize,
+}
+
+impl<'a> Tokens<'a> {
+  fn new(data: &'a str) -> Self {
+    let tokens = data
+      .lines()
+      .enumerate()
+      .flat_map(|(i, line)| line.split_whitespace().map(move |t| (i + 1, t)));
+    Tokens { tokens: Box::new(tokens), line: 1 }
+  }
+
+  fn error<T>(&self, message: String) -> Result<T, GrainTableError> {
+    Err(GrainTableError { line: self.line, message })
+  }
+
+  fn next_token(&mut self) -> Option<&'a str> {
+    let (line, token) = self.tokens.next()?;
+    self.line = line;
+    Some(token)
+  }
+
+  fn expect(&mut self, keyword: &str) -> Result<(), GrainTableError> {
+    match self.next_token() {
+      Some(token) if token == keyword => Ok(()),
+      Some(token) => {
+        self.error(format!("expected `{}`, found `{}`", keyword, token))
+      }
+      None => self.error(format!("expected `{}`, found the end", keyword)),
+    }
+  }
+
+  /// Parses a number, checking that it is within `min..=max`
+  fn number(
+    &mut self, name: &str, min: i64, max: i64
+  ) -> Result<i64, GrainTableError> {
+    let token = match self.next_token() {
+      Some(token) => token,
+      None => return self.error(format!("missing {}", name)),
+    };
+    match token.parse() {
+      Ok(v) if v >= min && v <= max => Ok(v),
+      Ok(v) => self.error(format!(
+        "{} is {}, out of the range {} to {}", name, v, min, max
+      )),
+      Err(_) => self.error(format!("{} is `{}`, not a number", name, token)),
+    }
+  }
+
+  fn flag(&mut self, name: &str) -> Result<bool, GrainTableError> {
+    Ok(self.number(name, 0, 1)? != 0)
+  }
+
+  /// Parses a scaling function into `points`, returning its point count
+  fn scaling_points(
+    &mut self, keyword: &str, points: &mut [[u8; 2]]
+  ) -> Result<usize, GrainTableError> {
+    self.expect(keyword)?;
+    let num_points = self.number(
+      &format!("{} point count", keyword), 0, points.len() as i64
+    )? as usize;
+    for i in 0..num_points {
+      let value = self.number(&format!("{} point value", keyword), 0, 255)?;
+      let scaling =
+        self.number(&format!("{} point scaling", keyword), 0, 255)?;
+      if i > 0 && value <= i64::from(points[i - 1][0]) {
+        return self.error(format!(
+          "{} point values are not increasing", keyword
+        ));
+      }
+      points[i] = [value as u8, scaling as u8];
+    }
+    Ok(num_points)
+  }
+
+  fn ar_coeffs(
+    &mut self, keyword: &str, coeffs: &mut [i8]
+  ) -> Result<(), GrainTableError> {
+    self.expect(keyword)?;
+    for c in coeffs.iter_mut() {
+      *c = self.number(&format!("{} coefficient", keyword), -128, 127)? as i8;
+    }
+    Ok(())
+  }
+
+  fn params(
+    &mut self, random_seed: u16
+  ) -> Result<FilmGrainParams, GrainTableError> {
+    let mut params = FilmGrainParams { random_seed, ..Default::default() };
+    self.expect("p")?;
+    params.ar_coeff_lag = self.number("ar_coeff_lag", 0, 3)? as usize;
+    params.ar_coeff_shift = self.number("ar_coeff_shift", 6, 9)? as u8;
+    params.grain_scale_shift = self.number("grain_scale_shift", 0, 3)? as u8;
+    params.scaling_shift = self.number("scaling_shift", 8, 11)? as u8;
+    params.chroma_scaling_from_luma =
+      self.flag("chroma_scaling_from_luma")?;
+    params.overlap_flag = self.flag("overlap_flag")?;
+    params.cb_mult = self.number("cb_mult", 0, 255)? as u8;
+    params.cb_luma_mult = self.number("cb_luma_mult", 0, 255)? as u8;
+    params.cb_offset = self.number("cb_offset", 0, 511)? as u16;
+    params.cr_mult = self.number("cr_mult", 0, 255)? as u8;
+    params.cr_luma_mult = self.number("cr_luma_mult", 0, 255)? as u8;
+    params.cr_offset = self.number("cr_offset", 0, 511)? as u16;
+
+    params.num_y_points =
+      self.scaling_points("sY", &mut params.scaling_points_y)?;
+    params.num_cb_points =
+      self.scaling_points("sCb", &mut params.scaling_points_cb)?;
+    params.num_cr_points =
+      self.scaling_points("sCr", &mut params.scaling_points_cr)?;
+
+    let num_pos_luma = params.num_pos_luma();
+    self.ar_coeffs("cY", &mut params.ar_coeffs_y[..num_pos_luma])?;
+    self.ar_coeffs("cCb", &mut params.ar_coeffs_cb[..=num_pos_luma])?;
+    self.ar_coeffs("cCr", &mut params.ar_coeffs_cr[..=num_pos_luma])?;
+    Ok(params)
+  }
+}
+
+impl GrainTable {
+  /// Parses a grain table in the format written by aomenc. Each segment
+  /// starts with a line
+  ///
+  /// ```text
+  /// E <start_time> <end_time> <apply_grain> <random_seed> <update_parameters>
+  /// ```
+  ///
+  /// followed, if `update_parameters` is set, by the `p`, `sY`, `sCb`,
+  /// `sCr`, `cY`, `cCb` and `cCr` lines of the parameters. Segments without
+  /// them reuse the parameters of the previous segment.
+  pub fn parse(data: &str) -> Result<GrainTable, GrainTableError> {
+    let mut tokens = Tokens::new(data);
+    tokens.expect("filmgrn1")?;
+
+    let mut segments = Vec::new();
+    let mut last_params: Option<FilmGrainParams> = None;
+    while let Some(token) = tokens.next_token() {
+      if token != "E" {
+        return tokens.error(format!("expected `E`, found `{}`", token));
+      }
+      let start_time = tokens.number("start_time", 0, i64::max_value())?;
+      let end_time = tokens.number("end_time", 0, i64::max_value())?;
+      if end_time <= start_time {
+        return tokens.error("the segment ends before it starts".to_string());
+      }
+      let apply_grain = tokens.flag("apply_grain")?;
+      let random_seed = tokens.number("random_seed", 0, 65535)? as u16;
+      let update_parameters = tokens.flag("update_parameters")?;
+
+      let params = if update_parameters {
+        tokens.params(random_seed)?
+      } else {
+        match last_params {
+          Some(params) => FilmGrainParams { random_seed, ..params },
+          None => {
+            return tokens.error(
+              "the first segment must have parameters".to_string()
+            )
+          }
+        }
+      };
+      last_params = Some(params);
+      segments.push(GrainTableSegment {
+        start_time: start_time as u64,
+        end_time: end_time as u64,
+        params: if apply_grain { Some(params) } else { None },
+      });
+    }
+
+    Ok(GrainTable { segments })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  // As written by aomenc with --film-grain-test=1
+  const TABLE: &str = "filmgrn1
+E 0 3336666 1 7391 1
+\tp 3 7 0 11 0 1 128 192 256 128 192 256
+\tsY 14  0 20 20 5 39 4 59 3 78 3 98 3 118 3 137 3 157 3 177 3 196 3 216 4 235 6 255 14
+\tsCb 10 0 0 28 0 57 0 85 0 113 0 142 0 170 0 198 0 227 0 255 1
+\tsCr 10 0 0 28 0 57 0 85 0 113 0 142 0 170 0 198 0 227 0 255 1
+\tcY 4 1 3 0 1 -3 8 -3 7 -23 1 -25 0 -2 6 -17 21 -8 17 52 0 -24 -2 0
+\tcCb -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 0
+\tcCr -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 -2 0
+E 3336666 6673333 1 14782 0
+E 6673333 10010000 0 0 0
+";
+
+  #[test]
+  fn parse_aomenc_table() {
+    let table = GrainTable::parse(TABLE).unwrap();
+    assert_eq!(table.segments.len(), 3);
+
+    let first = table.segments[0].params.unwrap();
+    assert_eq!(first.random_seed, 7391);
+    assert_eq!(first.ar_coeff_lag, 3);
+    assert_eq!(first.ar_coeff_shift, 7);
+    assert_eq!(first.scaling_shift, 11);
+    assert!(first.overlap_flag);
+    assert_eq!(first.cb_offset, 256);
+    assert_eq!(first.num_y_points, 14);
+    assert_eq!(first.scaling_points_y[13], [255, 14]);
+    assert_eq!(first.num_cb_points, 10);
+    assert_eq!(first.ar_coeffs_y[21], -24);
+    assert_eq!(first.ar_coeffs_cb[24], 0);
+
+    // Repeated parameters, with a new seed
+    let second = table.segments[1].params.unwrap();
+    assert_eq!(second.random_seed, 14782);
+    assert!(second.same_as(&first));
+    assert_eq!(table.segments[1].start_time, 3336666);
+
+    assert_eq!(table.segments[2].params, None);
+  }
+
+  #[test]
+  fn params_by_time() {
+    let source = GrainTableSource::File(GrainTable::parse(TABLE).unwrap());
+    assert_eq!(source.params_at(0, 0).unwrap().random_seed, 7391);
+    assert_eq!(source.params_at(333_333, 1).unwrap().random_seed, 7391 + 3381);
+    assert_eq!(source.params_at(3336666, 10).unwrap().random_seed,
+               14782u16.wrapping_add(33810));
+    assert_eq!(source.params_at(7_000_000, 21), None);
+    assert_eq!(source.params_at(20_000_000, 60), None);
+  }
+
+  #[test]
+  fn reseed() {
+    let params = FilmGrainParams { random_seed: 1234, ..Default::default() };
+    let reseeded = |seed| {
+      let mut params = params;
+      params.reseed(seed);
+      params.random_seed
+    };
+    assert_eq!(reseeded(0), 1234);
+    // Seeds differing in the bits an xor-fold of 16-bit words cancels
+    let seeds = [1, 1 << 16, 1 << 32, 1 << 48, 0x0001_0000_0000_0001];
+    for (i, &a) in seeds.iter().enumerate() {
+      assert_ne!(reseeded(a), 1234);
+      for &b in &seeds[i + 1..] {
+        assert_ne!(reseeded(a), reseeded(b), "{:#x} {:#x}", a, b);
+      }
+    }
+    // Never 0
+    assert_eq!(reseeded(53866), 7391);
+  }
+
+  #[test]
+  fn malformed_tables() {
+    let error = |data: &str| GrainTable::parse(data).unwrap_err();
+
+    assert_eq!(error("filmgrn2\n").line, 1);
+    // Truncated parameters
+    let truncated = &TABLE[..TABLE.find("\tcY").unwrap()];
+    let e = error(truncated);
+    assert_eq!(e.line, 6);
+    assert!(e.message.contains("cY"), "{}", e);
+    // Out of range values
+    let e = error(&TABLE.replace("\tp 3 7 0 11", "\tp 3 7 0 12"));
+    assert_eq!(e.line, 3);
+    assert!(e.message.contains("scaling_shift"), "{}", e);
+    let e = error(&TABLE.replace("\tcY 4 1", "\tcY 400 1"));
+    assert_eq!(e.line, 7);
+    // Unordered scaling points
+    let e = error(&TABLE.replace("\tsY 14  0 20 20 5", "\tsY 14  0 20 0 5"));
+    assert_eq!(e.line, 4);
+    // Reference to missing parameters
+    let e = error("filmgrn1\nE 0 10 1 1 0\n");
+    assert_eq!(e.line, 2);
+    // Empty time range
+    assert_eq!(error("filmgrn1\nE 10 10 1 1 0\n").line, 2);
+  }
+}
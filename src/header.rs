@@ -20,7 +20,7 @@ use crate::FrameState;
 use crate::FrameInvariants;
 use crate::Sequence;
 
-use bitstream_io::{BitWriter, BigEndian, LittleEndian};
+use bitstream_io::{BitReader, BitWriter, BigEndian, LittleEndian};
 
 use std;
 use std::io;
@@ -52,6 +52,7 @@ pub enum ReferenceMode {
   SELECT = 2,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum ObuType {
   OBU_SEQUENCE_HEADER = 1,
@@ -65,6 +66,35 @@ pub enum ObuType {
   OBU_PADDING = 15,
 }
 
+impl ObuType {
+  /// Returns the OBU type with the given value of the obu_type field, or
+  /// `None` for reserved values
+  pub fn from_u8(obu_type: u8) -> Option<ObuType> {
+    use self::ObuType::*;
+    match obu_type {
+      1 => Some(OBU_SEQUENCE_HEADER),
+      2 => Some(OBU_TEMPORAL_DELIMITER),
+      3 => Some(OBU_FRAME_HEADER),
+      4 => Some(OBU_TILE_GROUP),
+      5 => Some(OBU_METADATA),
+      6 => Some(OBU_FRAME),
+      7 => Some(OBU_REDUNDANT_FRAME_HEADER),
+      8 => Some(OBU_TILE_LIST),
+      15 => Some(OBU_PADDING),
+      _ => None
+    }
+  }
+}
+
+/// The OBU extension header, giving the layers an OBU belongs to. Every
+/// OBU of a frame has one as soon as the sequence has several operating
+/// points.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ObuExtension {
+  pub temporal_id: u8,
+  pub spatial_id: u8,
+}
+
 #[derive(Clone,Copy)]
 #[allow(non_camel_case_types)]
 pub enum ObuMetaType {
@@ -145,7 +175,7 @@ impl<W: io::Write> LEWriter for BitWriter<W, BigEndian> {
 pub trait UncompressedHeader {
   // Start of OBU Headers
   fn write_obu_header(
-    &mut self, obu_type: ObuType, obu_extension: u32
+    &mut self, obu_type: ObuType, obu_extension: Option<ObuExtension>
   ) -> io::Result<()>;
   fn write_metadata_obu(
     &mut self, obu_meta_type: ObuMetaType, seq: Sequence
@@ -176,6 +206,9 @@ pub trait UncompressedHeader {
   fn write_frame_cdef<T: Pixel>(
     &mut self, fi: &FrameInvariants<T>
   ) -> io::Result<()>;
+  fn write_film_grain_params<T: Pixel>(
+    &mut self, fi: &FrameInvariants<T>
+  ) -> io::Result<()>;
   fn write_frame_lrf<T: Pixel>(
     &mut self, fi: &FrameInvariants<T>, rs: &RestorationState
   ) -> io::Result<()>;
@@ -192,17 +225,18 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
   // Start of OBU Headers
   // Write OBU Header syntax
   fn write_obu_header(
-    &mut self, obu_type: ObuType, obu_extension: u32
+    &mut self, obu_type: ObuType, obu_extension: Option<ObuExtension>
   ) -> io::Result<()> {
     self.write_bit(false)?; // forbidden bit.
     self.write(4, obu_type as u32)?;
-    self.write_bit(obu_extension != 0)?;
+    self.write_bit(obu_extension.is_some())?;
     self.write_bit(true)?; // obu_has_payload_length_field
     self.write_bit(false)?; // reserved
 
-    if obu_extension != 0 {
-      unimplemented!();
-      //self.write(8, obu_extension & 0xFF)?; size += 8;
+    if let Some(extension) = obu_extension {
+      self.write(3, extension.temporal_id)?;
+      self.write(2, extension.spatial_id)?;
+      self.write(3, 0)?; // reserved
     }
 
     Ok(())
@@ -212,7 +246,7 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     &mut self, obu_meta_type: ObuMetaType, seq: Sequence
   ) -> io::Result<()> {
     // header
-    self.write_obu_header(ObuType::OBU_METADATA, 0)?;
+    self.write_obu_header(ObuType::OBU_METADATA, None)?;
 
     // uleb128() - length
     // we use a constant value to avoid computing the OBU size every time
@@ -260,10 +294,14 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     self.write_bit(false)?; // reduced_still_picture_header
     self.write_bit(false)?; // timing info present
     self.write_bit(false)?; // initial display delay present flag
-    self.write(5, 0)?; // one operating point
-    self.write(12, 0)?; // idc
-    self.write(5, 31)?; // level
-    self.write(1, 0)?; // tier
+    self.write(5, fi.sequence.operating_points_cnt_minus_1 as u8)?;
+    for i in 0..=fi.sequence.operating_points_cnt_minus_1 {
+      self.write(12, fi.sequence.operating_point_idc[i])?; // idc
+      self.write(5, fi.sequence.seq_level_idx())?; // level
+      if fi.sequence.seq_level_idx() > 7 {
+        self.write(1, fi.sequence.tier[i] as u8)?; // tier
+      }
+    }
     if fi.sequence.reduced_still_picture_hdr {
       unimplemented!();
     }
@@ -302,7 +340,7 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     }
 
     self.write_bit(seq.use_128x128_superblock)?;
-    self.write_bit(true)?; // enable filter intra
+    self.write_bit(seq.enable_filter_intra)?;
     self.write_bit(seq.enable_intra_edge_filter)?;
 
     if !seq.reduced_still_picture_hdr {
@@ -531,6 +569,8 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       } else {
         assert!(fi.refresh_frame_flags == ALL_REF_FRAMES_MASK);
       }
+    } else if fi.frame_type == FrameType::SWITCH {
+      assert!(fi.refresh_frame_flags == ALL_REF_FRAMES_MASK);
     } else {
       // Inter frame info goes here
       if fi.intra_only {
@@ -545,10 +585,12 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     if (!fi.intra_only || fi.refresh_frame_flags != ALL_REF_FRAMES_MASK) {
       // Write all ref frame order hints if error_resilient_mode == 1
       if (fi.error_resilient && fi.sequence.enable_order_hint) {
-        unimplemented!();
-        //for _ in 0..REF_FRAMES {
-        //  self.write(order_hint_bits_minus_1,ref_order_hint[i])?; // order_hint
-        //}
+        let n = fi.sequence.order_hint_bits_minus_1 + 1;
+        let mask = (1 << n) - 1;
+        for rec in fi.rec_buffer.frames.iter() {
+          let order_hint = rec.as_ref().map_or(0, |rec| rec.order_hint);
+          self.write(n, order_hint & mask)?; // ref_order_hint
+        }
       }
     }
 
@@ -559,7 +601,7 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
         unimplemented!();
       }
       if fi.sequence.enable_superres {
-        unimplemented!();
+        self.write_bit(false)?; // use_superres
       }
       self.write_bit(false)?; // render_and_frame_size_different
                               // if render_and_frame_size_different { }
@@ -589,13 +631,19 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       }
 
       if fi.error_resilient && frame_size_override_flag {
-        unimplemented!();
+        // frame_size(), with the field widths of the sequence header
+        self.write(fi.sequence.num_bits_width, (fi.width - 1) as u16)?;
+        self.write(fi.sequence.num_bits_height, (fi.height - 1) as u16)?;
+        if fi.sequence.enable_superres {
+          self.write_bit(false)?; // use_superres
+        }
+        self.write_bit(false)?; // render_and_frame_size_different
       } else {
         if frame_size_override_flag {
           unimplemented!();
         }
         if fi.sequence.enable_superres {
-          unimplemented!();
+          self.write_bit(false)?; // use_superres
         }
         self.write_bit(false)?; // render_and_frame_size_different
       }
@@ -673,8 +721,11 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     // segmentation
     self.write_segment_data(fi, &fs.segmentation)?;
 
-    // delta_q
-    self.write_bit(false)?; // delta_q_present_flag: no delta q
+    // delta_q, present since base_q_idx > 0
+    self.write_bit(fi.delta_q_present)?;
+    if fi.delta_q_present {
+      self.write(2, 0)?; // delta_q_res: deltas in units of 1
+    }
 
     // delta_lf_params in the spec
     self.write_deblock_filter_a(fi, &fs.deblock)?;
@@ -753,8 +804,10 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       }
     }
 
-    if fi.sequence.film_grain_params_present && fi.show_frame {
-      unimplemented!();
+    if fi.sequence.film_grain_params_present
+      && (fi.show_frame || fi.showable_frame)
+    {
+      self.write_film_grain_params(fi)?;
     }
 
     if fi.large_scale_tile {
@@ -849,6 +902,102 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     Ok(())
   }
 
+  fn write_film_grain_params<T: Pixel>(
+    &mut self, fi: &FrameInvariants<T>
+  ) -> io::Result<()> {
+    // <https://aomediacodec.github.io/av1-spec/#film-grain-params-syntax>
+    let params = match fi.film_grain_params {
+      Some(ref params) => params,
+      None => return self.write_bit(false) // apply_grain
+    };
+    self.write_bit(true)?; // apply_grain
+    self.write(16, params.random_seed)?;
+
+    if fi.frame_type == FrameType::INTER {
+      // Load the parameters of a reference frame when they are the same
+      let ref_slot = fi.ref_frames.iter().find(|&&slot| {
+        fi.rec_buffer.frames[slot as usize]
+          .as_ref()
+          .and_then(|rec| rec.film_grain_params)
+          .map_or(false, |ref_params| ref_params.same_as(params))
+      });
+      self.write_bit(ref_slot.is_none())?; // update_grain
+      if let Some(&slot) = ref_slot {
+        self.write(3, slot)?; // film_grain_params_ref_idx
+        return Ok(());
+      }
+    }
+
+    self.write(4, params.num_y_points as u8)?;
+    for point in &params.scaling_points_y[..params.num_y_points] {
+      self.write(8, point[0])?;
+      self.write(8, point[1])?;
+    }
+
+    let seq = &fi.sequence;
+    let mono_chrome = seq.chroma_sampling == ChromaSampling::Cs400;
+    if !mono_chrome {
+      self.write_bit(params.chroma_scaling_from_luma)?;
+    }
+    // The chroma scaling functions are not signaled, thus empty, in these
+    // cases
+    let (num_cb_points, num_cr_points) = if mono_chrome
+      || params.chroma_scaling_from_luma
+      || (seq.chroma_sampling == ChromaSampling::Cs420
+        && params.num_y_points == 0)
+    {
+      (0, 0)
+    } else {
+      self.write(4, params.num_cb_points as u8)?;
+      for point in &params.scaling_points_cb[..params.num_cb_points] {
+        self.write(8, point[0])?;
+        self.write(8, point[1])?;
+      }
+      self.write(4, params.num_cr_points as u8)?;
+      for point in &params.scaling_points_cr[..params.num_cr_points] {
+        self.write(8, point[0])?;
+        self.write(8, point[1])?;
+      }
+      (params.num_cb_points, params.num_cr_points)
+    };
+
+    self.write(2, params.scaling_shift - 8)?; // grain_scaling_minus_8
+    self.write(2, params.ar_coeff_lag as u8)?;
+    let num_pos_luma = params.num_pos_luma();
+    let num_pos_chroma = if params.num_y_points > 0 {
+      for &coeff in &params.ar_coeffs_y[..num_pos_luma] {
+        self.write(8, (i16::from(coeff) + 128) as u8)?;
+      }
+      num_pos_luma + 1
+    } else {
+      num_pos_luma
+    };
+    if params.chroma_scaling_from_luma || num_cb_points > 0 {
+      for &coeff in &params.ar_coeffs_cb[..num_pos_chroma] {
+        self.write(8, (i16::from(coeff) + 128) as u8)?;
+      }
+    }
+    if params.chroma_scaling_from_luma || num_cr_points > 0 {
+      for &coeff in &params.ar_coeffs_cr[..num_pos_chroma] {
+        self.write(8, (i16::from(coeff) + 128) as u8)?;
+      }
+    }
+    self.write(2, params.ar_coeff_shift - 6)?; // ar_coeff_shift_minus_6
+    self.write(2, params.grain_scale_shift)?;
+    if num_cb_points > 0 {
+      self.write(8, params.cb_mult)?;
+      self.write(8, params.cb_luma_mult)?;
+      self.write(9, params.cb_offset)?;
+    }
+    if num_cr_points > 0 {
+      self.write(8, params.cr_mult)?;
+      self.write(8, params.cr_luma_mult)?;
+      self.write(9, params.cr_offset)?;
+    }
+    self.write_bit(params.overlap_flag)?;
+    self.write_bit(params.clip_to_restricted_range)
+  }
+
   fn write_frame_cdef<T: Pixel>(&mut self, fi: &FrameInvariants<T>) -> io::Result<()> {
     if fi.sequence.enable_cdef {
       assert!(fi.cdef_damping >= 3);
@@ -947,3 +1096,158 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     Ok(())
   }
 }
+
+/// The leading fields of a frame header OBU, as read by `parse_frame_header`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameHeader {
+  /// Temporal and spatial layer of the OBU, 0 without an extension header
+  pub temporal_id: u8,
+  pub spatial_id: u8,
+  pub show_existing_frame: bool,
+  /// Slot of the frame shown, with `show_existing_frame`
+  pub frame_to_show_map_idx: u8,
+  /// Type of the frame coded, `None` with `show_existing_frame`
+  pub frame_type: Option<FrameType>,
+  pub show_frame: bool,
+  /// Whether the frame may be shown later with `show_existing_frame`
+  pub showable_frame: bool,
+  pub error_resilient: bool,
+}
+
+fn frame_header_obu(obu: &[u8]) -> Option<Obu<'_>> {
+  let mut obus = ObuIter::new(obu);
+  let header = obus.next()?;
+  match header.obu_type {
+    ObuType::OBU_FRAME_HEADER
+    | ObuType::OBU_REDUNDANT_FRAME_HEADER
+    | ObuType::OBU_FRAME
+      if header.data.len() == obu.len() =>
+    {
+      Some(header)
+    }
+    _ => None,
+  }
+}
+
+/// Parses the leading fields of the uncompressed header of `obu`, a whole
+/// frame header or frame OBU. Returns `None` for other OBUs or if `obu` is
+/// truncated.
+///
+/// The sequence header is not needed, as long as it has no reduced still
+/// picture header, decoder model info or frame ids, as in the streams of
+/// rav1e.
+pub fn parse_frame_header(obu: &[u8]) -> Option<FrameHeader> {
+  let header = frame_header_obu(obu)?;
+  let (temporal_id, spatial_id) = if header.data[0] & 0x4 != 0 {
+    (header.data[1] >> 5, (header.data[1] >> 3) & 0x3)
+  } else {
+    (0, 0)
+  };
+
+  let mut br = BitReader::endian(io::Cursor::new(header.payload), BigEndian);
+  let show_existing_frame = br.read_bit().ok()?;
+  if show_existing_frame {
+    let frame_to_show_map_idx = br.read::<u8>(3).ok()?;
+    return Some(FrameHeader {
+      temporal_id,
+      spatial_id,
+      show_existing_frame,
+      frame_to_show_map_idx,
+      frame_type: None,
+      show_frame: true,
+      showable_frame: false,
+      error_resilient: false,
+    });
+  }
+  let frame_type = match br.read::<u8>(2).ok()? {
+    0 => FrameType::KEY,
+    1 => FrameType::INTER,
+    2 => FrameType::INTRA_ONLY,
+    _ => FrameType::SWITCH,
+  };
+  let show_frame = br.read_bit().ok()?;
+  let showable_frame =
+    if show_frame { frame_type != FrameType::KEY } else { br.read_bit().ok()? };
+  let error_resilient = if frame_type == FrameType::SWITCH
+    || (frame_type == FrameType::KEY && show_frame)
+  {
+    true
+  } else {
+    br.read_bit().ok()?
+  };
+  Some(FrameHeader {
+    temporal_id,
+    spatial_id,
+    show_existing_frame,
+    frame_to_show_map_idx: 0,
+    frame_type: Some(frame_type),
+    show_frame,
+    showable_frame,
+    error_resilient,
+  })
+}
+
+/// Returns a copy of the frame header OBU `obu` with `show_frame` set to
+/// `show`, to reorder frames in a container without coding them again. A
+/// frame hidden this way is marked showable, to be shown by a later frame
+/// header with `show_existing_frame`.
+///
+/// Returns `None` for the headers of keyframes, which signal more fields
+/// depending on `show_frame`, of hidden frames which are not showable, whose
+/// film grain parameters would be missing, of existing frames shown, and
+/// for frame OBUs: their header length is only known from the sequence
+/// header, so the tile data following it cannot be moved.
+pub fn rewrite_show_frame(obu: &[u8], show: bool) -> Option<Vec<u8>> {
+  let header = parse_frame_header(obu)?;
+  let obu = frame_header_obu(obu)?;
+  if obu.obu_type == ObuType::OBU_FRAME
+    || header.show_existing_frame
+    || header.frame_type == Some(FrameType::KEY)
+  {
+    return None;
+  }
+  if header.show_frame == show {
+    return Some(obu.data.to_vec());
+  }
+  if show && !header.showable_frame {
+    return None;
+  }
+
+  // The header ends at the last bit set, the first of its trailing bits
+  let payload = obu.payload;
+  let last_byte = payload.iter().rposition(|&b| b != 0)?;
+  let header_bits = last_byte * 8 + 7 - payload[last_byte].trailing_zeros() as usize;
+  let bit = |i: usize| payload[i / 8] & (0x80 >> (i % 8)) != 0;
+
+  let mut new_payload = Vec::new();
+  {
+    let mut bw = BitWriter::endian(&mut new_payload, BigEndian);
+    // show_existing_frame and frame_type
+    for i in 0..3 {
+      bw.write_bit(bit(i)).ok()?;
+    }
+    bw.write_bit(show).ok()?;
+    // The showable_frame bit follows the show_frame bit of hidden frames
+    let rest = if show {
+      5
+    } else {
+      bw.write_bit(true).ok()?;
+      4
+    };
+    for i in rest..header_bits {
+      bw.write_bit(bit(i)).ok()?;
+    }
+    bw.write_bit(true).ok()?; // trailing bit
+    bw.byte_align().ok()?;
+  }
+
+  let obu_header_len = 1 + ((obu.data[0] >> 2) & 1) as usize;
+  let has_size_field = obu.data[0] & 0x2 != 0;
+  let mut rewritten = obu.data[..obu_header_len].to_vec();
+  if has_size_field {
+    let mut bw = BitWriter::endian(&mut rewritten, BigEndian);
+    bw.write_uleb128(new_payload.len() as u64).ok()?;
+  }
+  rewritten.extend(new_payload);
+  Some(rewritten)
+}
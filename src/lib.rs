@@ -12,6 +12,8 @@
 #[macro_use]
 extern crate serde_derive;
 extern crate bincode;
+#[macro_use]
+extern crate log;
 
 #[cfg(all(test, feature="decode_test_dav1d"))]
 extern crate dav1d_sys;
@@ -34,6 +36,11 @@ pub mod rdo_tables;
 #[macro_use]
 pub mod util;
 pub mod context;
+pub mod cpu_features;
+pub mod denoise;
+pub mod grain;
+pub mod prefilter;
+pub mod progress;
 pub mod entropymode;
 pub mod token_cdfs;
 pub mod deblock;
@@ -43,11 +50,14 @@ pub mod lrf;
 pub mod encoder;
 pub mod mc;
 pub mod me;
+pub mod global_motion;
 pub mod metrics;
 pub mod scan_order;
 pub mod scenechange;
 pub mod rate;
 pub mod tiling;
+#[cfg(feature="capi")]
+pub mod capi;
 
 mod api;
 mod header;
@@ -66,3 +76,6 @@ mod test_encode_decode_aom;
 #[cfg(all(test, feature="decode_test_dav1d"))]
 mod test_encode_decode_dav1d;
 
+#[cfg(test)]
+mod test_golden;
+
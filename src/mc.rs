@@ -12,6 +12,7 @@ pub use self::nasm::*;
 #[cfg(any(not(target_arch = "x86_64"), not(feature = "nasm")))]
 pub use self::native::*;
 
+use crate::cpu_features::CpuKernels;
 use crate::tiling::*;
 use crate::util::Pixel;
 
@@ -139,6 +140,7 @@ const SUBPEL_FILTERS: [[[i32; SUBPEL_FILTER_SIZE]; 16]; 6] = [
 #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
 mod nasm {
   use super::*;
+  use crate::cpu_features::{CpuFeatureLevel, Kernel};
   use crate::plane::*;
 
   use std::mem;
@@ -253,9 +255,9 @@ mod nasm {
   pub fn put_8tap<T: Pixel>(
     dst: &mut PlaneRegionMut<'_, T>, src: PlaneSlice<'_, T>, width: usize,
     height: usize, col_frac: i32, row_frac: i32, mode_x: FilterMode,
-    mode_y: FilterMode, bit_depth: usize
+    mode_y: FilterMode, bit_depth: usize, cpu: CpuKernels
   ) {
-    if mem::size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+    if mem::size_of::<T>() == 1 && cpu.select(Kernel::Mc, false) == CpuFeatureLevel::AVX2 {
       debug_assert!(bit_depth == 8);
       let dst_stride = dst.plane_cfg.stride as isize;
       let src_stride = src.plane.cfg.stride as isize;
@@ -275,15 +277,16 @@ mod nasm {
     }
     super::native::put_8tap(
       dst, src, width, height, col_frac, row_frac, mode_x, mode_y, bit_depth,
+      cpu
     );
   }
 
   pub fn prep_8tap<T: Pixel>(
     tmp: &mut [i16], src: PlaneSlice<'_, T>, width: usize, height: usize,
     col_frac: i32, row_frac: i32, mode_x: FilterMode, mode_y: FilterMode,
-    bit_depth: usize
+    bit_depth: usize, cpu: CpuKernels
   ) {
-    if mem::size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+    if mem::size_of::<T>() == 1 && cpu.select(Kernel::Mc, false) == CpuFeatureLevel::AVX2 {
       debug_assert!(bit_depth == 8);
       let src_stride = src.plane.cfg.stride as isize;
       unsafe {
@@ -300,15 +303,16 @@ mod nasm {
       return;
     }
     super::native::prep_8tap(
-      tmp, src, width, height, col_frac, row_frac, mode_x, mode_y, bit_depth
+      tmp, src, width, height, col_frac, row_frac, mode_x, mode_y, bit_depth,
+      cpu
     );
   }
 
   pub fn mc_avg<T: Pixel>(
     dst: &mut PlaneRegionMut<'_, T>, tmp1: &[i16], tmp2: &[i16], width: usize,
-    height: usize, bit_depth: usize
+    height: usize, bit_depth: usize, cpu: CpuKernels
   ) {
-    if mem::size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+    if mem::size_of::<T>() == 1 && cpu.select(Kernel::Mc, false) == CpuFeatureLevel::AVX2 {
       debug_assert!(bit_depth == 8);
       let dst_stride = dst.plane_cfg.stride as isize;
       unsafe {
@@ -323,7 +327,7 @@ mod nasm {
       }
       return;
     }
-    super::native::mc_avg(dst, tmp1, tmp2, width, height, bit_depth);
+    super::native::mc_avg(dst, tmp1, tmp2, width, height, bit_depth, cpu);
   }
 }
 
@@ -360,7 +364,7 @@ mod native {
   pub fn put_8tap<T: Pixel>(
     dst: &mut PlaneRegionMut<'_, T>, src: PlaneSlice<'_, T>, width: usize,
     height: usize, col_frac: i32, row_frac: i32, mode_x: FilterMode,
-    mode_y: FilterMode, bit_depth: usize
+    mode_y: FilterMode, bit_depth: usize, _cpu: CpuKernels
   ) {
     let ref_stride = src.plane.cfg.stride;
     let y_filter = get_filter(mode_y, row_frac, height);
@@ -448,7 +452,7 @@ mod native {
   pub fn prep_8tap<T: Pixel>(
     tmp: &mut [i16], src: PlaneSlice<'_, T>, width: usize, height: usize,
     col_frac: i32, row_frac: i32, mode_x: FilterMode, mode_y: FilterMode,
-    bit_depth: usize
+    bit_depth: usize, _cpu: CpuKernels
   ) {
     let ref_stride = src.plane.cfg.stride;
     let y_filter = get_filter(mode_y, row_frac, height);
@@ -524,7 +528,7 @@ mod native {
 
   pub fn mc_avg<T: Pixel>(
     dst: &mut PlaneRegionMut<'_, T>, tmp1: &[i16], tmp2: &[i16], width: usize,
-    height: usize, bit_depth: usize
+    height: usize, bit_depth: usize, _cpu: CpuKernels
   ) {
     let max_sample_val = ((1 << bit_depth) - 1) as i32;
     let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
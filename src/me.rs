@@ -11,20 +11,24 @@
 pub use self::nasm::get_sad;
 #[cfg(any(not(target_arch = "x86_64"), not(feature = "nasm")))]
 pub use self::native::get_sad;
-use crate::context::{BlockOffset, BLOCK_TO_PLANE_SHIFT, MI_SIZE};
+use crate::context::{
+  BlockOffset, BLOCK_TO_PLANE_SHIFT, MI_SIZE, MV_LOW, MV_UPP
+};
+use crate::cpu_features::CpuKernels;
 use crate::encoder::ReferenceFrame;
 use crate::FrameInvariants;
 use crate::partition::*;
 use crate::partition::RefType::*;
 use crate::plane::*;
 use crate::tiling::*;
-use crate::util::Pixel;
+use crate::util::{CastFromPrimitive, Pixel};
 
 use std::ops::{Index, IndexMut};
 use std::sync::Arc;
 
 #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
 mod nasm {
+  use crate::cpu_features::{CpuFeatureLevel, CpuKernels, Kernel};
   use crate::tiling::*;
   use crate::util::*;
   use std::mem;
@@ -217,24 +221,25 @@ mod nasm {
     blk_w: usize,
     blk_h: usize,
     bit_depth: usize,
+    cpu: CpuKernels,
   ) -> u32 {
     #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
     {
-      if mem::size_of::<T>() == 2 && is_x86_feature_detected!("ssse3") && blk_h >= 4 && blk_w >= 4 {
+      if mem::size_of::<T>() == 2 && cpu.select(Kernel::Sad, true) == CpuFeatureLevel::SSSE3 && blk_h >= 4 && blk_w >= 4 {
         return unsafe {
           let plane_org = &*(plane_org as *const _ as *const PlaneRegion<'_, u16>);
           let plane_ref = &*(plane_ref as *const _ as *const PlaneRegion<'_, u16>);
           sad_hbd_ssse3(plane_org, plane_ref, blk_w, blk_h, bit_depth)
         };
       }
-      if mem::size_of::<T>() == 1 && is_x86_feature_detected!("avx2") && blk_h >= 4 && blk_w >= 4 {
+      if mem::size_of::<T>() == 1 && cpu.select(Kernel::Sad, false) == CpuFeatureLevel::AVX2 && blk_h >= 4 && blk_w >= 4 {
         return unsafe {
           let plane_org = &*(plane_org as *const _ as *const PlaneRegion<'_, u8>);
           let plane_ref = &*(plane_ref as *const _ as *const PlaneRegion<'_, u8>);
           sad_avx2(plane_org, plane_ref, blk_w, blk_h)
         };
       }
-      if mem::size_of::<T>() == 1 && is_x86_feature_detected!("sse2") && blk_h >= 4 && blk_w >= 4 {
+      if mem::size_of::<T>() == 1 && cpu.select(Kernel::Sad, false) == CpuFeatureLevel::SSE2 && blk_h >= 4 && blk_w >= 4 {
         return unsafe {
           let plane_org = &*(plane_org as *const _ as *const PlaneRegion<'_, u8>);
           let plane_ref = &*(plane_ref as *const _ as *const PlaneRegion<'_, u8>);
@@ -242,11 +247,12 @@ mod nasm {
         };
       }
     }
-    super::native::get_sad(plane_org, plane_ref, blk_w, blk_h, bit_depth)
+    super::native::get_sad(plane_org, plane_ref, blk_w, blk_h, bit_depth, cpu)
   }
 }
 
 mod native {
+  use crate::cpu_features::CpuKernels;
   use crate::tiling::*;
   use crate::util::*;
 
@@ -257,6 +263,7 @@ mod native {
     blk_w: usize,
     blk_h: usize,
     _bit_depth: usize,
+    _cpu: CpuKernels,
   ) -> u32 {
     let mut sum = 0 as u32;
 
@@ -273,6 +280,53 @@ mod native {
   }
 }
 
+// 4-point Hadamard transform of the rows, then of the columns
+pub(crate) fn hadamard4x4(buf: &mut [i32; 16]) {
+  for i in 0..4 {
+    let (a0, a1) = (buf[i * 4] + buf[i * 4 + 1], buf[i * 4] - buf[i * 4 + 1]);
+    let (a2, a3) = (buf[i * 4 + 2] + buf[i * 4 + 3], buf[i * 4 + 2] - buf[i * 4 + 3]);
+    buf[i * 4] = a0 + a2;
+    buf[i * 4 + 1] = a1 + a3;
+    buf[i * 4 + 2] = a0 - a2;
+    buf[i * 4 + 3] = a1 - a3;
+  }
+  for j in 0..4 {
+    let (a0, a1) = (buf[j] + buf[4 + j], buf[j] - buf[4 + j]);
+    let (a2, a3) = (buf[8 + j] + buf[12 + j], buf[8 + j] - buf[12 + j]);
+    buf[j] = a0 + a2;
+    buf[4 + j] = a1 + a3;
+    buf[8 + j] = a0 - a2;
+    buf[12 + j] = a1 - a3;
+  }
+}
+
+/// Sum of absolute transformed differences: the sum of the absolute
+/// coefficients of the 4x4 Hadamard transforms of the differences between
+/// the blocks. It follows the cost of coding the residual more closely than
+/// the SAD, and equals it for flat differences.
+pub fn get_satd<T: Pixel>(
+  plane_org: &PlaneRegion<'_, T>, plane_ref: &PlaneRegion<'_, T>,
+  blk_w: usize, blk_h: usize
+) -> u32 {
+  debug_assert!(blk_w % 4 == 0 && blk_h % 4 == 0);
+  let mut sum = 0;
+  for y in (0..blk_h).step_by(4) {
+    for x in (0..blk_w).step_by(4) {
+      let mut buf = [0i32; 16];
+      for i in 0..4 {
+        let org = &plane_org[y + i][x..x + 4];
+        let rf = &plane_ref[y + i][x..x + 4];
+        for j in 0..4 {
+          buf[i * 4 + j] = i32::cast_from(org[j]) - i32::cast_from(rf[j]);
+        }
+      }
+      hadamard4x4(&mut buf);
+      sum += buf.iter().map(|&v| v.abs() as u32).sum::<u32>();
+    }
+  }
+  sum
+}
+
 #[derive(Debug, Clone)]
 pub struct FrameMotionVectors {
   mvs: Box<[MotionVector]>,
@@ -315,17 +369,119 @@ impl IndexMut<usize> for FrameMotionVectors {
   }
 }
 
+/// Returns the range of the motion vectors of a block, in 1/8 pel.
+///
+/// The block may move at most `me_range` full pels, and up to 16 pels past
+/// the frame edges: the reference frames are padded by more than that, and
+/// the decoder extends their edges likewise. The range is within the values
+/// the bitstream allows whatever the frame size.
 fn get_mv_range(
-  w_in_b: usize, h_in_b: usize, bo: BlockOffset, blk_w: usize, blk_h: usize
+  w_in_b: usize, h_in_b: usize, bo: BlockOffset, blk_w: usize, blk_h: usize,
+  me_range: usize
 ) -> (isize, isize, isize, isize) {
   let border_w = 128 + blk_w as isize * 8;
   let border_h = 128 + blk_h as isize * 8;
   let mvx_min = -(bo.x as isize) * (8 * MI_SIZE) as isize - border_w;
-  let mvx_max = (w_in_b - bo.x - blk_w / MI_SIZE) as isize * (8 * MI_SIZE) as isize + border_w;
+  let mvx_max = (w_in_b as isize - (bo.x + blk_w / MI_SIZE) as isize) * (8 * MI_SIZE) as isize + border_w;
   let mvy_min = -(bo.y as isize) * (8 * MI_SIZE) as isize - border_h;
-  let mvy_max = (h_in_b - bo.y - blk_h / MI_SIZE) as isize * (8 * MI_SIZE) as isize + border_h;
+  let mvy_max = (h_in_b as isize - (bo.y + blk_h / MI_SIZE) as isize) * (8 * MI_SIZE) as isize + border_h;
 
-  (mvx_min, mvx_max, mvy_min, mvy_max)
+  let range_max = (me_range as isize * 8).min(MV_UPP as isize - 1);
+  let range_min = (-(me_range as isize) * 8).max(MV_LOW as isize + 1);
+
+  (
+    mvx_min.max(range_min),
+    mvx_max.min(range_max),
+    mvy_min.max(range_min),
+    mvy_max.min(range_max)
+  )
+}
+
+/// Motion of a block found by a search outside of the encoder, see
+/// `Context::set_external_motion`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockMv {
+  /// Position of the block in the frame, in units of 4x4 luma samples
+  pub bo: BlockOffset,
+  pub bsize: BlockSize,
+  /// References the block predicts from, the second one `NONE_FRAME` unless
+  /// the block predicts from two
+  pub ref_frames: [RefType; 2],
+  /// Motion vector from each reference, in 1/8 pel
+  pub mvs: [MotionVector; 2],
+}
+
+impl BlockMv {
+  /// Whether the block is at most 64x64, lies at an offset aligned to its
+  /// size within a frame of `w_in_b`x`h_in_b` 4x4 blocks, predicts from
+  /// valid references and moves by at most the range of the bitstream, up
+  /// to 16 pels past the frame edges.
+  pub(crate) fn is_valid(&self, w_in_b: usize, h_in_b: usize) -> bool {
+    if self.bsize == BlockSize::BLOCK_INVALID
+      || self.bsize.width() > 64 || self.bsize.height() > 64 {
+      return false;
+    }
+    let (w_mi, h_mi) = (self.bsize.width_mi(), self.bsize.height_mi());
+    if self.bo.x >= w_in_b || self.bo.y >= h_in_b
+      || self.bo.x % w_mi != 0 || self.bo.y % h_mi != 0 {
+      return false;
+    }
+    let is_inter = |r: RefType| r != INTRA_FRAME && r != NONE_FRAME;
+    let refs = if self.ref_frames[1] == NONE_FRAME { 1 } else { 2 };
+    if !is_inter(self.ref_frames[0])
+      || (refs == 2 && (!is_inter(self.ref_frames[1])
+        || self.ref_frames[1] == self.ref_frames[0])) {
+      return false;
+    }
+    let (mvx_min, mvx_max, mvy_min, mvy_max) = get_mv_range(
+      w_in_b, h_in_b, self.bo, self.bsize.width(), self.bsize.height(),
+      (MV_UPP / 8) as usize
+    );
+    self.mvs[..refs].iter().all(|mv| {
+      let (col, row) = (mv.col as isize, mv.row as isize);
+      col >= mvx_min && col <= mvx_max && row >= mvy_min && row <= mvy_max
+    })
+  }
+}
+
+/// Motion vectors of the blocks of a frame given by
+/// `Context::set_external_motion`, used instead of searching them
+#[derive(Clone, Debug)]
+pub struct ExternalMotion {
+  blocks: Vec<BlockMv>,
+  /// Index in `blocks` plus one of the block covering each 4x4 block of
+  /// the frame, 0 if none does
+  map: Vec<u32>,
+  cols: usize,
+}
+
+impl ExternalMotion {
+  /// Maps `blocks` over a frame of `w_in_b`x`h_in_b` 4x4 blocks, the later
+  /// blocks overriding the earlier ones they overlap
+  pub(crate) fn new(blocks: &[BlockMv], w_in_b: usize, h_in_b: usize) -> Self {
+    let mut map = vec![0; w_in_b * h_in_b];
+    for (i, b) in blocks.iter().enumerate() {
+      let x_end = (b.bo.x + b.bsize.width_mi()).min(w_in_b);
+      let y_end = (b.bo.y + b.bsize.height_mi()).min(h_in_b);
+      for y in b.bo.y..y_end {
+        for idx in &mut map[y * w_in_b + b.bo.x..y * w_in_b + x_end] {
+          *idx = i as u32 + 1;
+        }
+      }
+    }
+    ExternalMotion { blocks: blocks.to_vec(), map, cols: w_in_b }
+  }
+
+  /// Motion vector from `ref_frame` of the block covering `bo`, if it
+  /// predicts from that reference
+  pub(crate) fn mv(
+    &self, bo: BlockOffset, ref_frame: RefType
+  ) -> Option<MotionVector> {
+    let idx = *self.map.get(bo.y * self.cols + bo.x)?;
+    let block = self.blocks.get(idx.checked_sub(1)? as usize)?;
+    block.ref_frames.iter().position(|&r| r == ref_frame)
+      .map(|i| block.mvs[i])
+  }
 }
 
 pub fn get_subset_predictors<T: Pixel>(
@@ -432,21 +588,25 @@ pub trait MotionEstimation {
       Some(ref rec) => {
         let blk_w = bsize.width();
         let blk_h = bsize.height();
-        let frame_bo = ts.to_frame_block_offset(tile_bo);
         let (mvx_min, mvx_max, mvy_min, mvy_max) =
-          get_mv_range(fi.w_in_b, fi.h_in_b, frame_bo, blk_w, blk_h);
-
-        // 0.5 is a fudge factor
-        let lambda = (fi.me_lambda * 256.0 * 0.5) as u32;
+          get_block_mv_range(fi, ts, tile_bo, bsize, ref_frame);
+        let lambda = get_me_lambda(fi);
 
         // Full-pixel motion estimation
 
         let mut lowest_cost = std::u64::MAX;
         let mut best_mv = MotionVector::default();
 
-        Self::full_pixel_me(fi, ts, rec, tile_bo, lambda, cmv, pmv,
-                           mvx_min, mvx_max, mvy_min, mvy_max, blk_w, blk_h,
-                           &mut best_mv, &mut lowest_cost, ref_frame);
+        if fi.config.speed_settings.me_downsampled && blk_w >= 8 && blk_h >= 8 {
+          downsampled_full_pixel_me(fi, ts, rec, tile_bo, lambda, cmv, pmv,
+                                    mvx_min, mvx_max, mvy_min, mvy_max,
+                                    blk_w, blk_h, &mut best_mv,
+                                    &mut lowest_cost, ref_frame);
+        } else {
+          Self::full_pixel_me(fi, ts, rec, tile_bo, lambda, cmv, pmv,
+                             mvx_min, mvx_max, mvy_min, mvy_max, blk_w, blk_h,
+                             &mut best_mv, &mut lowest_cost, ref_frame);
+        }
 
         Self::sub_pixel_me(fi, ts, rec, tile_bo, lambda, pmv,
                            mvx_min, mvx_max, mvy_min, mvy_max, blk_w, blk_h,
@@ -468,7 +628,10 @@ pub trait MotionEstimation {
       let blk_h = bsize.height();
       let tile_bo_adj = adjust_bo(tile_bo, ts.mi_width, ts.mi_height, blk_w, blk_h);
       let frame_bo_adj = ts.to_frame_block_offset(tile_bo_adj);
-      let (mvx_min, mvx_max, mvy_min, mvy_max) = get_mv_range(fi.w_in_b, fi.h_in_b, frame_bo_adj, blk_w, blk_h);
+      let (mvx_min, mvx_max, mvy_min, mvy_max) = get_mv_range(
+        fi.w_in_b, fi.h_in_b, frame_bo_adj, blk_w, blk_h,
+        fi.config.speed_settings.me_range
+      );
 
       let global_mv = [MotionVector{row: 0, col: 0}; 2];
       let tile_mvs = &ts.mvs[ref_frame].as_const();
@@ -487,7 +650,7 @@ pub trait MotionEstimation {
         &mut best_mv, &mut lowest_cost
       );
 
-      Some(MotionVector { row: best_mv.row * 2, col: best_mv.col * 2 })
+      Some(best_mv.upscale(1))
     } else {
       None
     }
@@ -505,6 +668,7 @@ pub trait MotionEstimation {
 }
 
 pub struct DiamondSearch {}
+pub struct HexSearch {}
 pub struct FullSearch {}
 
 impl MotionEstimation for DiamondSearch {
@@ -598,8 +762,7 @@ impl MotionEstimation for DiamondSearch {
         );
 
         for predictor in &mut predictors {
-          predictor.row >>= 1;
-          predictor.col >>= 1;
+          *predictor = predictor.downscale(1);
         }
 
         diamond_me_search(
@@ -617,25 +780,125 @@ impl MotionEstimation for DiamondSearch {
   }
 }
 
-impl MotionEstimation for FullSearch {
+impl MotionEstimation for HexSearch {
   fn full_pixel_me<T: Pixel>(
     fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, rec: &ReferenceFrame<T>,
     tile_bo: BlockOffset, lambda: u32,
     cmv: MotionVector, pmv: [MotionVector; 2], mvx_min: isize, mvx_max: isize,
     mvy_min: isize, mvy_max: isize, blk_w: usize, blk_h: usize,
+    best_mv: &mut MotionVector, lowest_cost: &mut u64, ref_frame: RefType
+  ) {
+    let tile_mvs = &ts.mvs[ref_frame.to_index()].as_const();
+    let frame_ref = fi.rec_buffer.frames[fi.ref_frames[0] as usize].as_ref().map(Arc::as_ref);
+    let predictors =
+      get_subset_predictors(tile_bo, cmv, tile_mvs, frame_ref, ref_frame.to_index());
+
+    let frame_bo = ts.to_frame_block_offset(tile_bo);
+    hex_me_search(
+      fi,
+      frame_bo.to_luma_plane_offset(),
+      &ts.input.planes[0],
+      &rec.frame.planes[0],
+      &predictors,
+      fi.sequence.bit_depth,
+      pmv,
+      lambda,
+      mvx_min,
+      mvx_max,
+      mvy_min,
+      mvy_max,
+      blk_w,
+      blk_h,
+      best_mv,
+      lowest_cost,
+      ref_frame
+    );
+  }
+
+  fn sub_pixel_me<T: Pixel>(
+    fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, _rec: &ReferenceFrame<T>,
+    tile_bo: BlockOffset, lambda: u32,
+    pmv: [MotionVector; 2], mvx_min: isize, mvx_max: isize,
+    mvy_min: isize, mvy_max: isize, blk_w: usize, blk_h: usize,
+    best_mv: &mut MotionVector, lowest_cost: &mut u64, ref_frame: RefType,
+  )
+  {
+    let frame_bo = ts.to_frame_block_offset(tile_bo);
+    let (mv, cost) = subpel_search(
+      fi,
+      ts,
+      frame_bo.to_luma_plane_offset(),
+      lambda,
+      ref_frame,
+      pmv,
+      mvx_min,
+      mvx_max,
+      mvy_min,
+      mvy_max,
+      blk_w,
+      blk_h,
+      *best_mv
+    );
+    *best_mv = mv;
+    *lowest_cost = cost;
+  }
+
+  fn me_ss2<T: Pixel>(
+    fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>,
+    pmvs: &[Option<MotionVector>; 3], tile_bo_adj: BlockOffset,
+    tile_mvs: &TileMotionVectors<'_>, frame_ref_opt: Option<&ReferenceFrame<T>>,
+    rec: &ReferenceFrame<T>, global_mv: [MotionVector; 2], lambda: u32,
+    mvx_min: isize, mvx_max: isize, mvy_min: isize, mvy_max: isize,
+    blk_w: usize, blk_h: usize,
+    best_mv: &mut MotionVector, lowest_cost: &mut u64
+  ) {
+    let frame_bo_adj = ts.to_frame_block_offset(tile_bo_adj);
+    let frame_po = PlaneOffset {
+      x: (frame_bo_adj.x as isize) << BLOCK_TO_PLANE_SHIFT >> 1,
+      y: (frame_bo_adj.y as isize) << BLOCK_TO_PLANE_SHIFT >> 1,
+    };
+    for omv in pmvs.iter() {
+      if let Some(pmv) = omv {
+        let mut predictors = get_subset_predictors::<T>(
+          tile_bo_adj,
+          MotionVector{row: pmv.row, col: pmv.col},
+          &tile_mvs, frame_ref_opt, 0
+        );
+
+        for predictor in &mut predictors {
+          *predictor = predictor.downscale(1);
+        }
+
+        hex_me_search(
+          fi, frame_po,
+          &ts.input_hres, &rec.input_hres,
+          &predictors, fi.sequence.bit_depth,
+          global_mv, lambda,
+          mvx_min >> 1, mvx_max >> 1, mvy_min >> 1, mvy_max >> 1,
+          blk_w >> 1, blk_h >> 1,
+          best_mv, lowest_cost,
+          LAST_FRAME
+        );
+      }
+    }
+  }
+}
+
+impl MotionEstimation for FullSearch {
+  fn full_pixel_me<T: Pixel>(
+    fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, rec: &ReferenceFrame<T>,
+    tile_bo: BlockOffset, lambda: u32,
+    _cmv: MotionVector, pmv: [MotionVector; 2], mvx_min: isize, mvx_max: isize,
+    mvy_min: isize, mvy_max: isize, blk_w: usize, blk_h: usize,
     best_mv: &mut MotionVector, lowest_cost: &mut u64, _ref_frame: RefType
   ) {
     let frame_bo = ts.to_frame_block_offset(tile_bo);
     let frame_po = frame_bo.to_luma_plane_offset();
-    let range = 16;
-    let x_lo = frame_po.x
-      + ((-range + (cmv.col / 8) as isize).max(mvx_min / 8).min(mvx_max / 8));
-    let x_hi = frame_po.x
-      + ((range + (cmv.col / 8) as isize).max(mvx_min / 8).min(mvx_max / 8));
-    let y_lo = frame_po.y
-      + ((-range + (cmv.row / 8) as isize).max(mvy_min / 8).min(mvy_max / 8));
-    let y_hi = frame_po.y
-      + ((range + (cmv.row / 8) as isize).max(mvy_min / 8).min(mvy_max / 8));
+    // The whole range is searched, rounding its bounds towards zero
+    let x_lo = frame_po.x + mvx_min / 8;
+    let x_hi = frame_po.x + mvx_max / 8;
+    let y_lo = frame_po.y + mvy_min / 8;
+    let y_hi = frame_po.y + mvy_max / 8;
 
     full_search(
       x_lo,
@@ -649,11 +912,12 @@ impl MotionEstimation for FullSearch {
       best_mv,
       lowest_cost,
       frame_po,
-      2,
+      1,
       fi.sequence.bit_depth,
       lambda,
       pmv,
-      fi.allow_high_precision_mv
+      fi.allow_high_precision_mv,
+      fi.cpu
     );
   }
 
@@ -666,7 +930,7 @@ impl MotionEstimation for FullSearch {
   )
   {
     let frame_bo = ts.to_frame_block_offset(tile_bo);
-    telescopic_subpel_search(
+    let (mv, cost) = subpel_search(
       fi,
       ts,
       frame_bo.to_luma_plane_offset(),
@@ -679,9 +943,10 @@ impl MotionEstimation for FullSearch {
       mvy_max,
       blk_w,
       blk_h,
-      best_mv,
-      lowest_cost
+      *best_mv
     );
+    *best_mv = mv;
+    *lowest_cost = cost;
   }
 
   fn me_ss2<T: Pixel>(
@@ -698,34 +963,91 @@ impl MotionEstimation for FullSearch {
       x: (frame_bo_adj.x as isize) << BLOCK_TO_PLANE_SHIFT >> 1,
       y: (frame_bo_adj.y as isize) << BLOCK_TO_PLANE_SHIFT >> 1,
     };
-    let range = 16;
-    for omv in pmvs.iter() {
-      if let Some(pmv) = omv {
-        let x_lo = frame_po.x + (((pmv.col as isize / 8 - range).max(mvx_min / 8).min(mvx_max / 8)) >> 1);
-        let x_hi = frame_po.x + (((pmv.col as isize / 8 + range).max(mvx_min / 8).min(mvx_max / 8)) >> 1);
-        let y_lo = frame_po.y + (((pmv.row as isize / 8 - range).max(mvy_min / 8).min(mvy_max / 8)) >> 1);
-        let y_hi = frame_po.y + (((pmv.row as isize / 8 + range).max(mvy_min / 8).min(mvy_max / 8)) >> 1);
-        full_search(
-          x_lo,
-          x_hi,
-          y_lo,
-          y_hi,
-          blk_h >> 1,
-          blk_w >> 1,
-          &ts.input_hres,
-          &rec.input_hres,
-          best_mv,
-          lowest_cost,
-          frame_po,
-          1,
-          fi.sequence.bit_depth,
-          lambda,
-          [MotionVector::default(); 2],
-          fi.allow_high_precision_mv
-        );
-      }
+    // The search does not depend on the predictors, only on the range
+    if pmvs.iter().all(Option::is_none) {
+      return;
+    }
+    full_search(
+      frame_po.x + mvx_min / 16,
+      frame_po.x + mvx_max / 16,
+      frame_po.y + mvy_min / 16,
+      frame_po.y + mvy_max / 16,
+      blk_h >> 1,
+      blk_w >> 1,
+      &ts.input_hres,
+      &rec.input_hres,
+      best_mv,
+      lowest_cost,
+      frame_po,
+      1,
+      fi.sequence.bit_depth,
+      lambda,
+      [MotionVector::default(); 2],
+      fi.allow_high_precision_mv,
+      fi.cpu
+    );
+  }
+}
+
+/// Full-pel motion search of a block on the half resolution planes, the
+/// ones `estimate_motion_ss2` searches, refined to the best of the 9
+/// full-pel vectors around it at full resolution. Each step of the search
+/// costs a quarter of the SAD at full resolution. Blocks smaller than 8x8
+/// would be matched on fewer than 4x4 pixels, so they are searched at full
+/// resolution.
+fn downsampled_full_pixel_me<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, rec: &ReferenceFrame<T>,
+  tile_bo: BlockOffset, lambda: u32, cmv: MotionVector,
+  pmv: [MotionVector; 2],
+  mvx_min: isize, mvx_max: isize, mvy_min: isize, mvy_max: isize,
+  blk_w: usize, blk_h: usize, best_mv: &mut MotionVector,
+  lowest_cost: &mut u64, ref_frame: RefType
+) {
+  let tile_mvs = &ts.mvs[ref_frame.to_index()].as_const();
+  let frame_ref =
+    fi.rec_buffer.frames[fi.ref_frames[0] as usize].as_ref().map(Arc::as_ref);
+  let predictors: Vec<_> = get_subset_predictors(
+    tile_bo, cmv, tile_mvs, frame_ref, ref_frame.to_index()
+  ).iter().map(|mv| mv.downscale(1)).collect();
+
+  let frame_po = ts.to_frame_block_offset(tile_bo).to_luma_plane_offset();
+  let hres_po = PlaneOffset { x: frame_po.x >> 1, y: frame_po.y >> 1 };
+  let mut hres_mv = MotionVector::default();
+  let mut hres_cost = std::u64::MAX;
+  // The SAD sums a quarter of the pixels, so divide lambda by 4 to weigh
+  // the motion vector rate against it as at full resolution, like
+  // estimate_motion_ss2
+  diamond_me_search(
+    fi, hres_po, ts.input_hres, &rec.input_hres, &predictors,
+    fi.sequence.bit_depth, [pmv[0].downscale(1), pmv[1].downscale(1)],
+    lambda / 4, mvx_min >> 1, mvx_max >> 1, mvy_min >> 1, mvy_max >> 1,
+    blk_w >> 1, blk_h >> 1, &mut hres_mv, &mut hres_cost, false, ref_frame
+  );
+
+  // The range rounded down at half resolution may exceed the full one
+  let center = hres_mv.upscale(1);
+  let center = MotionVector {
+    row: (center.row as isize).max(mvy_min).min(mvy_max) as i16,
+    col: (center.col as isize).max(mvx_min).min(mvx_max) as i16
+  };
+  let mut tmp_plane_opt = None;
+  for &(row, col) in &[
+    (0i16, 0i16), (-8, -8), (-8, 0), (-8, 8), (0, -8), (0, 8), (8, -8),
+    (8, 0), (8, 8)
+  ] {
+    let cand_mv = MotionVector { row: center.row + row, col: center.col + col };
+    let cost = get_mv_rd_cost(
+      fi, frame_po, &ts.input.planes[0], &rec.frame.planes[0],
+      fi.sequence.bit_depth, pmv, lambda, mvx_min, mvx_max, mvy_min, mvy_max,
+      blk_w, blk_h, cand_mv, &mut tmp_plane_opt, ref_frame
+    );
+    if cost < *lowest_cost {
+      *best_mv = cand_mv;
+      *lowest_cost = cost;
     }
   }
+
+  assert!(*lowest_cost < std::u64::MAX);
 }
 
 fn get_best_predictor<T: Pixel>(
@@ -822,6 +1144,59 @@ fn diamond_me_search<T: Pixel>(
   assert!(*center_mv_cost < std::u64::MAX);
 }
 
+fn hex_me_search<T: Pixel>(
+  fi: &FrameInvariants<T>,
+  po: PlaneOffset, p_org: &Plane<T>, p_ref: &Plane<T>,
+  predictors: &[MotionVector],
+  bit_depth: usize, pmv: [MotionVector; 2], lambda: u32,
+  mvx_min: isize, mvx_max: isize, mvy_min: isize, mvy_max: isize,
+  blk_w: usize, blk_h: usize,
+  center_mv: &mut MotionVector, center_mv_cost: &mut u64,
+  ref_frame: RefType)
+{
+  // Full pel steps, in 1/8 pel
+  let hex_pattern =
+    [(0i16, -16i16), (16, -8), (16, 8), (0, 16), (-16, 8), (-16, -8)];
+  let diamond_pattern = [(8i16, 0i16), (0, 8), (-8, 0), (0, -8)];
+  let mut tmp_plane_opt = None;
+
+  get_best_predictor(
+    fi, po, p_org, p_ref, &predictors,
+    bit_depth, pmv, lambda, mvx_min, mvx_max, mvy_min, mvy_max,
+    blk_w, blk_h, center_mv, center_mv_cost,
+    &mut tmp_plane_opt, ref_frame);
+
+  let mut best_step = |pattern: &[(i16, i16)], center_mv: &mut MotionVector,
+                       center_mv_cost: &mut u64| {
+    let center = *center_mv;
+    let mut moved = false;
+    for p in pattern.iter() {
+      let cand_mv = MotionVector {
+        row: center.row + p.0,
+        col: center.col + p.1
+      };
+
+      let rd_cost = get_mv_rd_cost(
+        fi, po, p_org, p_ref, bit_depth,
+        pmv, lambda, mvx_min, mvx_max, mvy_min, mvy_max,
+        blk_w, blk_h, cand_mv, &mut tmp_plane_opt, ref_frame);
+
+      if rd_cost < *center_mv_cost {
+        *center_mv = cand_mv;
+        *center_mv_cost = rd_cost;
+        moved = true;
+      }
+    }
+    moved
+  };
+
+  // The cost strictly decreases with every step, so the hexagon stops
+  while best_step(&hex_pattern, center_mv, center_mv_cost) {}
+  best_step(&diamond_pattern, center_mv, center_mv_cost);
+
+  assert!(*center_mv_cost < std::u64::MAX);
+}
+
 fn get_mv_rd_cost<T: Pixel>(
   fi: &FrameInvariants<T>,
   po: PlaneOffset, p_org: &Plane<T>, p_ref: &Plane<T>, bit_depth: usize,
@@ -841,18 +1216,10 @@ fn get_mv_rd_cost<T: Pixel>(
   let plane_org = p_org.region(Area::StartingAt { x: po.x, y: po.y });
 
   if let Some(ref mut tmp_plane) = tmp_plane_opt {
-    let tile_rect = TileRect {
-      x: 0,
-      y: 0,
-      width: tmp_plane.cfg.width,
-      height: tmp_plane.cfg.height
-    };
-
     PredictionMode::NEWMV.predict_inter(
       fi,
-      tile_rect,
       0,
-      po,
+      FramePlaneOffset(po),
       &mut tmp_plane.as_region_mut(),
       blk_w,
       blk_h,
@@ -884,7 +1251,10 @@ fn compute_mv_rd_cost<T: Pixel>(
   plane_org: &PlaneRegion<'_, T>, plane_ref: &PlaneRegion<'_, T>
 ) -> u64
 {
-  let sad = get_sad(&plane_org, &plane_ref, blk_w, blk_h, bit_depth);
+  let sad = get_sad(
+    &plane_org, &plane_ref, blk_w, blk_h, bit_depth,
+    fi.cpu
+  );
 
   let rate1 = get_mv_rate(cand_mv, pmv[0], fi.allow_high_precision_mv);
   let rate2 = get_mv_rate(cand_mv, pmv[1], fi.allow_high_precision_mv);
@@ -893,30 +1263,66 @@ fn compute_mv_rd_cost<T: Pixel>(
   256 * sad as u64 + rate as u64 * lambda as u64
 }
 
-fn telescopic_subpel_search<T: Pixel>(
+/// Refines `start_mv`, found by the full-pel search, to the precision of
+/// the motion vectors of the frame. The search moves to the best of the 8
+/// neighbours at each step, halving it from half pels down to the finest
+/// precision allowed, and costs a vector with the SATD of its prediction
+/// and its rate.
+pub fn refine_subpel<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, tile_bo: BlockOffset,
+  bsize: BlockSize, ref_frame: RefType, start_mv: MotionVector,
+  pmv: [MotionVector; 2]
+) -> MotionVector {
+  let (mvx_min, mvx_max, mvy_min, mvy_max) =
+    get_block_mv_range(fi, ts, tile_bo, bsize, ref_frame);
+  let frame_bo = ts.to_frame_block_offset(tile_bo);
+
+  subpel_search(
+    fi, ts, frame_bo.to_luma_plane_offset(), get_me_lambda(fi), ref_frame,
+    pmv, mvx_min, mvx_max, mvy_min, mvy_max, bsize.width(), bsize.height(),
+    start_mv
+  ).0
+}
+
+fn subpel_search<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, po: PlaneOffset,
   lambda: u32, ref_frame: RefType, pmv: [MotionVector; 2],
   mvx_min: isize, mvx_max: isize, mvy_min: isize, mvy_max: isize,
-  blk_w: usize, blk_h: usize,
-  best_mv: &mut MotionVector, lowest_cost: &mut u64
-) {
-  let mode = PredictionMode::NEWMV;
-
-  let mut steps = vec![8, 4, 2];
-  if fi.allow_high_precision_mv {
-    steps.push(1);
-  }
+  blk_w: usize, blk_h: usize, start_mv: MotionVector
+) -> (MotionVector, u64) {
+  let precision = fi.mv_precision();
+  let steps: &[i16] = match precision {
+    MvSubpelPrecision::MV_SUBPEL_NONE => &[],
+    MvSubpelPrecision::MV_SUBPEL_LOW_PRECISION => &[4, 2],
+    MvSubpelPrecision::MV_SUBPEL_HIGH_PRECISION => &[4, 2, 1]
+  };
 
+  let plane_org = ts.input.planes[0].region(Area::StartingAt { x: po.x, y: po.y });
   let mut tmp_plane = Plane::new(blk_w, blk_h, 0, 0, 0, 0);
-  let tile_rect = TileRect {
-    x: 0,
-    y: 0,
-    width: tmp_plane.cfg.width,
-    height: tmp_plane.cfg.height
+  let mut get_cost = |mv: MotionVector| {
+    PredictionMode::NEWMV.predict_inter(
+      fi,
+      0,
+      FramePlaneOffset(po),
+      &mut tmp_plane.as_region_mut(),
+      blk_w,
+      blk_h,
+      [ref_frame, NONE_FRAME],
+      [mv, MotionVector::default()]
+    );
+    let satd = get_satd(&plane_org, &tmp_plane.as_region(), blk_w, blk_h);
+
+    let rate1 = get_mv_rate(mv, pmv[0], fi.allow_high_precision_mv);
+    let rate2 = get_mv_rate(mv, pmv[1], fi.allow_high_precision_mv);
+    let rate = rate1.min(rate2 + 1);
+    256 * satd as u64 + rate as u64 * lambda as u64
   };
 
-  for step in steps {
-    let center_mv_h = *best_mv;
+  let mut best_mv = start_mv.round_to_precision(precision);
+  let mut lowest_cost = get_cost(best_mv);
+
+  for &step in steps {
+    let center_mv = best_mv;
     for i in 0..3 {
       for j in 0..3 {
         // Skip the center point that was already tested
@@ -925,8 +1331,8 @@ fn telescopic_subpel_search<T: Pixel>(
         }
 
         let cand_mv = MotionVector {
-          row: center_mv_h.row + step * (i as i16 - 1),
-          col: center_mv_h.col + step * (j as i16 - 1)
+          row: center_mv.row + step * (i as i16 - 1),
+          col: center_mv.col + step * (j as i16 - 1)
         };
 
         if (cand_mv.col as isize) < mvx_min || (cand_mv.col as isize) > mvx_max {
@@ -936,44 +1342,24 @@ fn telescopic_subpel_search<T: Pixel>(
           continue;
         }
 
-        {
-          mode.predict_inter(
-            fi,
-            tile_rect,
-            0,
-            po,
-            &mut tmp_plane.as_region_mut(),
-            blk_w,
-            blk_h,
-            [ref_frame, NONE_FRAME],
-            [cand_mv, MotionVector { row: 0, col: 0 }]
-          );
-        }
-
-        let plane_org = ts.input.planes[0].region(Area::StartingAt { x: po.x, y: po.y });
-        let plane_ref = tmp_plane.as_region();
-
-        let sad = get_sad(&plane_org, &plane_ref, blk_w, blk_h, fi.sequence.bit_depth);
-
-        let rate1 = get_mv_rate(cand_mv, pmv[0], fi.allow_high_precision_mv);
-        let rate2 = get_mv_rate(cand_mv, pmv[1], fi.allow_high_precision_mv);
-        let rate = rate1.min(rate2 + 1);
-        let cost = 256 * sad as u64 + rate as u64 * lambda as u64;
-
-        if cost < *lowest_cost {
-          *lowest_cost = cost;
-          *best_mv = cand_mv;
+        let cost = get_cost(cand_mv);
+        if cost < lowest_cost {
+          lowest_cost = cost;
+          best_mv = cand_mv;
         }
       }
     }
   }
+
+  (best_mv, lowest_cost)
 }
 
 fn full_search<T: Pixel>(
   x_lo: isize, x_hi: isize, y_lo: isize, y_hi: isize, blk_h: usize,
   blk_w: usize, p_org: &Plane<T>, p_ref: &Plane<T>, best_mv: &mut MotionVector,
   lowest_cost: &mut u64, po: PlaneOffset, step: usize, bit_depth: usize,
-  lambda: u32, pmv: [MotionVector; 2], allow_high_precision_mv: bool
+  lambda: u32, pmv: [MotionVector; 2], allow_high_precision_mv: bool,
+  cpu: CpuKernels
 ) {
     let search_range_y = (y_lo..=y_hi).step_by(step);
     let search_range_x = (x_lo..=x_hi).step_by(step);
@@ -982,7 +1368,7 @@ fn full_search<T: Pixel>(
     let (cost, mv) = search_area.map(|(y, x)| {
       let plane_org = p_org.region(Area::StartingAt { x: po.x, y: po.y });
       let plane_ref = p_ref.region(Area::StartingAt { x, y });
-      let sad = get_sad(&plane_org, &plane_ref, blk_w, blk_h, bit_depth);
+      let sad = get_sad(&plane_org, &plane_ref, blk_w, blk_h, bit_depth, cpu);
 
       let mv = MotionVector {
         row: 8 * (y as i16 - po.y as i16),
@@ -1001,6 +1387,34 @@ fn full_search<T: Pixel>(
     *best_mv = mv;
 }
 
+/// Range of the motion vectors searched for a block, which also keeps the
+/// prediction of intra refreshed blocks within the refreshed area
+fn get_block_mv_range<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, tile_bo: BlockOffset,
+  bsize: BlockSize, ref_frame: RefType
+) -> (isize, isize, isize, isize) {
+  let blk_w = bsize.width();
+  let frame_bo = ts.to_frame_block_offset(tile_bo);
+  let (mvx_min, mut mvx_max, mvy_min, mvy_max) = get_mv_range(
+    fi.w_in_b, fi.h_in_b, frame_bo, blk_w, bsize.height(),
+    fi.config.speed_settings.me_range
+  );
+
+  let frame_x = frame_bo.x << BLOCK_TO_PLANE_SHIFT;
+  if let Some(mv_col_max) =
+    fi.intra_refresh_mv_col_max(frame_x, blk_w, ref_frame)
+  {
+    mvx_max = mvx_max.min(mv_col_max.max(mvx_min));
+  }
+
+  (mvx_min, mvx_max, mvy_min, mvy_max)
+}
+
+fn get_me_lambda<T: Pixel>(fi: &FrameInvariants<T>) -> u32 {
+  // 0.5 is a fudge factor
+  (fi.me_lambda * 256.0 * 0.5) as u32
+}
+
 // Adjust block offset such that entire block lies within boundaries
 fn adjust_bo(bo: BlockOffset, mi_width: usize, mi_height: usize, blk_w: usize, blk_h: usize) -> BlockOffset {
   BlockOffset {
@@ -1038,9 +1452,13 @@ pub fn estimate_motion_ss4<T: Pixel>(
       y: (frame_bo_adj.y as isize) << BLOCK_TO_PLANE_SHIFT >> 2
     };
 
+    // The lookahead has its own range, wider than that of the blocks and
+    // independent of `me_range`
     let range_x = 192 * fi.me_range_scale as isize;
     let range_y = 64 * fi.me_range_scale as isize;
-    let (mvx_min, mvx_max, mvy_min, mvy_max) = get_mv_range(fi.w_in_b, fi.h_in_b, frame_bo_adj, blk_w, blk_h);
+    let (mvx_min, mvx_max, mvy_min, mvy_max) = get_mv_range(
+      fi.w_in_b, fi.h_in_b, frame_bo_adj, blk_w, blk_h, range_x as usize
+    );
     let x_lo = po.x + (((-range_x).max(mvx_min / 8)) >> 2);
     let x_hi = po.x + (((range_x).min(mvx_max / 8)) >> 2);
     let y_lo = po.y + (((-range_y).max(mvy_min / 8)) >> 2);
@@ -1068,10 +1486,11 @@ pub fn estimate_motion_ss4<T: Pixel>(
       fi.sequence.bit_depth,
       lambda,
       [MotionVector::default(); 2],
-      fi.allow_high_precision_mv
+      fi.allow_high_precision_mv,
+      fi.cpu
     );
 
-    Some(MotionVector { row: best_mv.row * 4, col: best_mv.col * 4 })
+    Some(best_mv.upscale(2))
   } else {
     None
   }
@@ -1080,6 +1499,10 @@ pub fn estimate_motion_ss4<T: Pixel>(
 #[cfg(test)]
 pub mod test {
   use super::*;
+  use crate::api::{ChromaSampling, EncoderConfig, MotionSearch};
+  use crate::context::CDFContext;
+  use crate::cpu_features::CpuFeatureLevel;
+  use crate::encoder::{Frame, FrameState, Sequence};
   use crate::partition::BlockSize;
   use crate::partition::BlockSize::*;
 
@@ -1141,18 +1564,24 @@ pub mod test {
     let bit_depth: usize = 8;
     let (input_plane, rec_plane) = setup_sad::<T>();
 
-    for block in blocks {
-      let bsw = block.0.width();
-      let bsh = block.0.height();
-      let area = Area::StartingAt { x: 32, y: 40 };
-
-      let mut input_region = input_plane.region(area);
-      let mut rec_region = rec_plane.region(area);
-
-      assert_eq!(
-        block.1,
-        get_sad(&mut input_region, &mut rec_region, bsw, bsh, bit_depth)
-      );
+    // Every kernel the CPU can run gives the same result
+    let supported = CpuFeatureLevel::supported();
+    for &level in CpuFeatureLevel::ALL.iter().filter(|&&l| l <= supported) {
+      let cpu = CpuKernels::new(level);
+      for block in blocks.iter() {
+        let bsw = block.0.width();
+        let bsh = block.0.height();
+        let area = Area::StartingAt { x: 32, y: 40 };
+
+        let mut input_region = input_plane.region(area);
+        let mut rec_region = rec_plane.region(area);
+
+        assert_eq!(
+          block.1,
+          get_sad(&mut input_region, &mut rec_region, bsw, bsh, bit_depth, cpu),
+          "{:?} at level {}", block.0, level
+        );
+      }
     }
   }
 
@@ -1165,4 +1594,299 @@ pub mod test {
   fn get_sad_same_u16() {
     get_sad_same_inner::<u16>();
   }
+
+  #[test]
+  fn get_satd_flat_and_impulse() {
+    let org = Plane::wrap(vec![100u8; 8 * 8], 8);
+    let mut flat = Plane::wrap(vec![97u8; 8 * 8], 8);
+    let area = Area::StartingAt { x: 0, y: 0 };
+
+    assert_eq!(get_satd(&org.region(area), &org.region(area), 8, 8), 0);
+    assert_eq!(
+      get_satd(&org.region(area), &flat.region(area), 8, 8),
+      get_sad(
+        &org.region(area), &flat.region(area), 8, 8, 8,
+        CpuKernels::NATIVE
+      )
+    );
+
+    // A lone differing sample spreads to every coefficient of its 4x4
+    flat.data.iter_mut().for_each(|v| *v = 100);
+    flat.data[9] = 90;
+    assert_eq!(get_satd(&org.region(area), &flat.region(area), 8, 8), 16 * 10);
+  }
+
+  #[test]
+  fn mv_range_within_padding() {
+    let frame = Frame::<u8>::new(256, 192, ChromaSampling::Cs420);
+    let cfg = &frame.planes[0].cfg;
+    let (w_in_b, h_in_b) = (cfg.width / MI_SIZE, cfg.height / MI_SIZE);
+    // The sub-pel filters read up to 4 more pixels on each side
+    let (xpad, ypad) = (cfg.xpad as isize - 4, cfg.ypad as isize - 4);
+
+    for &bsize in &[BLOCK_8X8, BLOCK_16X16, BLOCK_64X64, BLOCK_128X128] {
+      let (blk_w, blk_h) = (bsize.width(), bsize.height());
+      let corners = [
+        BlockOffset { x: 0, y: 0 },
+        BlockOffset { x: w_in_b - blk_w / MI_SIZE, y: h_in_b - blk_h / MI_SIZE }
+      ];
+      for &me_range in &[1, 16, 4096] {
+        for &bo in corners.iter() {
+          let (mvx_min, mvx_max, mvy_min, mvy_max) =
+            get_mv_range(w_in_b, h_in_b, bo, blk_w, blk_h, me_range);
+          let range = me_range as isize * 8;
+          assert!(mvx_min <= 0 && mvx_max >= 0 && mvy_min <= 0 && mvy_max >= 0);
+          assert!(mvx_min >= -range && mvx_max <= range);
+          assert!(mvy_min >= -range && mvy_max <= range);
+          assert!(mvx_min > MV_LOW as isize && mvx_max < MV_UPP as isize);
+
+          let po = bo.to_luma_plane_offset();
+          assert!(po.x + mvx_min / 8 >= -xpad);
+          assert!(po.y + mvy_min / 8 >= -ypad);
+          assert!(po.x + mvx_max / 8 + blk_w as isize <= cfg.width as isize + xpad);
+          assert!(po.y + mvy_max / 8 + blk_h as isize <= cfg.height as isize + ypad);
+        }
+      }
+    }
+  }
+
+  /// Frame invariants of 128x128 frames, an input frame and a reference
+  /// frame it moves `shift` pixels right and down from
+  fn translated_frames(
+    shift: isize
+  ) -> (FrameInvariants<u8>, Frame<u8>, Frame<u8>) {
+    let mut config = EncoderConfig::default();
+    config.width = 128;
+    config.height = 128;
+    let seq = Sequence::new(&config, None);
+    let fi = FrameInvariants::<u8>::new(config, seq);
+
+    let texture = |x: isize, y: isize| {
+      (128.0 + 60.0 * (x as f64 / 6.0).sin() + 60.0 * (y as f64 / 7.0).cos()) as u8
+    };
+    let fill = |frame: &mut Frame<u8>, shift: isize| {
+      let plane = &mut frame.planes[0];
+      let stride = plane.cfg.stride;
+      for (y, row) in plane.data_origin_mut().chunks_mut(stride).take(128).enumerate() {
+        for (x, v) in row[..128].iter_mut().enumerate() {
+          *v = texture(x as isize - shift, y as isize - shift);
+        }
+      }
+    };
+    let mut org = Frame::<u8>::new(128, 128, ChromaSampling::Cs420);
+    let mut rf = org.clone();
+    fill(&mut org, 0);
+    fill(&mut rf, shift);
+    (fi, org, rf)
+  }
+
+  #[test]
+  fn searches_respect_me_range() {
+    // The input moves by 12 pixels right and down from the reference
+    let (fi, org, rf) = translated_frames(12);
+
+    let bo = BlockOffset { x: 8, y: 8 };
+    let po = bo.to_luma_plane_offset();
+    let (blk_w, blk_h) = (16, 16);
+    let (mvx_min, mvx_max, mvy_min, mvy_max) =
+      get_mv_range(fi.w_in_b, fi.h_in_b, bo, blk_w, blk_h, 4);
+    let pmv = [MotionVector::default(); 2];
+    // The true motion is a predictor, but out of range
+    let predictors = [MotionVector::default(), MotionVector { row: 96, col: 96 }];
+    let check = |mv: MotionVector| {
+      assert!(mv.col as isize >= mvx_min && mv.col as isize <= mvx_max);
+      assert!(mv.row as isize >= mvy_min && mv.row as isize <= mvy_max);
+    };
+
+    let mut mv = MotionVector::default();
+    let mut cost = std::u64::MAX;
+    diamond_me_search(
+      &fi, po, &org.planes[0], &rf.planes[0], &predictors, 8, pmv, 0,
+      mvx_min, mvx_max, mvy_min, mvy_max, blk_w, blk_h, &mut mv, &mut cost,
+      false, LAST_FRAME
+    );
+    check(mv);
+
+    let mut mv = MotionVector::default();
+    let mut cost = std::u64::MAX;
+    hex_me_search(
+      &fi, po, &org.planes[0], &rf.planes[0], &predictors, 8, pmv, 0,
+      mvx_min, mvx_max, mvy_min, mvy_max, blk_w, blk_h, &mut mv, &mut cost,
+      LAST_FRAME
+    );
+    check(mv);
+
+    let mut mv = MotionVector::default();
+    let mut cost = std::u64::MAX;
+    full_search(
+      po.x + mvx_min / 8, po.x + mvx_max / 8,
+      po.y + mvy_min / 8, po.y + mvy_max / 8,
+      blk_h, blk_w, &org.planes[0], &rf.planes[0], &mut mv, &mut cost, po, 1,
+      8, 0, pmv, fi.allow_high_precision_mv, fi.cpu
+    );
+    check(mv);
+    // The closest position to the true motion
+    assert_eq!(mv, MotionVector { row: 32, col: 32 });
+  }
+
+  #[test]
+  fn searches_find_motion() {
+    let (fi, org, rf) = translated_frames(3);
+    let bo = BlockOffset { x: 8, y: 8 };
+    let po = bo.to_luma_plane_offset();
+    let (blk_w, blk_h) = (16, 16);
+    let (mvx_min, mvx_max, mvy_min, mvy_max) =
+      get_mv_range(fi.w_in_b, fi.h_in_b, bo, blk_w, blk_h, 16);
+    let pmv = [MotionVector::default(); 2];
+    let predictors = [MotionVector::default()];
+    let motion = MotionVector { row: 24, col: 24 };
+
+    for &search in &[MotionSearch::Diamond, MotionSearch::Hex, MotionSearch::Full] {
+      let mut mv = MotionVector::default();
+      let mut cost = std::u64::MAX;
+      match search {
+        MotionSearch::Diamond => diamond_me_search(
+          &fi, po, &org.planes[0], &rf.planes[0], &predictors, 8, pmv, 0,
+          mvx_min, mvx_max, mvy_min, mvy_max, blk_w, blk_h, &mut mv,
+          &mut cost, false, LAST_FRAME
+        ),
+        MotionSearch::Hex => hex_me_search(
+          &fi, po, &org.planes[0], &rf.planes[0], &predictors, 8, pmv, 0,
+          mvx_min, mvx_max, mvy_min, mvy_max, blk_w, blk_h, &mut mv,
+          &mut cost, LAST_FRAME
+        ),
+        MotionSearch::Full => full_search(
+          po.x + mvx_min / 8, po.x + mvx_max / 8,
+          po.y + mvy_min / 8, po.y + mvy_max / 8,
+          blk_h, blk_w, &org.planes[0], &rf.planes[0], &mut mv, &mut cost,
+          po, 1, 8, 0, pmv, fi.allow_high_precision_mv,
+          fi.cpu
+        ),
+      }
+      assert_eq!(mv, motion, "{:?} search", search);
+      assert_eq!(cost, 0, "{:?} search", search);
+    }
+  }
+
+  #[test]
+  fn downsampled_search_finds_motion() {
+    // An odd motion falls between two vectors at half resolution, found by
+    // the refinement at full resolution
+    for &shift in &[3, 4] {
+      let (mut fi, org, rf) = translated_frames(shift);
+      let mut rfs = FrameState::new_with_frame(&fi, Arc::new(rf));
+      rfs.input_hres.downsample_from(&rfs.input.planes[0]);
+      rfs.input_hres.pad(fi.width, fi.height);
+      fi.rec_buffer.frames[0] = Some(Arc::new(ReferenceFrame {
+        order_hint: 0,
+        base_q_idx: fi.base_q_idx,
+        frame: rfs.input.clone(),
+        input_hres: rfs.input_hres,
+        input_qres: rfs.input_qres,
+        cdfs: CDFContext::new(fi.base_q_idx),
+        frame_mvs: rfs.frame_mvs,
+        intra_refresh: None,
+        film_grain_params: None,
+        crc: None
+      }));
+      fi.ref_frames = [0; INTER_REFS_PER_FRAME];
+      let mut fs = FrameState::new_with_frame(&fi, Arc::new(org));
+      fs.input_hres.downsample_from(&fs.input.planes[0]);
+      fs.input_hres.pad(fi.width, fi.height);
+      let ts = fs.as_tile_state_mut();
+
+      let bo = BlockOffset { x: 8, y: 8 };
+      let (mvx_min, mvx_max, mvy_min, mvy_max) =
+        get_block_mv_range(&fi, &ts, bo, BLOCK_16X16, LAST_FRAME);
+      let rec = fi.rec_buffer.frames[0].as_ref().unwrap();
+      let mut mv = MotionVector::default();
+      let mut cost = std::u64::MAX;
+      downsampled_full_pixel_me(
+        &fi, &ts, rec, bo, get_me_lambda(&fi), MotionVector::default(),
+        [MotionVector::default(); 2], mvx_min, mvx_max, mvy_min, mvy_max,
+        16, 16, &mut mv, &mut cost, LAST_FRAME
+      );
+      let motion = MotionVector { row: shift as i16 * 8, col: shift as i16 * 8 };
+      assert_eq!(mv, motion, "shift {}", shift);
+    }
+  }
+
+  #[test]
+  fn lookahead_ignores_me_range() {
+    // Motion past the default me_range of 16, found by the lookahead
+    let shift = 24;
+    let (mut fi, org, rf) = translated_frames(shift);
+    assert!(fi.config.speed_settings.me_range < shift as usize);
+    let mut rfs = FrameState::new_with_frame(&fi, Arc::new(rf));
+    rfs.input_hres.downsample_from(&rfs.input.planes[0]);
+    rfs.input_hres.pad(fi.width, fi.height);
+    rfs.input_qres.downsample_from(&rfs.input_hres);
+    rfs.input_qres.pad(fi.width, fi.height);
+    fi.rec_buffer.frames[0] = Some(Arc::new(ReferenceFrame {
+      order_hint: 0,
+      base_q_idx: fi.base_q_idx,
+      frame: rfs.input.clone(),
+      input_hres: rfs.input_hres,
+      input_qres: rfs.input_qres,
+      cdfs: CDFContext::new(fi.base_q_idx),
+      frame_mvs: rfs.frame_mvs,
+      intra_refresh: None,
+      film_grain_params: None,
+      crc: None
+    }));
+    fi.ref_frames = [0; INTER_REFS_PER_FRAME];
+    let mut fs = FrameState::new_with_frame(&fi, Arc::new(org));
+    fs.input_hres.downsample_from(&fs.input.planes[0]);
+    fs.input_hres.pad(fi.width, fi.height);
+    fs.input_qres.downsample_from(&fs.input_hres);
+    fs.input_qres.pad(fi.width, fi.height);
+    let ts = fs.as_tile_state_mut();
+
+    let mv = estimate_motion_ss4(
+      &fi, &ts, BLOCK_64X64, 0, BlockOffset { x: 0, y: 0 }
+    );
+    let motion = MotionVector { row: shift as i16 * 8, col: shift as i16 * 8 };
+    assert_eq!(mv, Some(motion));
+  }
+
+  #[test]
+  fn subpel_refinement() {
+    let (mut fi, org, rf) = translated_frames(3);
+    fi.rec_buffer.frames[0] = Some(Arc::new(ReferenceFrame {
+      order_hint: 0,
+      base_q_idx: fi.base_q_idx,
+      frame: Arc::new(rf),
+      input_hres: Plane::new(64, 64, 1, 1, 0, 0),
+      input_qres: Plane::new(32, 32, 2, 2, 0, 0),
+      cdfs: CDFContext::new(fi.base_q_idx),
+      frame_mvs: Vec::new(),
+      intra_refresh: None,
+      film_grain_params: None,
+      crc: None
+    }));
+    fi.ref_frames = [0; INTER_REFS_PER_FRAME];
+    let mut fs = FrameState::new_with_frame(&fi, Arc::new(org));
+    let ts = fs.as_tile_state_mut();
+
+    let bo = BlockOffset { x: 8, y: 8 };
+    let pmv = [MotionVector::default(); 2];
+    let motion = MotionVector { row: 24, col: 24 };
+    let refine = |fi: &FrameInvariants<u8>, start_mv| {
+      refine_subpel(fi, &ts, bo, BLOCK_16X16, LAST_FRAME, start_mv, pmv)
+    };
+
+    // Half a pel away from the true motion
+    fi.allow_high_precision_mv = true;
+    assert_eq!(refine(&fi, MotionVector { row: 28, col: 20 }), motion);
+
+    // The start is first rounded to quarter pels
+    fi.allow_high_precision_mv = false;
+    let mv = refine(&fi, MotionVector { row: 21, col: 27 });
+    assert_eq!(mv, motion);
+
+    // Integer vectors are only rounded
+    fi.force_integer_mv = 1;
+    let start_mv = MotionVector { row: 19, col: 29 };
+    assert_eq!(refine(&fi, start_mv), MotionVector { row: 16, col: 32 });
+  }
 }
@@ -9,8 +9,73 @@
 
 use crate::encoder::Frame;
 use crate::plane::Plane;
+use crate::tiling::Area;
 use crate::util::{CastFromPrimitive, Pixel};
 
+/// Size of the square windows SSIM is computed over
+const SSIM_WINDOW: usize = 8;
+/// Distance between two consecutive SSIM windows
+const SSIM_STEP: usize = 4;
+
+/// Quality metrics of a reconstructed frame, for the Y, U and V planes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameMetrics {
+  pub mse: [f64; 3],
+  pub psnr: [f64; 3],
+  pub ssim: [f64; 3],
+}
+
+/// Quality metrics accumulated over several frames, for the Y, U and V planes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsSummary {
+  pub frames: u64,
+  /// Sum of the per-frame mean squared errors
+  pub mse_sum: [f64; 3],
+  /// Sum of the per-frame SSIM values
+  pub ssim_sum: [f64; 3],
+}
+
+impl MetricsSummary {
+  pub fn add_frame(&mut self, metrics: &FrameMetrics) {
+    self.frames += 1;
+    for p in 0..3 {
+      self.mse_sum[p] += metrics.mse[p];
+      self.ssim_sum[p] += metrics.ssim[p];
+    }
+  }
+
+  /// PSNR of the whole sequence, derived from the mean of the frame MSEs
+  pub fn psnr(&self, bit_depth: usize) -> [f64; 3] {
+    let mut psnr = [0.0; 3];
+    for p in 0..3 {
+      psnr[p] = mse_to_psnr(self.mse_sum[p] / self.frames.max(1) as f64, bit_depth);
+    }
+    psnr
+  }
+
+  /// Mean of the frame SSIMs
+  pub fn ssim(&self) -> [f64; 3] {
+    let mut ssim = [0.0; 3];
+    for p in 0..3 {
+      ssim[p] = self.ssim_sum[p] / self.frames.max(1) as f64;
+    }
+    ssim
+  }
+}
+
+/// Calculates the MSE, PSNR and SSIM of each plane of a `Frame` by comparing the
+/// original (uncompressed) to the compressed version of the frame.
+pub fn calculate_frame_metrics<T: Pixel>(original: &Frame<T>, compressed: &Frame<T>, bit_depth: usize) -> FrameMetrics {
+  let mut metrics = FrameMetrics::default();
+  for p in 0..3 {
+    let mse = calculate_plane_mse(&original.planes[p], &compressed.planes[p]);
+    metrics.mse[p] = mse;
+    metrics.psnr[p] = mse_to_psnr(mse, bit_depth);
+    metrics.ssim[p] = calculate_plane_ssim(&original.planes[p], &compressed.planes[p], bit_depth);
+  }
+  metrics
+}
+
 /// Calculates the PSNR for a `Frame` by comparing the original (uncompressed) to the compressed
 /// version of the frame. Higher PSNR is better--PSNR is capped at 100 in order to avoid skewed
 /// statistics from e.g. all black frames, which would otherwise show a PSNR of infinity.
@@ -22,10 +87,19 @@ pub fn calculate_frame_psnr<T: Pixel>(original: &Frame<T>, compressed: &Frame<T>
     calculate_plane_psnr(&original.planes[2], &compressed.planes[2], bit_depth))
 }
 
+/// Combines the PSNRs of the Y, U and V planes into a single value, weighting
+/// luma 6/8 and each chroma plane 1/8 as is customary for 4:2:0 content.
+pub fn weighted_psnr(psnr: (f64, f64, f64)) -> f64 {
+  (6.0 * psnr.0 + psnr.1 + psnr.2) / 8.0
+}
+
 /// Calculate the PSNR for a `Plane` by comparing the original (uncompressed) to the compressed
 /// version.
 fn calculate_plane_psnr<T: Pixel>(original: &Plane<T>, compressed: &Plane<T>, bit_depth: usize) -> f64 {
-  let mse = calculate_plane_mse(original, compressed);
+  mse_to_psnr(calculate_plane_mse(original, compressed), bit_depth)
+}
+
+fn mse_to_psnr(mse: f64, bit_depth: usize) -> f64 {
   if mse <= 0.000_000_000_1 {
     return 100.0;
   }
@@ -41,3 +115,113 @@ fn calculate_plane_mse<T: Pixel>(original: &Plane<T>, compressed: &Plane<T>) ->
     .map(|err| err * err)
     .sum::<u64>() as f64 / (original.cfg.width * original.cfg.height) as f64
 }
+
+/// Calculate the SSIM for a `Plane` by comparing the original (uncompressed) to the compressed
+/// version, as the mean SSIM of 8x8 windows spaced 4 pixels apart.
+///
+/// See https://en.wikipedia.org/wiki/Structural_similarity for more details.
+fn calculate_plane_ssim<T: Pixel>(original: &Plane<T>, compressed: &Plane<T>, bit_depth: usize) -> f64 {
+  let max = ((1 << bit_depth) - 1) as f64;
+  let c1 = (0.01 * max) * (0.01 * max);
+  let c2 = (0.03 * max) * (0.03 * max);
+
+  let width = original.cfg.width;
+  let height = original.cfg.height;
+  let window_w = SSIM_WINDOW.min(width);
+  let window_h = SSIM_WINDOW.min(height);
+  let n = (window_w * window_h) as f64;
+
+  let mut ssim_sum = 0.0;
+  let mut windows = 0;
+  for y in (0..=height - window_h).step_by(SSIM_STEP) {
+    for x in (0..=width - window_w).step_by(SSIM_STEP) {
+      let area = Area::Rect { x: x as isize, y: y as isize, width: window_w, height: window_h };
+      let org = original.region(area);
+      let rec = compressed.region(area);
+
+      let (mut sum_a, mut sum_b) = (0u64, 0u64);
+      let (mut sum_aa, mut sum_bb, mut sum_ab) = (0u64, 0u64, 0u64);
+      for (row_a, row_b) in org.rows_iter().zip(rec.rows_iter()) {
+        for (&a, &b) in row_a.iter().zip(row_b) {
+          let (a, b) = (u64::cast_from(a), u64::cast_from(b));
+          sum_a += a;
+          sum_b += b;
+          sum_aa += a * a;
+          sum_bb += b * b;
+          sum_ab += a * b;
+        }
+      }
+
+      let mu_a = sum_a as f64 / n;
+      let mu_b = sum_b as f64 / n;
+      let var_a = sum_aa as f64 / n - mu_a * mu_a;
+      let var_b = sum_bb as f64 / n - mu_b * mu_b;
+      let cov = sum_ab as f64 / n - mu_a * mu_b;
+      ssim_sum += ((2.0 * mu_a * mu_b + c1) * (2.0 * cov + c2))
+        / ((mu_a * mu_a + mu_b * mu_b + c1) * (var_a + var_b + c2));
+      windows += 1;
+    }
+  }
+  ssim_sum / windows as f64
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::api::ChromaSampling;
+
+  fn gradient_frame(w: usize, h: usize) -> Frame<u8> {
+    let mut frame = Frame::new(w, h, ChromaSampling::Cs420);
+    for plane in frame.planes.iter_mut() {
+      let stride = plane.cfg.stride;
+      for (y, row) in plane.data.chunks_mut(stride).enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+          *pixel = ((x + 2 * y) % 256) as u8;
+        }
+      }
+    }
+    frame
+  }
+
+  #[test]
+  fn identical_frames() {
+    let frame = gradient_frame(64, 48);
+    let metrics = calculate_frame_metrics(&frame, &frame, 8);
+    for p in 0..3 {
+      assert_eq!(metrics.mse[p], 0.0);
+      assert_eq!(metrics.psnr[p], 100.0);
+      assert!((metrics.ssim[p] - 1.0).abs() < 1e-9);
+    }
+  }
+
+  #[test]
+  fn distorted_frames() {
+    let original = gradient_frame(64, 48);
+    let mut compressed = original.clone();
+    // A constant offset of 2 on the luma plane
+    let stride = compressed.planes[0].cfg.stride;
+    for row in compressed.planes[0].data.chunks_mut(stride) {
+      for pixel in row.iter_mut() {
+        *pixel = pixel.saturating_add(2);
+      }
+    }
+    let metrics = calculate_frame_metrics(&original, &compressed, 8);
+    assert!((metrics.mse[0] - 4.0).abs() < 0.5);
+    assert!((metrics.psnr[0] - calculate_frame_psnr(&original, &compressed, 8).0).abs() < 1e-9);
+    assert!(metrics.ssim[0] < 1.0 && metrics.ssim[0] > 0.9);
+    assert!((metrics.ssim[1] - 1.0).abs() < 1e-9);
+
+    let mut summary = MetricsSummary::default();
+    summary.add_frame(&metrics);
+    summary.add_frame(&calculate_frame_metrics(&original, &original, 8));
+    assert!((summary.psnr(8)[0] - mse_to_psnr(metrics.mse[0] / 2.0, 8)).abs() < 1e-9);
+    assert!((summary.ssim()[0] - (metrics.ssim[0] + 1.0) / 2.0).abs() < 1e-9);
+  }
+
+  #[test]
+  fn weighted_psnr_favors_luma() {
+    assert_eq!(weighted_psnr((40.0, 40.0, 40.0)), 40.0);
+    assert_eq!(weighted_psnr((40.0, 48.0, 48.0)), 42.0);
+    assert_eq!(weighted_psnr((48.0, 40.0, 40.0)), 46.0);
+  }
+}
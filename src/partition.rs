@@ -10,10 +10,12 @@
 #![allow(non_camel_case_types)]
 #![allow(dead_code)]
 
-use std::ops;
+use std::convert::TryFrom;
+use std::{fmt, ops};
 use self::BlockSize::*;
 use self::TxSize::*;
 use crate::context::*;
+use crate::cpu_features::CpuKernels;
 use crate::encoder::FrameInvariants;
 use crate::mc::*;
 use crate::plane::*;
@@ -22,7 +24,7 @@ use crate::tiling::*;
 use crate::util::*;
 
 // LAST_FRAME through ALTREF_FRAME correspond to slots 0-6.
-#[derive(PartialEq, Eq, Copy, Clone)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
 pub enum RefType {
   INTRA_FRAME = 0,
   LAST_FRAME = 1,
@@ -75,6 +77,31 @@ pub const BWDREF_ALTREF2_FRAMES: usize = 7; // { BWDREF_FRAME, ALTREF2_FRAME }
 pub const ALTREF2_ALTREF_FRAMES: usize = 8; // { ALTREF2_FRAME, ALTREF_FRAME }
 pub const TOTAL_UNIDIR_COMP_REFS: usize = 9;
 
+/// Uni-directional compound references, indexed by the `*_FRAMES` constants
+pub static UNIDIR_COMP_REF_PAIRS: [[RefType; 2]; TOTAL_UNIDIR_COMP_REFS] = [
+  [LAST_FRAME, LAST2_FRAME],
+  [LAST_FRAME, LAST3_FRAME],
+  [LAST_FRAME, GOLDEN_FRAME],
+  [BWDREF_FRAME, ALTREF_FRAME],
+  [LAST2_FRAME, LAST3_FRAME],
+  [LAST2_FRAME, GOLDEN_FRAME],
+  [LAST3_FRAME, GOLDEN_FRAME],
+  [BWDREF_FRAME, ALTREF2_FRAME],
+  [ALTREF2_FRAME, ALTREF_FRAME]
+];
+
+/// Whether the pair can be coded as a compound reference: a forward
+/// reference followed by a backward one, or a uni-directional pair of
+/// `UNIDIR_COMP_REF_PAIRS`.
+pub fn is_valid_comp_ref(ref_frames: [RefType; 2]) -> bool {
+  let is_inter = |r: RefType| r != INTRA_FRAME && r != NONE_FRAME;
+  if !is_inter(ref_frames[0]) || !is_inter(ref_frames[1]) {
+    return false;
+  }
+  (ref_frames[0].is_fwd_ref() && ref_frames[1].is_bwd_ref())
+    || UNIDIR_COMP_REF_PAIRS.contains(&ref_frames)
+}
+
 // NOTE: UNIDIR_COMP_REFS is the number of uni-directional reference pairs
 //       that are explicitly signaled.
 pub const UNIDIR_COMP_REFS: usize = BWDREF_ALTREF_FRAMES + 1;
@@ -646,6 +673,72 @@ impl TxSize {
   pub fn is_rect(self) -> bool {
     self.width_log2() != self.height_log2()
   }
+
+  /// Size of the transforms a split of this one gives, itself for 4x4
+  pub fn sub(self) -> TxSize {
+    sub_tx_size_map[self as usize]
+  }
+
+  /// Offsets in 4x4 units of the transforms a split gives, in coding order
+  pub fn sub_offsets(self) -> Vec<(usize, usize)> {
+    let sub = self.sub();
+    let mut offsets = Vec::new();
+    for y in (0..self.height_mi()).step_by(sub.height_mi()) {
+      for x in (0..self.width_mi()).step_by(sub.width_mi()) {
+        offsets.push((x, y));
+      }
+    }
+    offsets
+  }
+}
+
+/// Transform partition of an inter block, splitting the largest transform
+/// of the block down to `MAX_VARTX_DEPTH` levels.
+///
+/// Bit 0 splits the largest transform, and bit `1 + i` further splits the
+/// `i`-th transform it gives, in coding order.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TxPartition(u8);
+
+impl TxPartition {
+  /// The largest transform, not split
+  pub const NONE: TxPartition = TxPartition(0);
+  /// The largest transform split once
+  pub const SPLIT: TxPartition = TxPartition(1);
+
+  pub fn is_split(self) -> bool {
+    self.0 & 1 != 0
+  }
+
+  pub fn is_sub_split(self, i: usize) -> bool {
+    self.is_split() && (self.0 >> (1 + i)) & 1 != 0
+  }
+
+  /// The partition with the split of the `i`-th sub transform toggled
+  pub fn toggle_sub_split(self, i: usize) -> TxPartition {
+    debug_assert!(self.is_split());
+    TxPartition(self.0 ^ (2 << i))
+  }
+
+  /// Offsets in 4x4 units and sizes of the transforms of the partition of
+  /// `tx_size`, in coding order
+  pub fn tx_blocks(self, tx_size: TxSize) -> Vec<(usize, usize, TxSize)> {
+    if !self.is_split() || tx_size == TX_4X4 {
+      return vec![(0, 0, tx_size)];
+    }
+    let sub = tx_size.sub();
+    let mut blocks = Vec::new();
+    for (i, (x, y)) in tx_size.sub_offsets().into_iter().enumerate() {
+      if self.is_sub_split(i) && sub != TX_4X4 {
+        for (x2, y2) in sub.sub_offsets() {
+          blocks.push((x + x2, y + y2, sub.sub()));
+        }
+      } else {
+        blocks.push((x, y, sub));
+      }
+    }
+    blocks
+  }
 }
 
 pub const TX_TYPES: usize = 16;
@@ -704,6 +797,67 @@ pub enum PredictionMode {
   NEW_NEWMV
 }
 
+/// A byte out of the range of the enum it is converted to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InvalidDiscriminant(pub u8);
+
+impl fmt::Display for InvalidDiscriminant {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Invalid discriminant {}", self.0)
+  }
+}
+
+impl std::error::Error for InvalidDiscriminant {}
+
+/// Converts the enum to and from its discriminant, for compact storage.
+/// The variants are listed in discriminant order; sentinels are left out so
+/// that they are rejected.
+macro_rules! impl_u8_conversions {
+  ($E:ident, [$($V:ident),*]) => {
+    impl From<$E> for u8 {
+      fn from(v: $E) -> u8 {
+        v as u8
+      }
+    }
+
+    impl TryFrom<u8> for $E {
+      type Error = InvalidDiscriminant;
+
+      fn try_from(v: u8) -> Result<Self, Self::Error> {
+        const ALL: &[$E] = &[$($E::$V),*];
+        ALL.get(v as usize).cloned().ok_or(InvalidDiscriminant(v))
+      }
+    }
+  };
+}
+
+impl_u8_conversions!(BlockSize, [
+  BLOCK_4X4, BLOCK_4X8, BLOCK_8X4, BLOCK_8X8, BLOCK_8X16, BLOCK_16X8,
+  BLOCK_16X16, BLOCK_16X32, BLOCK_32X16, BLOCK_32X32, BLOCK_32X64,
+  BLOCK_64X32, BLOCK_64X64, BLOCK_64X128, BLOCK_128X64, BLOCK_128X128,
+  BLOCK_4X16, BLOCK_16X4, BLOCK_8X32, BLOCK_32X8, BLOCK_16X64, BLOCK_64X16
+]);
+
+impl_u8_conversions!(TxSize, [
+  TX_4X4, TX_8X8, TX_16X16, TX_32X32, TX_64X64, TX_4X8, TX_8X4, TX_8X16,
+  TX_16X8, TX_16X32, TX_32X16, TX_32X64, TX_64X32, TX_4X16, TX_16X4, TX_8X32,
+  TX_32X8, TX_16X64, TX_64X16
+]);
+
+impl_u8_conversions!(TxType, [
+  DCT_DCT, ADST_DCT, DCT_ADST, ADST_ADST, FLIPADST_DCT, DCT_FLIPADST,
+  FLIPADST_FLIPADST, ADST_FLIPADST, FLIPADST_ADST, IDTX, V_DCT, H_DCT,
+  V_ADST, H_ADST, V_FLIPADST, H_FLIPADST
+]);
+
+impl_u8_conversions!(PredictionMode, [
+  DC_PRED, V_PRED, H_PRED, D45_PRED, D135_PRED, D117_PRED, D153_PRED,
+  D207_PRED, D63_PRED, SMOOTH_PRED, SMOOTH_V_PRED, SMOOTH_H_PRED, PAETH_PRED,
+  UV_CFL_PRED, NEARESTMV, NEAR0MV, NEAR1MV, NEAR2MV, GLOBALMV, NEWMV,
+  NEAREST_NEARESTMV, NEAR_NEARMV, NEAREST_NEWMV, NEW_NEARESTMV, NEAR_NEWMV,
+  NEW_NEARMV, GLOBAL_GLOBALMV, NEW_NEWMV
+]);
+
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
 pub enum InterIntraMode {
   II_DC_PRED,
@@ -796,6 +950,39 @@ impl MotionVector {
   pub fn is_zero(self) -> bool {
     self.row == 0 && self.col == 0
   }
+
+  /// The vector on a plane `2^shift` times larger in each dimension, e.g.
+  /// at full resolution for a vector found on the half resolution plane
+  pub fn upscale(self, shift: usize) -> Self {
+    Self { row: self.row << shift, col: self.col << shift }
+  }
+
+  /// The vector on a plane `2^shift` times smaller in each dimension,
+  /// rounded down
+  pub fn downscale(self, shift: usize) -> Self {
+    Self { row: self.row >> shift, col: self.col >> shift }
+  }
+
+  /// Rounds to the nearest vector codable with `precision`, as done by the
+  /// decoder: to the nearest full pel with ties towards zero, or towards
+  /// zero from eighth to quarter pels
+  pub fn round_to_precision(self, precision: MvSubpelPrecision) -> Self {
+    fn round(v: i16, precision: MvSubpelPrecision) -> i16 {
+      match precision {
+        MvSubpelPrecision::MV_SUBPEL_NONE => {
+          let rem = v % 8;
+          if rem.abs() <= 4 {
+            v - rem
+          } else {
+            v - rem + 8 * rem.signum()
+          }
+        }
+        MvSubpelPrecision::MV_SUBPEL_LOW_PRECISION => v - v % 2,
+        MvSubpelPrecision::MV_SUBPEL_HIGH_PRECISION => v
+      }
+    }
+    Self { row: round(self.row, precision), col: round(self.col, precision) }
+  }
 }
 
 pub const NEWMV_MODE_CONTEXTS: usize = 7;
@@ -815,6 +1002,91 @@ pub static RAV1E_PARTITION_TYPES: &'static [PartitionType] =
   &[PartitionType::PARTITION_NONE, PartitionType::PARTITION_HORZ,
     PartitionType::PARTITION_VERT, PartitionType::PARTITION_SPLIT];
 
+/// Returns the partition types of a block of size `bsize` into valid
+/// subblocks, in the order of `PartitionType`. Blocks which cannot be split,
+/// such as 4x4 and rectangular blocks, only have `PARTITION_NONE`.
+pub fn valid_partitions(
+  bsize: BlockSize
+) -> impl Iterator<Item = PartitionType> {
+  use self::PartitionType::*;
+  static PARTITIONS: [PartitionType; EXT_PARTITION_TYPES] = [
+    PARTITION_NONE, PARTITION_HORZ, PARTITION_VERT, PARTITION_SPLIT,
+    PARTITION_HORZ_A, PARTITION_HORZ_B, PARTITION_VERT_A, PARTITION_VERT_B,
+    PARTITION_HORZ_4, PARTITION_VERT_4
+  ];
+  PARTITIONS.iter()
+    .cloned()
+    .filter(move |&partition| bsize.subsize(partition) != BLOCK_INVALID)
+    // The partition symbol of 8x8 blocks has no extended types
+    .filter(move |&partition| bsize != BLOCK_8X8 || partition <= PARTITION_SPLIT)
+}
+
+/// Prior on the partition of a block from the sizes of the blocks coded
+/// above and to the left of it, which are final when the block is searched.
+/// Only used when `SpeedSettings::partition_heuristic` is enabled.
+#[derive(Clone, Copy, Debug)]
+pub struct PartitionHeuristic {
+  above: Option<BlockSize>,
+  left: Option<BlockSize>
+}
+
+impl PartitionHeuristic {
+  /// Neighbors outside the tile are unknown
+  pub fn new(blocks: &TileBlocks<'_>, bo: BlockOffset) -> Self {
+    PartitionHeuristic {
+      above: if bo.y > 0 { Some(blocks.above_of(bo).bsize) } else { None },
+      left: if bo.x > 0 { Some(blocks.left_of(bo).bsize) } else { None }
+    }
+  }
+
+  /// Both neighbors span the whole edge they share with the block
+  fn likely_whole(self, bsize: BlockSize) -> bool {
+    match (self.above, self.left) {
+      (Some(above), Some(left)) =>
+        above.width() >= bsize.width() && left.height() >= bsize.height(),
+      _ => false
+    }
+  }
+
+  /// Both neighbors are at most a quarter of the edge they share with the
+  /// block, which then likely splits at least twice
+  fn likely_split(self, bsize: BlockSize) -> bool {
+    match (self.above, self.left) {
+      (Some(above), Some(left)) =>
+        above.width() * 4 <= bsize.width()
+          && left.height() * 4 <= bsize.height(),
+      _ => false
+    }
+  }
+
+  /// Orders the candidate partitions of a block of size `bsize` in place,
+  /// most likely first: the unsplit block between larger neighbors, or its
+  /// split between much smaller ones, then coding it whole last. Candidates
+  /// are kept as is without a clear prior. None is dropped, as the
+  /// partitions chosen are the prior of the next blocks: the search saves
+  /// time by exiting early on the candidates costlier than the first ones.
+  pub fn rank(self, bsize: BlockSize, candidates: &mut [PartitionType]) {
+    use self::PartitionType::*;
+    let (first, last) = if self.likely_whole(bsize) {
+      (PARTITION_NONE, None)
+    } else if self.likely_split(bsize) {
+      (PARTITION_SPLIT, Some(PARTITION_NONE))
+    } else {
+      return;
+    };
+    // The sort is stable, and does not allocate for so few candidates
+    candidates.sort_by_key(|&p| {
+      if p == first {
+        0
+      } else if Some(p) == last {
+        2
+      } else {
+        1
+      }
+    });
+  }
+}
+
 pub static RAV1E_TX_TYPES: &'static [TxType] = &[
   TxType::DCT_DCT,
   TxType::ADST_DCT,
@@ -853,15 +1125,33 @@ pub enum MvJointType {
 
 pub fn get_intra_edges<T: Pixel>(
   dst: &PlaneRegion<'_, T>,
-  po: PlaneOffset,
+  po: TilePlaneOffset,
   tx_size: TxSize,
   bit_depth: usize,
-  opt_mode: Option<PredictionMode>
+  opt_mode: Option<PredictionMode>,
+  intra_param: IntraParam
 ) -> AlignedArray<[T; 4 * MAX_TX_SIZE + 1]> {
-  let plane_cfg = &dst.plane_cfg;
-
   let mut edge_buf: AlignedArray<[T; 4 * MAX_TX_SIZE + 1]> =
     UninitializedAlignedArray();
+  gather_intra_edges(
+    &mut edge_buf, dst, po, tx_size, bit_depth, opt_mode, intra_param
+  );
+  edge_buf
+}
+
+/// Writes the edges `opt_mode` predicts from, all of them for `None`,
+/// leaving the rest of `edge_buf` untouched
+fn gather_intra_edges<T: Pixel>(
+  edge_buf: &mut AlignedArray<[T; 4 * MAX_TX_SIZE + 1]>,
+  dst: &PlaneRegion<'_, T>,
+  po: TilePlaneOffset,
+  tx_size: TxSize,
+  bit_depth: usize,
+  opt_mode: Option<PredictionMode>,
+  intra_param: IntraParam
+) {
+  let plane_cfg = &dst.plane_cfg;
+
   let base = 128u16 << (bit_depth - 8);
 
   {
@@ -869,8 +1159,8 @@ pub fn get_intra_edges<T: Pixel>(
     let (left, not_left) = edge_buf.array.split_at_mut(2*MAX_TX_SIZE);
     let (top_left, above) = not_left.split_at_mut(1);
 
-    let x = po.x as usize;
-    let y = po.y as usize;
+    let x = po.0.x as usize;
+    let y = po.0.y as usize;
 
     let mut needs_left = true;
     let mut needs_topleft = true;
@@ -892,13 +1182,27 @@ pub fn get_intra_edges<T: Pixel>(
       let dc_or_cfl =
         mode == PredictionMode::DC_PRED || mode == PredictionMode::UV_CFL_PRED;
 
-      needs_left = mode != PredictionMode::V_PRED && (!dc_or_cfl || x != 0)
-        && !(mode == PredictionMode::D45_PRED || mode == PredictionMode::D63_PRED);
-      needs_topleft = mode == PredictionMode::PAETH_PRED || mode == PredictionMode::D117_PRED
-      || mode == PredictionMode::D135_PRED || mode == PredictionMode::D153_PRED;
-      needs_top = mode != PredictionMode::H_PRED && (!dc_or_cfl || y != 0);
-      needs_topright = mode == PredictionMode::D45_PRED || mode == PredictionMode::D63_PRED;
-      needs_bottomleft = mode == PredictionMode::D207_PRED;
+      if mode.is_directional() {
+        // The angle delta can move V_PRED and H_PRED out of their quadrant
+        let angle_delta = match intra_param {
+          IntraParam::AngleDelta(val) => val,
+          _ => 0
+        };
+        let p_angle = pred_angle(mode, angle_delta);
+
+        needs_left = p_angle > 90;
+        // The edge filter starts from the top-left sample
+        needs_topleft = true;
+        needs_top = p_angle < 180;
+        needs_topright = p_angle < 90;
+        needs_bottomleft = p_angle > 180;
+      } else {
+        needs_left = !dc_or_cfl || x != 0;
+        needs_topleft = mode == PredictionMode::PAETH_PRED;
+        needs_top = !dc_or_cfl || y != 0;
+        needs_topright = false;
+        needs_bottomleft = false;
+      }
     }
 
     // Needs left
@@ -941,16 +1245,19 @@ pub fn get_intra_edges<T: Pixel>(
     if needs_topright {
       debug_assert!(plane_cfg.xdec <= 1 && plane_cfg.ydec <= 1);
 
-      let bo = BlockOffset {
-        x: x >> (2 - plane_cfg.xdec),
-        y: y >> (2 - plane_cfg.ydec)
-      };
+      let bo = po.block_offset(plane_cfg.xdec, plane_cfg.ydec);
 
       let bsize = BlockSize::from_width_and_height(
           tx_size.width() << plane_cfg.xdec,
           tx_size.height() << plane_cfg.ydec
         );
 
+      // The predictors read as many samples past the top edge as the
+      // transform is high. Up to its width of them are copied from the
+      // above-right block, within the tile, like libaom does, and the last
+      // one copied is repeated for the rest: wide transforms only repeat
+      // samples at the right edge of the tile or without a top-right block.
+      let num_topright = tx_size.height();
       let num_avail = if y != 0 && has_tr(bo, bsize) {
         tx_size.width().min(dst.rect().width - x - tx_size.width())
       } else {
@@ -960,9 +1267,9 @@ pub fn get_intra_edges<T: Pixel>(
         above[tx_size.width()..tx_size.width() + num_avail]
         .copy_from_slice(&dst[y - 1][x + tx_size.width()..x + tx_size.width() + num_avail]);
       }
-      if num_avail < tx_size.height() {
+      if num_avail < num_topright {
         let val = above[tx_size.width() + num_avail - 1];
-        for v in above[tx_size.width() + num_avail..tx_size.width() + tx_size.height()].iter_mut() {
+        for v in above[tx_size.width() + num_avail..tx_size.width() + num_topright].iter_mut() {
           *v = val;
         }
       }
@@ -972,16 +1279,16 @@ pub fn get_intra_edges<T: Pixel>(
     if needs_bottomleft {
       debug_assert!(plane_cfg.xdec <= 1 && plane_cfg.ydec <= 1);
 
-      let bo = BlockOffset {
-        x: x >> (2 - plane_cfg.xdec),
-        y: y >> (2 - plane_cfg.ydec)
-        };
+      let bo = po.block_offset(plane_cfg.xdec, plane_cfg.ydec);
 
       let bsize = BlockSize::from_width_and_height(
         tx_size.width() << plane_cfg.xdec,
         tx_size.height() << plane_cfg.ydec
         );
 
+      // Likewise, as many samples below the left edge as the transform is
+      // wide, up to its height of them copied from the bottom-left block
+      let num_bottomleft = tx_size.width();
       let num_avail = if x != 0 && has_bl(bo, bsize) {
         tx_size.height().min(dst.rect().height - y - tx_size.height())
       } else {
@@ -992,9 +1299,9 @@ pub fn get_intra_edges<T: Pixel>(
           left[2*MAX_TX_SIZE - tx_size.height() - 1 - i] = dst[y + tx_size.height() + i][x - 1];
         }
       }
-      if num_avail < tx_size.width() {
+      if num_avail < num_bottomleft {
         let val = left[2 * MAX_TX_SIZE - tx_size.height() - num_avail];
-        for v in left[(2 * MAX_TX_SIZE - tx_size.height() - tx_size.width())
+        for v in left[(2 * MAX_TX_SIZE - tx_size.height() - num_bottomleft)
           ..(2 * MAX_TX_SIZE - tx_size.height() - num_avail)]
           .iter_mut()
         {
@@ -1004,64 +1311,65 @@ pub fn get_intra_edges<T: Pixel>(
     }
 
   }
-  edge_buf
 }
 
 impl PredictionMode {
   pub fn predict_intra<T: Pixel>(
     self, tile_rect: TileRect, dst: &mut PlaneRegionMut<'_, T>, tx_size: TxSize, bit_depth: usize,
-    ac: &[i16], alpha: i16, edge_buf: &AlignedArray<[T; 4 * MAX_TX_SIZE + 1]>
+    ac: &[i16], intra_param: IntraParam, edge_buf: &AlignedArray<[T; 4 * MAX_TX_SIZE + 1]>,
+    edge_filter: Option<IntraEdgeFilterType>, cpu: CpuKernels
   ) {
     assert!(self.is_intra());
 
     match tx_size {
       TxSize::TX_4X4 =>
-        self.predict_intra_inner::<Block4x4, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block4x4, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_8X8 =>
-        self.predict_intra_inner::<Block8x8, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block8x8, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_16X16 =>
-        self.predict_intra_inner::<Block16x16, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block16x16, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_32X32 =>
-        self.predict_intra_inner::<Block32x32, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block32x32, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_64X64 =>
-        self.predict_intra_inner::<Block64x64, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block64x64, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
 
       TxSize::TX_4X8 =>
-        self.predict_intra_inner::<Block4x8, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block4x8, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_8X4 =>
-        self.predict_intra_inner::<Block8x4, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block8x4, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_8X16 =>
-        self.predict_intra_inner::<Block8x16, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block8x16, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_16X8 =>
-        self.predict_intra_inner::<Block16x8, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block16x8, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_16X32 =>
-        self.predict_intra_inner::<Block16x32, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block16x32, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_32X16 =>
-        self.predict_intra_inner::<Block32x16, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block32x16, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_32X64 =>
-        self.predict_intra_inner::<Block32x64, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block32x64, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_64X32 =>
-        self.predict_intra_inner::<Block64x32, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block64x32, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
 
       TxSize::TX_4X16 =>
-        self.predict_intra_inner::<Block4x16, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block4x16, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_16X4 =>
-        self.predict_intra_inner::<Block16x4, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block16x4, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_8X32 =>
-        self.predict_intra_inner::<Block8x32, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block8x32, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_32X8 =>
-        self.predict_intra_inner::<Block32x8, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block32x8, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_16X64 =>
-        self.predict_intra_inner::<Block16x64, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block16x64, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
       TxSize::TX_64X16 =>
-        self.predict_intra_inner::<Block64x16, _>(tile_rect, dst, bit_depth, ac, alpha, edge_buf),
+        self.predict_intra_inner::<Block64x16, _>(tile_rect, dst, bit_depth, ac, intra_param, edge_buf, edge_filter, cpu),
     }
   }
 
   #[inline(always)]
   fn predict_intra_inner<B: Intra<T>, T: Pixel>(
     self, tile_rect: TileRect, dst: &mut PlaneRegionMut<'_, T>, bit_depth: usize, ac: &[i16],
-    alpha: i16, edge_buf: &AlignedArray<[T; 4 * MAX_TX_SIZE + 1]>
+    intra_param: IntraParam, edge_buf: &AlignedArray<[T; 4 * MAX_TX_SIZE + 1]>,
+    edge_filter: Option<IntraEdgeFilterType>, cpu: CpuKernels
   ) {
     // left pixels are order from bottom to top and right-aligned
     let (left, not_left) = edge_buf.array.split_at(2*MAX_TX_SIZE);
@@ -1073,6 +1381,15 @@ impl PredictionMode {
     let x = frame_x as usize - tile_rect.x;
     let y = frame_y as usize - tile_rect.y;
 
+    let alpha = match intra_param {
+      IntraParam::Alpha(val) => val,
+      _ => 0
+    };
+    let angle_delta = match intra_param {
+      IntraParam::AngleDelta(val) => val,
+      _ => 0
+    };
+
     let mode: PredictionMode = match self {
       PredictionMode::PAETH_PRED => match (x, y) {
         (0, 0) => PredictionMode::DC_PRED,
@@ -1095,20 +1412,21 @@ impl PredictionMode {
 
     match mode {
       PredictionMode::DC_PRED => match (x, y) {
-        (0, 0) => B::pred_dc_128(dst, bit_depth),
-        (_, 0) => B::pred_dc_left(dst, above_slice, left_slice),
-        (0, _) => B::pred_dc_top(dst, above_slice, left_slice),
-        _ => B::pred_dc(dst, above_slice, left_slice)
+        (0, 0) => B::pred_dc_128(dst, bit_depth, cpu),
+        (_, 0) => B::pred_dc_left(dst, above_slice, left_slice, cpu),
+        (0, _) => B::pred_dc_top(dst, above_slice, left_slice, cpu),
+        _ => B::pred_dc(dst, above_slice, left_slice, cpu)
       },
       PredictionMode::UV_CFL_PRED => match (x, y) {
-        (0, 0) => B::pred_cfl_128(dst, &ac, alpha, bit_depth),
+        (0, 0) => B::pred_cfl_128(dst, &ac, alpha, bit_depth, cpu),
         (_, 0) => B::pred_cfl_left(
           dst,
           &ac,
           alpha,
           bit_depth,
           above_slice,
-          left_slice
+          left_slice,
+          cpu
         ),
         (0, _) => B::pred_cfl_top(
           dst,
@@ -1116,7 +1434,8 @@ impl PredictionMode {
           alpha,
           bit_depth,
           above_slice,
-          left_slice
+          left_slice,
+          cpu
         ),
         _ => B::pred_cfl(
           dst,
@@ -1124,31 +1443,36 @@ impl PredictionMode {
           alpha,
           bit_depth,
           above_slice,
-          left_slice
+          left_slice,
+          cpu
         )
       },
-      PredictionMode::H_PRED => B::pred_h(dst, left_slice),
-      PredictionMode::V_PRED => B::pred_v(dst, above_slice),
+      PredictionMode::H_PRED if angle_delta == 0 =>
+        B::pred_h(dst, left_slice, cpu),
+      PredictionMode::V_PRED if angle_delta == 0 =>
+        B::pred_v(dst, above_slice, cpu),
       PredictionMode::PAETH_PRED =>
-        B::pred_paeth(dst, above_slice, left_slice, top_left[0]),
+        B::pred_paeth(dst, above_slice, left_slice, top_left[0], cpu),
       PredictionMode::SMOOTH_PRED =>
-        B::pred_smooth(dst, above_slice, left_slice),
+        B::pred_smooth(dst, above_slice, left_slice, cpu),
       PredictionMode::SMOOTH_H_PRED =>
-        B::pred_smooth_h(dst, above_slice, left_slice),
+        B::pred_smooth_h(dst, above_slice, left_slice, cpu),
       PredictionMode::SMOOTH_V_PRED =>
-        B::pred_smooth_v(dst, above_slice, left_slice),
-      PredictionMode::D45_PRED =>
-        B::pred_directional(dst, above_slice, left_and_left_below_slice, top_left, 45, bit_depth),
-      PredictionMode::D135_PRED =>
-        B::pred_directional(dst, above_slice, left_and_left_below_slice, top_left, 135, bit_depth),
-      PredictionMode::D117_PRED =>
-        B::pred_directional(dst, above_slice, left_and_left_below_slice, top_left, 113, bit_depth),
-      PredictionMode::D153_PRED =>
-        B::pred_directional(dst, above_slice, left_and_left_below_slice, top_left, 157, bit_depth),
-      PredictionMode::D207_PRED =>
-        B::pred_directional(dst, above_slice, left_and_left_below_slice, top_left, 203, bit_depth),
-      PredictionMode::D63_PRED =>
-        B::pred_directional(dst, above_slice, left_and_left_below_slice, top_left, 67, bit_depth),
+        B::pred_smooth_v(dst, above_slice, left_slice, cpu),
+      _ if mode.is_directional() => {
+        let p_angle = pred_angle(mode, angle_delta);
+        // Coded blocks never cross the edge of the frame aligned to 8 luma
+        // samples, so the edges are available over the whole block
+        let edge_filter = edge_filter.map(|filter_type| IntraEdgeFilter {
+          filter_type,
+          above_avail: if y != 0 { B::W } else { 0 },
+          left_avail: if x != 0 { B::H } else { 0 },
+        });
+        B::pred_directional(
+          dst, above_slice, left_and_left_below_slice, top_left, p_angle,
+          bit_depth, edge_filter
+        )
+      }
       _ => unimplemented!()
     }
   }
@@ -1165,20 +1489,41 @@ impl PredictionMode {
     self >= PredictionMode::V_PRED && self <= PredictionMode::D63_PRED
   }
 
+  /// Whether the intra prediction of the mode, with `angle_delta`, reads the
+  /// pixels above right of the block, as the angles below 90 degrees do
+  pub fn uses_above_right(self, angle_delta: i8) -> bool {
+    self.is_directional() && pred_angle(self, angle_delta) < 90
+  }
+
+  pub fn is_smooth(self) -> bool {
+    self >= PredictionMode::SMOOTH_PRED && self <= PredictionMode::SMOOTH_H_PRED
+  }
+
+  pub fn is_compound(self) -> bool {
+    self >= PredictionMode::NEAREST_NEARESTMV
+  }
+
   pub fn predict_inter<T: Pixel>(
-    self, fi: &FrameInvariants<T>, tile_rect: TileRect, p: usize, po: PlaneOffset,
+    self, fi: &FrameInvariants<T>, p: usize, frame_po: FramePlaneOffset,
     dst: &mut PlaneRegionMut<'_, T>, width: usize, height: usize,
     ref_frames: [RefType; 2], mvs: [MotionVector; 2]
   ) {
     assert!(!self.is_intra());
-    let frame_po = tile_rect.to_frame_plane_offset(po);
+    // Catch mode decision bugs here rather than in the slot lookup
+    assert!(
+      ref_frames[0] != INTRA_FRAME && ref_frames[0] != NONE_FRAME,
+      "{:?} predicts from {:?}, which is not an inter reference",
+      self, ref_frames[0]
+    );
+    let frame_po = frame_po.0;
 
     let mode = FilterMode::REGULAR;
     let is_compound =
       ref_frames[1] != INTRA_FRAME && ref_frames[1] != NONE_FRAME;
 
     fn get_params<'a, T: Pixel>(
-      rec_plane: &'a Plane<T>, po: PlaneOffset, mv: MotionVector
+      rec_plane: &'a Plane<T>, po: PlaneOffset, mv: MotionVector,
+      width: usize, height: usize
     ) -> (i32, i32, PlaneSlice<'a, T>) {
       let rec_cfg = &rec_plane.cfg;
       let shift_row = 3 + rec_cfg.ydec;
@@ -1193,12 +1538,22 @@ impl PredictionMode {
         x: po.x + col_offset as isize - 3,
         y: po.y + row_offset as isize - 3
       };
-      (row_frac, col_frac, rec_plane.slice(qo).clamp().subslice(3, 3))
+      let src = rec_plane.slice(qo).clamp();
+      // The 8-tap filter window must stay within the padded reference
+      debug_assert!(
+        src.x + (width + 7) as isize
+          <= (rec_cfg.stride - rec_cfg.xorigin) as isize
+      );
+      debug_assert!(
+        src.y + (height + 7) as isize
+          <= (rec_cfg.alloc_height - rec_cfg.yorigin) as isize
+      );
+      (row_frac, col_frac, src.subslice(3, 3))
     };
 
     if !is_compound {
       if let Some(ref rec) = fi.rec_buffer.frames[fi.ref_frames[ref_frames[0].to_index()] as usize] {
-        let (row_frac, col_frac, src) = get_params(&rec.frame.planes[p], frame_po, mvs[0]);
+        let (row_frac, col_frac, src) = get_params(&rec.frame.planes[p], frame_po, mvs[0], width, height);
         put_8tap(
           dst,
           src,
@@ -1208,15 +1563,20 @@ impl PredictionMode {
           row_frac,
           mode,
           mode,
-          fi.sequence.bit_depth
+          fi.sequence.bit_depth,
+          fi.cpu
         );
       }
     } else {
+      assert!(
+        is_valid_comp_ref(ref_frames),
+        "{:?} is not a valid compound reference", ref_frames
+      );
       let mut tmp: [AlignedArray<[i16; 128 * 128]>; 2] =
         [UninitializedAlignedArray(), UninitializedAlignedArray()];
       for i in 0..2 {
         if let Some(ref rec) = fi.rec_buffer.frames[fi.ref_frames[ref_frames[i].to_index()] as usize] {
-          let (row_frac, col_frac, src) = get_params(&rec.frame.planes[p], frame_po, mvs[i]);
+          let (row_frac, col_frac, src) = get_params(&rec.frame.planes[p], frame_po, mvs[i], width, height);
           prep_8tap(
             &mut tmp[i].array,
             src,
@@ -1226,7 +1586,8 @@ impl PredictionMode {
             row_frac,
             mode,
             mode,
-            fi.sequence.bit_depth
+            fi.sequence.bit_depth,
+            fi.cpu
           );
         }
       }
@@ -1236,7 +1597,8 @@ impl PredictionMode {
         &tmp[1].array,
         width,
         height,
-        fi.sequence.bit_depth
+        fi.sequence.bit_depth,
+        fi.cpu
       );
     }
   }
@@ -1266,7 +1628,8 @@ pub enum TxSet {
   TX_SET_ALL16
 }
 
-pub fn has_tr(bo: BlockOffset, bsize: BlockSize) -> bool {
+pub fn has_tr(bo: TileBlockOffset, bsize: BlockSize) -> bool {
+  let bo = bo.0;
   let sb_mi_size = BLOCK_64X64.width_mi(); /* Assume 64x64 for now */
   let mask_row = bo.y & LOCAL_BLOCK_MASK;
   let mask_col = bo.x & LOCAL_BLOCK_MASK;
@@ -1323,7 +1686,8 @@ pub fn has_tr(bo: BlockOffset, bsize: BlockSize) -> bool {
   has_tr
 }
 
-pub fn has_bl(bo: BlockOffset, bsize: BlockSize) -> bool {
+pub fn has_bl(bo: TileBlockOffset, bsize: BlockSize) -> bool {
+  let bo = bo.0;
   let sb_mi_size = BLOCK_64X64.width_mi(); /* Assume 64x64 for now */
   let mask_row = bo.y & LOCAL_BLOCK_MASK;
   let mask_col = bo.x & LOCAL_BLOCK_MASK;
@@ -1379,3 +1743,302 @@ pub fn has_bl(bo: BlockOffset, bsize: BlockSize) -> bool {
 
   has_bl
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn check_u8_conversions<E>(count: usize)
+  where
+    E: Copy + fmt::Debug + Into<u8> + TryFrom<u8, Error = InvalidDiscriminant>
+  {
+    for v in 0..=255u8 {
+      match E::try_from(v) {
+        Ok(e) => {
+          assert!((v as usize) < count);
+          assert_eq!(e.into(), v);
+        }
+        Err(e) => {
+          assert!((v as usize) >= count);
+          assert_eq!(e, InvalidDiscriminant(v));
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn u8_conversions() {
+    check_u8_conversions::<BlockSize>(BlockSize::BLOCK_SIZES_ALL);
+    check_u8_conversions::<TxSize>(TxSize::TX_SIZES_ALL);
+    check_u8_conversions::<TxType>(16);
+    check_u8_conversions::<PredictionMode>(PredictionMode::NEW_NEWMV as usize + 1);
+    assert_eq!(u8::from(BLOCK_INVALID), BlockSize::BLOCK_SIZES_ALL as u8);
+    assert!(BlockSize::try_from(u8::from(BLOCK_INVALID)).is_err());
+  }
+
+  #[test]
+  fn mv_round_to_precision() {
+    use MvSubpelPrecision::*;
+    let mv = |row, col| MotionVector { row, col };
+    let high = mv(-13, 7);
+    assert_eq!(high.round_to_precision(MV_SUBPEL_HIGH_PRECISION), high);
+    assert_eq!(high.round_to_precision(MV_SUBPEL_LOW_PRECISION), mv(-12, 6));
+    assert_eq!(high.round_to_precision(MV_SUBPEL_NONE), mv(-16, 8));
+    // Half pels round towards zero to full pels
+    assert_eq!(mv(4, -4).round_to_precision(MV_SUBPEL_NONE), mv(0, 0));
+    assert_eq!(mv(5, -5).round_to_precision(MV_SUBPEL_NONE), mv(8, -8));
+    assert_eq!(mv(-20, 20).round_to_precision(MV_SUBPEL_NONE), mv(-16, 16));
+    // Codable vectors are left as they are
+    for &precision in &[MV_SUBPEL_NONE, MV_SUBPEL_LOW_PRECISION] {
+      let codable = mv(-48, 16);
+      assert_eq!(codable.round_to_precision(precision), codable);
+    }
+  }
+
+  #[test]
+  #[should_panic(expected = "not an inter reference")]
+  fn predict_inter_from_intra_frame() {
+    use crate::api::EncoderConfig;
+    use crate::encoder::Sequence;
+
+    let config = EncoderConfig { width: 64, height: 64, ..Default::default() };
+    let sequence = Sequence::new(&config, None);
+    let fi = FrameInvariants::<u8>::new(config, sequence);
+    let mut dst = Plane::new(8, 8, 0, 0, 0, 0);
+    PredictionMode::NEWMV.predict_inter(
+      &fi, 0, FramePlaneOffset(PlaneOffset { x: 0, y: 0 }),
+      &mut dst.as_region_mut(), 8, 8,
+      [RefType::INTRA_FRAME, RefType::NONE_FRAME], [MotionVector::default(); 2]
+    );
+  }
+
+  #[test]
+  fn compound_references() {
+    use self::RefType::*;
+    assert!(is_valid_comp_ref([LAST_FRAME, ALTREF_FRAME]));
+    assert!(is_valid_comp_ref([GOLDEN_FRAME, BWDREF_FRAME]));
+    // Backward first
+    assert!(!is_valid_comp_ref([ALTREF_FRAME, LAST_FRAME]));
+    for &pair in UNIDIR_COMP_REF_PAIRS.iter() {
+      assert!(is_valid_comp_ref(pair));
+      assert!(!is_valid_comp_ref([pair[1], pair[0]]));
+    }
+    assert!(!is_valid_comp_ref([LAST2_FRAME, LAST_FRAME]));
+    assert!(!is_valid_comp_ref([GOLDEN_FRAME, LAST2_FRAME]));
+    assert!(!is_valid_comp_ref([LAST_FRAME, LAST_FRAME]));
+    assert!(!is_valid_comp_ref([LAST_FRAME, NONE_FRAME]));
+    assert!(!is_valid_comp_ref([INTRA_FRAME, ALTREF_FRAME]));
+  }
+
+  #[test]
+  fn partitions_of_block_sizes() {
+    use self::PartitionType::*;
+    let valid = |bsize| valid_partitions(bsize).collect::<Vec<_>>();
+    assert_eq!(valid(BLOCK_4X4), vec![PARTITION_NONE]);
+    assert_eq!(valid(BLOCK_16X8), vec![PARTITION_NONE]);
+    assert_eq!(
+      valid(BLOCK_8X8),
+      vec![PARTITION_NONE, PARTITION_HORZ, PARTITION_VERT, PARTITION_SPLIT]
+    );
+    // 4:1 partitions would give blocks wider than 4:1 at 128x128
+    assert!(valid(BLOCK_64X64).contains(&PARTITION_HORZ_4));
+    assert!(!valid(BLOCK_128X128).contains(&PARTITION_VERT_4));
+    for &bsize in &[BLOCK_16X16, BLOCK_128X128] {
+      assert!(valid_partitions(bsize).all(|p| bsize.subsize(p) != BLOCK_INVALID));
+    }
+  }
+
+  #[test]
+  fn partition_heuristic_ranking() {
+    use self::PartitionType::*;
+    let candidates: Vec<_> = valid_partitions(BLOCK_32X32).collect();
+    let heuristic = |above, left| PartitionHeuristic { above, left };
+    let rank = |heuristic: PartitionHeuristic, candidates: &[PartitionType]| {
+      let mut ranked = candidates.to_vec();
+      heuristic.rank(BLOCK_32X32, &mut ranked);
+      ranked
+    };
+
+    let same_set = |ranked: &[PartitionType]| {
+      let mut sorted = ranked.to_vec();
+      sorted.sort_by_key(|&p| p as usize);
+      sorted == candidates
+    };
+
+    let whole = heuristic(Some(BLOCK_64X64), Some(BLOCK_32X64));
+    let ranked = rank(whole, &candidates);
+    assert_eq!(ranked[0], PARTITION_NONE);
+    assert!(same_set(&ranked));
+    // An oversized block cannot be coded whole
+    assert_eq!(
+      rank(whole, &[PARTITION_SPLIT, PARTITION_HORZ]),
+      vec![PARTITION_SPLIT, PARTITION_HORZ]
+    );
+
+    let split = heuristic(Some(BLOCK_8X8), Some(BLOCK_4X8));
+    let ranked = rank(split, &candidates);
+    assert_eq!(ranked[0], PARTITION_SPLIT);
+    assert_eq!(ranked[ranked.len() - 1], PARTITION_NONE);
+    assert!(same_set(&ranked));
+
+    // Mixed or missing neighbors leave the candidates alone
+    let mixed = heuristic(Some(BLOCK_64X64), Some(BLOCK_8X8));
+    assert_eq!(rank(mixed, &candidates), candidates);
+    let edge = heuristic(None, Some(BLOCK_8X8));
+    assert_eq!(rank(edge, &candidates), candidates);
+  }
+
+  #[test]
+  fn tx_partition_blocks() {
+    assert_eq!(TxPartition::NONE.tx_blocks(TX_32X32), vec![(0, 0, TX_32X32)]);
+    assert_eq!(
+      TxPartition::SPLIT.tx_blocks(TX_16X8),
+      vec![(0, 0, TX_8X8), (2, 0, TX_8X8)]
+    );
+    let partition = TxPartition::SPLIT.toggle_sub_split(1);
+    assert_eq!(
+      partition.tx_blocks(TX_16X16),
+      vec![
+        (0, 0, TX_8X8),
+        (2, 0, TX_4X4), (3, 0, TX_4X4), (2, 1, TX_4X4), (3, 1, TX_4X4),
+        (0, 2, TX_8X8),
+        (2, 2, TX_8X8)
+      ]
+    );
+    // 4x4 transforms cannot be split
+    assert_eq!(
+      TxPartition::SPLIT.toggle_sub_split(0).tx_blocks(TX_8X8),
+      TxPartition::SPLIT.tx_blocks(TX_8X8)
+    );
+    // The transforms tile the partitioned one
+    for &tx_size in &[TX_64X64, TX_32X16, TX_16X64, TX_8X32, TX_16X4] {
+      let partition = TxPartition::SPLIT.toggle_sub_split(0);
+      let area: usize =
+        partition.tx_blocks(tx_size).iter().map(|&(_, _, t)| t.area()).sum();
+      assert_eq!(area, tx_size.area());
+    }
+  }
+
+  #[test]
+  fn mv_scaling() {
+    let mv = MotionVector { row: -13, col: 6 };
+    assert_eq!(mv.upscale(1), MotionVector { row: -26, col: 12 });
+    assert_eq!(mv.upscale(2).downscale(2), mv);
+    // Rounded down, like the positions of the pixels
+    assert_eq!(mv.downscale(1), MotionVector { row: -7, col: 3 });
+  }
+
+  #[test]
+  fn intra_edges_cover_prediction() {
+    use crate::predict::IntraEdgeFilterType::*;
+    use self::PredictionMode::*;
+
+    const SIZE: usize = 128;
+    let tile_rect = TileRect { x: 0, y: 0, width: SIZE, height: SIZE };
+    let plane = Plane::wrap(
+      (0..SIZE * SIZE).map(|i| (i * 97 % 251 + 2) as u8).collect(), SIZE
+    );
+    let ac: Vec<i16> = (0..32 * 32).map(|i| (i * 37 % 61) as i16 - 30).collect();
+    let cpu = CpuKernels::NATIVE;
+
+    let directional = [
+      V_PRED, H_PRED, D45_PRED, D135_PRED, D117_PRED, D153_PRED, D207_PRED,
+      D63_PRED
+    ];
+    let mut cases = Vec::new();
+    for &mode in &[DC_PRED, SMOOTH_PRED, SMOOTH_V_PRED, SMOOTH_H_PRED, PAETH_PRED] {
+      cases.push((mode, IntraParam::None, None));
+    }
+    for &alpha in &[-3, 2] {
+      cases.push((UV_CFL_PRED, IntraParam::Alpha(alpha), None));
+    }
+    for &mode in directional.iter() {
+      for &delta in &[-3, 0, 3] {
+        for &edge_filter in &[None, Some(Regular), Some(Smooth)] {
+          cases.push((mode, IntraParam::AngleDelta(delta), edge_filter));
+        }
+      }
+    }
+
+    let tx_sizes =
+      (0..TxSize::TX_SIZES_ALL as u8).map(|v| TxSize::try_from(v).unwrap());
+    for tx_size in tx_sizes {
+      let (w, h) = (tx_size.width(), tx_size.height());
+      for &(mode, intra_param, edge_filter) in cases.iter() {
+        if mode == UV_CFL_PRED && (w > 32 || h > 32) {
+          continue;
+        }
+        for (x, y) in (0..4).flat_map(|i| (0..4).map(move |j| (i * w, j * h))) {
+          if x + w > SIZE || y + h > SIZE {
+            continue;
+          }
+          // Edges the mode was not given stay at a sentinel, different in
+          // each prediction, so a prediction reading one changes
+          let predict = |sentinel: u8| {
+            let mut edge_buf: AlignedArray<[u8; 4 * MAX_TX_SIZE + 1]> =
+              UninitializedAlignedArray();
+            edge_buf.array = [sentinel; 4 * MAX_TX_SIZE + 1];
+            gather_intra_edges(
+              &mut edge_buf, &plane.as_region(),
+              TilePlaneOffset(PlaneOffset { x: x as isize, y: y as isize }),
+              tx_size, 8, Some(mode), intra_param
+            );
+            let mut output = Plane::wrap(vec![0u8; SIZE * SIZE], SIZE);
+            let mut region = output.region_mut(Area::Rect {
+              x: x as isize, y: y as isize, width: w, height: h
+            });
+            mode.predict_intra(
+              tile_rect, &mut region, tx_size, 8, &ac, intra_param,
+              &edge_buf, edge_filter, cpu
+            );
+            output.data
+          };
+          assert_eq!(
+            predict(0), predict(255),
+            "{:?} {:?} {:?} {:?} at ({}, {})",
+            mode, intra_param, edge_filter, tx_size, x, y
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn topright_extension_at_tile_edge() {
+    use self::TxSize::*;
+
+    const SIZE: usize = 128;
+    let plane = Plane::wrap(
+      (0..SIZE * SIZE).map(|i| (i * 97 % 251 + 2) as u8).collect(), SIZE
+    );
+    // The top of a superblock row, where the above-right block is coded
+    let (x, y) = (0, 64);
+    for &tx_size in &[
+      TX_32X8, TX_16X4, TX_64X16, TX_32X16, TX_8X32, TX_4X16, TX_16X64,
+      TX_16X16
+    ] {
+      let (w, h) = (tx_size.width(), tx_size.height());
+      // The tile ends right after the transform, a few samples further, and
+      // a whole transform width further
+      for &num_avail in &[0, 4, w] {
+        let tile = plane.region(Area::Rect {
+          x: 0, y: 0, width: x + w + num_avail, height: SIZE
+        });
+        let edge_buf = get_intra_edges(
+          &tile, TilePlaneOffset(PlaneOffset { x: x as isize, y: y as isize }),
+          tx_size, 8, Some(PredictionMode::D45_PRED), IntraParam::AngleDelta(0)
+        );
+        let above = &edge_buf.array[2 * MAX_TX_SIZE + 1..];
+        // The samples past the top edge are the ones of the tile, the last
+        // one repeated past its edge
+        for i in 0..h {
+          assert_eq!(
+            above[w + i], plane.p(x + w - 1 + (i + 1).min(num_avail), y - 1),
+            "{:?} with {} samples available, sample {}",
+            tx_size, num_avail, i
+          );
+        }
+      }
+    }
+  }
+}
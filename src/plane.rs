@@ -7,9 +7,9 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
-use std::alloc::{alloc, dealloc, Layout};
+use std::alloc::{alloc, dealloc, handle_alloc_error, Layout};
 use std::iter::FusedIterator;
-use std::fmt::{Debug, Display, Formatter};
+use std::fmt::{self, Debug, Display, Formatter};
 use std::marker::PhantomData;
 use std::mem;
 use std::ops::{Index, IndexMut, Range};
@@ -33,12 +33,41 @@ pub struct PlaneConfig {
 }
 
 /// Absolute offset in pixels inside a plane
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct PlaneOffset {
   pub x: isize,
   pub y: isize
 }
 
+/// Failure to allocate the samples of a plane
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AllocError {
+  /// Size of the allocation in bytes, `usize::MAX` if it overflows
+  pub bytes: usize,
+}
+
+impl AllocError {
+  /// Aborts as the infallible allocations of the standard library do
+  pub(crate) fn abort(self) -> ! {
+    match Layout::from_size_align(self.bytes, 1) {
+      Ok(layout) if self.bytes < std::usize::MAX => handle_alloc_error(layout),
+      _ => panic!("capacity overflow"),
+    }
+  }
+}
+
+impl Display for AllocError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    if self.bytes == std::usize::MAX {
+      write!(f, "The size of the plane overflows")
+    } else {
+      write!(f, "Failed to allocate a plane of {} bytes", self.bytes)
+    }
+  }
+}
+
+impl std::error::Error for AllocError {}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct PlaneData<T: Pixel> {
   ptr: std::ptr::NonNull<T>,
@@ -100,27 +129,40 @@ impl<T: Pixel> PlaneData<T> {
     )
   }
 
-  unsafe fn new_uninitialized(len: usize) -> Self {
-    let ptr = {
-      let ptr = alloc(Self::layout(len)) as *mut T;
-      std::ptr::NonNull::new_unchecked(ptr)
-    };
+  unsafe fn try_new_uninitialized(len: usize) -> Result<Self, AllocError> {
+    let bytes = len.checked_mul(mem::size_of::<T>());
+    let layout = bytes.and_then(|bytes| {
+      Layout::from_size_align(bytes, 1 << Self::DATA_ALIGNMENT_LOG2).ok()
+    });
+    let error = AllocError { bytes: bytes.unwrap_or(std::usize::MAX) };
+    let layout = layout.ok_or(error)?;
+    let ptr = std::ptr::NonNull::new(alloc(layout) as *mut T).ok_or(error)?;
 
-    PlaneData {
+    Ok(PlaneData {
       ptr,
       len,
       _marker: PhantomData
-    }
+    })
   }
 
-  pub fn new(len: usize) -> Self {
-    let mut pd = unsafe { Self::new_uninitialized(len) };
+  unsafe fn new_uninitialized(len: usize) -> Self {
+    Self::try_new_uninitialized(len).unwrap_or_else(|e| e.abort())
+  }
+
+  /// Allocates `len` samples set to 128, returning an error instead of
+  /// aborting if the allocation fails
+  pub fn try_new(len: usize) -> Result<Self, AllocError> {
+    let mut pd = unsafe { Self::try_new_uninitialized(len)? };
 
     for v in pd.iter_mut() {
       *v = T::cast_from(128);
     }
 
-    pd
+    Ok(pd)
+  }
+
+  pub fn new(len: usize) -> Self {
+    Self::try_new(len).unwrap_or_else(|e| e.abort())
   }
 
   pub fn from_slice(data: &[T]) -> Self {
@@ -153,6 +195,16 @@ impl<T: Pixel> Plane<T> {
     width: usize, height: usize, xdec: usize, ydec: usize, xpad: usize,
     ypad: usize
   ) -> Self {
+    Self::try_new(width, height, xdec, ydec, xpad, ypad)
+      .unwrap_or_else(|e| e.abort())
+  }
+
+  /// Like `new`, returning an error instead of aborting if the plane
+  /// cannot be allocated
+  pub fn try_new(
+    width: usize, height: usize, xdec: usize, ydec: usize, xpad: usize,
+    ypad: usize
+  ) -> Result<Self, AllocError> {
     let xorigin = xpad.align_power_of_two(
       Self::STRIDE_ALIGNMENT_LOG2 + 1 - mem::size_of::<T>()
     );
@@ -161,9 +213,12 @@ impl<T: Pixel> Plane<T> {
       Self::STRIDE_ALIGNMENT_LOG2 + 1 - mem::size_of::<T>()
     );
     let alloc_height = yorigin + height + ypad;
-    let data = PlaneData::new(stride * alloc_height);
+    let len = stride
+      .checked_mul(alloc_height)
+      .ok_or(AllocError { bytes: std::usize::MAX })?;
+    let data = PlaneData::try_new(len)?;
 
-    Plane {
+    Ok(Plane {
       data,
       cfg: PlaneConfig {
         stride,
@@ -177,7 +232,7 @@ impl<T: Pixel> Plane<T> {
         xorigin,
         yorigin
       }
-    }
+    })
   }
 
   pub fn wrap(data: Vec<T>, stride: usize) -> Self {
@@ -202,6 +257,11 @@ impl<T: Pixel> Plane<T> {
     }
   }
 
+  /// Extends the samples at the edges of the `w`x`h` (luma) picture of the
+  /// plane into the rest of it, its border included, for motion
+  /// compensation to read past the edges of references. Planes filled from
+  /// other buffers must be padded before being used as references, their
+  /// border at least `MC_BORDER` wide.
   pub fn pad(&mut self, w: usize, h: usize) {
     let xorigin = self.cfg.xorigin;
     let yorigin = self.cfg.yorigin;
@@ -249,6 +309,16 @@ impl<T: Pixel> Plane<T> {
     }
   }
 
+  /// Narrowest side of the border `pad` fills around the `w`x`h` (luma)
+  /// picture of the plane, in luma samples
+  pub fn border(&self, w: usize, h: usize) -> usize {
+    let PlaneConfig { stride, alloc_height, xdec, ydec, xorigin, yorigin, .. } =
+      self.cfg;
+    let right = stride - xorigin - (w >> xdec);
+    let bottom = alloc_height - yorigin - (h >> ydec);
+    (xorigin.min(right) << xdec).min(yorigin.min(bottom) << ydec)
+  }
+
   pub fn slice(&self, po: PlaneOffset) -> PlaneSlice<'_, T> {
     PlaneSlice { plane: self, x: po.x, y: po.y }
   }
@@ -382,6 +452,45 @@ impl<T: Pixel> Plane<T> {
     }
   }
 
+  /// Fills the top-left `width`x`height` samples of the plane by bilinear
+  /// interpolation of the top-left `src_width`x`src_height` ones of `src`,
+  /// their centers aligned
+  pub fn scale_from(
+    &mut self, width: usize, height: usize, src: &Plane<T>, src_width: usize,
+    src_height: usize
+  ) {
+    assert!(width <= self.cfg.width && height <= self.cfg.height);
+    assert!(src_width <= src.cfg.width && src_height <= src.cfg.height);
+
+    // Positions in the source of each output column or row, with 8
+    // fractional bits, along with the fraction
+    let positions = |len: usize, src_len: usize| -> Vec<(usize, usize, u32)> {
+      (0..len).map(|i| {
+        let pos = ((2 * i + 1) * src_len * 256 / (2 * len)) as isize - 128;
+        let pos = pos.max(0).min(((src_len - 1) * 256) as isize) as usize;
+        let p0 = pos >> 8;
+        (p0, (p0 + 1).min(src_len - 1), (pos & 255) as u32)
+      }).collect()
+    };
+    let cols = positions(width, src_width);
+    let rows = positions(height, src_height);
+
+    let lerp = |a: T, b: T, f: u32| {
+      u32::cast_from(a) * (256 - f) + u32::cast_from(b) * f
+    };
+
+    let stride = self.cfg.stride;
+    for (dst, &(y0, y1, fy)) in
+      self.data_origin_mut().chunks_mut(stride).zip(rows.iter())
+    {
+      for (v, &(x0, x1, fx)) in dst.iter_mut().zip(cols.iter()) {
+        let top = lerp(src.p(x0, y0), src.p(x1, y0), fx);
+        let bottom = lerp(src.p(x0, y1), src.p(x1, y1), fx);
+        *v = T::cast_from((top * (256 - fy) + bottom * fy + (1 << 15)) >> 16);
+      }
+    }
+  }
+
   /// Iterates over the pixels in the `Plane`, skipping stride data.
   pub fn iter(&self) -> PlaneIter<'_, T> {
     PlaneIter::new(self)
@@ -677,6 +786,18 @@ impl<'a, T: Pixel> IndexMut<usize> for PlaneMutSlice<'a, T> {
 pub mod test {
   use super::*;
 
+  #[test]
+  fn oversized_planes_fail_to_allocate() {
+    // Larger than any allocation can be
+    let e = PlaneData::<u16>::try_new(std::usize::MAX / 2).unwrap_err();
+    assert_eq!(e.bytes, std::usize::MAX / 2 * 2);
+    // The size overflows
+    let e = Plane::<u8>::try_new(std::usize::MAX / 4, 8, 0, 0, 0, 0).unwrap_err();
+    assert_eq!(e, AllocError { bytes: std::usize::MAX });
+
+    assert!(Plane::<u16>::try_new(64, 64, 0, 0, 8, 8).is_ok());
+  }
+
   #[test]
   fn copy_from_raw_u8() {
     let mut plane = Plane::wrap(
@@ -701,6 +822,32 @@ pub mod test {
     assert_eq!(&input[..64], &plane.data[..64]);
   }
 
+  #[test]
+  fn test_plane_scale() {
+    let mut src = Plane::<u16>::new(8, 4, 0, 0, 0, 0);
+    let stride = src.cfg.stride;
+    for (y, row) in src.data_origin_mut().chunks_mut(stride).enumerate() {
+      for (x, v) in row.iter_mut().enumerate() {
+        *v = (x * 64 + y) as u16;
+      }
+    }
+
+    // The same size is a copy
+    let mut dst = Plane::<u16>::new(8, 4, 0, 0, 0, 0);
+    dst.scale_from(8, 4, &src, 8, 4);
+    assert_eq!(&dst.data[..], &src.data[..]);
+
+    // Halving averages pairs of samples, the edges replicated when doubling
+    let mut dst = Plane::<u16>::new(4, 4, 0, 0, 0, 0);
+    dst.scale_from(4, 2, &src, 8, 4);
+    assert_eq!(&dst.data_origin()[..4], &[33, 161, 289, 417]);
+    assert_eq!(dst.p(3, 1), 419);
+    let mut dst = Plane::<u16>::new(16, 8, 0, 0, 0, 0);
+    dst.scale_from(16, 8, &src, 8, 4);
+    assert_eq!(&dst.data_origin()[..4], &[0, 16, 48, 80]);
+    assert_eq!(dst.p(15, 7), 448 + 3);
+  }
+
   #[test]
   fn test_plane_pad() {
     let mut plane = Plane::<u8> {
@@ -728,6 +875,7 @@ pub mod test {
         yorigin: 3,
       },
     };
+    assert_eq!(plane.border(4, 4), 2);
     plane.pad(4, 4);
     assert_eq!(
       &[
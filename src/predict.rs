@@ -10,6 +10,9 @@
 #![allow(non_upper_case_globals)]
 
 use crate::context::{INTRA_MODES, MAX_TX_SIZE};
+use crate::cpu_features::CpuKernels;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::cpu_features::{CpuFeatureLevel, Kernel};
 use crate::partition::*;
 use crate::tiling::*;
 use crate::util::*;
@@ -62,6 +65,165 @@ pub static RAV1E_INTER_COMPOUND_MODES: &'static [PredictionMode] = &[
   PredictionMode::NEW_NEARESTMV
 ];
 
+/// Step in degrees between the prediction angles an angle delta selects
+pub const ANGLE_STEP: i8 = 3;
+
+/// Angle deltas of the luma and chroma directional intra predictions, in
+/// units of `ANGLE_STEP`
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AngleDelta {
+  pub y: i8,
+  pub uv: i8
+}
+
+/// Parameter of an intra prediction in addition to its mode
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IntraParam {
+  AngleDelta(i8),
+  Alpha(i16),
+  None
+}
+
+/// Nominal angle in degrees of a directional intra mode
+pub fn intra_mode_to_angle(mode: PredictionMode) -> isize {
+  match mode {
+    PredictionMode::V_PRED => 90,
+    PredictionMode::H_PRED => 180,
+    PredictionMode::D45_PRED => 45,
+    PredictionMode::D135_PRED => 135,
+    PredictionMode::D117_PRED => 113,
+    PredictionMode::D153_PRED => 157,
+    PredictionMode::D207_PRED => 203,
+    PredictionMode::D63_PRED => 67,
+    _ => 0
+  }
+}
+
+/// Prediction angle of a directional intra mode with its angle delta applied
+pub fn pred_angle(mode: PredictionMode, angle_delta: i8) -> usize {
+  debug_assert!(mode.is_directional());
+  (intra_mode_to_angle(mode) + (angle_delta * ANGLE_STEP) as isize) as usize
+}
+
+/// Type of the filter of the edges of directional intra predictions
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IntraEdgeFilterType {
+  /// Neither the block above nor the one on the left uses a smooth mode
+  Regular,
+  /// The block above or the one on the left uses a smooth mode
+  Smooth,
+}
+
+/// Filtering of the edges of a directional intra prediction
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct IntraEdgeFilter {
+  pub filter_type: IntraEdgeFilterType,
+  /// Number of samples of the above row within the frame, up to the width
+  /// of the block, or 0 if the row is outside of the tile. Also filtered
+  /// are the top-left sample and, in the direction of the prediction, the
+  /// samples past the block.
+  pub above_avail: usize,
+  /// Number of samples of the left column within the frame, up to the
+  /// height of the block, or 0 if the column is outside of the tile
+  pub left_avail: usize,
+}
+
+/// Strength of the edge filter, 0 for none. `delta` is the angle from the
+/// edge, and `w + h` the size of the block. See the av1 spec section
+/// 7.11.2.9.
+fn intra_edge_filter_strength(
+  w: usize, h: usize, filter_type: IntraEdgeFilterType, delta: isize
+) -> usize {
+  let d = delta.abs();
+  let blk_wh = w + h;
+  match filter_type {
+    IntraEdgeFilterType::Regular => {
+      if blk_wh <= 8 {
+        if d >= 56 { 1 } else { 0 }
+      } else if blk_wh <= 16 {
+        if d >= 40 { 1 } else { 0 }
+      } else if blk_wh <= 24 {
+        if d >= 32 { 3 } else if d >= 16 { 2 } else if d >= 8 { 1 } else { 0 }
+      } else if blk_wh <= 32 {
+        if d >= 32 { 3 } else if d >= 4 { 2 } else if d >= 1 { 1 } else { 0 }
+      } else if d >= 1 {
+        3
+      } else {
+        0
+      }
+    }
+    IntraEdgeFilterType::Smooth => {
+      if blk_wh <= 8 {
+        if d >= 64 { 2 } else if d >= 40 { 1 } else { 0 }
+      } else if blk_wh <= 16 {
+        if d >= 48 { 2 } else if d >= 20 { 1 } else { 0 }
+      } else if blk_wh <= 24 {
+        if d >= 4 { 3 } else { 0 }
+      } else if d >= 1 {
+        3
+      } else {
+        0
+      }
+    }
+  }
+}
+
+/// Whether the edge is upsampled 2x. See the av1 spec section 7.11.2.10.
+fn use_intra_edge_upsample(
+  w: usize, h: usize, filter_type: IntraEdgeFilterType, delta: isize
+) -> bool {
+  let d = delta.abs();
+  let blk_wh = w + h;
+  if d == 0 || d >= 40 {
+    false
+  } else if filter_type == IntraEdgeFilterType::Smooth {
+    blk_wh <= 8
+  } else {
+    blk_wh <= 16
+  }
+}
+
+/// Offset of sample 0 in the edge arrays of `pred_directional`, which start
+/// at index -2 once upsampled
+const EDGE_OFFSET: usize = 2;
+
+/// Filters the first `sz - 1` samples of an edge, starting from the corner
+/// at index -1. See the av1 spec section 7.11.2.12.
+fn filter_intra_edge(edge: &mut [i32], sz: usize, strength: usize) {
+  const KERNEL: [[i32; 5]; 3] = [[0, 4, 8, 4, 0], [0, 5, 6, 5, 0], [2, 4, 4, 4, 2]];
+  if strength == 0 {
+    return;
+  }
+  let start = EDGE_OFFSET - 1;
+  let src: Vec<i32> = edge[start..start + sz].to_vec();
+  for i in 1..sz {
+    let s: i32 = KERNEL[strength - 1].iter().enumerate().map(|(j, &k)| {
+      let idx = (i + j).saturating_sub(2).min(sz - 1);
+      k * src[idx]
+    }).sum();
+    edge[start + i] = (s + 8) >> 4;
+  }
+}
+
+/// Upsamples the first `num_px` samples of an edge 2x, the original samples
+/// moving to the even indices. See the av1 spec section 7.11.2.11.
+fn upsample_intra_edge(edge: &mut [i32], num_px: usize, bit_depth: usize) {
+  let sample_max = (1 << bit_depth) - 1;
+  // The samples from index -1, the first and last ones repeated
+  let mut dup = [0i32; 3 + 2 * MAX_TX_SIZE];
+  dup[0] = edge[EDGE_OFFSET - 1];
+  dup[1..num_px + 2]
+    .copy_from_slice(&edge[EDGE_OFFSET - 1..EDGE_OFFSET + num_px]);
+  dup[num_px + 2] = edge[EDGE_OFFSET + num_px - 1];
+
+  edge[EDGE_OFFSET - 2] = dup[0];
+  for i in 0..num_px {
+    let s = -dup[i] + 9 * dup[i + 1] + 9 * dup[i + 2] - dup[i + 3];
+    edge[EDGE_OFFSET + 2 * i - 1] = ((s + 8) >> 4).max(0).min(sample_max);
+    edge[EDGE_OFFSET + 2 * i] = dup[i + 2];
+  }
+}
+
 // Weights are quadratic from '1' to '1 / block_size', scaled by 2^sm_weight_log2_scale.
 const sm_weight_log2_scale: u8 = 8;
 
@@ -214,14 +376,22 @@ decl_cfl_pred_fn!(rav1e_ipred_cfl_left_avx2);
 #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
 decl_cfl_pred_fn!(rav1e_ipred_cfl_top_avx2);
 
+/// Intra predictors, running the SIMD kernels allowed by `cpu` if any
+#[cfg_attr(
+  not(all(target_arch = "x86_64", feature = "nasm")),
+  allow(unused_variables)
+)]
 pub trait Intra<T>: Dim
 where
   T: Pixel,
 {
-  fn pred_dc(output: &mut PlaneRegionMut<'_, T>, above: &[T], left: &[T]) {
+  fn pred_dc(
+    output: &mut PlaneRegionMut<'_, T>, above: &[T], left: &[T],
+    cpu: CpuKernels
+  ) {
     #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
     {
-      if size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+      if size_of::<T>() == 1 && cpu.select(Kernel::Ipred, false) == CpuFeatureLevel::AVX2 {
         return unsafe {
           rav1e_ipred_dc_avx2(
             output.data_ptr_mut() as *mut _,
@@ -246,10 +416,12 @@ where
     }
   }
 
-  fn pred_dc_128(output: &mut PlaneRegionMut<'_, T>, bit_depth: usize) {
+  fn pred_dc_128(
+    output: &mut PlaneRegionMut<'_, T>, bit_depth: usize, cpu: CpuKernels
+  ) {
     #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
     {
-      if size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+      if size_of::<T>() == 1 && cpu.select(Kernel::Ipred, false) == CpuFeatureLevel::AVX2 {
         return unsafe {
           rav1e_ipred_dc_128_avx2(
             output.data_ptr_mut() as *mut _,
@@ -270,10 +442,13 @@ where
     }
   }
 
-  fn pred_dc_left(output: &mut PlaneRegionMut<'_, T>, _above: &[T], left: &[T]) {
+  fn pred_dc_left(
+    output: &mut PlaneRegionMut<'_, T>, _above: &[T], left: &[T],
+    cpu: CpuKernels
+  ) {
     #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
     {
-      if size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+      if size_of::<T>() == 1 && cpu.select(Kernel::Ipred, false) == CpuFeatureLevel::AVX2 {
         return unsafe {
           rav1e_ipred_dc_left_avx2(
             output.data_ptr_mut() as *mut _,
@@ -293,10 +468,13 @@ where
     }
   }
 
-  fn pred_dc_top(output: &mut PlaneRegionMut<'_, T>, above: &[T], _left: &[T]) {
+  fn pred_dc_top(
+    output: &mut PlaneRegionMut<'_, T>, above: &[T], _left: &[T],
+    cpu: CpuKernels
+  ) {
     #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
     {
-      if size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+      if size_of::<T>() == 1 && cpu.select(Kernel::Ipred, false) == CpuFeatureLevel::AVX2 {
         return unsafe {
           rav1e_ipred_dc_top_avx2(
             output.data_ptr_mut() as *mut _,
@@ -316,10 +494,12 @@ where
     }
   }
 
-  fn pred_h(output: &mut PlaneRegionMut<'_, T>, left: &[T]) {
+  fn pred_h(
+    output: &mut PlaneRegionMut<'_, T>, left: &[T], cpu: CpuKernels
+  ) {
     #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
     {
-      if size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+      if size_of::<T>() == 1 && cpu.select(Kernel::Ipred, false) == CpuFeatureLevel::AVX2 {
         return unsafe {
           rav1e_ipred_h_avx2(
             output.data_ptr_mut() as *mut _,
@@ -341,10 +521,12 @@ where
     }
   }
 
-  fn pred_v(output: &mut PlaneRegionMut<'_, T>, above: &[T]) {
+  fn pred_v(
+    output: &mut PlaneRegionMut<'_, T>, above: &[T], cpu: CpuKernels
+  ) {
     #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
     {
-      if size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+      if size_of::<T>() == 1 && cpu.select(Kernel::Ipred, false) == CpuFeatureLevel::AVX2 {
         return unsafe {
           rav1e_ipred_v_avx2(
             output.data_ptr_mut() as *mut _,
@@ -364,11 +546,11 @@ where
 
   fn pred_paeth(
     output: &mut PlaneRegionMut<'_, T>, above: &[T], left: &[T],
-    above_left: T
+    above_left: T, cpu: CpuKernels
   ) {
     #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
     {
-      if size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+      if size_of::<T>() == 1 && cpu.select(Kernel::Ipred, false) == CpuFeatureLevel::AVX2 {
         return unsafe {
           rav1e_ipred_paeth_avx2(
             output.data_ptr_mut() as *mut _,
@@ -407,11 +589,12 @@ where
   }
 
   fn pred_smooth(
-    output: &mut PlaneRegionMut<'_, T>, above: &[T], left: &[T]
+    output: &mut PlaneRegionMut<'_, T>, above: &[T], left: &[T],
+    cpu: CpuKernels
   ) {
     #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
     {
-      if size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+      if size_of::<T>() == 1 && cpu.select(Kernel::Ipred, false) == CpuFeatureLevel::AVX2 {
         return unsafe {
           rav1e_ipred_smooth_avx2(
             output.data_ptr_mut() as *mut _,
@@ -471,11 +654,12 @@ where
   }
 
   fn pred_smooth_h(
-    output: &mut PlaneRegionMut<'_, T>, above: &[T], left: &[T]
+    output: &mut PlaneRegionMut<'_, T>, above: &[T], left: &[T],
+    cpu: CpuKernels
   ) {
     #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
     {
-      if size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+      if size_of::<T>() == 1 && cpu.select(Kernel::Ipred, false) == CpuFeatureLevel::AVX2 {
         return unsafe {
           rav1e_ipred_smooth_h_avx2(
             output.data_ptr_mut() as *mut _,
@@ -521,11 +705,12 @@ where
   }
 
   fn pred_smooth_v(
-    output: &mut PlaneRegionMut<'_, T>, above: &[T], left: &[T]
+    output: &mut PlaneRegionMut<'_, T>, above: &[T], left: &[T],
+    cpu: CpuKernels
   ) {
     #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
     {
-      if size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+      if size_of::<T>() == 1 && cpu.select(Kernel::Ipred, false) == CpuFeatureLevel::AVX2 {
         return unsafe {
           rav1e_ipred_smooth_v_avx2(
             output.data_ptr_mut() as *mut _,
@@ -623,7 +808,8 @@ where
   }
 
   fn pred_cfl_inner(
-    output: &mut PlaneRegionMut<'_, T>, ac: &[i16], alpha: i16, bit_depth: usize
+    output: &mut PlaneRegionMut<'_, T>, ac: &[i16], alpha: i16, bit_depth: usize,
+    cpu: CpuKernels
   ) {
     if alpha == 0 {
       return;
@@ -634,7 +820,7 @@ where
     assert!(output.rows_iter().len() >= Self::H);
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     {
-      if is_x86_feature_detected!("ssse3") {
+      if cpu.select(Kernel::Cfl, size_of::<T>() == 2) >= CpuFeatureLevel::SSSE3 {
         return unsafe {
           Self::pred_cfl_ssse3(output.data_ptr_mut(), output.plane_cfg.stride, ac.as_ptr(), alpha, bit_depth)
         };
@@ -657,11 +843,11 @@ where
 
   fn pred_cfl(
     output: &mut PlaneRegionMut<'_, T>, ac: &[i16], alpha: i16, bit_depth: usize,
-    above: &[T], left: &[T]
+    above: &[T], left: &[T], cpu: CpuKernels
   ) {
     #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
     {
-      if size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+      if size_of::<T>() == 1 && cpu.select(Kernel::Ipred, false) == CpuFeatureLevel::AVX2 {
         return unsafe {
           rav1e_ipred_cfl_avx2(
             output.data_ptr_mut() as *mut _,
@@ -675,16 +861,17 @@ where
         }
       }
     }
-    Self::pred_dc(output, above, left);
-    Self::pred_cfl_inner(output, &ac, alpha, bit_depth);
+    Self::pred_dc(output, above, left, cpu);
+    Self::pred_cfl_inner(output, &ac, alpha, bit_depth, cpu);
   }
 
   fn pred_cfl_128(
-    output: &mut PlaneRegionMut<'_, T>, ac: &[i16], alpha: i16, bit_depth: usize
+    output: &mut PlaneRegionMut<'_, T>, ac: &[i16], alpha: i16, bit_depth: usize,
+    cpu: CpuKernels
   ) {
     #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
     {
-      if size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+      if size_of::<T>() == 1 && cpu.select(Kernel::Ipred, false) == CpuFeatureLevel::AVX2 {
         return unsafe {
           rav1e_ipred_cfl_128_avx2(
             output.data_ptr_mut() as *mut _,
@@ -698,17 +885,17 @@ where
         }
       }
     }
-    Self::pred_dc_128(output, bit_depth);
-    Self::pred_cfl_inner(output, &ac, alpha, bit_depth);
+    Self::pred_dc_128(output, bit_depth, cpu);
+    Self::pred_cfl_inner(output, &ac, alpha, bit_depth, cpu);
   }
 
   fn pred_cfl_left(
     output: &mut PlaneRegionMut<'_, T>, ac: &[i16], alpha: i16, bit_depth: usize,
-    above: &[T], left: &[T]
+    above: &[T], left: &[T], cpu: CpuKernels
   ) {
     #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
     {
-      if size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+      if size_of::<T>() == 1 && cpu.select(Kernel::Ipred, false) == CpuFeatureLevel::AVX2 {
         return unsafe {
           rav1e_ipred_cfl_left_avx2(
             output.data_ptr_mut() as *mut _,
@@ -722,17 +909,17 @@ where
         }
       }
     }
-    Self::pred_dc_left(output, above, left);
-    Self::pred_cfl_inner(output, &ac, alpha, bit_depth);
+    Self::pred_dc_left(output, above, left, cpu);
+    Self::pred_cfl_inner(output, &ac, alpha, bit_depth, cpu);
   }
 
   fn pred_cfl_top(
     output: &mut PlaneRegionMut<'_, T>, ac: &[i16], alpha: i16, bit_depth: usize,
-    above: &[T], left: &[T]
+    above: &[T], left: &[T], cpu: CpuKernels
   ) {
     #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
     {
-      if size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+      if size_of::<T>() == 1 && cpu.select(Kernel::Ipred, false) == CpuFeatureLevel::AVX2 {
         return unsafe {
           rav1e_ipred_cfl_top_avx2(
             output.data_ptr_mut() as *mut _,
@@ -746,32 +933,83 @@ where
         }
       }
     }
-    Self::pred_dc_top(output, above, left);
-    Self::pred_cfl_inner(output, &ac, alpha, bit_depth);
+    Self::pred_dc_top(output, above, left, cpu);
+    Self::pred_cfl_inner(output, &ac, alpha, bit_depth, cpu);
   }
 
+  /// Directional prediction at `angle` degrees. `left` holds the left
+  /// column from bottom to top, ending next to `top_left`. With an
+  /// `edge_filter`, the edges are filtered and upsampled first, as when
+  /// the sequence enables the intra edge filter.
   fn pred_directional(
-    output: &mut PlaneRegionMut<'_, T>, above: &[T], left: &[T], top_left: &[T], angle: usize, bit_depth: usize
+    output: &mut PlaneRegionMut<'_, T>, above: &[T], left: &[T], top_left: &[T],
+    angle: usize, bit_depth: usize, edge_filter: Option<IntraEdgeFilter>
   ) {
     let sample_max = ((1 << bit_depth) - 1) as i32;
-    let _angle_delta = 0;
-
-    let p_angle = angle; // TODO use Mode_to_Angle
-
-    let upsample_above = 0;
-    let upsample_left = 0;
-
-    let enable_intra_edge_filter = false; // FIXME
+    let (w, h) = (Self::W, Self::H);
+
+    let p_angle = angle;
+
+    // AboveRow and LeftCol of the spec, from index -2
+    let mut above_row = [0i32; EDGE_OFFSET + 2 * MAX_TX_SIZE];
+    let mut left_col = [0i32; EDGE_OFFSET + 2 * MAX_TX_SIZE];
+    above_row[EDGE_OFFSET - 1] = top_left[0].into();
+    left_col[EDGE_OFFSET - 1] = top_left[0].into();
+    for i in 0..w + h {
+      above_row[EDGE_OFFSET + i] = above[i].into();
+      left_col[EDGE_OFFSET + i] = left[w + h - 1 - i].into();
+    }
 
-    if enable_intra_edge_filter {
-      // TODO
+    let mut upsample_above = 0;
+    let mut upsample_left = 0;
+
+    if let Some(edge_filter) = edge_filter {
+      let filter_type = edge_filter.filter_type;
+      let above_delta = p_angle as isize - 90;
+      let left_delta = p_angle as isize - 180;
+      if p_angle != 90 && p_angle != 180 {
+        if p_angle > 90 && p_angle < 180 && w + h >= 24 {
+          let corner = (5 * left_col[EDGE_OFFSET] + 6 * above_row[EDGE_OFFSET - 1]
+            + 5 * above_row[EDGE_OFFSET] + 8) >> 4;
+          above_row[EDGE_OFFSET - 1] = corner;
+          left_col[EDGE_OFFSET - 1] = corner;
+        }
+        if edge_filter.above_avail > 0 {
+          let strength =
+            intra_edge_filter_strength(w, h, filter_type, above_delta);
+          let num_px =
+            edge_filter.above_avail + if p_angle < 90 { h } else { 0 } + 1;
+          filter_intra_edge(&mut above_row, num_px, strength);
+        }
+        if edge_filter.left_avail > 0 {
+          let strength =
+            intra_edge_filter_strength(w, h, filter_type, left_delta);
+          let num_px =
+            edge_filter.left_avail + if p_angle > 180 { w } else { 0 } + 1;
+          filter_intra_edge(&mut left_col, num_px, strength);
+        }
+      }
+      if use_intra_edge_upsample(w, h, filter_type, above_delta) {
+        let num_px = w + if p_angle < 90 { h } else { 0 };
+        upsample_intra_edge(&mut above_row, num_px, bit_depth);
+        upsample_above = 1;
+      }
+      if use_intra_edge_upsample(w, h, filter_type, left_delta) {
+        let num_px = h + if p_angle > 180 { w } else { 0 };
+        upsample_intra_edge(&mut left_col, num_px, bit_depth);
+        upsample_left = 1;
+      }
     }
 
+    // Edge samples from index -2
+    let above_at = |i: isize| above_row[(i + EDGE_OFFSET as isize) as usize];
+    let left_at = |i: isize| left_col[(i + EDGE_OFFSET as isize) as usize];
+
     fn dr_intra_derivative(p_angle: usize) -> usize {
       match p_angle {
-        4 => 1023,
-        7 => 547,
-        10 => 372,
+        3 => 1023,
+        6 => 547,
+        9 => 372,
         14 => 273,
         17 => 215,
         20 => 178,
@@ -817,66 +1055,54 @@ where
     };
 
     if p_angle < 90 {
-      for i in 0..Self::H {
+      for i in 0..h {
         let row = &mut output[i];
-        for j in 0..Self::W {
+        for j in 0..w {
           let idx = (i + 1) * dx;
           let base = (idx >> (6 - upsample_above)) + (j << upsample_above);
           let shift = (((idx << upsample_above) >> 1) & 31) as i32;
-          let max_base_x = (Self::H + Self::W - 1) << upsample_above;
+          let max_base_x = (h + w - 1) << upsample_above;
           let v = if base < max_base_x {
-            let a: i32 = above[base].into();
-            let b: i32 = above[base + 1].into();
+            let a = above_at(base as isize);
+            let b = above_at(base as isize + 1);
             round_shift(a * (32 - shift) + b * shift, 5)
           } else {
-            let c: i32 = above[max_base_x].into();
-            c
+            above_at(max_base_x as isize)
           }.max(0).min(sample_max);
           row[j] = T::cast_from(v);
         }
       }
     } else if p_angle > 90 && p_angle < 180 {
-      for i in 0..Self::H {
+      for i in 0..h {
         let row = &mut output[i];
-        for j in 0..Self::W {
+        for j in 0..w {
           let idx = (j << 6) as isize - ((i + 1) * dx) as isize;
           let base = idx >> (6 - upsample_above);
-          if base >= -(1 << upsample_above) {
+          let v = if base >= -(1 << upsample_above) {
             let shift = (((idx << upsample_above) >> 1) & 31) as i32;
-            let a: i32 =
-              if base < 0 { top_left[0] } else { above[base as usize] }.into();
-            let b: i32 = above[(base + 1) as usize].into();
-            let v = round_shift(a * (32 - shift) + b * shift, 5)
-                .max(0)
-                .min(sample_max);
-            row[j] = T::cast_from(v);
+            let a = above_at(base);
+            let b = above_at(base + 1);
+            round_shift(a * (32 - shift) + b * shift, 5)
           } else {
             let idx = (i << 6) as isize - ((j + 1) * dy) as isize;
             let base = idx >> (6 - upsample_left);
             let shift = (((idx << upsample_left) >> 1) & 31) as i32;
-            let a: i32 = if base < 0 {
-              top_left[0]
-            } else {
-              left[Self::W + Self::H - 1 - base as usize]
-            }
-            .into();
-            let b: i32 = left[Self::W + Self::H - (2 + base) as usize].into();
-            let v = round_shift(a * (32 - shift) + b * shift, 5)
-                .max(0)
-                .min(sample_max);
-            row[j] = T::cast_from(v);
-          }
+            let a = left_at(base);
+            let b = left_at(base + 1);
+            round_shift(a * (32 - shift) + b * shift, 5)
+          }.max(0).min(sample_max);
+          row[j] = T::cast_from(v);
         }
       }
     } else if p_angle > 180 {
-      for i in 0..Self::H {
+      for i in 0..h {
         let row = &mut output[i];
-        for j in 0..Self::W {
+        for j in 0..w {
           let idx = (j + 1) * dy;
           let base = (idx >> (6 - upsample_left)) + (i << upsample_left);
           let shift = (((idx << upsample_left) >> 1) & 31) as i32;
-          let a: i32 = left[Self::W + Self::H - 1 - base].into();
-          let b: i32 = left[Self::W + Self::H - 2 - base].into();
+          let a = left_at(base as isize);
+          let b = left_at(base as isize + 1);
           let v = round_shift(a * (32 - shift) + b * shift, 5)
               .max(0)
               .min(sample_max);
@@ -894,10 +1120,19 @@ pub trait Inter: Dim {}
 mod test {
   use super::*;
   use num_traits::*;
+  use crate::cpu_features::CpuFeatureLevel;
   use crate::plane::*;
 
   #[test]
   fn pred_matches_u8() {
+    // Every kernel the CPU can run gives the same result
+    let supported = CpuFeatureLevel::supported();
+    for &level in CpuFeatureLevel::ALL.iter().filter(|&&l| l <= supported) {
+      pred_matches_u8_at(CpuKernels::new(level));
+    }
+  }
+
+  fn pred_matches_u8_at(cpu: CpuKernels) {
     let mut edge_buf: AlignedArray<[u8; 2 * MAX_TX_SIZE + 1]> =
       UninitializedAlignedArray();
     for i in 0..edge_buf.array.len() {
@@ -909,64 +1144,168 @@ mod test {
 
     let mut output = Plane::wrap(vec![0u8; 4 * 4], 4);
 
-    Block4x4::pred_dc(&mut output.as_region_mut(), above, left);
+    Block4x4::pred_dc(&mut output.as_region_mut(), above, left, cpu);
     assert_eq!(&output.data[..], [32u8; 16]);
 
-    Block4x4::pred_dc_top(&mut output.as_region_mut(), above, left);
+    Block4x4::pred_dc_top(&mut output.as_region_mut(), above, left, cpu);
     assert_eq!(&output.data[..], [35u8; 16]);
 
-    Block4x4::pred_dc_left(&mut output.as_region_mut(), above, left);
+    Block4x4::pred_dc_left(&mut output.as_region_mut(), above, left, cpu);
     assert_eq!(&output.data[..], [30u8; 16]);
 
-    Block4x4::pred_dc_128(&mut output.as_region_mut(), 8);
+    Block4x4::pred_dc_128(&mut output.as_region_mut(), 8, cpu);
     assert_eq!(&output.data[..], [128u8; 16]);
 
-    Block4x4::pred_v(&mut output.as_region_mut(), above);
+    Block4x4::pred_v(&mut output.as_region_mut(), above, cpu);
     assert_eq!(
       &output.data[..],
       [33, 34, 35, 36, 33, 34, 35, 36, 33, 34, 35, 36, 33, 34, 35, 36]
     );
 
-    Block4x4::pred_h(&mut output.as_region_mut(), left);
+    Block4x4::pred_h(&mut output.as_region_mut(), left, cpu);
     assert_eq!(
       &output.data[..],
       [31, 31, 31, 31, 30, 30, 30, 30, 29, 29, 29, 29, 28, 28, 28, 28]
     );
 
-    Block4x4::pred_paeth(&mut output.as_region_mut(), above, left, top_left);
+    Block4x4::pred_paeth(&mut output.as_region_mut(), above, left, top_left, cpu);
     assert_eq!(
       &output.data[..],
       [32, 34, 35, 36, 30, 32, 32, 36, 29, 32, 32, 32, 28, 28, 32, 32]
     );
 
-    Block4x4::pred_smooth(&mut output.as_region_mut(), above, left);
+    Block4x4::pred_smooth(&mut output.as_region_mut(), above, left, cpu);
     assert_eq!(
       &output.data[..],
       [32, 34, 35, 35, 30, 32, 33, 34, 29, 31, 32, 32, 29, 30, 32, 32]
     );
 
-    Block4x4::pred_smooth_h(&mut output.as_region_mut(), above, left);
+    Block4x4::pred_smooth_h(&mut output.as_region_mut(), above, left, cpu);
     assert_eq!(
       &output.data[..],
       [31, 33, 34, 35, 30, 33, 34, 35, 29, 32, 34, 34, 28, 31, 33, 34]
     );
 
-    Block4x4::pred_smooth_v(&mut output.as_region_mut(), above, left);
+    Block4x4::pred_smooth_v(&mut output.as_region_mut(), above, left, cpu);
     assert_eq!(
       &output.data[..],
       [33, 34, 35, 36, 31, 31, 32, 33, 30, 30, 30, 31, 29, 30, 30, 30]
     );
   }
 
+  #[test]
+  fn pred_directional_angle_delta() {
+    let mut output = Plane::wrap(vec![0u16; 4 * 4], 4);
+
+    // One step right of vertical, interpolated from the above row
+    let above: Vec<u16> = (0..8).map(|i| i * 32).collect();
+    let left = [0u16; 8];
+    let angle = pred_angle(PredictionMode::V_PRED, -1);
+    assert_eq!(angle, 87);
+    Block4x4::pred_directional(&mut output.as_region_mut(), &above, &left, &[0], angle, 10, None);
+    assert_eq!(
+      &output.data[..],
+      [1, 33, 65, 97, 3, 35, 67, 99, 4, 36, 68, 100, 6, 38, 70, 102]
+    );
+
+    // One step up from horizontal only reads the left column
+    let above = [0u16; 8];
+    let left = [1000u16; 8];
+    let angle = pred_angle(PredictionMode::H_PRED, -1);
+    assert_eq!(angle, 177);
+    Block4x4::pred_directional(&mut output.as_region_mut(), &above, &left, &[1000], angle, 10, None);
+    assert_eq!(&output.data[..], [1000u16; 16]);
+  }
+
+  #[test]
+  fn intra_edge_filter_strength_table() {
+    use IntraEdgeFilterType::*;
+    assert_eq!(intra_edge_filter_strength(4, 4, Regular, -3), 0);
+    assert_eq!(intra_edge_filter_strength(4, 4, Regular, 84), 1);
+    assert_eq!(intra_edge_filter_strength(8, 16, Regular, 16), 2);
+    assert_eq!(intra_edge_filter_strength(16, 16, Regular, -3), 2);
+    assert_eq!(intra_edge_filter_strength(16, 16, Regular, 45), 3);
+    assert_eq!(intra_edge_filter_strength(4, 4, Smooth, 45), 1);
+    assert_eq!(intra_edge_filter_strength(8, 8, Smooth, -48), 2);
+    assert_eq!(intra_edge_filter_strength(8, 16, Smooth, 3), 0);
+    assert_eq!(intra_edge_filter_strength(32, 32, Smooth, 0), 0);
+
+    assert!(use_intra_edge_upsample(8, 8, Regular, 3));
+    assert!(!use_intra_edge_upsample(8, 8, Smooth, 3));
+    assert!(!use_intra_edge_upsample(4, 4, Regular, 40));
+    assert!(!use_intra_edge_upsample(4, 4, Regular, 0));
+  }
+
+  #[test]
+  fn pred_directional_edge_filter() {
+    let filter = |filter_type, above_avail, left_avail| {
+      Some(IntraEdgeFilter { filter_type, above_avail, left_avail })
+    };
+
+    // A flat edge is left unchanged
+    let mut output = Plane::wrap(vec![0u8; 8 * 8], 8);
+    Block8x8::pred_directional(
+      &mut output.as_region_mut(), &[90; 16], &[90; 16], &[90], 157, 8,
+      filter(IntraEdgeFilterType::Regular, 8, 8)
+    );
+    assert_eq!(&output.data[..], &[90u8; 64][..]);
+
+    // Small blocks close to vertical upsample the above row
+    let above = [0u8, 0, 255, 255, 0, 0, 255, 255];
+    let mut output = Plane::wrap(vec![0u8; 4 * 4], 4);
+    Block4x4::pred_directional(
+      &mut output.as_region_mut(), &above, &[0; 8], &[0], 87, 8,
+      filter(IntraEdgeFilterType::Regular, 4, 4)
+    );
+    assert_eq!(
+      &output.data[..],
+      [0, 12, 255, 243, 0, 24, 255, 231, 0, 36, 255, 219, 0, 48, 255, 207]
+    );
+    Block4x4::pred_directional(
+      &mut output.as_region_mut(), &above, &[0; 8], &[0], 87, 8, None
+    );
+    assert_eq!(
+      &output.data[..],
+      [0, 8, 255, 247, 0, 24, 255, 231, 0, 32, 255, 223, 0, 48, 255, 207]
+    );
+
+    // Large blocks filter the corner and both edges at full strength
+    let above: Vec<u8> =
+      (0..32).map(|i| if i % 4 < 2 { 200 } else { 40 }).collect();
+    let left: Vec<u8> = (0..32).rev().map(|i| (i * 7) as u8).collect();
+    let mut output = Plane::wrap(vec![0u8; 16 * 16], 16);
+    Block16x16::pred_directional(
+      &mut output.as_region_mut(), &above, &left, &[100], 135, 8,
+      filter(IntraEdgeFilterType::Regular, 16, 16)
+    );
+    assert_eq!(&output.data[..8], [100, 143, 128, 120, 120, 120, 120, 120]);
+    assert_eq!(&output.data[8 * 16..8 * 16 + 8], [49, 42, 35, 28, 21, 14, 20, 41]);
+
+    // Only the available edges are filtered
+    let left: Vec<u8> = (0..16).rev().map(|i| (i * i % 200) as u8).collect();
+    let mut output = Plane::wrap(vec![0u8; 8 * 8], 8);
+    Block8x8::pred_directional(
+      &mut output.as_region_mut(), &[50; 16], &left, &[50], 203, 8,
+      filter(IntraEdgeFilterType::Smooth, 8, 8)
+    );
+    assert_eq!(&output.data[7 * 8..], [56, 63, 69, 77, 84, 92, 100, 109]);
+    Block8x8::pred_directional(
+      &mut output.as_region_mut(), &[50; 16], &left, &[50], 203, 8,
+      filter(IntraEdgeFilterType::Smooth, 8, 0)
+    );
+    assert_eq!(&output.data[7 * 8..], [55, 62, 68, 76, 83, 91, 99, 108]);
+  }
+
   #[test]
   fn pred_max() {
+    let cpu = CpuKernels::new(CpuFeatureLevel::detect());
     let max12bit = 4096 - 1;
     let above = [max12bit; 32];
     let left = [max12bit; 32];
 
     let mut o = Plane::wrap(vec![0u16; 32 * 32], 32);
 
-    Block4x4::pred_dc(&mut o.as_region_mut(), &above[..4], &left[..4]);
+    Block4x4::pred_dc(&mut o.as_region_mut(), &above[..4], &left[..4], cpu);
 
     for l in o.data.chunks(32).take(4) {
       for v in l[..4].iter() {
@@ -974,7 +1313,7 @@ mod test {
       }
     }
 
-    Block4x4::pred_h(&mut o.as_region_mut(), &left[..4]);
+    Block4x4::pred_h(&mut o.as_region_mut(), &left[..4], cpu);
 
     for l in o.data.chunks(32).take(4) {
       for v in l[..4].iter() {
@@ -982,7 +1321,7 @@ mod test {
       }
     }
 
-    Block4x4::pred_v(&mut o.as_region_mut(), &above[..4]);
+    Block4x4::pred_v(&mut o.as_region_mut(), &above[..4], cpu);
 
     for l in o.data.chunks(32).take(4) {
       for v in l[..4].iter() {
@@ -992,7 +1331,7 @@ mod test {
 
     let above_left = unsafe { *above.as_ptr().offset(-1) };
 
-    Block4x4::pred_paeth(&mut o.as_region_mut(), &above[..4], &left[..4], above_left);
+    Block4x4::pred_paeth(&mut o.as_region_mut(), &above[..4], &left[..4], above_left, cpu);
 
     for l in o.data.chunks(32).take(4) {
       for v in l[..4].iter() {
@@ -1000,7 +1339,7 @@ mod test {
       }
     }
 
-    Block4x4::pred_smooth(&mut o.as_region_mut(), &above[..4], &left[..4]);
+    Block4x4::pred_smooth(&mut o.as_region_mut(), &above[..4], &left[..4], cpu);
 
     for l in o.data.chunks(32).take(4) {
       for v in l[..4].iter() {
@@ -1008,7 +1347,7 @@ mod test {
       }
     }
 
-    Block4x4::pred_smooth_h(&mut o.as_region_mut(), &above[..4], &left[..4]);
+    Block4x4::pred_smooth_h(&mut o.as_region_mut(), &above[..4], &left[..4], cpu);
 
     for l in o.data.chunks(32).take(4) {
       for v in l[..4].iter() {
@@ -1016,7 +1355,7 @@ mod test {
       }
     }
 
-    Block4x4::pred_smooth_v(&mut o.as_region_mut(), &above[..4], &left[..4]);
+    Block4x4::pred_smooth_v(&mut o.as_region_mut(), &above[..4], &left[..4], cpu);
 
     for l in o.data.chunks(32).take(4) {
       for v in l[..4].iter() {
@@ -0,0 +1,158 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Filters applied by the application to the input frames, as the encoder
+//! receives them.
+
+use crate::encoder::Frame;
+use crate::plane::Plane;
+use crate::util::{CastFromPrimitive, Pixel};
+
+use std::any::Any;
+use std::fmt;
+
+/// A filter processing each input frame in place.
+///
+/// It is run exactly once per frame, in display order, when the frame is
+/// sent to the encoder: after it is scaled to the internal bit depth and
+/// before any analysis, including scene change detection.
+pub trait FrameFilter<T: Pixel>: Send {
+  /// Processes the frame number `frameno`, counted from the first frame
+  /// sent to the context
+  fn process(&mut self, frame: &mut Frame<T>, frameno: u64);
+}
+
+/// A filter for both 8-bit and high bit depth frames, as registered in
+/// `Config::frame_filter`
+pub trait AnyFrameFilter: FrameFilter<u8> + FrameFilter<u16> + fmt::Debug {}
+
+impl<F> AnyFrameFilter for F
+where
+  F: FrameFilter<u8> + FrameFilter<u16> + fmt::Debug
+{}
+
+/// Runs the filter for the pixel type of the frame
+pub(crate) fn apply_filter<T: Pixel>(
+  filter: &mut dyn AnyFrameFilter, frame: &mut Frame<T>, frameno: u64
+) {
+  let frame: &mut dyn Any = frame;
+  if let Some(frame) = frame.downcast_mut::<Frame<u8>>() {
+    FrameFilter::<u8>::process(filter, frame, frameno);
+  } else if let Some(frame) = frame.downcast_mut::<Frame<u16>>() {
+    FrameFilter::<u16>::process(filter, frame, frameno);
+  }
+}
+
+/// Unsharp mask: each sample is moved away from the average of its 3x3
+/// neighbourhood by `amount` eighths of the difference. A negative amount
+/// blurs instead, down to -8 for the plain [1 2 1] blur.
+#[derive(Clone, Copy, Debug)]
+pub struct Sharpen {
+  amount: i32,
+  bit_depth: usize,
+}
+
+impl Sharpen {
+  pub fn new(amount: i32, bit_depth: usize) -> Self {
+    assert!(amount >= -8, "Cannot blur by more than -8");
+    Sharpen { amount, bit_depth }
+  }
+
+  fn process_plane<T: Pixel>(&self, plane: &mut Plane<T>) {
+    let src = plane.clone();
+    let (width, height) = (plane.cfg.width, plane.cfg.height);
+    let stride = plane.cfg.stride;
+    let max = (1 << self.bit_depth) - 1;
+    let sample = |x: isize, y: isize| {
+      let x = x.max(0).min(width as isize - 1) as usize;
+      let y = y.max(0).min(height as isize - 1) as usize;
+      i32::cast_from(src.p(x, y))
+    };
+
+    let dst = plane.data_origin_mut();
+    for y in 0..height {
+      for x in 0..width {
+        let (xi, yi) = (x as isize, y as isize);
+        let row = |y| sample(xi - 1, y) + 2 * sample(xi, y) + sample(xi + 1, y);
+        let blur = (row(yi - 1) + 2 * row(yi) + row(yi + 1) + 8) >> 4;
+        let c = sample(xi, yi);
+        let v = c + ((self.amount * (c - blur) + 4) >> 3);
+        dst[y * stride + x] = T::cast_from(v.max(0).min(max));
+      }
+    }
+  }
+}
+
+impl<T: Pixel> FrameFilter<T> for Sharpen {
+  fn process(&mut self, frame: &mut Frame<T>, _frameno: u64) {
+    if self.amount == 0 {
+      return;
+    }
+    for plane in frame.planes.iter_mut() {
+      self.process_plane(plane);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::api::ChromaSampling;
+
+  fn edge_frame() -> Frame<u8> {
+    let mut frame = Frame::new(16, 16, ChromaSampling::Cs420);
+    for plane in frame.planes.iter_mut() {
+      let stride = plane.cfg.stride;
+      let width = plane.cfg.width;
+      for row in plane.data_origin_mut().chunks_mut(stride) {
+        for (x, v) in row.iter_mut().enumerate() {
+          *v = if x < width / 2 { 64 } else { 192 };
+        }
+      }
+    }
+    frame
+  }
+
+  #[test]
+  fn sharpen_and_blur_edges() {
+    let src = edge_frame();
+    let step = |frame: &Frame<u8>| {
+      let luma = &frame.planes[0];
+      i32::from(luma.p(8, 4)) - i32::from(luma.p(7, 4))
+    };
+
+    let mut sharp = src.clone();
+    FrameFilter::<u8>::process(&mut Sharpen::new(8, 8), &mut sharp, 0);
+    assert!(step(&sharp) > step(&src));
+    // Flat areas are left alone
+    assert_eq!(sharp.planes[0].p(2, 4), 64);
+
+    let mut blurred = src.clone();
+    FrameFilter::<u8>::process(&mut Sharpen::new(-8, 8), &mut blurred, 0);
+    assert!(step(&blurred) < step(&src));
+    assert_eq!(blurred.planes[0].p(2, 4), 64);
+  }
+
+  #[test]
+  fn sharpen_clamps_to_bit_depth() {
+    let mut frame = Frame::<u16>::new(16, 16, ChromaSampling::Cs420);
+    for plane in frame.planes.iter_mut() {
+      let stride = plane.cfg.stride;
+      for row in plane.data_origin_mut().chunks_mut(stride) {
+        for (x, v) in row.iter_mut().enumerate() {
+          *v = if x % 2 == 0 { 0 } else { 1023 };
+        }
+      }
+    }
+    FrameFilter::<u16>::process(&mut Sharpen::new(16, 10), &mut frame, 0);
+    for plane in frame.planes.iter() {
+      assert!(plane.data.iter().all(|&v| v <= 1023));
+    }
+  }
+}
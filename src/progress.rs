@@ -0,0 +1,81 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Hooks for the application to follow the encoding of each frame and to
+//! cancel it.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Observer of the encoding of the frames, superblock by superblock.
+///
+/// The tiles of a frame are coded in parallel, so the methods may be called
+/// from several threads at once.
+pub trait EncodeProgress: Send + Sync + fmt::Debug {
+  /// Called for the superblock `sb_idx` of the frame number `frame_idx`,
+  /// `sb_idx` counting the `total_sb` superblocks of the frame in raster
+  /// order. The superblocks are reported in that order once the frame is
+  /// coded for good, never for an encode recoded to fit
+  /// `max_frame_size_bytes` or cancelled.
+  fn on_superblock(&self, _frame_idx: u64, _sb_idx: usize, _total_sb: usize) {
+  }
+
+  /// Checked before each superblock is coded: once it returns `true`, the
+  /// frame being coded is abandoned and `Context::receive_packet` returns
+  /// `EncoderStatus::Cancelled`
+  fn is_cancelled(&self) -> bool {
+    false
+  }
+}
+
+/// A cancellation flag shared by its clones, for instance between the
+/// encoder and the user interface
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+  cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Requests the encoders using the token to stop at the next superblock
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::SeqCst);
+  }
+
+  /// Clears the request, so that the encoding can resume
+  pub fn reset(&self) {
+    self.cancelled.store(false, Ordering::SeqCst);
+  }
+}
+
+impl EncodeProgress for CancelToken {
+  fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::SeqCst)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn cancel_token_is_shared() {
+    let token = CancelToken::new();
+    let progress: Arc<dyn EncodeProgress> = Arc::new(token.clone());
+    assert!(!progress.is_cancelled());
+    token.cancel();
+    assert!(progress.is_cancelled());
+    token.reset();
+    assert!(!progress.is_cancelled());
+  }
+}
@@ -22,32 +22,71 @@ pub trait Coefficient:
 impl Coefficient for i16 {}
 impl Coefficient for i32 {}
 
+/// Log2 of the factor the coefficients of a transform are scaled down by
+/// before quantization, and up by after dequantization. It only depends on
+/// the area: the transforms of the 2:1 rectangular sizes include their
+/// sqrt(2) factor.
 pub fn get_log_tx_scale(tx_size: TxSize) -> usize {
   let num_pixels = tx_size.area();
 
   Into::<usize>::into(num_pixels > 256) + Into::<usize>::into(num_pixels > 1024)
 }
 
-pub fn dc_q(qindex: u8, delta_q: i8, bit_depth: usize) -> i16 {
-  let &table = match bit_depth {
+/// Quantizers of the DC coefficients of the spec for each `qindex`, in Q3
+/// relative to the samples of the bit depth
+fn dc_qlookup(bit_depth: usize) -> &'static [i16; QINDEX_RANGE] {
+  match bit_depth {
     8 => &dc_qlookup_Q3,
     10 => &dc_qlookup_10_Q3,
     12 => &dc_qlookup_12_Q3,
     _ => unimplemented!()
-  };
-
-  table[(qindex as isize + delta_q as isize).max(0).min(255) as usize]
+  }
 }
 
-pub fn ac_q(qindex: u8, delta_q: i8, bit_depth: usize) -> i16 {
-  let &table = match bit_depth {
+/// Quantizers of the AC coefficients of the spec for each `qindex`, in Q3
+/// relative to the samples of the bit depth
+fn ac_qlookup(bit_depth: usize) -> &'static [i16; QINDEX_RANGE] {
+  match bit_depth {
     8 => &ac_qlookup_Q3,
     10 => &ac_qlookup_10_Q3,
     12 => &ac_qlookup_12_Q3,
     _ => unimplemented!()
-  };
+  }
+}
+
+fn offset_qindex(qindex: u8, delta_q: i8) -> usize {
+  (qindex as isize + delta_q as isize).max(MINQ as isize).min(MAXQ as isize)
+    as usize
+}
+
+pub fn dc_q(qindex: u8, delta_q: i8, bit_depth: usize) -> i16 {
+  dc_qlookup(bit_depth)[offset_qindex(qindex, delta_q)]
+}
+
+pub fn ac_q(qindex: u8, delta_q: i8, bit_depth: usize) -> i16 {
+  ac_qlookup(bit_depth)[offset_qindex(qindex, delta_q)]
+}
 
-  table[(qindex as isize + delta_q as isize).max(0).min(255) as usize]
+/// Default rounding of the deadzone quantizer for intra blocks, in 1/64 of
+/// the quantizer. To be tuned.
+pub const INTRA_ROUNDING: u8 = 21;
+/// Default rounding of the deadzone quantizer for inter blocks, in 1/64 of
+/// the quantizer. To be tuned.
+pub const INTER_ROUNDING: u8 = 15;
+
+/// Rounding offset of the deadzone quantizer, `rounding` being in 1/64 of
+/// the quantizer: coefficients within `quant - offset` of zero quantize to
+/// zero, so a larger rounding shrinks the deadzone
+fn deadzone_offset(quant: i32, rounding: u8) -> i32 {
+  quant * rounding as i32 / 64
+}
+
+/// Reconstructs a coefficient from its quantized `level`, as the decoder
+/// does: the product is scaled down rounding towards zero
+#[inline]
+pub fn dequantize_coeff(level: i32, quant: i32, log_tx_scale: usize) -> i32 {
+  let offset = (1 << log_tx_scale) - 1;
+  (level * quant + ((level >> 31) & offset)) >> log_tx_scale
 }
 
 // TODO: Handle lossless properly.
@@ -76,23 +115,11 @@ fn select_qi(quantizer: i64, qlookup: &[i16; QINDEX_RANGE]) -> u8 {
 }
 
 pub fn select_dc_qi(quantizer: i64, bit_depth: usize) -> u8 {
-  let qlookup = match bit_depth {
-    8 => &dc_qlookup_Q3,
-    10 => &dc_qlookup_10_Q3,
-    12 => &dc_qlookup_12_Q3,
-    _ => unimplemented!()
-  };
-  select_qi(quantizer, qlookup)
+  select_qi(quantizer, dc_qlookup(bit_depth))
 }
 
 pub fn select_ac_qi(quantizer: i64, bit_depth: usize) -> u8 {
-  let qlookup = match bit_depth {
-    8 => &ac_qlookup_Q3,
-    10 => &ac_qlookup_10_Q3,
-    12 => &ac_qlookup_12_Q3,
-    _ => unimplemented!()
-  };
-  select_qi(quantizer, qlookup)
+  select_qi(quantizer, ac_qlookup(bit_depth))
 }
 
 #[derive(Debug, Default, Clone, Copy)]
@@ -143,6 +170,7 @@ fn divu_pair(x: i32, d: (u32, u32, u32)) -> i32 {
 #[cfg(test)]
 mod test {
   use super::*;
+  use crate::api::QuantRounding;
   use crate::partition::TxSize::*;
 
   #[test]
@@ -188,11 +216,138 @@ mod test {
       assert!(tx_size.1 == get_log_tx_scale(tx_size.0));
     }
   }
+
+  #[test]
+  fn quantizer_tables() {
+    for &bit_depth in &[8, 10, 12] {
+      for &table in &[dc_qlookup(bit_depth), ac_qlookup(bit_depth)] {
+        assert!(table.windows(2).all(|w| w[0] <= w[1]));
+      }
+      // The quantizers scale with the samples, by 2 bits per 2 bits of depth
+      let shift = bit_depth - 8;
+      let ratio = ac_q(255, 0, bit_depth) as f64 / (ac_q(255, 0, 8) << shift) as f64;
+      assert!((ratio - 1.).abs() < 0.01, "{}-bit ratio {}", bit_depth, ratio);
+      assert_eq!(dc_q(0, -10, bit_depth), dc_q(0, 0, bit_depth));
+      assert_eq!(ac_q(250, 10, bit_depth), ac_q(255, 0, bit_depth));
+    }
+  }
+
+  #[test]
+  fn quantize_dequantize_round_trip() {
+    let tx_sizes = [
+      TX_4X4, TX_8X8, TX_16X16, TX_32X32, TX_64X64, TX_4X8, TX_8X4,
+      TX_8X16, TX_16X8, TX_16X32, TX_32X16, TX_32X64, TX_64X32, TX_4X16,
+      TX_16X4, TX_8X32, TX_32X8, TX_16X64, TX_64X16
+    ];
+    let mut qc = QuantizationContext::default();
+    for &bit_depth in &[8, 10, 12] {
+      for qindex in 0..=255u8 {
+        let dc_quant = dc_q(qindex, 0, bit_depth) as i32;
+        let ac_quant = ac_q(qindex, 0, bit_depth) as i32;
+        for &tx_size in tx_sizes.iter() {
+          let log_tx_scale = get_log_tx_scale(tx_size);
+          // Coefficients up to 32 times the quantizer, of both signs
+          let n = tx_size.area().min(1024);
+          let coeffs: Vec<i32> = (0..n as i32)
+            .map(|i| (i - n as i32 / 2) * 64 * ac_quant / n as i32 + i % 7)
+            .collect();
+          let mut qcoeffs = vec![0; n];
+          let mut rcoeffs = vec![0; n];
+          for &rounding in &[INTRA_ROUNDING, INTER_ROUNDING] {
+            qc.update(qindex, tx_size, rounding, bit_depth, 0, 0);
+            qc.quantize(&coeffs, &mut qcoeffs, n);
+            dequantize(
+              qindex, &qcoeffs, &mut rcoeffs, tx_size, bit_depth, 0, 0
+            );
+
+            for (i, (&c, &r)) in coeffs.iter().zip(rcoeffs.iter()).enumerate() {
+              let quant = if i == 0 { dc_quant } else { ac_quant };
+              let offset = deadzone_offset(quant, rounding);
+              // Below by less than the rounding offset, and above by less
+              // than the rest of a step, both scaled by the transform
+              let err = c.abs() - r.abs();
+              assert!(
+                c.signum() * r.signum() >= 0
+                  && err >= -(offset >> log_tx_scale) - 1
+                  && err <= ((quant - offset) >> log_tx_scale) + 1,
+                "{}-bit qindex {} {:?}: {} reconstructed as {}",
+                bit_depth, qindex, tx_size, c, r
+              );
+            }
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn default_rounding_is_bit_exact() {
+    // The offsets the quantizer used before they were configurable
+    assert_eq!((INTRA_ROUNDING, INTER_ROUNDING), (21, 15));
+    let mut qc = QuantizationContext::default();
+    let n = 256;
+    let coeffs: Vec<i32> =
+      (0..n as i32).map(|i| (i - 128) * 37 + i % 11).collect();
+    let mut qcoeffs = vec![0; n];
+    let mut expected = vec![0; n];
+    for &bit_depth in &[8, 10] {
+      for qindex in (1..=255u8).step_by(17) {
+        let dc_quant = dc_q(qindex, 0, bit_depth) as i32;
+        let ac_quant = ac_q(qindex, 0, bit_depth) as i32;
+        for &(rounding, factor) in &[(INTRA_ROUNDING, 21), (INTER_ROUNDING, 15)] {
+          qc.update(qindex, TX_16X16, rounding, bit_depth, 0, 0);
+          qc.quantize(&coeffs, &mut qcoeffs, n);
+          for (i, (e, &c)) in expected.iter_mut().zip(coeffs.iter()).enumerate() {
+            let quant = if i == 0 { dc_quant } else { ac_quant };
+            *e = (c + c.signum() * (quant * factor / 64)) / quant;
+          }
+          assert_eq!(qcoeffs, expected);
+        }
+        quantize_wo_mf(qindex, &coeffs, &mut expected, TX_16X16, bit_depth, 0, 0);
+        qc.update(qindex, TX_16X16, INTRA_ROUNDING, bit_depth, 0, 0);
+        qc.quantize(&coeffs, &mut qcoeffs, n);
+        assert_eq!(qcoeffs, expected);
+      }
+    }
+  }
+
+  #[test]
+  fn rounding_moves_nonzero_count() {
+    let mut qc = QuantizationContext::default();
+    let n = 1024;
+    // Coefficients spread evenly up to 4 times the quantizer
+    let ac_quant = ac_q(100, 0, 8) as i32;
+    let coeffs: Vec<i32> = (0..n as i32)
+      .map(|i| (i - n as i32 / 2) * 8 * ac_quant / n as i32)
+      .collect();
+    let mut qcoeffs = vec![0; n];
+    let mut prev: Option<(usize, i32)> = None;
+    for rounding in 0..=32 {
+      qc.update(100, TX_32X32, rounding, 8, 0, 0);
+      qc.quantize(&coeffs, &mut qcoeffs, n);
+      let nonzero = qcoeffs.iter().filter(|&&c| c != 0).count();
+      let sum: i32 = qcoeffs.iter().map(|c| c.abs()).sum();
+      // A smaller deadzone codes more, and larger, coefficients
+      if let Some((prev_nonzero, prev_sum)) = prev {
+        assert!(nonzero >= prev_nonzero && sum >= prev_sum, "{}/64", rounding);
+      }
+      prev = Some((nonzero, sum));
+    }
+    let mut count = |rounding| {
+      qc.update(100, TX_32X32, rounding, 8, 0, 0);
+      qc.quantize(&coeffs, &mut qcoeffs, n);
+      qcoeffs.iter().filter(|&&c| c != 0).count()
+    };
+    assert!(count(INTER_ROUNDING) < count(INTRA_ROUNDING));
+    assert!(count(0) < count(QuantRounding::MAX));
+  }
 }
 
 impl QuantizationContext {
+  /// Sets the quantizers of the block, `rounding` being the rounding
+  /// offset in 1/64 of the quantizer, see `FrameInvariants::quant_rounding`
   pub fn update(
-    &mut self, qindex: u8, tx_size: TxSize, is_intra: bool, bit_depth: usize,
+    &mut self, qindex: u8, tx_size: TxSize, rounding: u8, bit_depth: usize,
     dc_delta_q: i8, ac_delta_q: i8
   ) {
     self.log_tx_scale = get_log_tx_scale(tx_size);
@@ -203,10 +358,8 @@ impl QuantizationContext {
     self.ac_quant = ac_q(qindex, ac_delta_q, bit_depth) as u32;
     self.ac_mul_add = divu_gen(self.ac_quant);
 
-    self.dc_offset =
-      self.dc_quant as i32 * (if is_intra { 21 } else { 15 }) / 64;
-    self.ac_offset =
-      self.ac_quant as i32 * (if is_intra { 21 } else { 15 }) / 64;
+    self.dc_offset = deadzone_offset(self.dc_quant as i32, rounding);
+    self.ac_offset = deadzone_offset(self.ac_quant as i32, rounding);
   }
 
   #[inline]
@@ -241,9 +394,8 @@ pub fn quantize_wo_mf(
   let dc_quant = dc_q(qindex, dc_delta_q, bit_depth) as i32;
   let ac_quant = ac_q(qindex, ac_delta_q, bit_depth) as i32;
 
-  // using 21/64=0.328125 as rounding offset. To be tuned
-  let dc_offset = dc_quant * 21 / 64 as i32;
-  let ac_offset = ac_quant * 21 / 64 as i32;
+  let dc_offset = deadzone_offset(dc_quant, INTRA_ROUNDING);
+  let ac_offset = deadzone_offset(ac_quant, INTRA_ROUNDING);
 
   qcoeffs[0] = coeffs[0] << log_tx_scale;
   qcoeffs[0] += qcoeffs[0].signum() * dc_offset;
@@ -260,15 +412,14 @@ pub fn dequantize(
   qindex: u8, coeffs: &[i32], rcoeffs: &mut [i32], tx_size: TxSize,
   bit_depth: usize, dc_delta_q: i8, ac_delta_q: i8
 ) {
-  let log_tx_scale = get_log_tx_scale(tx_size) as i32;
-  let offset = (1 << log_tx_scale) - 1;
+  let log_tx_scale = get_log_tx_scale(tx_size);
 
   let dc_quant = dc_q(qindex, dc_delta_q, bit_depth) as i32;
   let ac_quant = ac_q(qindex, ac_delta_q, bit_depth) as i32;
 
   for (i, (r, &c)) in rcoeffs.iter_mut().zip(coeffs.iter()).enumerate() {
     let quant = if i == 0 { dc_quant } else { ac_quant };
-    *r = (c * quant + ((c >> 31) & offset)) >> log_tx_scale;
+    *r = dequantize_coeff(c, quant, log_tx_scale);
   }
 }
 
@@ -8,12 +8,16 @@
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
 use crate::api::ContextInner;
+use crate::api::QuantRounding;
 use crate::quantize::ac_q;
 use crate::quantize::dc_q;
+use crate::quantize::INTER_ROUNDING;
+use crate::quantize::INTRA_ROUNDING;
 use crate::quantize::select_ac_qi;
 use crate::quantize::select_dc_qi;
 use crate::util::clamp;
 use crate::util::Pixel;
+use std::cmp::Ordering;
 
 // The number of frame sub-types for which we track distinct parameters.
 pub const FRAME_NSUBTYPES: usize = 4;
@@ -57,6 +61,17 @@ const DQP_Q57: &[i64; FRAME_NSUBTYPES] = &[
   (2.0 * (33_810_170.0 / 86_043_287.0) * (1i64 << 57) as f64) as i64
 ];
 
+// The change in the log of the scale of the rate model, in Q57, for a
+//  quantizer rounding offset moved from its default, both in 1/64 of the
+//  quantizer.
+// Smaller deadzones keep more coefficients at the same quantizer: each 1/64
+//  is taken as 1/32 of an octave more bits.
+// This slope is a guess, not fit to measured frame sizes; it only sets the
+//  starting point of the model, which then adapts to the actual sizes.
+fn rounding_log_scale(rounding: u8, default: u8) -> i64 {
+  (rounding as i64 - default as i64)*q57(1)/32
+}
+
 // Integer binary logarithm of a 64-bit value.
 // v: A 64-bit value.
 // Returns floor(log2(v)) + 1, or 0 if v == 0.
@@ -370,6 +385,10 @@ pub struct RCState {
   // The maximum quantizer index to allow (for the luma AC coefficients, other
   //  quantizers will still be adjusted to match).
   maybe_ac_qi_max: Option<u8>,
+  // The range of luma AC quantizer indices frames are coded with, whatever
+  //  the rate target.
+  ac_qi_min: u8,
+  ac_qi_max: u8,
   // Will we drop frames to meet bitrate requirements?
   drop_frames: bool,
   // Do we respect the maximum reservoir fullness?
@@ -383,7 +402,8 @@ pub struct RCState {
   twopass_state: i32,
   // The log of the number of pixels in a frame in Q57 format.
   log_npixels: i64,
-  // The target average bits per frame.
+  // The target average bits per frame, for a frame lasting a single tick of
+  //  the time base.
   bits_per_frame: i64,
   // The current bit reservoir fullness (bits available to be used).
   reservoir_fullness: i64,
@@ -397,19 +417,23 @@ pub struct RCState {
   log_scale: [i64; FRAME_NSUBTYPES],
   // The exponent used in the rate model in Q6 format.
   exp: [u8; FRAME_NSUBTYPES],
-  // The log of an estimated scale factor used to obtain the real framerate,
-  //  for VFR sources or, e.g., 12 fps content doubled to 24 fps, etc.
-  // TODO vfr: log_vfr_scale: i64,
+  // The estimated average frame duration in ticks of the time base, in Q8
+  //  format, used to obtain the real framerate for VFR sources or, e.g.,
+  //  12 fps content doubled to 24 fps, etc.
+  vfr_duration_q8: i32,
   // Second-order lowpass filters to track scale and VFR.
   scalefilter: [IIRBessel2; FRAME_NSUBTYPES],
-  // TODO vfr: vfrfilter: IIRBessel2,
+  vfrfilter: IIRBessel2,
   // The number of frames of each type we have seen, for filter adaptation
   //  purposes.
   nframes: [i32; FRAME_NSUBTYPES],
   inter_delay: [i32; FRAME_NSUBTYPES - 1],
   inter_delay_target: i32,
   // The total accumulated estimation bias.
-  rate_bias: i64
+  rate_bias: i64,
+  // The bits spent on the frames coded so far, and the bits they were given.
+  bits_spent: i64,
+  bits_budget: i64
 }
 
 // TODO: Separate qi values for each color plane.
@@ -427,7 +451,11 @@ pub struct QuantizerParameters {
   pub log_target_q: i64,
   pub dc_qi: [u8; 3],
   pub ac_qi: [u8; 3],
-  pub lambda: f64
+  pub lambda: f64,
+  // Less if the quantizer was raised to the minimum of the range allowed
+  //  instead of meeting the rate target, Greater if it was lowered to its
+  //  maximum, Equal otherwise.
+  pub(crate) clamped: Ordering
 }
 
 const Q57_SQUARE_EXP_SCALE: f64 =
@@ -467,8 +495,24 @@ impl QuantizerParameters {
         select_ac_qi(quantizer_v, bit_depth).max(1)
       ],
       lambda: (::std::f64::consts::LN_2 / 6.0)
-        * ((log_target_q as f64) * Q57_SQUARE_EXP_SCALE).exp()
+        * ((log_target_q as f64) * Q57_SQUARE_EXP_SCALE).exp(),
+      clamped: Ordering::Equal
+    }
+  }
+
+  // Returns the parameters for a quantizer roughly a quarter of an octave
+  //  coarser than this one, or None if the luma AC quantizer index is already
+  //  at its maximum.
+  // This is used to recode frames that exceed a size limit.
+  pub(crate) fn coarser(&self, bit_depth: usize) -> Option<QuantizerParameters> {
+    if self.ac_qi[0] == 255 {
+      return None;
     }
+    Some(QuantizerParameters::new_from_log_q(
+      self.log_base_q,
+      self.log_target_q + (q57(1) >> 2),
+      bit_depth
+    ))
   }
 }
 
@@ -476,7 +520,8 @@ impl RCState {
   pub fn new(
     frame_width: i32, frame_height: i32, framerate_num: i64,
     framerate_den: i64, target_bitrate: i32, maybe_ac_qi_max: Option<u8>,
-    max_key_frame_interval: i32
+    ac_qi_min: u8, ac_qi_max: u8, max_key_frame_interval: i32,
+    quant_rounding: QuantRounding
   ) -> RCState {
     // The buffer size is set equal to 1.5x the keyframe interval, clamped to
     //  the range [12, 256] frames.
@@ -528,11 +573,22 @@ impl RCState {
       p_exp = 73;
       p_log_scale = blog64(1260) - q57(QSCALE);
     }
+    // Account for the deadzones of the quantizer. The B1 frames are the top
+    //  of the default pyramid, which no other frame predicts from.
+    let i_log_scale = i_log_scale
+      + rounding_log_scale(quant_rounding.key, INTRA_ROUNDING);
+    let b1_log_scale = p_log_scale
+      + rounding_log_scale(quant_rounding.inter_nonref, INTER_ROUNDING);
+    let p_log_scale = p_log_scale
+      + rounding_log_scale(quant_rounding.inter_ref, INTER_ROUNDING);
     // TODO: Add support for "golden" P frames.
     RCState {
       target_bitrate,
       reservoir_frame_delay,
       maybe_ac_qi_max,
+      // Lossless coding is not supported.
+      ac_qi_min: ac_qi_min.max(1),
+      ac_qi_max,
       // By default, enforce hard buffer constraints.
       drop_frames: true,
       cap_overflow: true,
@@ -544,7 +600,7 @@ impl RCState {
       reservoir_fullness: reservoir_target,
       reservoir_target,
       reservoir_max,
-      log_scale: [i_log_scale, p_log_scale, p_log_scale, p_log_scale],
+      log_scale: [i_log_scale, p_log_scale, p_log_scale, b1_log_scale],
       exp: [i_exp, p_exp, p_exp, p_exp],
       scalefilter: [
         IIRBessel2::new(4, q57_to_q24(i_log_scale)),
@@ -552,17 +608,22 @@ impl RCState {
         IIRBessel2::new(INTER_DELAY_TARGET_MIN, q57_to_q24(p_log_scale)),
         IIRBessel2::new(INTER_DELAY_TARGET_MIN, q57_to_q24(p_log_scale))
       ],
-      // TODO VFR
+      vfr_duration_q8: 1 << 8,
+      vfrfilter: IIRBessel2::new(INTER_DELAY_TARGET_MIN, 1 << 8),
       nframes: [0; FRAME_NSUBTYPES],
       inter_delay: [INTER_DELAY_TARGET_MIN; FRAME_NSUBTYPES - 1],
       inter_delay_target: reservoir_frame_delay >> 1,
-      rate_bias: 0
+      rate_bias: 0,
+      bits_spent: 0,
+      bits_budget: 0
     }
   }
 
   // TODO: Separate quantizers for Cb and Cr.
+  // The frame duration is in ticks of the time base.
   pub fn select_qi<T: Pixel>(
-    &self, ctx: &ContextInner<T>, fti: usize, maybe_prev_log_base_q: Option<i64>
+    &self, ctx: &ContextInner<T>, fti: usize, maybe_prev_log_base_q: Option<i64>,
+    duration: u64
   ) -> QuantizerParameters {
     // Is rate control active?
     if self.target_bitrate <= 0 {
@@ -600,14 +661,18 @@ impl RCState {
           let mut nframes: [i32; FRAME_NSUBTYPES] = [0; FRAME_NSUBTYPES];
           let reservoir_frames = ctx.guess_frame_subtypes(&mut nframes,
             self.reservoir_frame_delay);
-          // TODO: Scale for VFR.
+          // Scale the budget of an average frame for VFR.
+          let vfr_bits_per_frame =
+            (self.bits_per_frame*(self.vfr_duration_q8 as i64) + 128) >> 8;
+          // The budget of this specific frame, given how long it lasts.
+          let frame_bits = self.frame_bits(duration);
           // If we've been missing our target, add a penalty term.
           let rate_bias =
             (self.rate_bias/(ctx.idx as i64 + 100))*(reservoir_frames as i64);
           // rate_total is the total bits available over the next
           //  reservoir_frames frames.
           let rate_total = self.reservoir_fullness - self.reservoir_target
-            + rate_bias + (reservoir_frames as i64)*self.bits_per_frame;
+            + rate_bias + (reservoir_frames as i64)*vfr_bits_per_frame;
           // Find a target quantizer that meets our rate target for the
           //  specific mix of frame types we'll have over the next
           //  reservoir_frame frames.
@@ -668,6 +733,14 @@ impl RCState {
           let mut log_q =
             ((log_base_q + (1i64 << 11)) >> 12)*(MQP_Q12[fti] as i64)
             + DQP_Q57[fti];
+          // Frames lasting longer than average get a proportionally larger
+          //  share of the bits, and shorter ones a smaller one.
+          if frame_bits != vfr_bits_per_frame {
+            let log_duration_scale = blog64(frame_bits) - blog64(vfr_bits_per_frame);
+            let exp = self.exp[fti] as i64;
+            let log_q_exp = ((log_q + 32) >> 6)*exp - log_duration_scale;
+            log_q = ((log_q_exp + (exp >> 1))/exp) << 6;
+          }
           // The above allocation looks only at the total rate we'll accumulate
           //  in the next reservoir_frame_delay frames.
           // However, we could overflow the bit reservoir on the very next
@@ -679,7 +752,7 @@ impl RCState {
             // We only want to keep these bits from being completely wasted.
             let margin = (self.reservoir_max + 31) >> 5;
             // We want to use at least this many bits next frame.
-            let soft_limit = self.reservoir_fullness + self.bits_per_frame
+            let soft_limit = self.reservoir_fullness + frame_bits
               - (self.reservoir_max - margin);
             let log_soft_limit = blog64(soft_limit);
             // If we're predicting we won't use that many bits...
@@ -703,7 +776,7 @@ impl RCState {
             // This may not be enough for keyframes or sudden changes in
             //  complexity.
             let log_hard_limit =
-              blog64(self.reservoir_fullness + (self.bits_per_frame >> 1));
+              blog64(self.reservoir_fullness + (frame_bits >> 1));
             // If we're predicting we'll use more than this...
             let log_scale_pixels = self.log_scale[fti] + self.log_npixels;
             let exp = self.exp[fti] as i64;
@@ -715,18 +788,45 @@ impl RCState {
               // If that target is unreasonable, oh well; we'll have to drop.
             }
           }
-          QuantizerParameters::new_from_log_q(log_base_q, log_q, bit_depth)
+          // Finally, keep the quantizer within the range allowed, including
+          //  for the keyframe boost, whatever the rate target.
+          let log_q_min = blog64(ac_q(self.ac_qi_min, 0, bit_depth) as i64)
+            - q57(QSCALE + bit_depth as i32 - 8);
+          let log_q_max = blog64(ac_q(self.ac_qi_max, 0, bit_depth) as i64)
+            - q57(QSCALE + bit_depth as i32 - 8);
+          let clamped = if log_q < log_q_min {
+            Ordering::Less
+          } else if log_q > log_q_max {
+            Ordering::Greater
+          } else {
+            Ordering::Equal
+          };
+          let mut qps = QuantizerParameters::new_from_log_q(
+            log_base_q, clamp(log_q, log_q_min, log_q_max), bit_depth
+          );
+          // The nearest indices may lie just outside the range.
+          qps.ac_qi[0] = clamp(qps.ac_qi[0], self.ac_qi_min, self.ac_qi_max);
+          qps.dc_qi[0] = clamp(qps.dc_qi[0], self.ac_qi_min, self.ac_qi_max);
+          qps.clamped = clamped;
+          qps
         }
       }
     }
   }
 
+  // The bit budget of a frame lasting the given number of ticks.
+  fn frame_bits(&self, duration: u64) -> i64 {
+    self.bits_per_frame.saturating_mul(duration.max(1) as i64)
+  }
+
   pub fn update_state(
-    &mut self, bits: i64, fti: usize, log_target_q: i64, droppable: bool
+    &mut self, bits: i64, fti: usize, log_target_q: i64, duration: u64,
+    droppable: bool
   ) -> bool {
     let mut dropped = false;
     // Update rate control only if rate control is active.
     if self.target_bitrate > 0 {
+      let frame_bits = self.frame_bits(duration);
       let log_q_exp = ((log_target_q + 32) >> 6)*(self.exp[fti] as i64);
       let prev_log_scale = self.log_scale[fti];
       let mut bits = bits;
@@ -769,23 +869,24 @@ impl RCState {
         // If this frame busts our budget, it must be dropped.
         if droppable
           && self.drop_frames
-          && self.reservoir_fullness + self.bits_per_frame < bits
+          && self.reservoir_fullness + frame_bits < bits
         {
           // TODO: Adjust VFR rate based on drop count.
           bits = 0;
           dropped = true;
         } else {
-          // TODO: Update a low-pass filter to estimate the "real" frame rate
-          //  taking timestamps and drops into account.
-          // This is only done if the frame is coded, as it needs the final
-          //  count of dropped frames.
+          // Update the low-pass filter estimating the "real" frame rate.
+          // This is only done if the frame is coded.
+          // TODO: Take drops into account.
+          let duration_q8 = (duration.max(1) << 8).min(::std::i32::MAX as u64);
+          self.vfr_duration_q8 = self.vfrfilter.update(duration_q8 as i32).max(1);
         }
         // Increment the frame count for filter adaptation purposes.
         if self.nframes[fti] < ::std::i32::MAX {
           self.nframes[fti] += 1;
         }
       }
-      self.reservoir_fullness += self.bits_per_frame - bits;
+      self.reservoir_fullness += frame_bits - bits;
       // If we're too quick filling the buffer and overflow is capped, that
       //  rate is lost forever.
       if self.cap_overflow {
@@ -800,14 +901,103 @@ impl RCState {
       // Adjust the bias for the real bits we've used.
       self.rate_bias +=
         bexp64(prev_log_scale + self.log_npixels - log_q_exp) - bits;
+      self.bits_spent += bits;
+      self.bits_budget += frame_bits;
     }
     dropped
   }
+
+  // Whether the quantizer range keeps us from the rate target, given how a
+  //  frame's quantizer was clamped: the reservoir is empty even though the
+  //  quantizer is at its maximum, or full even though it is at its minimum.
+  pub(crate) fn misses_target(&self, clamped: Ordering) -> bool {
+    match clamped {
+      Ordering::Greater => self.reservoir_fullness < 0,
+      Ordering::Less => self.reservoir_fullness >= self.reservoir_max,
+      Ordering::Equal => false,
+    }
+  }
+
+  // The relative difference of the bits spent so far from their target,
+  //  positive when over it.
+  pub(crate) fn bitrate_deviation(&self) -> f64 {
+    if self.bits_budget == 0 {
+      return 0.;
+    }
+    self.bits_spent as f64 / self.bits_budget as f64 - 1.
+  }
+}
+
+// The strength of the per-tile quantizer modulation (Q12): the log quantizer
+//  of a tile moves by this fraction of the difference between its log
+//  complexity and the frame average, toward a finer quantizer for the more
+//  complex tiles.
+const TILE_LOG_Q_SLOPE_Q12: i64 = 1024;
+// The largest change of the quantizer of a tile from the frame quantizer, a
+//  factor of 2.
+const TILE_LOG_Q_OFFSET_MAX: i64 = q57(1);
+
+/// Selects the AC quantizer index of each tile of a frame from its
+/// complexity, given as the estimated cost of coding the tile and its area
+/// in pixels.
+///
+/// Complex tiles get a finer quantizer and simple ones a coarser one, so that
+/// the bits of the frame are shared out by complexity. The log quantizers of
+/// the tiles average to the frame quantizer, weighting each tile by its area.
+pub fn select_tile_qi(
+  base_q_idx: u8, bit_depth: usize, complexity: &[(u64, usize)]
+) -> Vec<u8> {
+  // Cost per pixel in Q8, so that smooth tiles remain distinguishable.
+  let log_cost: Vec<i64> = complexity.iter()
+    .map(|&(cost, area)| blog64(((cost << 8) / area.max(1) as u64) as i64 + 1))
+    .collect();
+  let total_area: i128 =
+    complexity.iter().map(|&(_, area)| area as i128).sum();
+  let mean_log_cost = (log_cost.iter().zip(complexity)
+    .map(|(&log_cost, &(_, area))| log_cost as i128 * area as i128)
+    .sum::<i128>() / total_area.max(1)) as i64;
+
+  let log_base_q = blog64(ac_q(base_q_idx, 0, bit_depth) as i64);
+  log_cost.iter().map(|&log_cost| {
+    let log_q_offset = clamp(
+      -((log_cost - mean_log_cost + (1i64 << 11)) >> 12)*TILE_LOG_Q_SLOPE_Q12,
+      -TILE_LOG_Q_OFFSET_MAX,
+      TILE_LOG_Q_OFFSET_MAX
+    );
+    select_ac_qi(bexp64(log_base_q + log_q_offset), bit_depth).max(1)
+  }).collect()
+}
+
+/// The difference (Q57) between the effective log quantizer of a frame coded
+/// with per-superblock quantizers and the log quantizer of `base_q_idx`.
+///
+/// The log quantizers of the superblocks are averaged within each tile, and
+/// the tiles weighted by their coded sizes. Adding the result to the target
+/// log quantizer of the frame when updating the rate control model keeps the
+/// model consistent with the quantizers the bits were actually spent with.
+pub fn tile_log_q_offset(
+  base_q_idx: u8, bit_depth: usize, tile_sb_qi: &[Vec<u8>],
+  tile_bytes: &[usize]
+) -> i64 {
+  let log_base_q = blog64(ac_q(base_q_idx, 0, bit_depth) as i64);
+  let total_bytes: i128 = tile_bytes.iter().map(|&b| b as i128).sum();
+  if total_bytes == 0 {
+    return 0;
+  }
+  (tile_sb_qi.iter().zip(tile_bytes)
+    .filter(|(sb_qi, _)| !sb_qi.is_empty())
+    .map(|(sb_qi, &bytes)| {
+      let log_q_offset = sb_qi.iter()
+        .map(|&qi| (blog64(ac_q(qi, 0, bit_depth) as i64) - log_base_q) as i128)
+        .sum::<i128>() / sb_qi.len() as i128;
+      log_q_offset * bytes as i128
+    })
+    .sum::<i128>() / total_bytes) as i64
 }
 
 #[cfg(test)]
 mod test {
-  use super::{bexp64, blog64};
+  use super::{bexp64, blog64, select_tile_qi, tile_log_q_offset};
 
   #[test]
   fn blog64_vectors() -> () {
@@ -832,4 +1022,40 @@ mod test {
       assert!((bexp64(log_ab) - a * b).abs() < 128);
     }
   }
+
+  #[test]
+  fn tile_qi_follows_complexity() {
+    for &bit_depth in &[8, 10, 12] {
+      let base_q_idx = 100;
+      // A static half and a high motion half of the same area
+      let qi = select_tile_qi(base_q_idx, bit_depth, &[(1_000, 4096), (400_000, 4096)]);
+      assert!(qi[1] < base_q_idx && base_q_idx < qi[0]);
+
+      // Uniform complexity keeps the frame quantizer
+      let qi = select_tile_qi(base_q_idx, bit_depth, &[(5_000, 4096); 4]);
+      assert_eq!(qi, vec![base_q_idx; 4]);
+
+      // The quantizer changes by at most a factor of 2
+      let qi = select_tile_qi(base_q_idx, bit_depth, &[(0, 4096), (1 << 40, 4096)]);
+      let sb_qi = vec![vec![qi[0]], vec![qi[1]]];
+      let offset = tile_log_q_offset(base_q_idx, bit_depth, &sb_qi, &[1, 0]);
+      assert!(offset <= (1i64 << 57) + (1i64 << 53));
+      let offset = tile_log_q_offset(base_q_idx, bit_depth, &sb_qi, &[0, 1]);
+      assert!(offset >= -(1i64 << 57) - (1i64 << 53));
+    }
+  }
+
+  #[test]
+  fn tile_log_q_offset_weights_by_size() {
+    let sb_qi = vec![vec![80, 80], vec![120]];
+    assert_eq!(tile_log_q_offset(100, 8, &sb_qi, &[0, 0]), 0);
+    assert!(tile_log_q_offset(100, 8, &sb_qi, &[1000, 10]) < 0);
+    assert!(tile_log_q_offset(100, 8, &sb_qi, &[10, 1000]) > 0);
+    let uniform = vec![vec![100, 100], vec![100]];
+    assert_eq!(tile_log_q_offset(100, 8, &uniform, &[10, 1000]), 0);
+    // Superblocks are averaged within a tile
+    let mixed = vec![vec![80, 120]];
+    let single = vec![vec![80]];
+    assert!(tile_log_q_offset(100, 8, &mixed, &[10]) > tile_log_q_offset(100, 8, &single, &[10]));
+  }
 }
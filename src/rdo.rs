@@ -14,11 +14,13 @@ use crate::api::*;
 use crate::cdef::*;
 use crate::lrf::*;
 use crate::context::*;
-use crate::ec::{OD_BITRES, Writer, WriterCounter};
+use crate::cpu_features::CpuKernels;
+use crate::ec::{OD_BITRES, SymbolWriterCounter, Writer, WriterBase, WriterCounter};
 use crate::header::ReferenceMode;
 use crate::encode_block_a;
 use crate::encode_block_b;
 use crate::encode_block_with_modes;
+use crate::encoder::get_qidx;
 use crate::FrameInvariants;
 use crate::Frame;
 use crate::luma_ac;
@@ -27,13 +29,18 @@ use crate::motion_compensate;
 use crate::partition::*;
 use crate::partition::RefType::*;
 use crate::plane::*;
-use crate::predict::{RAV1E_INTRA_MODES, RAV1E_INTER_MODES_MINIMAL, RAV1E_INTER_COMPOUND_MODES};
+use crate::predict::{
+  AngleDelta, IntraParam, RAV1E_INTRA_MODES,
+  RAV1E_INTER_MODES_MINIMAL, RAV1E_INTER_COMPOUND_MODES
+};
 use crate::Tune;
 use crate::write_tx_blocks;
 use crate::write_tx_tree;
 use crate::tiling::*;
 use crate::util::{AlignedArray, CastFromPrimitive, Pixel, UninitializedAlignedArray};
 use crate::rdo_tables::*;
+use crate::quantize::{ac_q, dc_q, dequantize_coeff, get_log_tx_scale};
+use crate::scan_order::av1_scan_orders;
 
 use std;
 use std::cmp;
@@ -85,11 +92,13 @@ pub struct RDOPartitionOutput {
   pub pred_mode_luma: PredictionMode,
   pub pred_mode_chroma: PredictionMode,
   pub pred_cfl_params: CFLParams,
+  pub angle_delta: AngleDelta,
   pub ref_frames: [RefType; 2],
   pub mvs: [MotionVector; 2],
   pub skip: bool,
   pub tx_size: TxSize,
   pub tx_type: TxType,
+  pub tx_partition: TxPartition,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -138,6 +147,8 @@ impl RDOTracker {
       self.rate_bins[q_bin_idx][bs_index][bin_idx] += rate;
     }
   }
+  /// Prints the rate table trained so far to stdout as Rust code, to be
+  /// pasted in place of `RDO_RATE_TABLE`
   pub fn print_code(&self) {
     println!("pub static RDO_RATE_TABLE: [[[u64; RDO_NUM_BINS]; TxSize::TX_SIZES_ALL]; RDO_QUANT_BINS] = [");
     for q_bin in 0..RDO_QUANT_BINS {
@@ -172,6 +183,51 @@ pub fn estimate_rate(qindex: u8, ts: TxSize, fast_distortion: u64) -> u64 {
   (y0 + (((fast_distortion as i64 - x0) * slope) >> 8)).max(0) as u64
 }
 
+/// Estimates the RD cost of coding the luma residual between `org` and
+/// `pred`, without transforming and quantizing it exactly, for screening
+/// candidate predictions. Each coefficient of the 4x4 Hadamard transforms
+/// of the residual, scaled as the orthonormal DCT, is assumed to quantize
+/// to zero below the quantizer step, costing its energy, and otherwise to
+/// cost the noise of a uniform quantizer and two bits per doubling of its
+/// magnitude over the step. Only the ranking of the estimates for the same
+/// block is meaningful.
+pub fn estimate_residual_rd_cost<T: Pixel>(
+  fi: &FrameInvariants<T>, org: &PlaneRegion<'_, T>, pred: &PlaneRegion<'_, T>,
+  w: usize, h: usize, qidx: u8
+) -> f64 {
+  let bit_depth = fi.sequence.bit_depth;
+  // Steps of the orthonormal coefficients; the quantizers are in Q3
+  let dc_step = f64::from(dc_q(qidx, fi.dc_delta_q[0], bit_depth)) / 8.;
+  let ac_step = f64::from(ac_q(qidx, fi.ac_delta_q[0], bit_depth)) / 8.;
+
+  let mut distortion = 0.;
+  let mut bits = 0.;
+  for y in (0..h).step_by(4) {
+    for x in (0..w).step_by(4) {
+      let mut buf = [0i32; 16];
+      for i in 0..4 {
+        for j in 0..4 {
+          buf[i * 4 + j] = i32::cast_from(org[y + i][x + j])
+            - i32::cast_from(pred[y + i][x + j]);
+        }
+      }
+      hadamard4x4(&mut buf);
+      for (k, &c) in buf.iter().enumerate() {
+        // The 4x4 Hadamard transform scales by 4
+        let c = f64::from(c.abs()) / 4.;
+        let step = if k == 0 { dc_step } else { ac_step };
+        if c < step {
+          distortion += c * c;
+        } else {
+          distortion += step * step / 12.;
+          bits += 2. * (c / step).log2() + 3.;
+        }
+      }
+    }
+  }
+  distortion + fi.lambda * bits
+}
+
 #[allow(unused)]
 fn cdef_dist_wxh_8x8<T: Pixel>(
   src1: &PlaneRegion<'_, T>, src2: &PlaneRegion<'_, T>, bit_depth: usize
@@ -258,7 +314,19 @@ fn compute_distortion<T: Pixel>(
 ) -> u64 {
   let input_region = ts.input_tile.planes[0].subregion(Area::BlockStartingAt { bo: tile_bo });
   let rec_region = ts.rec.planes[0].subregion(Area::BlockStartingAt { bo: tile_bo });
+  let filter_aware = fi.config.speed_settings.filter_aware_distortion
+    && fi.sequence.enable_cdef && w_y >= 8 && h_y >= 8;
   let mut distortion = match fi.config.tune {
+    _ if filter_aware => {
+      cdef_model_sse(
+        &input_region,
+        &rec_region,
+        w_y,
+        h_y,
+        fi.sequence.bit_depth,
+        fi.cdef_damping
+      )
+    }
     Tune::Psychovisual if w_y >= 8 && h_y >= 8 => {
       cdef_dist_wxh(
         &input_region,
@@ -351,17 +419,117 @@ fn compute_tx_distortion<T: Pixel>(
   distortion
 }
 
+/// Saves the state of the Writer the next block is coded to, for the exact
+/// rates of its mode decision, with the `exact_rate` speed setting
+pub fn save_ec_state<T: Pixel>(
+  fi: &FrameInvariants<T>, cw: &mut ContextWriter,
+  w_pre_cdef: &mut dyn Writer, w_post_cdef: &mut dyn Writer
+) {
+  if fi.config.speed_settings.exact_rate {
+    let w: &mut dyn Writer = if cw.bc.cdef_coded {w_post_cdef} else {w_pre_cdef};
+    cw.ec_state = Some(w.checkpoint());
+  }
+}
+
+/// Continues the Writer of the symbols after the CDEF index of the
+/// superblock from the state of the Writer of those before, once the block
+/// coding the index is known, with the `exact_rate` speed setting. The bits
+/// of the index, only chosen after the superblock, are left out.
+pub fn continue_ec_state<T: Pixel>(
+  fi: &FrameInvariants<T>, w_pre_cdef: &mut dyn Writer,
+  w_post_cdef: &mut dyn Writer
+) {
+  if fi.config.speed_settings.exact_rate {
+    w_post_cdef.resume(&w_pre_cdef.checkpoint());
+  }
+}
+
+/// A Writer counting the rate of the symbols of a candidate, continuing
+/// from the range coder state of the tile when `exact_rate` recorded it
+fn rate_counter(cw: &ContextWriter) -> WriterBase<WriterCounter> {
+  match cw.ec_state {
+    Some(ref checkpoint) => SymbolWriterCounter::new(checkpoint),
+    None => WriterCounter::new(),
+  }
+}
+
 fn compute_rd_cost<T: Pixel>(fi: &FrameInvariants<T>, rate: u32, distortion: u64) -> f64 {
   let rate_in_bits = (rate as f64) / ((1 << OD_BITRES) as f64);
   (distortion as f64) + fi.lambda * rate_in_bits
 }
 
+/// Rate-distortion optimized quantization.
+///
+/// Starting from the deadzone quantization of `coeffs` in `qcoeffs`, lowers
+/// the magnitude of the coefficients one step at a time, from the last one
+/// in scan order to the first, whenever the bits saved according to
+/// `ContextWriter::coeff_cost` outweigh the distortion added in the
+/// transform domain. The entropy contexts are left untouched.
+pub fn rdoq<T: Pixel>(
+  fi: &FrameInvariants<T>, cw: &mut ContextWriter, p: usize,
+  tile_bo: BlockOffset, mode: PredictionMode, tx_size: TxSize,
+  tx_type: TxType, plane_bsize: BlockSize, xdec: usize, ydec: usize,
+  qidx: u8, coeffs: &[i32], qcoeffs: &mut [i32]
+) {
+  let bit_depth = fi.sequence.bit_depth;
+  let log_tx_scale = get_log_tx_scale(tx_size);
+  let dc_quant = dc_q(qidx, fi.dc_delta_q[p], bit_depth) as i32;
+  let ac_quant = ac_q(qidx, fi.ac_delta_q[p], bit_depth) as i32;
+  let dist = |pos: usize, level: i32| -> f64 {
+    let quant = if pos == 0 { dc_quant } else { ac_quant };
+    let r = dequantize_coeff(level, quant, log_tx_scale);
+    let d = (coeffs[pos] - r) as f64;
+    d * d
+  };
+  // Matches the scaling of the transform domain distortion of RDO
+  let dist_scale = 1.0 / (1 << (2 * (3 - log_tx_scale))) as f64;
+  let lambda = fi.lambda / ((1 << OD_BITRES) as f64);
+
+  let mut cost = |qcoeffs: &[i32]| {
+    cw.coeff_cost(
+      p, tile_bo, qcoeffs, mode, tx_size, tx_type, plane_bsize, xdec, ydec,
+      fi.use_reduced_tx_set
+    )
+  };
+
+  let scan = av1_scan_orders[tx_size as usize][tx_type as usize].scan;
+  let coded_area = av1_get_coded_tx_size(tx_size).area();
+  let eob = match scan[..coded_area].iter().rposition(|&pos| qcoeffs[pos as usize] != 0) {
+    Some(last) => last + 1,
+    None => return
+  };
+
+  let mut rate = cost(qcoeffs);
+  for &pos in scan[..eob].iter().rev() {
+    let pos = pos as usize;
+    let level = qcoeffs[pos];
+    if level == 0 {
+      continue;
+    }
+    let lowered = level - level.signum();
+    let delta_dist = (dist(pos, lowered) - dist(pos, level)) * dist_scale;
+    // No rate saving can make up for it
+    if delta_dist >= lambda * rate as f64 {
+      continue;
+    }
+    qcoeffs[pos] = lowered;
+    let lowered_rate = cost(qcoeffs);
+    if delta_dist + lambda * (lowered_rate as f64 - rate as f64) < 0.0 {
+      rate = lowered_rate;
+    } else {
+      qcoeffs[pos] = level;
+    }
+  }
+}
+
 pub fn rdo_tx_size_type<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
   cw: &mut ContextWriter, bsize: BlockSize, tile_bo: BlockOffset,
-  luma_mode: PredictionMode, ref_frames: [RefType; 2], mvs: [MotionVector; 2], skip: bool
-) -> (TxSize, TxType) {
+  luma_mode: PredictionMode, angle_delta: AngleDelta, ref_frames: [RefType; 2],
+  mvs: [MotionVector; 2], skip: bool
+) -> (TxSize, TxType, TxPartition) {
   let mut tx_size = max_txsize_rect_lookup[bsize as usize];
+  debug_assert!(fi.config.disabled_tx.allows_size(tx_size));
   let mut best_tx_type = TxType::DCT_DCT;
   let mut best_tx_size = tx_size;
   let mut best_rd = std::f64::MAX;
@@ -378,7 +546,7 @@ pub fn rdo_tx_size_type<T: Pixel>(
     let do_rdo_tx_type = tx_set > TxSet::TX_SET_DCTONLY &&
         fi.config.speed_settings.rdo_tx_decision && !skip;
 
-    if !do_rdo_tx_size && !do_rdo_tx_type { return (best_tx_size, best_tx_type) };
+    if !do_rdo_tx_size && !do_rdo_tx_type { break };
 
     let tx_types = if do_rdo_tx_type { RAV1E_TX_TYPES } else { &[TxType::DCT_DCT] };
 
@@ -389,6 +557,7 @@ pub fn rdo_tx_size_type<T: Pixel>(
           ts,
           cw,
           luma_mode,
+          angle_delta,
           ref_frames,
           mvs,
           bsize,
@@ -414,19 +583,131 @@ pub fn rdo_tx_size_type<T: Pixel>(
     cw.rollback(&cw_checkpoint);
   }
 
-  (best_tx_size, best_tx_type)
+  // Transform partitions are only searched in blocks of a single largest
+  // transform, inside the frame
+  let tile_bo_end = tile_bo.add_size(bsize);
+  let do_var_tx = fi.tx_mode_select && fi.config.speed_settings.var_tx &&
+    is_inter && !skip && bsize.greater_than(BlockSize::BLOCK_4X4) &&
+    bsize.width() <= 64 && bsize.height() <= 64 &&
+    tile_bo_end.x <= ts.mi_width && tile_bo_end.y <= ts.mi_height;
+  if !do_var_tx {
+    return (best_tx_size, best_tx_type, TxPartition::NONE);
+  }
+
+  let (tx_partition, tx_type) = rdo_tx_partition_decision(
+    fi, ts, cw, luma_mode, ref_frames, mvs, bsize, tile_bo, best_tx_size,
+    best_tx_type
+  );
+  (best_tx_size, tx_type, tx_partition)
+}
+
+/// The transform type used by the transforms of the partition, `DCT_DCT`
+/// if `tx_type` is not in the set of all of them
+fn tx_partition_type<T: Pixel>(
+  fi: &FrameInvariants<T>, tx_size: TxSize, tx_type: TxType,
+  tx_partition: TxPartition
+) -> TxType {
+  let in_set = |&(_, _, tx_size): &(usize, usize, TxSize)| {
+    let tx_set = get_tx_set(tx_size, true, fi.use_reduced_tx_set);
+    av1_tx_used[tx_set as usize][tx_type as usize] != 0
+  };
+  if tx_partition.tx_blocks(tx_size).iter().all(in_set) {
+    tx_type
+  } else {
+    TxType::DCT_DCT
+  }
+}
+
+/// RD cost of the luma residual of an inter block coded with the transform
+/// partition, including its signalling
+fn rdo_tx_partition_cost<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>, cw: &mut ContextWriter,
+  mode: PredictionMode, ref_frames: [RefType; 2], mvs: [MotionVector; 2],
+  bsize: BlockSize, tile_bo: BlockOffset, tx_size: TxSize, tx_type: TxType,
+  tx_partition: TxPartition
+) -> f64 {
+  let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
+  let is_chroma_block = has_chroma(tile_bo, bsize, xdec, ydec);
+  let rdo_type = if fi.use_tx_domain_distortion {
+    RDOType::TxDistRealRate
+  } else {
+    RDOType::PixelDistRealRate
+  };
+
+  motion_compensate(fi, ts, cw, mode, ref_frames, mvs, bsize, tile_bo, true);
+
+  let wr: &mut dyn Writer = &mut rate_counter(cw);
+  let tell = wr.tell_frac();
+  cw.write_tx_partition(wr, tile_bo, bsize, tx_partition);
+  let tx_dist = write_tx_tree(
+    fi, ts, cw, wr, mode, tile_bo, bsize, tx_size, tx_type, tx_partition,
+    false, true, rdo_type, true
+  );
+  let rate = wr.tell_frac() - tell;
+  let (w, h) = (bsize.width(), bsize.height());
+  let distortion = if fi.use_tx_domain_distortion {
+    compute_tx_distortion(
+      fi, ts, w, h, is_chroma_block, tile_bo, tx_dist, false, true
+    )
+  } else {
+    compute_distortion(fi, ts, w, h, is_chroma_block, tile_bo, true)
+  };
+  compute_rd_cost(fi, rate, distortion)
+}
+
+/// Decides the transform partition of an inter block: the largest transform
+/// is split when this lowers the RD cost, and then each transform of the
+/// split in turn. Returns the partition with the transform type to use.
+fn rdo_tx_partition_decision<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>, cw: &mut ContextWriter,
+  mode: PredictionMode, ref_frames: [RefType; 2], mvs: [MotionVector; 2],
+  bsize: BlockSize, tile_bo: BlockOffset, tx_size: TxSize, tx_type: TxType
+) -> (TxPartition, TxType) {
+  let cw_checkpoint = cw.checkpoint();
+  let cost = |ts: &mut TileStateMut<'_, T>, cw: &mut ContextWriter,
+                  tx_partition: TxPartition| {
+    let tx_type = tx_partition_type(fi, tx_size, tx_type, tx_partition);
+    let rd = rdo_tx_partition_cost(
+      fi, ts, cw, mode, ref_frames, mvs, bsize, tile_bo, tx_size, tx_type,
+      tx_partition
+    );
+    cw.rollback(&cw_checkpoint);
+    rd
+  };
+
+  let none_rd = cost(ts, cw, TxPartition::NONE);
+  let mut best = TxPartition::SPLIT;
+  let mut best_rd = cost(ts, cw, best);
+  if tx_size.sub() != TxSize::TX_4X4 {
+    for i in 0..tx_size.sub_offsets().len() {
+      let tx_partition = best.toggle_sub_split(i);
+      let rd = cost(ts, cw, tx_partition);
+      if rd < best_rd {
+        best = tx_partition;
+        best_rd = rd;
+      }
+    }
+  }
+
+  if none_rd <= best_rd {
+    (TxPartition::NONE, tx_type)
+  } else {
+    (best, tx_partition_type(fi, tx_size, tx_type, best))
+  }
 }
 
 struct EncodingSettings {
   mode_luma: PredictionMode,
   mode_chroma: PredictionMode,
   cfl_params: CFLParams,
+  angle_delta: AngleDelta,
   skip: bool,
   rd: f64,
   ref_frames: [RefType; 2],
   mvs: [MotionVector; 2],
   tx_size: TxSize,
-  tx_type: TxType
+  tx_type: TxType,
+  tx_partition: TxPartition
 }
 
 impl Default for EncodingSettings {
@@ -435,15 +716,31 @@ impl Default for EncodingSettings {
       mode_luma: PredictionMode::DC_PRED,
       mode_chroma: PredictionMode::DC_PRED,
       cfl_params: CFLParams::default(),
+      angle_delta: AngleDelta::default(),
       skip: false,
       rd: std::f64::MAX,
       ref_frames: [INTRA_FRAME, NONE_FRAME],
       mvs: [MotionVector::default(); 2],
       tx_size: TxSize::TX_4X4,
-      tx_type: TxType::DCT_DCT
+      tx_type: TxType::DCT_DCT,
+      tx_partition: TxPartition::NONE
     }
   }
 }
+// Whether an inter prediction only reads the area of its references that
+// periodic intra refresh allows
+fn intra_refresh_allows<T: Pixel>(
+  fi: &FrameInvariants<T>, frame_x: usize, blk_w: usize,
+  ref_frames: [RefType; 2], mvs: [MotionVector; 2]
+) -> bool {
+  ref_frames.iter().zip(mvs.iter()).all(|(&ref_frame, mv)| {
+    ref_frame == NONE_FRAME
+      || fi
+        .intra_refresh_mv_col_max(frame_x, blk_w, ref_frame)
+        .map_or(true, |mv_col_max| mv.col as isize <= mv_col_max)
+  })
+}
+
 // RDO-based mode decision
 pub fn rdo_mode_decision<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
@@ -461,6 +758,9 @@ pub fn rdo_mode_decision<T: Pixel>(
 
   let cw_checkpoint = cw.checkpoint();
 
+  let frame_x = ts.to_frame_block_offset(tile_bo).x << BLOCK_TO_PLANE_SHIFT;
+  let force_intra = fi.intra_refresh_forces_intra(frame_x, w);
+
   let mut ref_frames_set = Vec::new();
   let mut ref_slot_set = Vec::new();
   let mut mvs_from_me = Vec::new();
@@ -477,7 +777,7 @@ pub fn rdo_mode_decision<T: Pixel>(
     RDOType::PixelDistRealRate
   };
 
-  if fi.frame_type == FrameType::INTER {
+  if fi.frame_type.has_inter() && !force_intra {
     for i in ALL_INTER_REFS.iter() {
       // Don't search LAST3 since it's used only for probs
       if *i == LAST3_FRAME { continue; }
@@ -500,24 +800,39 @@ pub fn rdo_mode_decision<T: Pixel>(
   let mut mv_stacks = Vec::new();
   let mut mode_contexts = Vec::new();
 
-  let motion_estimation = if fi.config.speed_settings.diamond_me {
-    crate::me::DiamondSearch::motion_estimation
-  } else {
-    crate::me::FullSearch::motion_estimation
+  let motion_estimation = match fi.config.speed_settings.me_method {
+    MotionSearch::Diamond => crate::me::DiamondSearch::motion_estimation,
+    MotionSearch::Hex => crate::me::HexSearch::motion_estimation,
+    MotionSearch::Full => crate::me::FullSearch::motion_estimation
   };
 
   for (i, &ref_frames) in ref_frames_set.iter().enumerate() {
     let mut mv_stack: Vec<CandidateMV> = Vec::new();
     mode_contexts.push(cw.find_mvrefs(tile_bo, ref_frames, &mut mv_stack, bsize, fi, false));
 
-    if fi.frame_type == FrameType::INTER {
+    if fi.frame_type.has_inter() {
       let mut pmv = [MotionVector::default(); 2];
       if !mv_stack.is_empty() { pmv[0] = mv_stack[0].this_mv; }
       if mv_stack.len() > 1 { pmv[1] = mv_stack[1].this_mv; }
       let ref_slot = ref_slot_set[i] as usize;
       let cmv = pmvs[ref_slot].unwrap_or_else(Default::default);
 
-      let b_me = motion_estimation(fi, ts, bsize, tile_bo, ref_frames[0], cmv, pmv);
+      let b_me = match fi.external_motion {
+        Some(ref motion) => {
+          let frame_bo = ts.to_frame_block_offset(tile_bo);
+          // Rounded to the precision of the frame, as coded
+          let mv = motion.mv(frame_bo, ref_frames[0]).unwrap_or_default()
+            .round_to_precision(fi.mv_precision());
+          // Keep the prediction of refreshed blocks within the refreshed area
+          let frame_x = frame_bo.x << BLOCK_TO_PLANE_SHIFT;
+          match fi.intra_refresh_mv_col_max(frame_x, bsize.width(), ref_frames[0]) {
+            Some(mv_col_max) if mv.col as isize > mv_col_max =>
+              MotionVector { row: mv.row, col: mv_col_max as i16 },
+            _ => mv
+          }
+        }
+        None => motion_estimation(fi, ts, bsize, tile_bo, ref_frames[0], cmv, pmv)
+      };
 
       if !fi.config.speed_settings.encode_bottomup &&
         (bsize == BlockSize::BLOCK_32X32 || bsize == BlockSize::BLOCK_64X64) {
@@ -558,11 +873,12 @@ pub fn rdo_mode_decision<T: Pixel>(
 
   let sz = bsize.width_mi().min(bsize.height_mi());
 
-  if fi.frame_type == FrameType::INTER && fi.reference_mode != ReferenceMode::SINGLE && sz >= 2 {
+  if fi.frame_type.has_inter() && fi.reference_mode != ReferenceMode::SINGLE && sz >= 2
+    && !fi.config.disabled_modes.compound {
     // Adding compound candidate
-    if let Some(r0) = fwdref {
-      if let Some(r1) = bwdref {
-        let ref_frames = [ref_frames_set[r0][0], ref_frames_set[r1][0]];
+    if let (Some(r0), Some(r1)) = (fwdref, bwdref) {
+      let ref_frames = [ref_frames_set[r0][0], ref_frames_set[r1][0]];
+      if is_valid_comp_ref(ref_frames) {
         ref_frames_set.push(ref_frames);
         let mv0 = mvs_from_me[r0][0];
         let mv1 = mvs_from_me[r1][0];
@@ -578,6 +894,7 @@ pub fn rdo_mode_decision<T: Pixel>(
   }
 
   let luma_chroma_mode_rdo = |luma_mode: PredictionMode,
+  angle_delta_y: i8,
   ts: &mut TileStateMut<'_, T>,
   cw: &mut ContextWriter,
   best: &mut EncodingSettings,
@@ -587,17 +904,27 @@ pub fn rdo_mode_decision<T: Pixel>(
   luma_mode_is_intra: bool,
   mode_context: usize,
   mv_stack: &Vec<CandidateMV>| {
-    let (tx_size, mut tx_type) = rdo_tx_size_type(
-      fi, ts, cw, bsize, tile_bo, luma_mode, ref_frames, mvs, false,
+    let (tx_size, mut tx_type, mut tx_partition) = rdo_tx_size_type(
+      fi, ts, cw, bsize, tile_bo, luma_mode,
+      AngleDelta { y: angle_delta_y, uv: 0 }, ref_frames, mvs, false,
     );
 
     // Find the best chroma prediction mode for the current luma prediction mode
     let mut chroma_rdo = |skip: bool| {
       mode_set_chroma.iter().for_each(|&chroma_mode| {
-        let wr: &mut dyn Writer = &mut WriterCounter::new();
+        let wr: &mut dyn Writer = &mut rate_counter(cw);
         let tell = wr.tell_frac();
 
-        if skip { tx_type = TxType::DCT_DCT; };
+        if skip {
+          tx_type = TxType::DCT_DCT;
+          tx_partition = TxPartition::NONE;
+        };
+
+        // A chroma mode equal to the luma mode follows its angle
+        let angle_delta = AngleDelta {
+          y: angle_delta_y,
+          uv: if chroma_mode == luma_mode { angle_delta_y } else { 0 }
+        };
 
         if bsize >= BlockSize::BLOCK_8X8 && bsize.is_sqr() {
           cw.write_partition(wr, tile_bo, PartitionType::PARTITION_NONE, bsize);
@@ -621,8 +948,10 @@ pub fn rdo_mode_decision<T: Pixel>(
             tile_bo,
             skip,
             CFLParams::default(),
+            angle_delta,
             tx_size,
             tx_type,
+            tx_partition,
             mode_context,
             mv_stack,
             rdo_type,
@@ -659,11 +988,13 @@ pub fn rdo_mode_decision<T: Pixel>(
           best.rd = rd;
           best.mode_luma = luma_mode;
           best.mode_chroma = chroma_mode;
+          best.angle_delta = angle_delta;
           best.ref_frames = ref_frames;
           best.mvs = mvs;
           best.skip = skip;
           best.tx_size = tx_size;
           best.tx_type = tx_type;
+          best.tx_partition = tx_partition;
         }
 
         cw.rollback(&cw_checkpoint);
@@ -677,11 +1008,11 @@ pub fn rdo_mode_decision<T: Pixel>(
     };
   };
 
-  if fi.frame_type != FrameType::INTER {
+  if !fi.frame_type.has_inter() {
     assert!(mode_set.is_empty());
   }
 
-  mode_set.iter().for_each(|&(luma_mode, i)| {
+  let mut inter_candidates: Vec<_> = mode_set.iter().filter_map(|&(luma_mode, i)| {
     let mvs = match luma_mode {
       PredictionMode::NEWMV | PredictionMode::NEW_NEWMV => mvs_from_me[i],
       PredictionMode::NEARESTMV | PredictionMode::NEAREST_NEARESTMV => if !mv_stacks[i].is_empty() {
@@ -701,9 +1032,43 @@ pub fn rdo_mode_decision<T: Pixel>(
       PredictionMode::NEW_NEARESTMV => [mvs_from_me[i][0], mv_stacks[i][0].comp_mv],
       _ => [MotionVector::default(); 2]
     };
+    if intra_refresh_allows(fi, frame_x, w, ref_frames_set[i], mvs) {
+      Some((luma_mode, i, mvs))
+    } else {
+      None
+    }
+  }).collect();
+
+  // Keep the inter modes whose luma prediction leaves the residual of
+  // lowest estimated cost, for the full RD evaluation
+  let num_modes_rdo = fi.config.speed_settings.inter_modes_rdo;
+  if num_modes_rdo > 0 && inter_candidates.len() > num_modes_rdo {
+    let qidx = get_qidx(fi, ts, cw, tile_bo);
+    let area = Area::BlockStartingAt { bo: tile_bo };
+    let mut costs: Vec<_> = inter_candidates.iter().map(|&(luma_mode, i, mvs)| {
+      motion_compensate(
+        fi, ts, cw, luma_mode, ref_frames_set[i], mvs, bsize, tile_bo, true
+      );
+      let cost = estimate_residual_rd_cost(
+        fi,
+        &ts.input_tile.planes[0].subregion(area),
+        &ts.rec.planes[0].subregion(area),
+        w,
+        h,
+        qidx
+      );
+      (cost, luma_mode, i, mvs)
+    }).collect();
+    costs.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    inter_candidates = costs.iter().take(num_modes_rdo)
+      .map(|&(_, luma_mode, i, mvs)| (luma_mode, i, mvs))
+      .collect();
+  }
+
+  inter_candidates.iter().for_each(|&(luma_mode, i, mvs)| {
     let mode_set_chroma = vec![luma_mode];
 
-    luma_chroma_mode_rdo(luma_mode, ts, cw, &mut best, mvs, ref_frames_set[i], &mode_set_chroma, false,
+    luma_chroma_mode_rdo(luma_mode, 0, ts, cw, &mut best, mvs, ref_frames_set[i], &mode_set_chroma, false,
              mode_contexts[i], &mv_stacks[i]);
   });
 
@@ -714,60 +1079,70 @@ pub fn rdo_mode_decision<T: Pixel>(
     let num_modes_rdo = if (fi.frame_type == FrameType::KEY
                             && fi.config.speed_settings.prediction_modes
                             >= PredictionModesSetting::ComplexKeyframes)
-      || (fi.frame_type == FrameType::INTER
+      || (fi.frame_type.has_inter()
           && fi.config.speed_settings.prediction_modes
           >= PredictionModesSetting::ComplexAll)
     {
-      7
+      fi.config.speed_settings.intra_modes_rdo
     } else {
       3
     };
 
-    let intra_mode_set = RAV1E_INTRA_MODES;
-    let mut sads = {
-      let edge_buf = {
-        let rec = &ts.rec.planes[0].as_const();
-        let po = tile_bo.plane_offset(&rec.plane_cfg);
-        get_intra_edges(rec, po, tx_size, fi.sequence.bit_depth, None)
+    // Keep the prediction of refreshed blocks within the refreshed area
+    let no_above_right = fi.intra_refresh_forbids_above_right(frame_x, w, h);
+    let intra_mode_set: Vec<PredictionMode> = RAV1E_INTRA_MODES.iter()
+      .cloned()
+      .filter(|&mode| fi.config.disabled_modes.allows(mode))
+      .filter(|&mode| !no_above_right || !mode.uses_above_right(0))
+      .collect();
+
+    // Rank the modes by the SATD of their prediction, made in a scratch
+    // buffer from the same edges
+    let mut satds = {
+      let tile_rect = ts.tile_rect();
+      let rec = &ts.rec.planes[0];
+      let po = TileBlockOffset(tile_bo).plane_offset(&rec.plane_cfg);
+      let edge_buf = get_intra_edges(
+        &rec.as_const(), po, tx_size, fi.sequence.bit_depth, None, IntraParam::None
+      );
+      let rect = Rect {
+        width: tx_size.width(),
+        height: tx_size.height(),
+        ..*rec.subregion(Area::BlockStartingAt { bo: tile_bo }).rect()
+      };
+      let plane_org = ts.input_tile.planes[0].subregion(Area::BlockStartingAt { bo: tile_bo });
+      let mut scratch = Plane::wrap(vec![T::cast_from(0); tx_size.area()], tx_size.width());
+      let edge_filter = if fi.sequence.enable_intra_edge_filter {
+        Some(cw.bc.intra_edge_filter_type(tile_bo, bsize, 0, 0, 0))
+      } else {
+        None
       };
-      intra_mode_set
-        .iter()
-        .map(|&luma_mode| {
-          let tile_rect = ts.tile_rect();
-          let rec = &mut ts.rec.planes[0];
-          let mut rec_region = rec.subregion_mut(Area::BlockStartingAt { bo: tile_bo });
-          luma_mode.predict_intra(
-            tile_rect,
-            &mut rec_region,
-            tx_size,
-            fi.sequence.bit_depth,
-            &[0i16; 2],
-            0,
-            &edge_buf
-          );
-
-          let plane_org = ts.input_tile.planes[0].subregion(Area::BlockStartingAt { bo: tile_bo });
-          let plane_ref = rec_region.as_const();
 
-          (
-            luma_mode,
-            get_sad(
-              &plane_org,
-              &plane_ref,
-              tx_size.width(),
-              tx_size.height(),
-              fi.sequence.bit_depth
-            )
-          )
-        })
-        .collect::<Vec<_>>()
+      let mut satds = Vec::with_capacity(intra_mode_set.len());
+      for &luma_mode in intra_mode_set.iter() {
+        let mut pred = PlaneRegionMut::from_slice(&mut scratch.data, &scratch.cfg, rect);
+        luma_mode.predict_intra(
+          tile_rect,
+          &mut pred,
+          tx_size,
+          fi.sequence.bit_depth,
+          &[0i16; 2],
+          IntraParam::None,
+          &edge_buf,
+          edge_filter,
+          fi.cpu
+        );
+        let satd = get_satd(&plane_org, &pred.as_const(), tx_size.width(), tx_size.height());
+        satds.push((luma_mode, satd));
+      }
+      satds
     };
 
-    sads.sort_by_key(|a| a.1);
+    satds.sort_by_key(|a| a.1);
 
     // Find mode with lowest rate cost
     let mut z = 32768;
-    let probs_all = if fi.frame_type == FrameType::INTER {
+    let probs_all = if fi.frame_type.has_inter() {
       cw.get_cdf_intra_mode(bsize)
     } else {
       cw.get_cdf_intra_mode_kf(tile_bo)
@@ -782,7 +1157,7 @@ pub fn rdo_mode_decision<T: Pixel>(
       .iter()
       .take(num_modes_rdo / 2)
       .for_each(|&(luma_mode, _prob)| modes.push(luma_mode));
-    sads.iter().take(num_modes_rdo).for_each(|&(luma_mode, _sad)| {
+    satds.iter().take(num_modes_rdo).for_each(|&(luma_mode, _satd)| {
       if !modes.contains(&luma_mode) {
         modes.push(luma_mode)
       }
@@ -795,15 +1170,34 @@ pub fn rdo_mode_decision<T: Pixel>(
       if is_chroma_block && luma_mode != PredictionMode::DC_PRED {
         mode_set_chroma.push(PredictionMode::DC_PRED);
       }
-      luma_chroma_mode_rdo(luma_mode, ts, cw, &mut best, mvs, ref_frames, &mode_set_chroma, true,
+      luma_chroma_mode_rdo(luma_mode, 0, ts, cw, &mut best, mvs, ref_frames, &mode_set_chroma, true,
                0, &Vec::new());
     });
+
+    // Refine the angle of the best directional mode
+    if fi.config.speed_settings.angle_delta_search && best.mode_luma.is_directional()
+      && bsize >= BlockSize::BLOCK_8X8 {
+      let luma_mode = best.mode_luma;
+      let mut mode_set_chroma = vec![luma_mode];
+      if is_chroma_block {
+        mode_set_chroma.push(PredictionMode::DC_PRED);
+      }
+      let max_delta = MAX_ANGLE_DELTA as i8;
+      for angle_delta_y in (-max_delta..=max_delta).filter(|&d| {
+        d != 0 && !(no_above_right && luma_mode.uses_above_right(d))
+      }) {
+        luma_chroma_mode_rdo(luma_mode, angle_delta_y, ts, cw, &mut best,
+                 [MotionVector::default(); 2], [INTRA_FRAME, NONE_FRAME],
+                 &mode_set_chroma, true, 0, &Vec::new());
+      }
+    }
   }
 
-  if best.mode_luma.is_intra() && is_chroma_block && bsize.cfl_allowed() {
+  if best.mode_luma.is_intra() && is_chroma_block && bsize.cfl_allowed()
+    && !fi.config.disabled_modes.cfl {
     let chroma_mode = PredictionMode::UV_CFL_PRED;
     let cw_checkpoint = cw.checkpoint();
-    let wr: &mut dyn Writer = &mut WriterCounter::new();
+    let wr: &mut dyn Writer = &mut rate_counter(cw);
     write_tx_blocks(
       fi,
       ts,
@@ -811,6 +1205,7 @@ pub fn rdo_mode_decision<T: Pixel>(
       wr,
       best.mode_luma,
       best.mode_luma,
+      best.angle_delta,
       tile_bo,
       bsize,
       best.tx_size,
@@ -822,8 +1217,10 @@ pub fn rdo_mode_decision<T: Pixel>(
       false
     );
     cw.rollback(&cw_checkpoint);
-    if let Some(cfl) = rdo_cfl_alpha(ts, tile_bo, bsize, fi.sequence.bit_depth) {
-      let wr: &mut dyn Writer = &mut WriterCounter::new();
+    if let Some(cfl) = rdo_cfl_alpha(
+      ts, tile_bo, bsize, fi.sequence.bit_depth, fi.cpu
+    ) {
+      let wr: &mut dyn Writer = &mut rate_counter(cw);
       let tell = wr.tell_frac();
 
       encode_block_a(&fi.sequence, ts, cw, wr, bsize, tile_bo, best.skip);
@@ -840,8 +1237,10 @@ pub fn rdo_mode_decision<T: Pixel>(
         tile_bo,
         best.skip,
         cfl,
+        AngleDelta { y: best.angle_delta.y, uv: 0 },
         best.tx_size,
         best.tx_type,
+        best.tx_partition,
         0,
         &Vec::new(),
         rdo_type,
@@ -866,6 +1265,7 @@ pub fn rdo_mode_decision<T: Pixel>(
         best.rd = rd;
         best.mode_chroma = chroma_mode;
         best.cfl_params = cfl;
+        best.angle_delta.uv = 0;
       }
 
       cw.rollback(&cw_checkpoint);
@@ -884,17 +1284,20 @@ pub fn rdo_mode_decision<T: Pixel>(
     pred_mode_luma: best.mode_luma,
     pred_mode_chroma: best.mode_chroma,
     pred_cfl_params: best.cfl_params,
+    angle_delta: best.angle_delta,
     ref_frames: best.ref_frames,
     mvs: best.mvs,
     rd_cost: best.rd,
     skip: best.skip,
     tx_size: best.tx_size,
     tx_type: best.tx_type,
+    tx_partition: best.tx_partition,
   }
 }
 
 pub fn rdo_cfl_alpha<T: Pixel>(
-  ts: &mut TileStateMut<'_, T>, tile_bo: BlockOffset, bsize: BlockSize, bit_depth: usize
+  ts: &mut TileStateMut<'_, T>, tile_bo: BlockOffset, bsize: BlockSize, bit_depth: usize,
+  cpu: CpuKernels
 ) -> Option<CFLParams> {
   let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
   let uv_tx_size = bsize.largest_uv_tx_size(xdec, ydec);
@@ -907,7 +1310,7 @@ pub fn rdo_cfl_alpha<T: Pixel>(
       let tile_rect = ts.tile_rect().decimated(xdec, ydec);
       let rec = &mut ts.rec.planes[p];
       let input = &ts.input_tile.planes[p];
-      let po = tile_bo.plane_offset(rec.plane_cfg);
+      let po = TileBlockOffset(tile_bo).plane_offset(rec.plane_cfg);
       (-16i16..17i16)
         .min_by_key(|&alpha| {
           let edge_buf = get_intra_edges(
@@ -915,7 +1318,8 @@ pub fn rdo_cfl_alpha<T: Pixel>(
             po,
             uv_tx_size,
             bit_depth,
-            Some(PredictionMode::UV_CFL_PRED)
+            Some(PredictionMode::UV_CFL_PRED),
+            IntraParam::Alpha(alpha)
           );
 
           let mut rec_region = rec.subregion_mut(Area::BlockStartingAt { bo: tile_bo });
@@ -925,8 +1329,10 @@ pub fn rdo_cfl_alpha<T: Pixel>(
             uv_tx_size,
             bit_depth,
             &ac.array,
-            alpha,
-            &edge_buf
+            IntraParam::Alpha(alpha),
+            &edge_buf,
+            None,
+            cpu
           );
           sse_wxh(
             &input.subregion(Area::BlockStartingAt { bo: tile_bo }),
@@ -947,9 +1353,9 @@ pub fn rdo_cfl_alpha<T: Pixel>(
 // RDO-based transform type decision
 pub fn rdo_tx_type_decision<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>, cw: &mut ContextWriter,
-  mode: PredictionMode, ref_frames: [RefType; 2], mvs: [MotionVector; 2],
-  bsize: BlockSize, tile_bo: BlockOffset, tx_size: TxSize, tx_set: TxSet,
-  tx_types: &[TxType]
+  mode: PredictionMode, angle_delta: AngleDelta, ref_frames: [RefType; 2],
+  mvs: [MotionVector; 2], bsize: BlockSize, tile_bo: BlockOffset,
+  tx_size: TxSize, tx_set: TxSet, tx_types: &[TxType]
 ) -> (TxType, f64) {
   let mut best_type = TxType::DCT_DCT;
   let mut best_rd = std::f64::MAX;
@@ -976,16 +1382,20 @@ pub fn rdo_tx_type_decision<T: Pixel>(
     if av1_tx_used[tx_set as usize][tx_type as usize] == 0 {
       continue;
     }
+    if !fi.config.disabled_tx.allows_type(tx_type) {
+      continue;
+    }
 
     if is_inter {
       motion_compensate(fi, ts, cw, mode, ref_frames, mvs, bsize, tile_bo, true);
     }
 
-    let wr: &mut dyn Writer = &mut WriterCounter::new();
+    let wr: &mut dyn Writer = &mut rate_counter(cw);
     let tell = wr.tell_frac();
     let tx_dist = if is_inter {
       write_tx_tree(
-        fi, ts, cw, wr, mode, tile_bo, bsize, tx_size, tx_type, false, true, rdo_type, true
+        fi, ts, cw, wr, mode, tile_bo, bsize, tx_size, tx_type,
+        TxPartition::NONE, false, true, rdo_type, true
       )
     }  else {
       write_tx_blocks(
@@ -995,6 +1405,7 @@ pub fn rdo_tx_type_decision<T: Pixel>(
         wr,
         mode,
         mode,
+        angle_delta,
         tile_bo,
         bsize,
         tx_size,
@@ -1102,6 +1513,19 @@ pub fn get_sub_partitions_with_border_check(
   partitions
 }
 
+/// RD cost of the partition symbol of a square block in the current
+/// partition context. The symbol is written, adapting the CDF.
+fn partition_rd_cost<T: Pixel>(
+  fi: &FrameInvariants<T>, cw: &mut ContextWriter,
+  w_pre_cdef: &mut dyn Writer, w_post_cdef: &mut dyn Writer,
+  bo: BlockOffset, partition: PartitionType, bsize: BlockSize
+) -> f64 {
+  let w: &mut dyn Writer = if cw.bc.cdef_coded {w_post_cdef} else {w_pre_cdef};
+  let tell = w.tell_frac();
+  cw.write_partition(w, bo, partition, bsize);
+  (w.tell_frac() - tell) as f64 * fi.lambda / ((1 << OD_BITRES) as f64)
+}
+
 // RDO-based single level partitioning decision
 pub fn rdo_partition_decision<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
@@ -1118,6 +1542,20 @@ pub fn rdo_partition_decision<T: Pixel>(
   let w_pre_checkpoint = w_pre_cdef.checkpoint();
   let w_post_checkpoint = w_post_cdef.checkpoint();
 
+  // The cached cost of an unsplit block leaves out its partition symbol
+  if cached_block.part_type == PartitionType::PARTITION_NONE
+    && bsize >= BlockSize::BLOCK_8X8
+    && best_rd < std::f64::MAX
+  {
+    best_rd += partition_rd_cost(
+      fi, cw, w_pre_cdef, w_post_cdef, tile_bo,
+      PartitionType::PARTITION_NONE, bsize
+    );
+    cw.rollback(&cw_checkpoint);
+    w_pre_cdef.rollback(&w_pre_checkpoint);
+    w_post_cdef.rollback(&w_post_checkpoint);
+  }
+
   for &partition in partition_types {
     // Do not re-encode results we already have
     if partition == cached_block.part_type {
@@ -1141,6 +1579,12 @@ pub fn rdo_partition_decision<T: Pixel>(
 
         let spmvs = &mut pmvs[pmv_idx];
 
+        if bsize >= BlockSize::BLOCK_8X8 {
+          cost = partition_rd_cost(
+            fi, cw, w_pre_cdef, w_post_cdef, tile_bo, partition, bsize
+          );
+        }
+        save_ec_state(fi, cw, w_pre_cdef, w_post_cdef);
         let mode_decision = rdo_mode_decision(fi, ts, cw, bsize, tile_bo, spmvs);
         child_modes.push(mode_decision);
       }
@@ -1176,15 +1620,25 @@ pub fn rdo_partition_decision<T: Pixel>(
         }).collect::<Vec<_>>();
 
         if bsize >= BlockSize::BLOCK_8X8 {
-          let w: &mut dyn Writer = if cw.bc.cdef_coded {w_post_cdef} else {w_pre_cdef};
-          let tell = w.tell_frac();
-          cw.write_partition(w, tile_bo, partition, bsize);
-          cost = (w.tell_frac() - tell) as f64 * fi.lambda
-            / ((1 << OD_BITRES) as f64);
+          cost = partition_rd_cost(
+            fi, cw, w_pre_cdef, w_post_cdef, tile_bo, partition, bsize
+          );
         }
-        let mut rd_cost_sum = 0.0;
+        let mut rd_cost_sum = cost;
 
         for (&offset, pmv_idx) in partitions.iter().zip(pmv_idxs) {
+          // Each sub-block is coded unsplit, in the partition context left
+          // by its preceding siblings
+          if subsize >= BlockSize::BLOCK_8X8 && subsize.is_sqr() {
+            let none_cost = partition_rd_cost(
+              fi, cw, w_pre_cdef, w_post_cdef, offset,
+              PartitionType::PARTITION_NONE, subsize
+            );
+            cost += none_cost;
+            rd_cost_sum += none_cost;
+          }
+
+          save_ec_state(fi, cw, w_pre_cdef, w_post_cdef);
           let mode_decision =
             rdo_mode_decision(fi, ts, cw, subsize, offset, &mut pmvs[pmv_idx]);
 
@@ -1195,14 +1649,21 @@ pub fn rdo_partition_decision<T: Pixel>(
             break;
           }
 
-          if subsize >= BlockSize::BLOCK_8X8 && subsize.is_sqr() {
-            let w: &mut dyn Writer = if cw.bc.cdef_coded {w_post_cdef} else {w_pre_cdef};
-            cw.write_partition(w, offset, PartitionType::PARTITION_NONE, subsize);
-          }
           encode_block_with_modes(fi, ts, cw, w_pre_cdef, w_post_cdef, subsize,
                                   offset, &mode_decision, rdo_type);
+          if subsize >= BlockSize::BLOCK_8X8 && subsize.is_sqr() {
+            cw.bc.update_partition_context(offset, subsize, subsize);
+          }
           child_modes.push(mode_decision);
         }
+
+        // A split block leaves the contexts of its sub-blocks, the others
+        // those of the partition, as the decoder does
+        if !early_exit && partition != PARTITION_SPLIT
+          && bsize >= BlockSize::BLOCK_8X8
+        {
+          cw.bc.update_partition_context(tile_bo, subsize, bsize);
+        }
       }
       _ => {
         unreachable!();
@@ -1270,6 +1731,47 @@ fn rdo_loop_plane_error<T: Pixel>(tile_sbo: SuperBlockOffset, fi: &FrameInvarian
   err
 }
 
+/// Copies the reconstruction of the superblock into `dst`, padded
+fn copy_sb_rec<T: Pixel>(
+  tile_sbo: SuperBlockOffset, ts: &TileStateMut<'_, T>, dst: &mut Frame<T>
+) {
+  for p in 0..3 {
+    let po = tile_sbo.plane_offset(&ts.rec.planes[p].plane_cfg);
+    let rec_region = ts.rec.planes[p].subregion(Area::StartingAt {
+      x: po.x,
+      y: po.y,
+    });
+    let width = dst.planes[p].cfg.width.min(rec_region.rect().width);
+    let height = dst.planes[p].cfg.height.min(rec_region.rect().height);
+    for (rec, inp) in rec_region.rows_iter().zip(
+      dst.planes[p].as_region_mut().rows_iter_mut()
+    ).take(height) {
+      inp[..width].copy_from_slice(&rec[..width]);
+    }
+    dst.planes[p].pad(width, height);
+  }
+}
+
+/// Whether the unfiltered reconstruction of the superblock, copied in
+/// `rec`, is already so close to the source that CDEF cannot be worth
+/// searching: its luma distortion is below a quarter of the noise expected
+/// from the quantizer.
+fn cdef_sb_is_clean<T: Pixel>(
+  tile_sbo: SuperBlockOffset, fi: &FrameInvariants<T>,
+  ts: &TileStateMut<'_, T>, blocks: &TileBlocks<'_>, rec: &Frame<T>
+) -> bool {
+  let err = rdo_loop_plane_error(tile_sbo, fi, ts, blocks, rec, 0);
+  let po = tile_sbo.plane_offset(&ts.rec.planes[0].plane_cfg);
+  let rect = *ts.rec.planes[0].subregion(Area::StartingAt {
+    x: po.x,
+    y: po.y,
+  }).rect();
+  let pixels = (rec.planes[0].cfg.width.min(rect.width)
+    * rec.planes[0].cfg.height.min(rect.height)) as u64;
+  let q = ac_q(fi.base_q_idx, 0, fi.sequence.bit_depth) as u64;
+  err * 48 < q * q * pixels
+}
+
 pub fn rdo_loop_decision<T: Pixel>(tile_sbo: SuperBlockOffset, fi: &FrameInvariants<T>,
                                    ts: &mut TileStateMut<'_, T>,
                                    cw: &mut ContextWriter, w: &mut dyn Writer) {
@@ -1289,24 +1791,21 @@ pub fn rdo_loop_decision<T: Pixel>(tile_sbo: SuperBlockOffset, fi: &FrameInvaria
   let const_rec = ts.rec.as_const();
   let mut lrf_input = cdef_sb_frame(fi, &const_rec);
   let mut lrf_output = cdef_sb_frame(fi, &const_rec);
-  if fi.sequence.enable_cdef {
+  // Superblocks reconstructed cleanly enough keep the zero strength index
+  // without searching the others
+  let mut cdef_skip = false;
+  if fi.sequence.enable_cdef && fi.config.speed_settings.fast_cdef {
+    copy_sb_rec(tile_sbo, ts, &mut lrf_input);
+    cdef_skip =
+      cdef_sb_is_clean(tile_sbo, fi, ts, &cw.bc.blocks.as_const(), &lrf_input);
+  }
+  if cdef_skip {
+    // Index 0 has zero strengths
+    best_index = 0;
+  } else if fi.sequence.enable_cdef {
     cdef_input = Some(cdef_sb_padded_frame_copy(fi, tile_sbo, &const_rec, 2));
   } else {
-    for p in 0..3 {
-      let po = tile_sbo.plane_offset(&ts.rec.planes[p].plane_cfg);
-      let rec_region = ts.rec.planes[p].subregion(Area::StartingAt {
-        x: po.x,
-        y: po.y,
-      });
-      let width = lrf_input.planes[p].cfg.width.min(rec_region.rect().width);
-      let height = lrf_input.planes[p].cfg.height.min(rec_region.rect().height);
-      for (rec, inp) in rec_region.rows_iter().zip(
-        lrf_input.planes[p].as_region_mut().rows_iter_mut()
-      ).take(height) {
-        inp[..width].copy_from_slice(&rec[..width]);
-      }
-      lrf_input.planes[p].pad(width, height);
-    }
+    copy_sb_rec(tile_sbo, ts, &mut lrf_input);
   }
 
   // CDEF/LRF decision iteration
@@ -1449,3 +1948,157 @@ pub fn rdo_loop_decision<T: Pixel>(tile_sbo: SuperBlockOffset, fi: &FrameInvaria
 fn estimate_rate_test() {
   assert_eq!(estimate_rate(0, TxSize::TX_4X4, 0), RDO_RATE_TABLE[0][0][0]);
 }
+
+#[test]
+fn estimate_residual_rd_cost_ranks_like_exact() {
+  use crate::encoder::Sequence;
+  use crate::quantize::{dequantize, QuantizationContext, INTER_ROUNDING};
+  use crate::transform::forward_transform;
+
+  let mut config = EncoderConfig::default();
+  config.width = 64;
+  config.height = 64;
+  let seq = Sequence::new(&config, None);
+  let mut fi = FrameInvariants::<u8>::new(config, seq);
+  let qidx = 100;
+  let step = f64::from(ac_q(qidx, 0, 8)) / 8.;
+  fi.base_q_idx = qidx;
+  fi.lambda = std::f64::consts::LN_2 / 6. * step * step;
+
+  let mut blocks = FrameBlocks::new(fi.w_in_b, fi.h_in_b);
+  let mut tb = blocks.as_tile_blocks_mut();
+  let mut fc = CDFContext::new(qidx);
+  let mut cw = ContextWriter::new(&mut fc, BlockContext::new(&mut tb));
+  let mut qc = QuantizationContext::default();
+  qc.update(qidx, TxSize::TX_16X16, INTER_ROUNDING, 8, 0, 0);
+
+  // Candidate predictions of a textured block: the windows around it, as
+  // motion vectors up to 3 pels off would fetch, so that the residuals
+  // differ in structure and not only in amplitude
+  let mut seed = 1u32;
+  let mut noise = || {
+    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+    f64::from((seed >> 16) & 0xff) / 127.5 - 1.
+  };
+  let texture: Vec<Vec<u8>> = (0..22).map(|y| (0..22).map(|x| {
+    let (x, y) = (x as f64, y as f64);
+    let v = 128. + 40. * (0.7 * x + 0.3 * y).sin()
+      + 25. * (0.9 * y - 0.2 * x).sin() + 6. * noise();
+    v.round().max(0.).min(255.) as u8
+  }).collect()).collect();
+  let window = |dx: usize, dy: usize| -> Vec<u8> {
+    (0..16).flat_map(|y| texture[dy + y][dx..dx + 16].to_vec()).collect()
+  };
+  let org_data = window(3, 3);
+  let org = Plane::wrap(org_data.clone(), 16);
+
+  let mut estimated = Vec::new();
+  let mut exact = Vec::new();
+  for dy in 0..7 {
+    for dx in 0..7 {
+      if (dx, dy) == (3, 3) {
+        continue;
+      }
+      let pred_data = window(dx, dy);
+      let residual: Vec<i16> = org_data.iter().zip(pred_data.iter())
+        .map(|(&o, &p)| i16::from(o) - i16::from(p))
+        .collect();
+      let pred = Plane::wrap(pred_data, 16);
+      estimated.push(estimate_residual_rd_cost(
+        &fi, &org.as_region(), &pred.as_region(), 16, 16, qidx
+      ));
+
+      let mut coeffs = [0i32; 256];
+      let mut qcoeffs = [0i32; 256];
+      let mut rcoeffs = [0i32; 256];
+      forward_transform(
+        &residual, &mut coeffs, 16, TxSize::TX_16X16, TxType::DCT_DCT, 8,
+        fi.cpu
+      );
+      qc.quantize(&coeffs, &mut qcoeffs, 256);
+      let rate = cw.coeff_cost(
+        0, BlockOffset { x: 0, y: 0 }, &qcoeffs, PredictionMode::NEWMV,
+        TxSize::TX_16X16, TxType::DCT_DCT, BlockSize::BLOCK_16X16, 0, 0, false
+      );
+      dequantize(qidx, &qcoeffs, &mut rcoeffs, TxSize::TX_16X16, 8, 0, 0);
+      // The coefficients of 16x16 transforms are in Q3
+      let distortion = coeffs.iter().zip(rcoeffs.iter())
+        .map(|(&c, &r)| ((c - r) as i64).pow(2) as u64)
+        .sum::<u64>() >> 6;
+      exact.push(compute_rd_cost(&fi, rate, distortion));
+    }
+  }
+
+  // Spearman's rank correlation
+  let ranks = |costs: &[f64]| {
+    let mut order: Vec<usize> = (0..costs.len()).collect();
+    order.sort_by(|&a, &b| costs[a].partial_cmp(&costs[b]).unwrap());
+    let mut ranks = vec![0i64; costs.len()];
+    for (rank, &i) in order.iter().enumerate() {
+      ranks[i] = rank as i64;
+    }
+    ranks
+  };
+  let n = exact.len() as f64;
+  let d2: i64 = ranks(&estimated).iter().zip(ranks(&exact).iter())
+    .map(|(&a, &b)| (a - b).pow(2))
+    .sum();
+  let correlation = 1. - 6. * d2 as f64 / (n * (n * n - 1.));
+  assert!(correlation > 0.95, "rank correlation {}", correlation);
+
+  // Screening keeps the 3 candidates of lowest estimate, which must hold
+  // the best one
+  let best = (0..exact.len())
+    .min_by(|&a, &b| exact[a].partial_cmp(&exact[b]).unwrap())
+    .unwrap();
+  assert!(ranks(&estimated)[best] < 3);
+}
+
+#[test]
+fn partition_cost_follows_sibling_context() {
+  use crate::encoder::Sequence;
+
+  let mut config = EncoderConfig::default();
+  config.width = 64;
+  config.height = 64;
+  let seq = Sequence::new(&config, None);
+  let mut fi = FrameInvariants::<u8>::new(config, seq);
+  fi.lambda = 100.;
+
+  let mut blocks = FrameBlocks::new(fi.w_in_b, fi.h_in_b);
+  let mut tb = blocks.as_tile_blocks_mut();
+  let mut fc = CDFContext::new(fi.base_q_idx);
+  let mut cw = ContextWriter::new(&mut fc, BlockContext::new(&mut tb));
+  let mut w_pre_cdef = WriterCounter::new();
+  let mut w_post_cdef = WriterCounter::new();
+  let checkpoint = cw.checkpoint();
+
+  let left = BlockOffset { x: 0, y: 0 };
+  let right = BlockOffset { x: 4, y: 0 };
+  let none_cost = |cw: &mut ContextWriter,
+                   w_pre_cdef: &mut WriterBase<WriterCounter>,
+                   w_post_cdef: &mut WriterBase<WriterCounter>| {
+    partition_rd_cost(
+      &fi, cw, w_pre_cdef, w_post_cdef, right,
+      PartitionType::PARTITION_NONE, BlockSize::BLOCK_16X16
+    )
+  };
+
+  // The cost is that of the symbol, at the rate of lambda per bit
+  let mut w = WriterCounter::new();
+  cw.write_partition(
+    &mut w, right, PartitionType::PARTITION_NONE, BlockSize::BLOCK_16X16
+  );
+  let bits = w.tell_frac() as f64 / (1 << OD_BITRES) as f64;
+  cw.rollback(&checkpoint);
+  let unsplit_left = none_cost(&mut cw, &mut w_pre_cdef, &mut w_post_cdef);
+  assert!((unsplit_left - bits * fi.lambda).abs() < 1e-9);
+
+  // Next to a block split further, not splitting is less likely
+  for &subsize in &[BlockSize::BLOCK_8X8, BlockSize::BLOCK_16X8] {
+    cw.rollback(&checkpoint);
+    cw.bc.update_partition_context(left, subsize, BlockSize::BLOCK_16X16);
+    let split_left = none_cost(&mut cw, &mut w_pre_cdef, &mut w_post_cdef);
+    assert!(split_left > unsplit_left, "{:?}: {} {}", subsize, split_left, unsplit_left);
+  }
+}
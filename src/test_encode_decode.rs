@@ -10,6 +10,10 @@
 use super::*;
 use rand::{ChaChaRng, Rng, SeedableRng};
 use std::sync::Arc;
+use crate::context::BlockOffset;
+use crate::me::BlockMv;
+use crate::partition::{BlockSize, MotionVector};
+use crate::partition::RefType::{LAST_FRAME, NONE_FRAME};
 use crate::util::Pixel;
 #[cfg(feature="decode_test")]
 use crate::test_encode_decode_aom::AomDecoder;
@@ -55,19 +59,27 @@ pub(crate) trait TestDecoder<T: Pixel> {
     min_keyint: u64, max_keyint: u64, low_latency: bool, bitrate: i32,
     tile_cols_log2: usize, tile_rows_log2: usize,
   ) {
-    let mut ra = ChaChaRng::from_seed([0; 32]);
-
-    let mut ctx: Context<T> =
+    let ctx: Context<T> =
       setup_encoder(w, h, speed, quantizer, bit_depth, chroma_sampling,
                     min_keyint, max_keyint, low_latency, bitrate,
                     tile_cols_log2, tile_rows_log2);
 
     println!("Encoding {}x{} speed {} quantizer {} bit-depth {}", w, h, speed, quantizer, bit_depth);
+    self.encode_decode_context(
+      ctx, w, h, limit, bit_depth,
+      &format!("{}x{}-s{}-q{}-{:?}", w, h, speed, quantizer, chroma_sampling)
+    );
+  }
+  fn encode_decode_context(
+    &mut self, mut ctx: Context<T>, w: usize, h: usize, limit: usize,
+    bit_depth: usize, _name: &str
+  ) {
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+
     #[cfg(feature="dump_ivf")]
-    let mut out = std::fs::File::create(&format!("out-{}x{}-s{}-q{}-{:?}.ivf",
-                                                   w, h, speed, quantizer, chroma_sampling)).unwrap();
+    let mut out = std::fs::File::create(&format!("out-{}.ivf", _name)).unwrap();
     #[cfg(feature="dump_ivf")]
-    ivf::write_ivf_header(&mut out, w, h, 30, 1);
+    ivf::write_ivf_header(&mut out, b"AV01", w, h, 30, 1);
 
     let mut rec_fifo = VecDeque::new();
     read_frame_batch(&mut ctx, &mut ra, limit);
@@ -77,17 +89,24 @@ pub(crate) trait TestDecoder<T: Pixel> {
       let mut corrupted_count = 0;
       loop {
         let res = ctx.receive_packet();
-        if let Ok(pkt) = res {
-          println!("Encoded packet {}", pkt.number);
+        if let Ok(mut pkt) = res {
+          // A temporal unit split over several packets is decoded whole
+          while pkt.continued {
+            let next = ctx.receive_packet().unwrap();
+            let mut data = pkt.data;
+            data.extend_from_slice(&next.data);
+            pkt = Packet { data, ..next };
+          }
+          println!("Encoded packet {}", pkt.coded_order);
 
           #[cfg(feature="dump_ivf")]
-          ivf::write_ivf_frame(&mut out, pkt.number, &pkt.data);
+          ivf::write_ivf_frame(&mut out, pkt.timestamp, &pkt.data);
 
           if let Some(pkt_rec) = pkt.rec {
-            rec_fifo.push_back(pkt_rec.clone());
+            rec_fifo.push_back((*pkt_rec).clone());
           }
           let packet = pkt.data;
-          println!("Decoding frame {}", pkt.number);
+          println!("Decoding packet {}", pkt.coded_order);
           match self.decode_packet(&packet, &mut rec_fifo, w, h, bit_depth) {
             DecodeResult::Done => { break; }
             DecodeResult::NotDone => {}
@@ -131,12 +150,9 @@ pub(crate) fn setup_encoder<T: Pixel>(
   enc.tile_cols_log2 = tile_cols_log2;
   enc.tile_rows_log2 = tile_rows_log2;
 
-  let cfg = Config {
-    enc,
-    threads: 0,
-  };
+  let cfg = Config::new(enc);
 
-  cfg.new_context()
+  cfg.new_context().unwrap()
 }
 
 // TODO: support non-multiple-of-16 dimensions
@@ -369,6 +385,27 @@ macro_rules! test_high_bit_depth {
 
 test_high_bit_depth!{10, 12}
 
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn internal_bit_depth(decoder: &str) {
+  let limit = 3;
+  let w = 64;
+  let h = 80;
+
+  // 8-bit input encoded at 10 bits
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.quantizer = 100;
+  enc.bit_depth = 8;
+  enc.internal_bit_depth = Some(10);
+  let ctx: Context<u16> = Config::new(enc).new_context().unwrap();
+
+  // The reconstruction is compared with the output of a 10-bit decoder
+  let mut dec = get_decoder::<u16>(decoder, w, h);
+  dec.encode_decode_context(ctx, w, h, limit, 10, "internal-bit-depth-10");
+}
+
 fn chroma_sampling(decoder: &str, cs: ChromaSampling) {
   let quantizer = 100;
   let limit = 3; // Include inter frames
@@ -398,6 +435,30 @@ macro_rules! test_chroma_sampling {
 
 test_chroma_sampling!{(420, ChromaSampling::Cs420), (422, ChromaSampling::Cs422), (444, ChromaSampling::Cs444)}
 
+// Inter frames, where 4x4 blocks share their chroma with their neighbours
+fn sub8x8_inter_chroma(decoder: &str, cs: ChromaSampling) {
+  let quantizer = 100;
+  let limit = 3;
+  let speed = 0;
+  let w = 64;
+  let h = 80;
+
+  let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+  dec.encode_decode(w, h, speed, quantizer, limit, 8, cs, 15, 15, true, 0, 0, 0);
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn sub8x8_inter_chroma_420(decoder: &str) {
+  sub8x8_inter_chroma(decoder, ChromaSampling::Cs420);
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn sub8x8_inter_chroma_422(decoder: &str) {
+  sub8x8_inter_chroma(decoder, ChromaSampling::Cs422);
+}
+
 #[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
 #[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
 fn tile_encoding_with_stretched_restoration_units(decoder: &str) {
@@ -413,6 +474,286 @@ fn tile_encoding_with_stretched_restoration_units(decoder: &str) {
   dec.encode_decode(w, h, speed, q, limit, 8, Default::default(), 15, 15, true, 0, 2, 2);
 }
 
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn max_frame_size(decoder: &str) {
+  let limit = 3;
+  let w = 256;
+  let h = 256;
+  let speed = 10;
+  let q = 20;
+
+  // A cap too small for any frame: frames get recoded up to the maximum
+  // quantizer, then have their tiles spread over several tile groups and
+  // their temporal unit split over several packets, put back together
+  // before decoding.
+  let mut enc = EncoderConfig::with_speed_preset(speed);
+  enc.width = w;
+  enc.height = h;
+  enc.quantizer = q;
+  enc.min_key_frame_interval = 15;
+  enc.max_key_frame_interval = 15;
+  enc.low_latency = true;
+  enc.tile_cols_log2 = 1;
+  enc.tile_rows_log2 = 1;
+  enc.max_frame_size_bytes = Some(64);
+  let ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+
+  let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+  dec.encode_decode_context(ctx, w, h, limit, 8, "max-frame-size");
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn max_tile_group_size(decoder: &str) {
+  let limit = 3;
+  let w = 256;
+  let h = 256;
+  let speed = 10;
+  let q = 20;
+
+  // Small enough for every tile to get a tile group of its own
+  let mut enc = EncoderConfig::with_speed_preset(speed);
+  enc.width = w;
+  enc.height = h;
+  enc.quantizer = q;
+  enc.min_key_frame_interval = 15;
+  enc.max_key_frame_interval = 15;
+  enc.low_latency = true;
+  enc.tile_cols_log2 = 1;
+  enc.tile_rows_log2 = 1;
+  enc.max_tile_group_size = Some(64);
+  let ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+
+  let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+  dec.encode_decode_context(ctx, w, h, limit, 8, "max-tile-group-size");
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn tile_rate_control(decoder: &str) {
+  let limit = 5;
+  let w = 256;
+  let h = 256;
+
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.quantizer = 100;
+  enc.tile_cols_log2 = 1;
+  enc.tile_rows_log2 = 1;
+  enc.tile_rate_control = true;
+  let ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+
+  let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+  dec.encode_decode_context(ctx, w, h, limit, 8, "tile-rate-control");
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn rdoq(decoder: &str) {
+  let limit = 3;
+  let w = 64;
+  let h = 80;
+
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.quantizer = 100;
+  enc.min_key_frame_interval = 15;
+  enc.max_key_frame_interval = 15;
+  enc.low_latency = true;
+  enc.speed_settings.rdoq = true;
+  let ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+
+  let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+  dec.encode_decode_context(ctx, w, h, limit, 8, "rdoq");
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn angle_delta_search(decoder: &str) {
+  let limit = 3;
+  let w = 64;
+  let h = 80;
+
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.quantizer = 100;
+  enc.min_key_frame_interval = 15;
+  enc.max_key_frame_interval = 15;
+  enc.low_latency = true;
+  enc.speed_settings.angle_delta_search = true;
+  let ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+
+  let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+  dec.encode_decode_context(ctx, w, h, limit, 8, "angle_delta_search");
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn var_tx(decoder: &str) {
+  let limit = 5;
+  let w = 64;
+  let h = 80;
+
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.quantizer = 100;
+  enc.min_key_frame_interval = 15;
+  enc.max_key_frame_interval = 15;
+  enc.low_latency = true;
+  enc.speed_settings.min_block_size = BlockSize::BLOCK_8X8;
+  enc.speed_settings.var_tx = true;
+  let ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+
+  let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+  dec.encode_decode_context(ctx, w, h, limit, 8, "var_tx");
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn cdef_off(decoder: &str) {
+  let limit = 3;
+  let w = 64;
+  let h = 80;
+
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.quantizer = 100;
+  enc.min_key_frame_interval = 15;
+  enc.max_key_frame_interval = 15;
+  enc.low_latency = true;
+  enc.speed_settings.cdef = false;
+  let ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+
+  let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+  dec.encode_decode_context(ctx, w, h, limit, 8, "cdef_off");
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn external_motion_odd_mv(decoder: &str) {
+  let limit = 3;
+  let w = 64;
+  let h = 64;
+
+  let mut ctx = setup_encoder::<u8>(
+    w, h, 10, 100, 8, ChromaSampling::Cs420, 0, 30, true, 0, 0, 0
+  );
+  // 1/8-pel motion, coded at the precision of the frames
+  let blocks = [BlockMv {
+    bo: BlockOffset { x: 0, y: 0 },
+    bsize: BlockSize::BLOCK_64X64,
+    ref_frames: [LAST_FRAME, NONE_FRAME],
+    mvs: [MotionVector { row: 3, col: -5 }, MotionVector::default()],
+  }];
+  for frameno in 1..limit as u64 {
+    ctx.set_external_motion(frameno, &blocks).unwrap();
+  }
+
+  let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+  dec.encode_decode_context(ctx, w, h, limit, 8, "external_motion_odd_mv");
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn coding_tools(decoder: &str) {
+  let limit = 3;
+  let w = 64;
+  let h = 80;
+
+  // Each tool toggled from its default in turn
+  let toggles: [fn(&mut CodingTools); 8] = [
+    |t| t.cdef = false,
+    |t| t.restoration = false,
+    |t| t.superres = true,
+    |t| t.warped_motion = true,
+    |t| t.dual_filter = true,
+    |t| t.jnt_comp = true,
+    |t| t.ref_frame_mvs = true,
+    |t| t.filter_intra = false,
+  ];
+  for (i, toggle) in toggles.iter().enumerate() {
+    let mut enc = EncoderConfig::with_speed_preset(5);
+    enc.width = w;
+    enc.height = h;
+    enc.quantizer = 100;
+    enc.min_key_frame_interval = 15;
+    enc.max_key_frame_interval = 15;
+    enc.disabled_modes.compound = true;
+    toggle(&mut enc.tools);
+    let ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+
+    let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+    dec.encode_decode_context(ctx, w, h, limit, 8, &format!("coding_tools-{}", i));
+  }
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn reduced_tx_set_auto(decoder: &str) {
+  let limit = 3;
+  let w = 64;
+  let h = 80;
+
+  let mut enc = EncoderConfig::with_speed_preset(3);
+  enc.width = w;
+  enc.height = h;
+  enc.quantizer = 200;
+  enc.min_key_frame_interval = 15;
+  enc.max_key_frame_interval = 15;
+  enc.low_latency = true;
+  enc.speed_settings.reduced_tx_set = ReducedTxSet::Auto;
+  let ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+
+  let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+  dec.encode_decode_context(ctx, w, h, limit, 8, "reduced_tx_set_auto");
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn denoise(decoder: &str) {
+  let limit = 5;
+  let w = 64;
+  let h = 80;
+
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.quantizer = 100;
+  enc.preprocess = Some(Preprocess::Denoise { strength: 8 });
+  let ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+
+  let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+  dec.encode_decode_context(ctx, w, h, limit, 8, "denoise");
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn intra_refresh(decoder: &str) {
+  let limit = 10;
+  let w = 256;
+  let h = 80;
+  let speed = 10;
+  let q = 100;
+
+  let mut enc = EncoderConfig::with_speed_preset(speed);
+  enc.width = w;
+  enc.height = h;
+  enc.quantizer = q;
+  enc.min_key_frame_interval = 60;
+  enc.max_key_frame_interval = 60;
+  enc.intra_refresh_period = Some(3);
+  let ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+
+  let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+  dec.encode_decode_context(ctx, w, h, limit, 8, "intra-refresh");
+}
+
 fn get_decoder<T: Pixel>(decoder: &str, w: usize, h: usize) -> Box<dyn TestDecoder<T>> {
   match decoder {
     #[cfg(feature="decode_test")]
@@ -422,3 +763,197 @@ fn get_decoder<T: Pixel>(decoder: &str, w: usize, h: usize) -> Box<dyn TestDecod
     _ => unimplemented!()
   }
 }
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn max_block_size_16x16(decoder: &str) {
+  let limit = 3;
+  let w = 64;
+  let h = 80;
+
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.quantizer = 100;
+  enc.min_key_frame_interval = 15;
+  enc.max_key_frame_interval = 15;
+  enc.low_latency = true;
+  enc.speed_settings.min_block_size = BlockSize::BLOCK_8X8;
+  enc.speed_settings.max_block_size = BlockSize::BLOCK_16X16;
+  let ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+
+  let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+  dec.encode_decode_context(ctx, w, h, limit, 8, "max_block_size_16x16");
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn max_reference_frames(decoder: &str) {
+  let limit = 10;
+  let w = 64;
+  let h = 80;
+
+  for &low_latency in [false, true].iter() {
+    for &max_refs in [1, 2, 3, 5].iter() {
+      let mut enc = EncoderConfig::with_speed_preset(10);
+      enc.width = w;
+      enc.height = h;
+      enc.quantizer = 100;
+      enc.min_key_frame_interval = 15;
+      enc.max_key_frame_interval = 15;
+      enc.low_latency = low_latency;
+      enc.max_reference_frames = max_refs;
+      let ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+
+      let mut dec = get_decoder::<u8>(decoder, w as usize, h as usize);
+      dec.encode_decode_context(ctx, w, h, limit, 8, &format!("max_refs-{}", max_refs));
+    }
+  }
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn long_term_recovery(decoder: &str) {
+  let limit = 10;
+  let w = 64;
+  let h = 80;
+
+  let mut ctx: Context<u8> = setup_encoder(
+    w, h, 10, 100, 8, ChromaSampling::Cs420, 0, 30, true, 0, 0, 0
+  );
+  ctx.mark_long_term(2).unwrap();
+  let mut ra = ChaChaRng::from_seed([0; 32]);
+  for i in 0..limit {
+    let mut input = ctx.new_frame();
+    fill_frame(&mut ra, Arc::get_mut(&mut input).unwrap());
+    let params = FrameParameters {
+      only_reference_long_term: i == 7,
+      ..Default::default()
+    };
+    ctx.send_frame_with_params(input, params).unwrap();
+  }
+  ctx.flush();
+
+  // Frames 4 to 6 are lost, the decoder seeing frame 7 right after frame 3
+  let mut dec = get_decoder::<u8>(decoder, w, h);
+  let mut rec_fifo = VecDeque::new();
+  let mut decoded = 0;
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => {
+        if pkt.input_frameno.map_or(false, |number| (4..7).contains(&number)) {
+          continue;
+        }
+        if let Some(pkt_rec) = pkt.rec {
+          rec_fifo.push_back((*pkt_rec).clone());
+        }
+        match dec.decode_packet(&pkt.data, &mut rec_fifo, w, h, 8) {
+          DecodeResult::Corrupted(corrupted) => {
+            panic!("{} frames corrupted after frame {}", corrupted, pkt.input_frameno.unwrap())
+          }
+          _ => decoded += 1,
+        }
+      }
+      Err(EncoderStatus::LimitReached) => break,
+      Err(e) => panic!("{:?}", e),
+    }
+  }
+  assert_eq!(decoded, limit - 3);
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn temporal_layers(decoder: &str) {
+  let limit = 12;
+  let w = 64;
+  let h = 80;
+
+  // Each operating point, dropping the frames of the layers above it
+  for max_temporal_id in 0..3 {
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = w;
+    enc.height = h;
+    enc.quantizer = 100;
+    enc.min_key_frame_interval = 30;
+    enc.max_key_frame_interval = 30;
+    enc.low_latency = true;
+    enc.temporal_layers = 3;
+    let mut ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    read_frame_batch(&mut ctx, &mut ra, limit);
+
+    let mut dec = get_decoder::<u8>(decoder, w, h);
+    let mut rec_fifo = VecDeque::new();
+    let mut decoded = 0;
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => {
+          if pkt.temporal_id > max_temporal_id {
+            continue;
+          }
+          if let Some(pkt_rec) = pkt.rec {
+            rec_fifo.push_back((*pkt_rec).clone());
+          }
+          match dec.decode_packet(&pkt.data, &mut rec_fifo, w, h, 8) {
+            DecodeResult::Corrupted(corrupted) => {
+              panic!("{} frames corrupted at layer {}", corrupted, max_temporal_id)
+            }
+            _ => decoded += 1,
+          }
+        }
+        Err(EncoderStatus::LimitReached) => break,
+        Err(e) => panic!("{:?}", e),
+      }
+    }
+    assert_eq!(decoded, limit >> (2 - max_temporal_id));
+  }
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn parallel_segments(decoder: &str) {
+  let limit = 10;
+  let w = 64;
+  let h = 80;
+
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.quantizer = 100;
+  enc.max_key_frame_interval = 4;
+  enc.speed_settings.no_scene_detection = true;
+  let mut ctx = Config::new(enc).new_parallel_context::<u8>(2);
+  let mut ra = ChaChaRng::from_seed([0; 32]);
+  let mut packets = Vec::new();
+  for _ in 0..limit {
+    let mut input = ctx.new_frame();
+    fill_frame(&mut ra, Arc::get_mut(&mut input).unwrap());
+    while let Err(EncoderStatus::EnoughData) = ctx.send_frame(input.clone()) {
+      packets.push(ctx.receive_packet().unwrap());
+    }
+  }
+  ctx.flush();
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => packets.push(pkt),
+      Err(EncoderStatus::LimitReached) => break,
+      Err(e) => panic!("{:?}", e),
+    }
+  }
+  assert_eq!(packets.len(), limit);
+
+  // The segments decode as a single stream
+  let mut dec = get_decoder::<u8>(decoder, w, h);
+  let mut rec_fifo = VecDeque::new();
+  for pkt in packets {
+    if let Some(pkt_rec) = pkt.rec {
+      rec_fifo.push_back((*pkt_rec).clone());
+    }
+    if let DecodeResult::Corrupted(corrupted) =
+      dec.decode_packet(&pkt.data, &mut rec_fifo, w, h, 8)
+    {
+      panic!("{} frames corrupted at packet {}", corrupted, pkt.coded_order)
+    }
+  }
+  assert!(rec_fifo.is_empty());
+}
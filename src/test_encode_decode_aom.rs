@@ -15,6 +15,7 @@ use std::collections::VecDeque;
 use std::ffi::CStr;
 use crate::util::Pixel;
 use crate::test_encode_decode::{compare_plane, TestDecoder, DecodeResult};
+use std::sync::Arc;
 use aom_sys::*;
 
 pub(crate) struct AomDecoder<T: Pixel> {
@@ -126,6 +127,7 @@ impl<T: Pixel> Drop for AomDecoder<T> {
 
 fn compare_img<T: Pixel>(img: *const aom_image_t, frame: &Frame<T>, bit_depth: usize, width: usize, height: usize) {
   let img = unsafe { *img };
+  assert_eq!(img.bit_depth as usize, bit_depth);
   let img_iter = img.planes.iter().zip(img.stride.iter());
 
   for (img_plane, frame_plane) in img_iter.zip(frame.planes.iter()) {
@@ -164,3 +166,448 @@ fn compare_img<T: Pixel>(img: *const aom_image_t, frame: &Frame<T>, bit_depth: u
     }
   }
 }
+
+// Extracts single tiles of the camera frames of a large scale tile stream,
+// by their location in the packet, and decodes each on its own from a tile
+// list of one entry, as a light field viewer would.
+#[test]
+fn large_scale_tile_single_tile_decode() {
+  let (w, h) = (128, 128);
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.tile_cols_log2 = 1;
+  enc.tile_rows_log2 = 1;
+  enc.large_scale_tile = true;
+  let mut ctx: Context<u8> =
+    Config::new(enc).new_context().unwrap();
+  let limit = 3;
+  for i in 0..limit {
+    let mut input = ctx.new_frame();
+    for plane in Arc::get_mut(&mut input).unwrap().planes.iter_mut() {
+      let stride = plane.cfg.stride;
+      for (y, row) in plane.data.chunks_mut(stride).enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+          *pixel = ((x + 2 * i) * 3 + y * 2) as u8;
+        }
+      }
+    }
+    let _ = ctx.send_frame(input);
+  }
+  ctx.flush();
+  let mut packets = Vec::new();
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => packets.push(pkt),
+      Err(EncoderStatus::NeedMoreData) => {},
+      Err(EncoderStatus::LimitReached) => break,
+      Err(e) => panic!("{:?}", e),
+    }
+  }
+  assert_eq!(packets.len(), limit);
+  assert_eq!(packets[0].frame_type, FrameType::KEY);
+
+  unsafe {
+    let mut decoder = AomDecoder::<u8>::setup_decoder(w, h);
+    let dec = &mut decoder.dec;
+    let mut iter: aom_codec_iter_t = ptr::null_mut();
+
+    // The key frame is the anchor frame, decoded as usual and kept aside
+    assert_eq!(aom_codec_control_(dec, aom_dec_control_id::AV1_SET_TILE_MODE as i32, 0 as i32), 0);
+    let anchor = &packets[0].data;
+    assert_eq!(aom_codec_decode(dec, anchor.as_ptr(), anchor.len(), ptr::null_mut()), 0);
+    let img = aom_codec_get_frame(dec, &mut iter);
+    assert!(!img.is_null());
+    let mut anchor_img: aom_image_t = mem::zeroed();
+    // With the border of the decoder, to predict from
+    assert!(!aom_img_alloc_with_border(
+      &mut anchor_img, (*img).fmt, w as u32, h as u32, 32, 8, 288
+    ).is_null());
+    assert_eq!(aom_codec_control_(dec, aom_dec_control_id::AV1_COPY_NEW_FRAME_IMAGE as i32, &mut anchor_img as *mut aom_image_t), 0);
+
+    assert_eq!(aom_codec_control_(dec, aom_dec_control_id::AV1_SET_TILE_MODE as i32, 1 as i32), 0);
+    let mut ext_ref = av1_ext_ref_frame_t { img: &mut anchor_img, num: 1 };
+    assert_eq!(aom_codec_control_(dec, aom_dec_control_id::AV1D_SET_EXT_REF_PTR as i32, &mut ext_ref as *mut av1_ext_ref_frame_t), 0);
+
+    for pkt in &packets[1..] {
+      let rec = pkt.rec.as_ref().unwrap();
+      let tile_list = pkt.obus().find(|obu| obu.obu_type == ObuType::OBU_TILE_LIST).unwrap();
+      // obu_header, with obu_extension_flag
+      let obu_header = &tile_list.data[..1 + (tile_list.data[0] >> 2 & 1) as usize];
+      let headers = &pkt.data[..tile_list.data.as_ptr() as usize - pkt.data.as_ptr() as usize];
+
+      for tile in &pkt.tiles {
+        // The camera frame header comes first
+        iter = ptr::null_mut();
+        assert_eq!(aom_codec_decode(dec, headers.as_ptr(), headers.len(), ptr::null_mut()), 0);
+
+        // output_frame_{width,height}_in_tiles_minus_1, tile_count_minus_1,
+        // anchor_frame_idx, anchor_tile_row, anchor_tile_col and
+        // tile_data_size_minus_1, as in the packet
+        let mut payload = vec![0, 0, 0, 0];
+        payload.extend_from_slice(&pkt.data[tile.offset - 5..tile.offset + tile.size]);
+        let mut obu = obu_header.to_vec();
+        // obu_size, leb128
+        let mut size = payload.len();
+        loop {
+          let byte = (size & 0x7f) as u8;
+          size >>= 7;
+          if size == 0 {
+            obu.push(byte);
+            break;
+          }
+          obu.push(byte | 0x80);
+        }
+        obu.extend_from_slice(&payload);
+        assert_eq!(aom_codec_decode(dec, obu.as_ptr(), obu.len(), ptr::null_mut()), 0);
+
+        let img = aom_codec_get_frame(dec, &mut iter);
+        assert!(!img.is_null());
+        let img = *img;
+        for (p, frame_plane) in rec.planes.iter().enumerate() {
+          let (xdec, ydec) = (frame_plane.cfg.xdec, frame_plane.cfg.ydec);
+          let (tw, th) = (64 >> xdec, 64 >> ydec);
+          let dec_stride = img.stride[p] as usize;
+          let dec = slice::from_raw_parts(img.planes[p] as *const u8, dec_stride * th);
+          let rec_stride = frame_plane.cfg.stride;
+          let origin = (tile.row * th) * rec_stride + tile.col * tw;
+          let rec = &frame_plane.data_origin()[origin..];
+          compare_plane::<u8>(rec, rec_stride, dec, dec_stride, tw, th);
+        }
+      }
+    }
+    aom_img_free(&mut anchor_img);
+  }
+}
+
+// MD5 of a decoded image as `aomdec --rawvideo --md5` computes it, over
+// the visible rows of each plane at the width of a sample
+fn img_md5<T: Pixel>(img: &aom_image_t, width: usize, height: usize) -> [u8; 16] {
+  let mut md5 = crate::util::Md5::new();
+  for p in 0..3 {
+    let (xdec, ydec) = if p == 0 {
+      (0, 0)
+    } else {
+      (img.x_chroma_shift as usize, img.y_chroma_shift as usize)
+    };
+    let (w, h) = ((width + xdec) >> xdec, (height + ydec) >> ydec);
+    let row_bytes = w * mem::size_of::<T>();
+    for y in 0..h {
+      let row = unsafe {
+        slice::from_raw_parts(
+          img.planes[p].offset(y as isize * img.stride[p] as isize) as *const u8,
+          row_bytes
+        )
+      };
+      md5.update(row);
+    }
+  }
+  md5.finish()
+}
+
+// The frame hashes of the command line are `Frame::md5` of the frames
+// shown, which must match the ones of the decoded frames across several
+// GOPs with reordering
+fn frame_md5_matches_decoded<T: Pixel>(bit_depth: usize) {
+  let (w, h) = (64, 48);
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.bit_depth = bit_depth;
+  enc.min_key_frame_interval = 5;
+  enc.max_key_frame_interval = 5;
+  let mut ctx: Context<T> = Config::new(enc).new_context().unwrap();
+  let limit = 12;
+  for i in 0..limit {
+    let mut input = ctx.new_frame();
+    for plane in Arc::get_mut(&mut input).unwrap().planes.iter_mut() {
+      let stride = plane.cfg.stride;
+      for (y, row) in plane.data.chunks_mut(stride).enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+          *pixel = T::cast_from(((x + 2 * i) * 3 + y * 2) % (1 << bit_depth));
+        }
+      }
+    }
+    let _ = ctx.send_frame(input);
+  }
+  ctx.flush();
+
+  let mut decoder = AomDecoder::<T>::setup_decoder(w, h);
+  let mut shown = 0;
+  loop {
+    let pkt = match ctx.receive_packet() {
+      Ok(pkt) => pkt,
+      Err(EncoderStatus::NeedMoreData) => continue,
+      Err(EncoderStatus::LimitReached) => break,
+      Err(e) => panic!("{:?}", e),
+    };
+    let rec = pkt.rec.as_ref().unwrap();
+    unsafe {
+      let dec = &mut decoder.dec;
+      assert_eq!(aom_codec_decode(dec, pkt.data.as_ptr(), pkt.data.len(), ptr::null_mut()), 0);
+      let mut iter: aom_codec_iter_t = ptr::null_mut();
+      let img = aom_codec_get_frame(dec, &mut iter);
+      assert!(!img.is_null());
+      assert_eq!(img_md5::<T>(&*img, w, h), rec.md5(w, h), "frame {}", shown);
+      // One frame shown per packet
+      assert!(aom_codec_get_frame(dec, &mut iter).is_null());
+    }
+    shown += 1;
+  }
+  assert_eq!(shown, limit);
+}
+
+#[test]
+fn frame_md5_matches_decoded_8bit() {
+  frame_md5_matches_decoded::<u8>(8);
+}
+
+#[test]
+fn frame_md5_matches_decoded_10bit() {
+  frame_md5_matches_decoded::<u16>(10);
+}
+
+// A decoder joining the stream at the start of an intra refresh cycle,
+// after the frames of another stream, outputs the frames of the encoder
+// once the cycle has swept the frame
+#[test]
+fn intra_refresh_recovers_within_a_period() {
+  let (w, h) = (256, 80);
+  let period = 3;
+  // The first frame of the second refresh cycle
+  let join = 1 + period;
+  let limit = join + 2 * period;
+  let encode = |offset: usize| {
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.width = w;
+    enc.height = h;
+    enc.quantizer = 100;
+    enc.min_key_frame_interval = 60;
+    enc.max_key_frame_interval = 60;
+    enc.speed_settings.no_scene_detection = true;
+    enc.intra_refresh_period = Some(period as u64);
+    // Every frame starts from the default probabilities
+    enc.error_resilient = true;
+    let mut ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+    for i in 0..limit {
+      // The streams only differ before the join
+      let offset = if i < join { offset } else { 0 };
+      let mut input = ctx.new_frame();
+      for plane in Arc::get_mut(&mut input).unwrap().planes.iter_mut() {
+        let stride = plane.cfg.stride;
+        for (y, row) in plane.data.chunks_mut(stride).enumerate() {
+          for (x, pixel) in row.iter_mut().enumerate() {
+            *pixel = ((x + 2 * i) * 3 + y * 2 + offset) as u8;
+          }
+        }
+      }
+      let _ = ctx.send_frame(input);
+    }
+    ctx.flush();
+    let mut packets = Vec::new();
+    loop {
+      match ctx.receive_packet() {
+        Ok(pkt) => packets.push(pkt),
+        Err(EncoderStatus::NeedMoreData) => {},
+        Err(EncoderStatus::LimitReached) => break,
+        Err(e) => panic!("{:?}", e),
+      }
+    }
+    assert_eq!(packets.len(), limit);
+    packets
+  };
+  let stream = encode(0);
+  let other = encode(97);
+
+  let mut decoder = AomDecoder::<u8>::setup_decoder(w, h);
+  let dec = &mut decoder.dec;
+  for (i, pkt) in other[..join].iter().chain(stream[join..].iter()).enumerate() {
+    unsafe {
+      assert_eq!(aom_codec_decode(dec, pkt.data.as_ptr(), pkt.data.len(), ptr::null_mut()), 0);
+      let mut iter: aom_codec_iter_t = ptr::null_mut();
+      let img = aom_codec_get_frame(dec, &mut iter);
+      assert!(!img.is_null());
+      // Clean from the end of the cycle the decoder joined
+      if i + 1 >= join + period {
+        compare_img::<u8>(img, pkt.rec.as_ref().unwrap(), 8, w, h);
+      }
+    }
+  }
+}
+
+// Grain table segments as applied by libaom over the reconstructed frames:
+// explicit parameters, the same ones signaled by reference with a new
+// seed, then no grain
+#[test]
+fn film_grain_table_applied_by_decoder() {
+  use crate::grain::{GrainTable, GrainTableSource};
+
+  let (w, h) = (64, 64);
+  // Frames 0 and 1, 2 and 3, then 4 and 5 at 30 fps
+  let table = GrainTable::parse("filmgrn1
+E 0 600000 1 1000 1
+\tp 0 6 0 8 0 1 128 192 256 128 192 256
+\tsY 2 0 64 255 64
+\tsCb 2 0 32 255 32
+\tsCr 2 0 32 255 32
+\tcY
+\tcCb 0
+\tcCr 0
+E 600000 1300000 1 2000 0
+E 1300000 10000000 0 0 0
+").unwrap();
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.quantizer = 50;
+  enc.low_latency = true;
+  enc.film_grain = Some(GrainTableSource::File(table));
+  let mut ctx: Context<u8> = Config::new(enc).new_context().unwrap();
+  let limit = 6;
+  for _ in 0..limit {
+    let mut input = ctx.new_frame();
+    for plane in Arc::get_mut(&mut input).unwrap().planes.iter_mut() {
+      for v in plane.data.iter_mut() {
+        *v = 128;
+      }
+    }
+    let _ = ctx.send_frame(input);
+  }
+  ctx.flush();
+
+  // Per plane differences of the decoded frames to the reconstructed ones
+  let mut grain: Vec<Vec<Vec<i16>>> = Vec::new();
+  let mut decoder = AomDecoder::<u8>::setup_decoder(w, h);
+  let dec = &mut decoder.dec;
+  loop {
+    let pkt = match ctx.receive_packet() {
+      Ok(pkt) => pkt,
+      Err(EncoderStatus::NeedMoreData) => continue,
+      Err(EncoderStatus::LimitReached) => break,
+      Err(e) => panic!("{:?}", e),
+    };
+    let rec = pkt.rec.as_ref().unwrap();
+    assert_eq!(pkt.input_frameno, Some(grain.len() as u64));
+    unsafe {
+      assert_eq!(aom_codec_decode(dec, pkt.data.as_ptr(), pkt.data.len(), ptr::null_mut()), 0);
+      let mut iter: aom_codec_iter_t = ptr::null_mut();
+      let img = aom_codec_get_frame(dec, &mut iter);
+      assert!(!img.is_null());
+      let img = *img;
+      let planes: Vec<Vec<i16>> = rec.planes.iter().enumerate().map(|(p, plane)| {
+        let (pw, ph) = (w >> plane.cfg.xdec, h >> plane.cfg.ydec);
+        let stride = img.stride[p] as usize;
+        let decoded = slice::from_raw_parts(img.planes[p] as *const u8, stride * ph);
+        (0..ph).flat_map(|y| (0..pw).map(move |x| (x, y)))
+          .map(|(x, y)| i16::from(decoded[y * stride + x]) - i16::from(plane.p(x, y)))
+          .collect::<Vec<_>>()
+      }).collect();
+      grain.push(planes);
+    }
+  }
+  assert_eq!(grain.len(), limit);
+
+  let mean_abs = |diff: &[i16]| {
+    diff.iter().map(|&d| f64::from(d.abs())).sum::<f64>() / diff.len() as f64
+  };
+  for frame in 0..4 {
+    // Luma scaled by 64/256, chroma by 32/256, of grain of a few units
+    assert!(mean_abs(&grain[frame][0]) > 1., "frame {}", frame);
+    assert!(mean_abs(&grain[frame][1]) > 0.5, "frame {}", frame);
+    assert!(mean_abs(&grain[frame][2]) > 0.5, "frame {}", frame);
+    // Each frame has its own seed
+    if frame > 0 {
+      assert_ne!(grain[frame][0], grain[frame - 1][0], "frame {}", frame);
+    }
+  }
+  // The parameters loaded from the reference give the same strength
+  let strength =
+    |frames: &[Vec<Vec<i16>>]| frames.iter().map(|f| mean_abs(&f[0])).sum::<f64>();
+  let (explicit, referenced) = (strength(&grain[0..2]), strength(&grain[2..4]));
+  assert!((referenced / explicit - 1.).abs() < 0.25, "{} {}", explicit, referenced);
+  // No grain at all over the last segment
+  for frame in 4..limit {
+    assert!(grain[frame].iter().all(|plane| plane.iter().all(|&d| d == 0)),
+            "frame {}", frame);
+  }
+}
+
+// The streams of a `DualContext` decode as the color and alpha images of
+// an AVIF image with transparency: of the same size and bit depth, the
+// alpha image in full range and close to the alpha plane.
+#[test]
+fn alpha_stream_decodes_for_avif() {
+  fn decode(dec: &mut aom_codec_ctx, data: &[u8]) -> aom_image_t {
+    unsafe {
+      assert_eq!(aom_codec_decode(dec, data.as_ptr(), data.len(), ptr::null_mut()), 0);
+      let mut iter: aom_codec_iter_t = ptr::null_mut();
+      let img = aom_codec_get_frame(dec, &mut iter);
+      assert!(!img.is_null());
+      *img
+    }
+  }
+
+  let (w, h) = (64, 64);
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = w;
+  enc.height = h;
+  enc.speed_settings.no_scene_detection = true;
+  let mut ctx: DualContext<u8> = Config::new(enc).new_dual_context().unwrap();
+  let mut alpha = ctx.new_alpha_plane();
+  let stride = alpha.cfg.stride;
+  for (y, row) in alpha.data_origin_mut().chunks_mut(stride).take(h).enumerate() {
+    for (x, v) in row.iter_mut().take(w).enumerate() {
+      // Opaque, transparent, then a gradient
+      *v = match x {
+        0..=15 => 255,
+        16..=31 => 0,
+        _ => ((x - 32) * 4 + y * 2) as u8,
+      };
+    }
+  }
+  let limit = 3;
+  for i in 0..limit {
+    let mut input = ctx.new_frame();
+    for plane in Arc::get_mut(&mut input).unwrap().planes.iter_mut() {
+      for v in plane.data.iter_mut() {
+        *v = (64 + i * 32) as u8;
+      }
+    }
+    ctx.send_frame(Some((input, &alpha))).unwrap();
+  }
+  ctx.flush();
+
+  let mut color_decoder = AomDecoder::<u8>::setup_decoder(w, h);
+  let mut alpha_decoder = AomDecoder::<u8>::setup_decoder(w, h);
+  let mut frames = 0;
+  loop {
+    let (color_pkt, alpha_pkt) =
+      match (ctx.receive_color_packet(), ctx.receive_alpha_packet()) {
+        (Ok(color_pkt), Ok(alpha_pkt)) => (color_pkt, alpha_pkt),
+        (Err(EncoderStatus::NeedMoreData), Err(EncoderStatus::NeedMoreData)) => continue,
+        (Err(EncoderStatus::LimitReached), Err(EncoderStatus::LimitReached)) => break,
+        (color_pkt, alpha_pkt) => panic!("{:?} {:?}", color_pkt.err(), alpha_pkt.err()),
+      };
+    assert_eq!(color_pkt.frame_type, alpha_pkt.frame_type);
+    let color_img = decode(&mut color_decoder.dec, &color_pkt.data);
+    let alpha_img = decode(&mut alpha_decoder.dec, &alpha_pkt.data);
+    assert_eq!((alpha_img.d_w, alpha_img.d_h), (color_img.d_w, color_img.d_h));
+    assert_eq!(alpha_img.bit_depth, color_img.bit_depth);
+    assert_eq!(alpha_img.range, aom_color_range::AOM_CR_FULL_RANGE);
+
+    // Decoded as reconstructed, within a few levels of the alpha plane
+    compare_img(&alpha_img, alpha_pkt.rec.as_ref().unwrap(), 8, w, h);
+    let stride = alpha_img.stride[0] as usize;
+    let decoded = unsafe {
+      slice::from_raw_parts(alpha_img.planes[0] as *const u8, stride * h)
+    };
+    for y in 0..h {
+      for x in 0..w {
+        let diff = i32::from(decoded[y * stride + x]) - i32::from(alpha.p(x, y));
+        assert!(diff.abs() <= 4, "frame {} at ({}, {}): {}", frames, x, y, diff);
+      }
+    }
+    frames += 1;
+  }
+  assert_eq!(frames, limit);
+}
@@ -0,0 +1,183 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+//! Golden-output regression tests.
+//!
+//! Each test encodes a small synthetic sequence and compares the SHA-256
+//! digest of every packet against the ones checked in under `tests/golden`.
+//! After an intentional change of the encoder output, regenerate them with:
+//!
+//! ```text
+//! RAV1E_UPDATE_GOLDEN=1 cargo test golden -- --ignored
+//! ```
+//!
+//! A missing digest file fails the test unless `RAV1E_UPDATE_GOLDEN` is set.
+//! The digests have not been generated yet, so the tests are ignored until
+//! `tests/golden` is checked in.
+
+use super::*;
+use rand::{ChaChaRng, Rng, SeedableRng};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use crate::util::Pixel;
+
+const WIDTH: usize = 64;
+const HEIGHT: usize = 64;
+const LIMIT: usize = 8;
+
+enum Content {
+  Noise,
+  MovingGradient,
+}
+
+fn fixture<T: Pixel>(content: Content, w: usize, h: usize, bit_depth: usize) -> Vec<Arc<Frame<T>>> {
+  let mut ra = ChaChaRng::from_seed([0; 32]);
+  (0..LIMIT).map(|i| {
+    let mut frame = Frame::new(w, h, ChromaSampling::Cs420);
+    for plane in frame.planes.iter_mut() {
+      let stride = plane.cfg.stride;
+      for (y, row) in plane.data.chunks_mut(stride).enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+          let v: u8 = match content {
+            Content::Noise => ra.gen(),
+            Content::MovingGradient => ((x + 2 * i) * 3 + y * 2) as u8,
+          };
+          *pixel = T::cast_from((v as u16) << (bit_depth - 8));
+        }
+      }
+    }
+    Arc::new(frame)
+  }).collect()
+}
+
+fn config(speed: usize, bit_depth: usize, low_latency: bool, bitrate: i32, threads: usize) -> Config {
+  let mut enc = EncoderConfig::with_speed_preset(speed);
+  enc.width = WIDTH;
+  enc.height = HEIGHT;
+  enc.bit_depth = bit_depth;
+  enc.quantizer = 100;
+  enc.low_latency = low_latency;
+  enc.bitrate = bitrate;
+  enc.deterministic = true;
+  Config::new(enc).with_threads(threads)
+}
+
+/// Returns the frame number and the SHA-256 digest of each packet
+fn encode<T: Pixel>(config: &Config, frames: &[Arc<Frame<T>>]) -> Vec<String> {
+  let mut ctx: Context<T> = config.new_context().unwrap();
+  for frame in frames {
+    ctx.send_frame(frame.clone()).unwrap();
+  }
+  ctx.flush();
+
+  let mut packets = Vec::new();
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => {
+        let digest = Sha256::digest(&pkt.data);
+        let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        packets.push(format!("{} {}", pkt.input_frameno.unwrap(), hex));
+      }
+      Err(EncoderStatus::NeedMoreData) => {}
+      Err(EncoderStatus::LimitReached) => return packets,
+      Err(e) => panic!("{:?}", e),
+    }
+  }
+}
+
+/// Panics at the first packet that differs, reporting the frame it holds
+fn compare(what: &str, expected: &[String], actual: &[String]) {
+  for (i, (e, a)) in expected.iter().zip(actual.iter()).enumerate() {
+    if e != a {
+      panic!(
+        "{}: packet {} diverged\n  expected (frame digest): {}\n  actual   (frame digest): {}",
+        what, i, e, a
+      );
+    }
+  }
+  assert_eq!(
+    expected.len(), actual.len(),
+    "{}: expected {} packets, got {}", what, expected.len(), actual.len()
+  );
+}
+
+fn check_golden<T: Pixel>(name: &str, config: Config, frames: &[Arc<Frame<T>>]) {
+  let packets = encode(&config, frames);
+
+  // The output must not depend on the number of threads
+  let multithreaded = config.clone().with_threads(4);
+  compare(&format!("{} with 4 threads", name), &packets, &encode(&multithreaded, frames));
+
+  let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+    .join("tests")
+    .join("golden")
+    .join(format!("{}.sha256", name));
+  if env::var_os("RAV1E_UPDATE_GOLDEN").is_some() {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, packets.join("\n") + "\n").unwrap();
+    return;
+  }
+
+  let golden = fs::read_to_string(&path).unwrap_or_else(|e| panic!(
+    "{}: cannot read {} ({}), generate it with RAV1E_UPDATE_GOLDEN=1",
+    name, path.display(), e
+  ));
+  let expected: Vec<String> = golden.lines().map(String::from).collect();
+  compare(name, &expected, &packets);
+}
+
+#[test]
+#[ignore]
+fn golden_noise_low_latency() {
+  let frames = fixture::<u8>(Content::Noise, WIDTH, HEIGHT, 8);
+  check_golden("noise_low_latency", config(10, 8, true, 0, 1), &frames);
+}
+
+#[test]
+#[ignore]
+fn golden_noise_reorder() {
+  let frames = fixture::<u8>(Content::Noise, WIDTH, HEIGHT, 8);
+  check_golden("noise_reorder", config(10, 8, false, 0, 1), &frames);
+}
+
+#[test]
+#[ignore]
+fn golden_gradient_speed_5() {
+  let frames = fixture::<u8>(Content::MovingGradient, WIDTH, HEIGHT, 8);
+  check_golden("gradient_speed_5", config(5, 8, false, 0, 1), &frames);
+}
+
+#[test]
+#[ignore]
+fn golden_gradient_bitrate() {
+  let frames = fixture::<u8>(Content::MovingGradient, WIDTH, HEIGHT, 8);
+  check_golden("gradient_bitrate", config(10, 8, true, 100_000, 1), &frames);
+}
+
+#[test]
+#[ignore]
+fn golden_gradient_10bit() {
+  let frames = fixture::<u16>(Content::MovingGradient, WIDTH, HEIGHT, 10);
+  check_golden("gradient_10bit", config(10, 10, false, 0, 1), &frames);
+}
+
+#[test]
+#[ignore]
+fn golden_noise_tiles() {
+  let frames = fixture::<u8>(Content::Noise, 2 * WIDTH, 2 * HEIGHT, 8);
+  let mut config = config(10, 8, true, 0, 1);
+  config.enc.width = 2 * WIDTH;
+  config.enc.height = 2 * HEIGHT;
+  config.enc.tile_cols_log2 = 1;
+  config.enc.tile_rows_log2 = 1;
+  check_golden("noise_tiles", config, &frames);
+}
@@ -289,6 +289,23 @@ plane_region_common!(PlaneRegion, as_ptr);
 plane_region_common!(PlaneRegionMut, as_mut_ptr, mut);
 
 impl<'a, T: Pixel> PlaneRegionMut<'a, T> {
+  /// Mutable region over a separate buffer, laid out with the stride of
+  /// `cfg`, which stands for the area `rect` of the plane
+  ///
+  /// This lets the operations depending on the position in the plane, like
+  /// intra prediction, write to a scratch buffer.
+  #[inline(always)]
+  pub fn from_slice(data: &'a mut [T], cfg: &'a PlaneConfig, rect: Rect) -> Self {
+    assert!(rect.width <= cfg.stride);
+    assert!(rect.height * cfg.stride <= data.len());
+    Self {
+      data: data.as_mut_ptr(),
+      plane_cfg: cfg,
+      rect,
+      phantom: PhantomData,
+    }
+  }
+
   #[inline(always)]
   pub fn data_ptr_mut(&mut self) -> *mut T {
     self.data
@@ -81,6 +81,101 @@ impl TileRect {
   }
 }
 
+/// Offset in blocks from the top-left of the frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameBlockOffset(pub BlockOffset);
+
+/// Offset in blocks from the top-left of a tile
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TileBlockOffset(pub BlockOffset);
+
+/// Offset in pixels from the origin of a plane of the frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FramePlaneOffset(pub PlaneOffset);
+
+/// Offset in pixels from the top-left of a tile, in one of its planes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TilePlaneOffset(pub PlaneOffset);
+
+// Blocks are in luma units in every plane, the conversions between blocks
+// and pixels take the decimation of the plane. The conversions between
+// tiles and frames take the luma rectangle of the tile.
+
+impl FrameBlockOffset {
+  #[inline(always)]
+  pub fn to_tile(self, luma_rect: &TileRect) -> TileBlockOffset {
+    let bx = luma_rect.x >> MI_SIZE_LOG2;
+    let by = luma_rect.y >> MI_SIZE_LOG2;
+    debug_assert!(self.0.x >= bx && self.0.y >= by);
+    TileBlockOffset(BlockOffset { x: self.0.x - bx, y: self.0.y - by })
+  }
+
+  /// Offset of the top-left pixel of the block
+  #[inline(always)]
+  pub fn plane_offset(self, plane_cfg: &PlaneConfig) -> FramePlaneOffset {
+    FramePlaneOffset(self.0.plane_offset(plane_cfg))
+  }
+}
+
+impl TileBlockOffset {
+  #[inline(always)]
+  pub fn to_frame(self, luma_rect: &TileRect) -> FrameBlockOffset {
+    debug_assert!(luma_rect.x % MI_SIZE == 0 && luma_rect.y % MI_SIZE == 0);
+    FrameBlockOffset(BlockOffset {
+      x: (luma_rect.x >> MI_SIZE_LOG2) + self.0.x,
+      y: (luma_rect.y >> MI_SIZE_LOG2) + self.0.y
+    })
+  }
+
+  /// Offset of the top-left pixel of the block
+  #[inline(always)]
+  pub fn plane_offset(self, plane_cfg: &PlaneConfig) -> TilePlaneOffset {
+    TilePlaneOffset(self.0.plane_offset(plane_cfg))
+  }
+}
+
+impl FramePlaneOffset {
+  #[inline(always)]
+  pub fn to_tile(
+    self, luma_rect: &TileRect, xdec: usize, ydec: usize
+  ) -> TilePlaneOffset {
+    let rect = luma_rect.decimated(xdec, ydec);
+    TilePlaneOffset(PlaneOffset {
+      x: self.0.x - rect.x as isize,
+      y: self.0.y - rect.y as isize
+    })
+  }
+
+  /// Offset of the block containing the pixel
+  #[inline(always)]
+  pub fn block_offset(self, xdec: usize, ydec: usize) -> FrameBlockOffset {
+    debug_assert!(self.0.x >= 0 && self.0.y >= 0);
+    FrameBlockOffset(BlockOffset {
+      x: (self.0.x as usize) << xdec >> MI_SIZE_LOG2,
+      y: (self.0.y as usize) << ydec >> MI_SIZE_LOG2
+    })
+  }
+}
+
+impl TilePlaneOffset {
+  #[inline(always)]
+  pub fn to_frame(
+    self, luma_rect: &TileRect, xdec: usize, ydec: usize
+  ) -> FramePlaneOffset {
+    FramePlaneOffset(luma_rect.decimated(xdec, ydec).to_frame_plane_offset(self.0))
+  }
+
+  /// Offset of the block containing the pixel
+  #[inline(always)]
+  pub fn block_offset(self, xdec: usize, ydec: usize) -> TileBlockOffset {
+    debug_assert!(self.0.x >= 0 && self.0.y >= 0);
+    TileBlockOffset(BlockOffset {
+      x: (self.0.x as usize) << xdec >> MI_SIZE_LOG2,
+      y: (self.0.y as usize) << ydec >> MI_SIZE_LOG2
+    })
+  }
+}
+
 impl From<TileRect> for Rect {
   #[inline(always)]
   fn from(tile_rect: TileRect) -> Rect {
@@ -159,3 +254,76 @@ impl<'a, T: Pixel> TileMut<'a, T> {
     }
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const LUMA_RECT: TileRect = TileRect { x: 128, y: 64, width: 64, height: 64 };
+
+  fn plane_cfg(xdec: usize, ydec: usize) -> PlaneConfig {
+    Plane::<u8>::new(320 >> xdec, 192 >> ydec, xdec, ydec, 0, 0).cfg
+  }
+
+  #[test]
+  fn block_offsets() {
+    let tile_bo = TileBlockOffset(BlockOffset { x: 3, y: 5 });
+    let frame_bo = tile_bo.to_frame(&LUMA_RECT);
+    assert_eq!(frame_bo, FrameBlockOffset(BlockOffset { x: 35, y: 21 }));
+    assert_eq!(frame_bo.to_tile(&LUMA_RECT), tile_bo);
+  }
+
+  #[test]
+  fn plane_offsets() {
+    let tile_po = TilePlaneOffset(PlaneOffset { x: 12, y: 20 });
+    let luma_po = tile_po.to_frame(&LUMA_RECT, 0, 0);
+    assert_eq!(luma_po, FramePlaneOffset(PlaneOffset { x: 140, y: 84 }));
+    assert_eq!(luma_po.to_tile(&LUMA_RECT, 0, 0), tile_po);
+
+    // 4:2:0 chroma tiles start at half the luma offset
+    let chroma_po = tile_po.to_frame(&LUMA_RECT, 1, 1);
+    assert_eq!(chroma_po, FramePlaneOffset(PlaneOffset { x: 76, y: 52 }));
+    assert_eq!(chroma_po.to_tile(&LUMA_RECT, 1, 1), tile_po);
+
+    // 4:2:2 only halves the columns
+    let chroma_po = tile_po.to_frame(&LUMA_RECT, 1, 0);
+    assert_eq!(chroma_po, FramePlaneOffset(PlaneOffset { x: 76, y: 84 }));
+    assert_eq!(chroma_po.to_tile(&LUMA_RECT, 1, 0), tile_po);
+  }
+
+  #[test]
+  fn blocks_to_pixels() {
+    let tile_bo = TileBlockOffset(BlockOffset { x: 6, y: 4 });
+    assert_eq!(
+      tile_bo.plane_offset(&plane_cfg(0, 0)),
+      TilePlaneOffset(PlaneOffset { x: 24, y: 16 })
+    );
+    assert_eq!(
+      tile_bo.plane_offset(&plane_cfg(1, 1)),
+      TilePlaneOffset(PlaneOffset { x: 12, y: 8 })
+    );
+    let frame_bo = tile_bo.to_frame(&LUMA_RECT);
+    assert_eq!(
+      frame_bo.plane_offset(&plane_cfg(1, 0)),
+      FramePlaneOffset(PlaneOffset { x: 76, y: 80 })
+    );
+
+    // Every pixel of a block maps back to it
+    for &(xdec, ydec) in &[(0, 0), (1, 0), (1, 1)] {
+      let cfg = plane_cfg(xdec, ydec);
+      let po = tile_bo.plane_offset(&cfg).0;
+      for &(dx, dy) in &[(0, 0), (3 >> xdec, 3 >> ydec)] {
+        let tile_po = TilePlaneOffset(PlaneOffset { x: po.x + dx, y: po.y + dy });
+        assert_eq!(tile_po.block_offset(xdec, ydec), tile_bo);
+        let frame_po = tile_po.to_frame(&LUMA_RECT, xdec, ydec);
+        assert_eq!(frame_po.block_offset(xdec, ydec), frame_bo);
+        assert_eq!(frame_bo.plane_offset(&cfg).block_offset(xdec, ydec), frame_bo);
+      }
+    }
+
+    // The end of a block is the offset of the blocks past it
+    let end = tile_bo.0.add_size(crate::partition::BlockSize::BLOCK_8X16);
+    assert_eq!(end, BlockOffset { x: 8, y: 8 });
+    assert_eq!(end.to_luma_plane_offset(), PlaneOffset { x: 32, y: 32 });
+  }
+}
@@ -178,6 +178,16 @@ impl TileBlocksMut<'_> {
     self.for_each(bo, bsize, |block| block.mode = mode);
   }
 
+  #[inline(always)]
+  pub fn set_uv_mode(
+    &mut self,
+    bo: BlockOffset,
+    bsize: BlockSize,
+    uv_mode: PredictionMode,
+  ) {
+    self.for_each(bo, bsize, |block| block.uv_mode = uv_mode);
+  }
+
   #[inline(always)]
   pub fn set_block_size(&mut self, bo: BlockOffset, bsize: BlockSize) {
     let n4_w = bsize.width_mi();
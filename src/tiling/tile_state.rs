@@ -53,6 +53,9 @@ pub struct TileStateMut<'a, T: Pixel> {
   pub deblock: &'a DeblockState,
   pub rec: TileMut<'a, T>,
   pub qc: QuantizationContext,
+  /// Quantizer index of each superblock of the tile, in raster order, or
+  /// empty to use the one of the frame everywhere
+  pub sb_qidx: Vec<u8>,
   pub segmentation: &'a SegmentationState,
   pub restoration: TileRestorationStateMut<'a>,
   pub mvs: Vec<TileMotionVectorsMut<'a>>,
@@ -77,6 +80,17 @@ impl<'a, T: Pixel> TileStateMut<'a, T> {
     };
     let sb_width = width.align_power_of_two_and_shift(sb_size_log2);
     let sb_height = height.align_power_of_two_and_shift(sb_size_log2);
+    let sb_qidx = if fs.sb_qidx.is_empty() {
+      Vec::new()
+    } else {
+      let frame_sb_qidx = &fs.sb_qidx;
+      let frame_sb_cols =
+        fs.rec.planes[0].cfg.width.align_power_of_two_and_shift(sb_size_log2);
+      (sbo.y..sbo.y + sb_height).flat_map(|sby| {
+        let row = sby * frame_sb_cols;
+        frame_sb_qidx[row + sbo.x..row + sbo.x + sb_width].iter().cloned()
+      }).collect()
+    };
     Self {
       sbo,
       sb_size_log2,
@@ -93,6 +107,7 @@ impl<'a, T: Pixel> TileStateMut<'a, T> {
       deblock: &fs.deblock,
       rec: TileMut::new(&mut fs.rec, luma_rect),
       qc: Default::default(),
+      sb_qidx,
       segmentation: &fs.segmentation,
       restoration: TileRestorationStateMut::new(
         &mut fs.restoration,
@@ -117,6 +132,13 @@ impl<'a, T: Pixel> TileStateMut<'a, T> {
     }
   }
 
+  /// Quantizer index of the superblock at `tile_sbo`, `None` if it uses the
+  /// one of the frame
+  #[inline(always)]
+  pub fn sb_qidx(&self, tile_sbo: SuperBlockOffset) -> Option<u8> {
+    self.sb_qidx.get(tile_sbo.y * self.sb_width + tile_sbo.x).cloned()
+  }
+
   #[inline(always)]
   pub fn tile_rect(&self) -> TileRect {
     TileRect {
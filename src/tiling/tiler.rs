@@ -20,6 +20,11 @@ pub const MAX_TILE_AREA: usize = 4096 * 2304;
 pub const MAX_TILE_COLS: usize = 64;
 pub const MAX_TILE_ROWS: usize = 64;
 
+/// Smallest tile dimensions chosen by `TilingInfo::tile_log2s_for_count`.
+/// Narrower tiles cost too much in compression for the parallelism gained.
+const AUTO_MIN_TILE_WIDTH: usize = 256;
+const AUTO_MIN_TILE_HEIGHT: usize = 256;
+
 /// Tiling information
 ///
 /// This stores everything necessary to split a frame into tiles, and write
@@ -106,6 +111,40 @@ impl TilingInfo {
     }
   }
 
+  /// Chooses the log2 of the number of tile columns and rows to split a frame
+  /// into about `tiles` tiles, rounded down to a power of two.
+  ///
+  /// Columns are preferred over rows. Tiles are kept at least 256 pixels wide
+  /// and high when possible, so small frames may use fewer tiles than asked,
+  /// while large frames use at least as many tiles as the maximum tile width
+  /// and area require.
+  pub fn tile_log2s_for_count(
+    sb_size_log2: usize, frame_width: usize, frame_height: usize,
+    tiles: usize
+  ) -> (usize, usize) {
+    let ti = Self::new(sb_size_log2, frame_width, frame_height, 0, 0);
+    let tiles_log2 = tiles.max(1).ilog() - 1;
+
+    let fit_cols_log2 = (frame_width / AUTO_MIN_TILE_WIDTH).max(1).ilog() - 1;
+    let fit_rows_log2 =
+      (frame_height / AUTO_MIN_TILE_HEIGHT).max(1).ilog() - 1;
+
+    let tile_cols_log2 = tiles_log2
+      .min(fit_cols_log2)
+      .min(ti.max_tile_cols_log2)
+      .max(ti.min_tile_cols_log2);
+    // The maximum tile area may require extra rows
+    let min_tile_rows_log2 =
+      Self::new(sb_size_log2, frame_width, frame_height, tile_cols_log2, 0)
+        .min_tile_rows_log2;
+    let tile_rows_log2 = tiles_log2
+      .saturating_sub(tile_cols_log2)
+      .min(fit_rows_log2)
+      .min(ti.max_tile_rows_log2)
+      .max(min_tile_rows_log2);
+    (tile_cols_log2, tile_rows_log2)
+  }
+
   /// Return the smallest value for `k` such that `blkSize << k` is greater than
   /// or equal to `target`.
   ///
@@ -244,6 +283,44 @@ pub mod test {
     assert_eq!(16, ti.tile_height_sb);
   }
 
+  #[test]
+  fn test_tile_log2s_for_count() {
+    let sb_size_log2 = 6;
+    let resolutions = [
+      (426, 240),
+      (640, 360),
+      (1280, 720),
+      (1920, 1080),
+      (3840, 2160),
+      (7680, 4320),
+    ];
+
+    for &(width, height) in resolutions.iter() {
+      let mut prev_count = 0;
+      for tiles in 1..=64 {
+        let (cols_log2, rows_log2) =
+          TilingInfo::tile_log2s_for_count(sb_size_log2, width, height, tiles);
+        let ti = TilingInfo::new(sb_size_log2, width, height, cols_log2, rows_log2);
+        let required = 1 << (ti.min_tile_cols_log2 + ti.min_tile_rows_log2);
+
+        // The heuristic never asks for more than the frame allows
+        assert_eq!((cols_log2, rows_log2), (ti.tile_cols_log2, ti.tile_rows_log2));
+        assert!(ti.tile_width_sb << sb_size_log2 <= MAX_TILE_WIDTH);
+        assert!(ti.tile_count() <= tiles.max(required));
+        assert!(ti.tile_count() >= prev_count);
+        assert!(cols_log2 >= rows_log2);
+        prev_count = ti.tile_count();
+      }
+    }
+
+    // Too small to be worth splitting
+    assert_eq!((0, 0), TilingInfo::tile_log2s_for_count(sb_size_log2, 426, 240, 64));
+    assert_eq!((2, 1), TilingInfo::tile_log2s_for_count(sb_size_log2, 1920, 1080, 8));
+    assert_eq!((4, 2), TilingInfo::tile_log2s_for_count(sb_size_log2, 7680, 4320, 64));
+    // The maximum tile width and area require 2 columns and 2 rows
+    assert_eq!((1, 1), TilingInfo::tile_log2s_for_count(sb_size_log2, 7680, 4320, 1));
+  }
+
   fn create_frame_invariants(
     width: usize,
     height: usize,
@@ -259,7 +336,7 @@ pub mod test {
       chroma_sampling,
       ..Default::default()
     };
-    let sequence = Sequence::new(&config);
+    let sequence = Sequence::new(&config, None);
     FrameInvariants::new(config, sequence)
   }
 
@@ -8,6 +8,9 @@
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
 use super::*;
+use crate::cpu_features::CpuKernels;
+#[cfg(target_arch = "x86_64")]
+use crate::cpu_features::{CpuFeatureLevel, Kernel};
 use crate::partition::{TxSize, TxType};
 
 type TxfmShift = [i8; 3];
@@ -58,7 +61,7 @@ const FWD_TXFM_SHIFT_LS: [TxfmShifts; TxSize::TX_SIZES_ALL] = [
   FWD_SHIFT_64X16
 ];
 
-type TxfmFunc = dyn Fn(&[i32], &mut [i32]);
+type TxfmFunc<T> = fn(&[T], &mut [T]);
 
 use std::ops::*;
 
@@ -1651,21 +1654,21 @@ impl TxfmType {
     [TxfmType::DCT64, TxfmType::Invalid, TxfmType::Invalid, TxfmType::Invalid]
   ];
 
-  fn get_func(self) -> &'static TxfmFunc {
+  fn get_func<T: TxOperations>(self) -> TxfmFunc<T> {
     use self::TxfmType::*;
     match self {
-      DCT4 => &daala_fdct4,
-      DCT8 => &daala_fdct8,
-      DCT16 => &daala_fdct16,
-      DCT32 => &daala_fdct32,
-      DCT64 => &daala_fdct64,
-      ADST4 => &daala_fdst_vii_4,
-      ADST8 => &daala_fdst8,
-      ADST16 => &daala_fdst16,
-      Identity4 => &fidentity4,
-      Identity8 => &fidentity8,
-      Identity16 => &fidentity16,
-      Identity32 => &fidentity32,
+      DCT4 => daala_fdct4,
+      DCT8 => daala_fdct8,
+      DCT16 => daala_fdct16,
+      DCT32 => daala_fdct32,
+      DCT64 => daala_fdct64,
+      ADST4 => daala_fdst_vii_4,
+      ADST8 => daala_fdst8,
+      ADST16 => daala_fdst16,
+      Identity4 => fidentity4,
+      Identity8 => fidentity8,
+      Identity16 => fidentity16,
+      Identity32 => fidentity32,
       _ => unreachable!()
     }
   }
@@ -1728,7 +1731,25 @@ impl Txfm2DFlipCfg {
 }
 
 trait FwdTxfm2D: Dim {
+  #[cfg_attr(not(target_arch = "x86_64"), allow(unused_variables))]
   fn fwd_txfm2d_daala(
+    input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
+    bd: usize, cpu: CpuKernels
+  ) {
+    #[cfg(target_arch = "x86_64")]
+    {
+      if cpu.select(Kernel::FwdTxfm, bd > 8) == CpuFeatureLevel::AVX2 {
+        return unsafe {
+          avx2::fwd_txfm2d(
+            input, output, stride, tx_type, bd, Self::W, Self::H
+          )
+        };
+      }
+    }
+    Self::fwd_txfm2d_rs(input, output, stride, tx_type, bd);
+  }
+
+  fn fwd_txfm2d_rs(
     input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
     bd: usize
   ) {
@@ -1746,8 +1767,8 @@ trait FwdTxfm2D: Dim {
     let txfm_size_col = TxSize::width(cfg.tx_size);
     let txfm_size_row = TxSize::height(cfg.tx_size);
 
-    let txfm_func_col = cfg.txfm_type_col.get_func();
-    let txfm_func_row = cfg.txfm_type_row.get_func();
+    let txfm_func_col = cfg.txfm_type_col.get_func::<i32>();
+    let txfm_func_row = cfg.txfm_type_row.get_func::<i32>();
 
     // Columns
     for c in 0..txfm_size_col {
@@ -1799,6 +1820,167 @@ trait FwdTxfm2D: Dim {
   }
 }
 
+/// AVX2 version of the 2D forward transform. The 1D kernels are the same
+/// generic daala_tx functions as the scalar path, instantiated on vectors of
+/// 8 lanes so that 8 columns (then 8 rows) are transformed at once. Every
+/// lane computes exactly what the scalar code computes for its column.
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+  use super::*;
+  use std::arch::x86_64::*;
+
+  #[derive(Copy, Clone)]
+  struct I32X8(__m256i);
+
+  impl I32X8 {
+    #[inline]
+    unsafe fn load(lanes: &[i32; 8]) -> Self {
+      I32X8(_mm256_loadu_si256(lanes.as_ptr() as *const _))
+    }
+
+    #[inline]
+    unsafe fn store(self, lanes: &mut [i32; 8]) {
+      _mm256_storeu_si256(lanes.as_mut_ptr() as *mut _, self.0)
+    }
+  }
+
+  impl Default for I32X8 {
+    #[inline]
+    fn default() -> Self {
+      unsafe { I32X8(_mm256_setzero_si256()) }
+    }
+  }
+
+  impl Add for I32X8 {
+    type Output = Self;
+
+    #[inline]
+    fn add(self, b: Self) -> Self {
+      unsafe { I32X8(_mm256_add_epi32(self.0, b.0)) }
+    }
+  }
+
+  impl Sub for I32X8 {
+    type Output = Self;
+
+    #[inline]
+    fn sub(self, b: Self) -> Self {
+      unsafe { I32X8(_mm256_sub_epi32(self.0, b.0)) }
+    }
+  }
+
+  impl TxOperations for I32X8 {
+    #[inline]
+    fn tx_mul(self, mul: (i32, i32)) -> Self {
+      unsafe {
+        let prod = _mm256_mullo_epi32(self.0, _mm256_set1_epi32(mul.0));
+        let rounded =
+          _mm256_add_epi32(prod, _mm256_set1_epi32(1 << mul.1 >> 1));
+        I32X8(_mm256_sra_epi32(rounded, _mm_cvtsi32_si128(mul.1)))
+      }
+    }
+
+    #[inline]
+    fn rshift1(self) -> Self {
+      unsafe {
+        // Subtracting the sign mask adds 1 to negative values
+        let sign = _mm256_srai_epi32(self.0, 31);
+        I32X8(_mm256_srai_epi32(_mm256_sub_epi32(self.0, sign), 1))
+      }
+    }
+
+    #[inline]
+    fn add_avg(self, b: Self) -> Self {
+      unsafe { I32X8(_mm256_srai_epi32(_mm256_add_epi32(self.0, b.0), 1)) }
+    }
+
+    #[inline]
+    fn sub_avg(self, b: Self) -> Self {
+      unsafe { I32X8(_mm256_srai_epi32(_mm256_sub_epi32(self.0, b.0), 1)) }
+    }
+  }
+
+  /// Vector counterpart of av1_round_shift_array
+  #[inline]
+  unsafe fn round_shift_array(arr: &mut [I32X8], bit: i8) {
+    if bit == 0 {
+      return;
+    }
+    if bit > 0 {
+      let rounding = _mm256_set1_epi32(1 << (bit - 1));
+      let shift = _mm_cvtsi32_si128(bit as i32);
+      for v in arr.iter_mut() {
+        v.0 = _mm256_sra_epi32(_mm256_add_epi32(v.0, rounding), shift);
+      }
+    } else {
+      let shift = _mm_cvtsi32_si128(-bit as i32);
+      for v in arr.iter_mut() {
+        v.0 = _mm256_sll_epi32(v.0, shift);
+      }
+    }
+  }
+
+  #[target_feature(enable = "avx2")]
+  pub(super) unsafe fn fwd_txfm2d(
+    input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
+    bd: usize, w: usize, h: usize
+  ) {
+    let mut tmp: AlignedArray<[i32; 64 * 64]> = UninitializedAlignedArray();
+    let buf = &mut tmp.array[..w * h];
+    let cfg = Txfm2DFlipCfg::fwd(tx_type, TxSize::by_dims(w, h), bd);
+
+    let txfm_func_col = cfg.txfm_type_col.get_func::<I32X8>();
+    let txfm_func_row = cfg.txfm_type_row.get_func::<I32X8>();
+
+    let mut temp_in = [I32X8::default(); 64];
+    let mut temp_out = [I32X8::default(); 64];
+    let mut lanes = [0i32; 8];
+
+    // Columns, 8 at a time. Narrower blocks leave the upper lanes unused.
+    for c in (0..w).step_by(8) {
+      let n = (w - c).min(8);
+      for r in 0..h {
+        let src_r = if cfg.ud_flip { h - r - 1 } else { r };
+        let src = &input[src_r * stride + c..];
+        for (lane, &s) in lanes[..n].iter_mut().zip(src.iter()) {
+          *lane = s.into();
+        }
+        temp_in[r] = I32X8::load(&lanes);
+      }
+      round_shift_array(&mut temp_in[..h], -cfg.shift[0]);
+      txfm_func_col(&temp_in[..h], &mut temp_out[..h]);
+      round_shift_array(&mut temp_out[..h], -cfg.shift[1]);
+      for r in 0..h {
+        temp_out[r].store(&mut lanes);
+        let dst = &mut buf[r * w..(r + 1) * w];
+        for (i, &v) in lanes[..n].iter().enumerate() {
+          let col = c + i;
+          dst[if cfg.lr_flip { w - col - 1 } else { col }] = v;
+        }
+      }
+    }
+
+    // Rows, 8 at a time
+    for r in (0..h).step_by(8) {
+      let n = (h - r).min(8);
+      for c in 0..w {
+        for (i, lane) in lanes[..n].iter_mut().enumerate() {
+          *lane = buf[(r + i) * w + c];
+        }
+        temp_in[c] = I32X8::load(&lanes);
+      }
+      txfm_func_row(&temp_in[..w], &mut temp_out[..w]);
+      round_shift_array(&mut temp_out[..w], -cfg.shift[2]);
+      for c in 0..w {
+        temp_out[c].store(&mut lanes);
+        for (i, &v) in lanes[..n].iter().enumerate() {
+          output[(r + i) * w + c] = v;
+        }
+      }
+    }
+  }
+}
+
 macro_rules! impl_fwd_txs {
   ($(($W:expr, $H:expr)),+) => {
     $(
@@ -1817,42 +1999,42 @@ impl_fwd_txs! { (16, 4), (32, 8), (64, 16) }
 
 pub fn fht4x4(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
-  Block4x4::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth);
+  Block4x4::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth, cpu);
 }
 
 pub fn fht8x8(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
-  Block8x8::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth);
+  Block8x8::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth, cpu);
 }
 
 pub fn fht16x16(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
-  Block16x16::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth);
+  Block16x16::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth, cpu);
 }
 
 pub fn fht32x32(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
-  Block32x32::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth);
+  Block32x32::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth, cpu);
 }
 
 pub fn fht64x64(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
   assert!(tx_type == TxType::DCT_DCT);
   let mut aligned: AlignedArray<[i32; 4096]> = UninitializedAlignedArray();
   let tmp = &mut aligned.array;
 
   //Block64x64::fwd_txfm2d(input, &mut tmp, stride, tx_type, bit_depth);
-  Block64x64::fwd_txfm2d_daala(input, tmp, stride, tx_type, bit_depth);
+  Block64x64::fwd_txfm2d_daala(input, tmp, stride, tx_type, bit_depth, cpu);
 
   for i in 0..2 {
     for (row_out, row_in) in output[2048*i..].chunks_mut(32).zip(tmp[32*i..].chunks(64)).take(64) {
@@ -1863,57 +2045,57 @@ pub fn fht64x64(
 
 pub fn fht4x8(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
-  Block4x8::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth);
+  Block4x8::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth, cpu);
 }
 
 pub fn fht8x4(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
-  Block8x4::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth);
+  Block8x4::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth, cpu);
 }
 
 pub fn fht8x16(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
-  Block8x16::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth);
+  Block8x16::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth, cpu);
 }
 
 pub fn fht16x8(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
-  Block16x8::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth);
+  Block16x8::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth, cpu);
 }
 
 pub fn fht16x32(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
   assert!(tx_type == TxType::DCT_DCT || tx_type == TxType::IDTX);
-  Block16x32::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth);
+  Block16x32::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth, cpu);
 }
 
 pub fn fht32x16(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
   assert!(tx_type == TxType::DCT_DCT || tx_type == TxType::IDTX);
-  Block32x16::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth);
+  Block32x16::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth, cpu);
 }
 
 pub fn fht32x64(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
   assert!(tx_type == TxType::DCT_DCT);
   let mut aligned: AlignedArray<[i32; 2048]> = UninitializedAlignedArray();
   let tmp = &mut aligned.array;
 
-  Block32x64::fwd_txfm2d_daala(input, tmp, stride, tx_type, bit_depth);
+  Block32x64::fwd_txfm2d_daala(input, tmp, stride, tx_type, bit_depth, cpu);
 
   for (row_out, row_in) in output.chunks_mut(32).
     zip(tmp.chunks(32)).take(64) {
@@ -1923,13 +2105,13 @@ pub fn fht32x64(
 
 pub fn fht64x32(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
   assert!(tx_type == TxType::DCT_DCT);
   let mut aligned: AlignedArray<[i32; 2048]> = UninitializedAlignedArray();
   let tmp = &mut aligned.array;
 
-  Block64x32::fwd_txfm2d_daala(input, tmp, stride, tx_type, bit_depth);
+  Block64x32::fwd_txfm2d_daala(input, tmp, stride, tx_type, bit_depth, cpu);
 
   for i in 0..2 {
     for (row_out, row_in) in output[1024*i..].chunks_mut(32).
@@ -1941,43 +2123,43 @@ pub fn fht64x32(
 
 pub fn fht4x16(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
-  Block4x16::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth);
+  Block4x16::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth, cpu);
 }
 
 pub fn fht16x4(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
-  Block16x4::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth);
+  Block16x4::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth, cpu);
 }
 
 pub fn fht8x32(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
   assert!(tx_type == TxType::DCT_DCT || tx_type == TxType::IDTX);
-  Block8x32::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth);
+  Block8x32::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth, cpu);
 }
 
 pub fn fht32x8(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
   assert!(tx_type == TxType::DCT_DCT || tx_type == TxType::IDTX);
-  Block32x8::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth);
+  Block32x8::fwd_txfm2d_daala(input, output, stride, tx_type, bit_depth, cpu);
 }
 
 pub fn fht16x64(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
   assert!(tx_type == TxType::DCT_DCT);
   let mut aligned: AlignedArray<[i32; 1024]> = UninitializedAlignedArray();
   let tmp = &mut aligned.array;
 
-  Block16x64::fwd_txfm2d_daala(input, tmp, stride, tx_type, bit_depth);
+  Block16x64::fwd_txfm2d_daala(input, tmp, stride, tx_type, bit_depth, cpu);
 
   for (row_out, row_in) in output.chunks_mut(16).
     zip(tmp.chunks(16)).take(64) {
@@ -1987,13 +2169,13 @@ pub fn fht16x64(
 
 pub fn fht64x16(
   input: &[i16], output: &mut [i32], stride: usize, tx_type: TxType,
-  bit_depth: usize
+  bit_depth: usize, cpu: CpuKernels
 ) {
   assert!(tx_type == TxType::DCT_DCT);
   let mut aligned: AlignedArray<[i32; 1024]> = UninitializedAlignedArray();
   let tmp = &mut aligned.array;
 
-  Block64x16::fwd_txfm2d_daala(input, tmp, stride, tx_type, bit_depth);
+  Block64x16::fwd_txfm2d_daala(input, tmp, stride, tx_type, bit_depth, cpu);
 
   for i in 0..2 {
     for (row_out, row_in) in output[512*i..].chunks_mut(32).
@@ -2002,3 +2184,89 @@ pub fn fht64x16(
     }
   }
 }
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod test {
+  use super::*;
+  use rand::random;
+
+  const TX_TYPES_ALL: [TxType; 16] = [
+    TxType::DCT_DCT,
+    TxType::ADST_DCT,
+    TxType::DCT_ADST,
+    TxType::ADST_ADST,
+    TxType::FLIPADST_DCT,
+    TxType::DCT_FLIPADST,
+    TxType::FLIPADST_FLIPADST,
+    TxType::ADST_FLIPADST,
+    TxType::FLIPADST_ADST,
+    TxType::IDTX,
+    TxType::V_DCT,
+    TxType::H_DCT,
+    TxType::V_ADST,
+    TxType::H_ADST,
+    TxType::V_FLIPADST,
+    TxType::H_FLIPADST
+  ];
+
+  fn check_bit_exact<B: FwdTxfm2D>(bd: usize) {
+    let tx_size = TxSize::by_dims(B::W, B::H);
+    let max_dim = B::W.max(B::H);
+    let range = 1u16 << bd;
+    for &tx_type in TX_TYPES_ALL.iter() {
+      // 32 point transforms only come in DCT and identity flavors and the
+      // 64 point ones only in DCT.
+      if (max_dim == 32
+        && tx_type != TxType::DCT_DCT
+        && tx_type != TxType::IDTX)
+        || (max_dim == 64 && tx_type != TxType::DCT_DCT)
+      {
+        continue;
+      }
+      for _ in 0..16 {
+        let input: Vec<i16> = (0..tx_size.area())
+          .map(|_| {
+            (random::<u16>() % range) as i16
+              - (random::<u16>() % range) as i16
+          })
+          .collect();
+        let mut expected = vec![0i32; tx_size.area()];
+        let mut actual = vec![0i32; tx_size.area()];
+        B::fwd_txfm2d_rs(&input, &mut expected, B::W, tx_type, bd);
+        unsafe {
+          avx2::fwd_txfm2d(
+            &input, &mut actual, B::W, tx_type, bd, B::W, B::H
+          );
+        }
+        assert_eq!(
+          expected, actual,
+          "{:?} {:?} {}-bit", tx_size, tx_type, bd
+        );
+      }
+    }
+  }
+
+  macro_rules! test_bit_exact {
+    ($(($W:expr, $H:expr)),+) => {
+      $(
+        paste::item! {
+          #[test]
+          fn [<avx2_bit_exact_ $W x $H>]() {
+            if !is_x86_feature_detected!("avx2") {
+              return;
+            }
+            for &bd in [8, 10, 12].iter() {
+              check_bit_exact::<[<Block $W x $H>]>(bd);
+            }
+          }
+        }
+      )*
+    }
+  }
+
+  test_bit_exact! { (4, 4), (8, 8), (16, 16), (32, 32), (64, 64) }
+  test_bit_exact! { (4, 8), (8, 16), (16, 32), (32, 64) }
+  test_bit_exact! { (8, 4), (16, 8), (32, 16), (64, 32) }
+  test_bit_exact! { (4, 16), (8, 32), (16, 64) }
+  test_bit_exact! { (16, 4), (32, 8), (64, 16) }
+}
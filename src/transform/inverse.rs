@@ -15,6 +15,7 @@ pub use self::native::*;
 // TODO: move 1d txfm code to native module.
 
 use super::*;
+use crate::cpu_features::CpuKernels;
 use crate::partition::TxType;
 
 static COSPI_INV: [i32; 64] = [
@@ -1495,19 +1496,15 @@ fn av1_idct64(input: &[i32], output: &mut [i32], range: usize) {
 static INV_TXFM_FNS: [[fn(&[i32], &mut [i32], usize); 5]; 4] = [
   [av1_idct4, av1_idct8, av1_idct16, av1_idct32, av1_idct64],
   [av1_iadst4, av1_iadst8, av1_iadst16, |_, _, _| unimplemented!(), |_, _, _| unimplemented!()],
-  [
-    |_, _, _| unimplemented!(),
-    |_, _, _| unimplemented!(),
-    |_, _, _| unimplemented!(),
-    |_, _, _| unimplemented!(),
-    |_, _, _| unimplemented!()
-  ],
+  // FLIPADST is ADST with the output reversed by the caller
+  [av1_iadst4, av1_iadst8, av1_iadst16, |_, _, _| unimplemented!(), |_, _, _| unimplemented!()],
   [av1_iidentity4, av1_iidentity8, av1_iidentity16, av1_iidentity32, |_, _, _| unimplemented!()]
 ];
 
 #[cfg(all(target_arch = "x86_64", feature = "nasm"))]
 mod nasm {
   use super::*;
+  use crate::cpu_features::{CpuFeatureLevel, Kernel};
   use crate::partition::TxType;
 
   type InvTxfmFunc =
@@ -1518,11 +1515,11 @@ mod nasm {
 
     fn inv_txfm2d_add<T>(
       input: &[i32], output: &mut PlaneRegionMut<'_, T>, tx_type: TxType,
-      bd: usize
+      bd: usize, cpu: CpuKernels
     ) where
       T: Pixel,
     {
-      if std::mem::size_of::<T>() == 1 && is_x86_feature_detected!("avx2") {
+      if std::mem::size_of::<T>() == 1 && cpu.select(Kernel::InvTxfm, false) == CpuFeatureLevel::AVX2 {
         debug_assert!(bd == 8);
 
         // 64x only uses 32 coeffs
@@ -1552,7 +1549,7 @@ mod nasm {
         return;
       }
       <Self as super::native::InvTxfm2D>::inv_txfm2d_add(
-        input, output, tx_type, bd,
+        input, output, tx_type, bd, cpu,
       );
     }
   }
@@ -1661,7 +1658,7 @@ mod native {
 
     fn inv_txfm2d_add<T: Pixel>(
       input: &[i32], output: &mut PlaneRegionMut<'_, T>, tx_type: TxType,
-      bd: usize
+      bd: usize, _cpu: CpuKernels
     ) where
       T: Pixel,
     {
@@ -1669,8 +1666,9 @@ mod native {
       //   to zero for filling out missing input coeffs.
       let buffer = &mut [0i32; 64 * 64][..Self::W * Self::H];
       let rect_type = get_rect_tx_log_ratio(Self::W, Self::H);
-      let tx_types_1d = get_1d_tx_types(tx_type)
-        .expect("TxType not supported by rust txfm code.");
+      let tx_types_1d = (VTX_TAB[tx_type as usize], HTX_TAB[tx_type as usize]);
+      let ud_flip = tx_types_1d.0 == TxType1D::FLIPADST;
+      let lr_flip = tx_types_1d.1 == TxType1D::FLIPADST;
 
       // perform inv txfm on every row
       let range = bd + 8;
@@ -1693,6 +1691,9 @@ mod native {
           *clamped = clamp_value(val, range);
         }
         txfm_fn(&temp_in, buffer_slice, range);
+        if lr_flip {
+          buffer_slice.reverse();
+        }
       }
 
       // perform inv txfm on every col
@@ -1708,6 +1709,9 @@ mod native {
             clamp_value(round_shift(*raw, Self::INTERMEDIATE_SHIFT), range);
         }
         txfm_fn(&temp_in, &mut temp_out, range);
+        if ud_flip {
+          temp_out[..Self::H].reverse();
+        }
         for (temp, out) in temp_out
           .iter()
           .zip(output.rows_iter_mut().map(|row| &mut row[c]).take(Self::H))
@@ -1756,12 +1760,12 @@ macro_rules! impl_iht_fns {
       paste::item! {
         pub fn [<iht $W x $H _add>]<T: Pixel>(
           input: &[i32], output: &mut PlaneRegionMut<'_, T>, tx_type: TxType,
-          bit_depth: usize
+          bit_depth: usize, cpu: CpuKernels
         ) where
           T: Pixel,
         {
           [<Block $W x $H>]::inv_txfm2d_add(
-            input, output, tx_type, bit_depth
+            input, output, tx_type, bit_depth, cpu
           );
         }
       }
@@ -1790,3 +1794,86 @@ impl_iht_fns!(
   (4, 8),
   (4, 4)
 );
+
+#[cfg(all(test, target_arch = "x86_64", feature = "nasm"))]
+mod test {
+  use super::*;
+  use crate::cpu_features::CpuFeatureLevel;
+  use crate::plane::*;
+  use rand::random;
+
+  fn check_bit_exact<B: nasm::InvTxfm2D>(tx_types: &[TxType]) {
+    let tx_size = TxSize::by_dims(B::W, B::H);
+    for &tx_type in tx_types {
+      for _ in 0..16 {
+        // Derive the coefficients from a residual so they stay in the range
+        // a conforming stream can produce.
+        let residual: Vec<i16> = (0..tx_size.area())
+          .map(|_| random::<u8>() as i16 - random::<u8>() as i16)
+          .collect();
+        let mut coeffs = [0i32; 64 * 64];
+        forward_transform(
+          &residual, &mut coeffs, B::W, tx_size, tx_type, 8,
+          CpuKernels::NATIVE
+        );
+        let pred: Vec<u8> = (0..tx_size.area()).map(|_| random()).collect();
+        let mut expected = Plane::wrap(pred.clone(), B::W);
+        let mut actual = Plane::wrap(pred, B::W);
+        <B as native::InvTxfm2D>::inv_txfm2d_add(
+          &coeffs, &mut expected.as_region_mut(), tx_type, 8,
+          CpuKernels::NATIVE
+        );
+        <B as nasm::InvTxfm2D>::inv_txfm2d_add(
+          &coeffs, &mut actual.as_region_mut(), tx_type, 8,
+          CpuKernels::new(CpuFeatureLevel::AVX2)
+        );
+        assert_eq!(
+          expected.data[..], actual.data[..],
+          "{:?} {:?}", tx_size, tx_type
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn avx2_bit_exact() {
+    use crate::partition::TxType::*;
+    if !is_x86_feature_detected!("avx2") {
+      return;
+    }
+    let types_64 = [DCT_DCT];
+    let types_32 = [DCT_DCT, IDTX];
+    let types_16 = [
+      DCT_DCT, ADST_DCT, DCT_ADST, ADST_ADST, FLIPADST_DCT, DCT_FLIPADST,
+      FLIPADST_FLIPADST, ADST_FLIPADST, FLIPADST_ADST, IDTX, V_DCT, H_DCT
+    ];
+    let types_all = [
+      DCT_DCT, ADST_DCT, DCT_ADST, ADST_ADST, FLIPADST_DCT, DCT_FLIPADST,
+      FLIPADST_FLIPADST, ADST_FLIPADST, FLIPADST_ADST, IDTX, V_DCT, H_DCT,
+      V_ADST, H_ADST, V_FLIPADST, H_FLIPADST
+    ];
+
+    check_bit_exact::<Block64x64>(&types_64);
+    check_bit_exact::<Block64x32>(&types_64);
+    check_bit_exact::<Block32x64>(&types_64);
+    check_bit_exact::<Block16x64>(&types_64);
+    check_bit_exact::<Block64x16>(&types_64);
+
+    check_bit_exact::<Block32x32>(&types_32);
+    check_bit_exact::<Block32x16>(&types_32);
+    check_bit_exact::<Block16x32>(&types_32);
+    check_bit_exact::<Block32x8>(&types_32);
+    check_bit_exact::<Block8x32>(&types_32);
+
+    check_bit_exact::<Block16x16>(&types_16);
+
+    check_bit_exact::<Block16x8>(&types_all);
+    check_bit_exact::<Block8x16>(&types_all);
+    check_bit_exact::<Block16x4>(&types_all);
+    check_bit_exact::<Block4x16>(&types_all);
+    check_bit_exact::<Block8x8>(&types_all);
+    check_bit_exact::<Block8x4>(&types_all);
+    check_bit_exact::<Block4x8>(&types_all);
+    check_bit_exact::<Block4x4>(&types_all);
+  }
+}
@@ -10,6 +10,7 @@
 pub use self::forward::*;
 pub use self::inverse::*;
 
+use crate::cpu_features::CpuKernels;
 use crate::partition::{TxSize, TxType, TX_TYPES};
 use crate::predict::*;
 use crate::tiling::*;
@@ -81,7 +82,7 @@ fn av1_round_shift_array_rs(arr: &mut [i32], size: usize, bit: i8) {
   }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum TxType1D {
   DCT,
   ADST,
@@ -89,22 +90,6 @@ enum TxType1D {
   IDTX
 }
 
-// Option can be removed when the table is completely filled
-fn get_1d_tx_types(tx_type: TxType) -> Option<(TxType1D, TxType1D)> {
-  match tx_type {
-    TxType::DCT_DCT => Some((TxType1D::DCT, TxType1D::DCT)),
-    TxType::ADST_DCT => Some((TxType1D::ADST, TxType1D::DCT)),
-    TxType::DCT_ADST => Some((TxType1D::DCT, TxType1D::ADST)),
-    TxType::ADST_ADST => Some((TxType1D::ADST, TxType1D::ADST)),
-    TxType::IDTX => Some((TxType1D::IDTX, TxType1D::IDTX)),
-    TxType::V_DCT => Some((TxType1D::DCT, TxType1D::IDTX)),
-    TxType::H_DCT => Some((TxType1D::IDTX, TxType1D::DCT)),
-    TxType::V_ADST => Some((TxType1D::ADST, TxType1D::IDTX)),
-    TxType::H_ADST => Some((TxType1D::IDTX, TxType1D::ADST)),
-    _ => None
-  }
-}
-
 const VTX_TAB: [TxType1D; TX_TYPES] = [
   TxType1D::DCT,
   TxType1D::ADST,
@@ -145,61 +130,61 @@ const HTX_TAB: [TxType1D; TX_TYPES] = [
 
 pub fn forward_transform(
   input: &[i16], output: &mut [i32], stride: usize, tx_size: TxSize,
-  tx_type: TxType, bit_depth: usize
+  tx_type: TxType, bit_depth: usize, cpu: CpuKernels
 ) {
   use self::TxSize::*;
   match tx_size {
-    TX_4X4 => fht4x4(input, output, stride, tx_type, bit_depth),
-    TX_8X8 => fht8x8(input, output, stride, tx_type, bit_depth),
-    TX_16X16 => fht16x16(input, output, stride, tx_type, bit_depth),
-    TX_32X32 => fht32x32(input, output, stride, tx_type, bit_depth),
-    TX_64X64 => fht64x64(input, output, stride, tx_type, bit_depth),
+    TX_4X4 => fht4x4(input, output, stride, tx_type, bit_depth, cpu),
+    TX_8X8 => fht8x8(input, output, stride, tx_type, bit_depth, cpu),
+    TX_16X16 => fht16x16(input, output, stride, tx_type, bit_depth, cpu),
+    TX_32X32 => fht32x32(input, output, stride, tx_type, bit_depth, cpu),
+    TX_64X64 => fht64x64(input, output, stride, tx_type, bit_depth, cpu),
 
-    TX_4X8 => fht4x8(input, output, stride, tx_type, bit_depth),
-    TX_8X4 => fht8x4(input, output, stride, tx_type, bit_depth),
-    TX_8X16 => fht8x16(input, output, stride, tx_type, bit_depth),
-    TX_16X8 => fht16x8(input, output, stride, tx_type, bit_depth),
-    TX_16X32 => fht16x32(input, output, stride, tx_type, bit_depth),
-    TX_32X16 => fht32x16(input, output, stride, tx_type, bit_depth),
-    TX_32X64 => fht32x64(input, output, stride, tx_type, bit_depth),
-    TX_64X32 => fht64x32(input, output, stride, tx_type, bit_depth),
+    TX_4X8 => fht4x8(input, output, stride, tx_type, bit_depth, cpu),
+    TX_8X4 => fht8x4(input, output, stride, tx_type, bit_depth, cpu),
+    TX_8X16 => fht8x16(input, output, stride, tx_type, bit_depth, cpu),
+    TX_16X8 => fht16x8(input, output, stride, tx_type, bit_depth, cpu),
+    TX_16X32 => fht16x32(input, output, stride, tx_type, bit_depth, cpu),
+    TX_32X16 => fht32x16(input, output, stride, tx_type, bit_depth, cpu),
+    TX_32X64 => fht32x64(input, output, stride, tx_type, bit_depth, cpu),
+    TX_64X32 => fht64x32(input, output, stride, tx_type, bit_depth, cpu),
 
-    TX_4X16 => fht4x16(input, output, stride, tx_type, bit_depth),
-    TX_16X4 => fht16x4(input, output, stride, tx_type, bit_depth),
-    TX_8X32 => fht8x32(input, output, stride, tx_type, bit_depth),
-    TX_32X8 => fht32x8(input, output, stride, tx_type, bit_depth),
-    TX_16X64 => fht16x64(input, output, stride, tx_type, bit_depth),
-    TX_64X16 => fht64x16(input, output, stride, tx_type, bit_depth),
+    TX_4X16 => fht4x16(input, output, stride, tx_type, bit_depth, cpu),
+    TX_16X4 => fht16x4(input, output, stride, tx_type, bit_depth, cpu),
+    TX_8X32 => fht8x32(input, output, stride, tx_type, bit_depth, cpu),
+    TX_32X8 => fht32x8(input, output, stride, tx_type, bit_depth, cpu),
+    TX_16X64 => fht16x64(input, output, stride, tx_type, bit_depth, cpu),
+    TX_64X16 => fht64x16(input, output, stride, tx_type, bit_depth, cpu),
   }
 }
 
 pub fn inverse_transform_add<T: Pixel>(
   input: &[i32], output: &mut PlaneRegionMut<'_, T>, tx_size: TxSize,
-  tx_type: TxType, bit_depth: usize
+  tx_type: TxType, bit_depth: usize, cpu: CpuKernels
 ) {
   use self::TxSize::*;
   match tx_size {
-    TX_4X4 => iht4x4_add(input, output, tx_type, bit_depth),
-    TX_8X8 => iht8x8_add(input, output, tx_type, bit_depth),
-    TX_16X16 => iht16x16_add(input, output, tx_type, bit_depth),
-    TX_32X32 => iht32x32_add(input, output, tx_type, bit_depth),
-    TX_64X64 => iht64x64_add(input, output, tx_type, bit_depth),
+    TX_4X4 => iht4x4_add(input, output, tx_type, bit_depth, cpu),
+    TX_8X8 => iht8x8_add(input, output, tx_type, bit_depth, cpu),
+    TX_16X16 => iht16x16_add(input, output, tx_type, bit_depth, cpu),
+    TX_32X32 => iht32x32_add(input, output, tx_type, bit_depth, cpu),
+    TX_64X64 => iht64x64_add(input, output, tx_type, bit_depth, cpu),
 
-    TX_4X8 => iht4x8_add(input, output, tx_type, bit_depth),
-    TX_8X4 => iht8x4_add(input, output, tx_type, bit_depth),
-    TX_8X16 => iht8x16_add(input, output, tx_type, bit_depth),
-    TX_16X8 => iht16x8_add(input, output, tx_type, bit_depth),
-    TX_16X32 => iht16x32_add(input, output, tx_type, bit_depth),
-    TX_32X16 => iht32x16_add(input, output, tx_type, bit_depth),
-    TX_32X64 => iht32x64_add(input, output, tx_type, bit_depth),
-    TX_64X32 => iht64x32_add(input, output, tx_type, bit_depth),
+    TX_4X8 => iht4x8_add(input, output, tx_type, bit_depth, cpu),
+    TX_8X4 => iht8x4_add(input, output, tx_type, bit_depth, cpu),
+    TX_8X16 => iht8x16_add(input, output, tx_type, bit_depth, cpu),
+    TX_16X8 => iht16x8_add(input, output, tx_type, bit_depth, cpu),
+    TX_16X32 => iht16x32_add(input, output, tx_type, bit_depth, cpu),
+    TX_32X16 => iht32x16_add(input, output, tx_type, bit_depth, cpu),
+    TX_32X64 => iht32x64_add(input, output, tx_type, bit_depth, cpu),
+    TX_64X32 => iht64x32_add(input, output, tx_type, bit_depth, cpu),
 
-    TX_4X16 => iht4x16_add(input, output, tx_type, bit_depth),
-    TX_16X4 => iht16x4_add(input, output, tx_type, bit_depth),
-    TX_8X32 => iht8x32_add(input, output, tx_type, bit_depth),
-    TX_32X8 => iht32x8_add(input, output, tx_type, bit_depth),
-    TX_16X64 => iht16x64_add(input, output, tx_type, bit_depth),
-    TX_64X16 => iht64x16_add(input, output, tx_type, bit_depth),
+    TX_4X16 => iht4x16_add(input, output, tx_type, bit_depth, cpu),
+    TX_16X4 => iht16x4_add(input, output, tx_type, bit_depth, cpu),
+    TX_8X32 => iht8x32_add(input, output, tx_type, bit_depth, cpu),
+    TX_32X8 => iht32x8_add(input, output, tx_type, bit_depth, cpu),
+    TX_16X64 => iht16x64_add(input, output, tx_type, bit_depth, cpu),
+    TX_64X16 => iht64x16_add(input, output, tx_type, bit_depth, cpu),
   }
 }
 
@@ -207,6 +192,7 @@ pub fn inverse_transform_add<T: Pixel>(
 mod test {
   use super::*;
   use rand::random;
+  use crate::cpu_features::CpuFeatureLevel;
   use crate::plane::*;
 
   fn test_roundtrip<T: Pixel>(tx_size: TxSize, tx_type: TxType, tolerance: i16) {
@@ -222,8 +208,11 @@ mod test {
       *d = T::cast_from(random::<u8>());
       *r = i16::cast_from(*s) - i16::cast_from(*d);
     }
-    forward_transform(res, freq, tx_size.width(), tx_size, tx_type, 8);
-    inverse_transform_add(freq, &mut dst.as_region_mut(), tx_size, tx_type, 8);
+    let cpu = CpuKernels::new(CpuFeatureLevel::detect());
+    forward_transform(res, freq, tx_size.width(), tx_size, tx_type, 8, cpu);
+    inverse_transform_add(
+      freq, &mut dst.as_region_mut(), tx_size, tx_type, 8, cpu
+    );
 
     for (s, d) in src.iter().zip(dst.data.iter()) {
       assert!(i16::abs(i16::cast_from(*s) - i16::cast_from(*d)) <= tolerance);
@@ -275,6 +264,11 @@ mod test {
       (TX_4X4, H_DCT, 0),
       (TX_4X4, V_ADST, 0),
       (TX_4X4, H_ADST, 0),
+      (TX_4X4, FLIPADST_DCT, 0),
+      (TX_4X4, DCT_FLIPADST, 0),
+      (TX_4X4, FLIPADST_FLIPADST, 0),
+      (TX_4X4, V_FLIPADST, 0),
+      (TX_4X4, H_FLIPADST, 0),
       (TX_8X8, DCT_DCT, 1),
       (TX_8X8, ADST_DCT, 1),
       (TX_8X8, DCT_ADST, 1),
@@ -284,6 +278,8 @@ mod test {
       (TX_8X8, H_DCT, 0),
       (TX_8X8, V_ADST, 0),
       (TX_8X8, H_ADST, 1),
+      (TX_8X8, FLIPADST_ADST, 1),
+      (TX_8X8, ADST_FLIPADST, 1),
       (TX_16X16, DCT_DCT, 1),
       (TX_16X16, ADST_DCT, 1),
       (TX_16X16, DCT_ADST, 1),
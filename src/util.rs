@@ -176,6 +176,124 @@ pub trait ILog: PrimInt {
 
 impl<T> ILog for T where T: PrimInt {}
 
+/// Feeds a byte to a CRC-32 (IEEE 802.3, reflected), started from `!0` and
+/// finished by inverting it
+#[inline(always)]
+pub fn crc32_update(mut crc: u32, byte: u8) -> u32 {
+  crc ^= u32::from(byte);
+  for _ in 0..8 {
+    crc = (crc >> 1) ^ (0xEDB8_8320 & (!(crc & 1)).wrapping_add(1));
+  }
+  crc
+}
+
+/// Per-round left rotations of MD5 (RFC 1321)
+const MD5_SHIFTS: [u32; 16] =
+  [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+/// Additive constants of MD5, `floor(abs(sin(i + 1)) * 2^32)`
+const MD5_K: [u32; 64] = [
+  0xd76a_a478, 0xe8c7_b756, 0x2420_70db, 0xc1bd_ceee, 0xf57c_0faf, 0x4787_c62a,
+  0xa830_4613, 0xfd46_9501, 0x6980_98d8, 0x8b44_f7af, 0xffff_5bb1, 0x895c_d7be,
+  0x6b90_1122, 0xfd98_7193, 0xa679_438e, 0x49b4_0821, 0xf61e_2562, 0xc040_b340,
+  0x265e_5a51, 0xe9b6_c7aa, 0xd62f_105d, 0x0244_1453, 0xd8a1_e681, 0xe7d3_fbc8,
+  0x21e1_cde6, 0xc337_07d6, 0xf4d5_0d87, 0x455a_14ed, 0xa9e3_e905, 0xfcef_a3f8,
+  0x676f_02d9, 0x8d2a_4c8a, 0xfffa_3942, 0x8771_f681, 0x6d9d_6122, 0xfde5_380c,
+  0xa4be_ea44, 0x4bde_cfa9, 0xf6bb_4b60, 0xbebf_bc70, 0x289b_7ec6, 0xeaa1_27fa,
+  0xd4ef_3085, 0x0488_1d05, 0xd9d4_d039, 0xe6db_99e5, 0x1fa2_7cf8, 0xc4ac_5665,
+  0xf429_2244, 0x432a_ff97, 0xab94_23a7, 0xfc93_a039, 0x655b_59c3, 0x8f0c_cc92,
+  0xffef_f47d, 0x8584_5dd1, 0x6fa8_7e4f, 0xfe2c_e6e0, 0xa301_4314, 0x4e08_11a1,
+  0xf753_7e82, 0xbd3a_f235, 0x2ad7_d2bb, 0xeb86_d391,
+];
+
+/// MD5 digest of a stream of bytes (RFC 1321), as printed by the
+/// `--md5` option of the decoders
+#[derive(Clone)]
+pub struct Md5 {
+  state: [u32; 4],
+  /// Bytes not yet hashed, fewer than a block
+  buffer: [u8; 64],
+  /// Number of bytes hashed or buffered
+  len: u64,
+}
+
+impl Default for Md5 {
+  fn default() -> Self {
+    Md5 {
+      state: [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476],
+      buffer: [0; 64],
+      len: 0,
+    }
+  }
+}
+
+impl Md5 {
+  pub fn new() -> Self {
+    Default::default()
+  }
+
+  pub fn update(&mut self, mut data: &[u8]) {
+    let buffered = (self.len % 64) as usize;
+    self.len += data.len() as u64;
+    if buffered > 0 {
+      let n = data.len().min(64 - buffered);
+      self.buffer[buffered..buffered + n].copy_from_slice(&data[..n]);
+      data = &data[n..];
+      if buffered + n < 64 {
+        return;
+      }
+      let block = self.buffer;
+      self.process(&block);
+    }
+    let mut blocks = data.chunks_exact(64);
+    for block in &mut blocks {
+      self.process(block);
+    }
+    let rest = blocks.remainder();
+    self.buffer[..rest.len()].copy_from_slice(rest);
+  }
+
+  pub fn finish(mut self) -> [u8; 16] {
+    let bits = self.len.wrapping_mul(8);
+    let buffered = (self.len % 64) as usize;
+    let padding = if buffered < 56 { 56 - buffered } else { 120 - buffered };
+    let mut tail = [0u8; 72];
+    tail[0] = 0x80;
+    tail[padding..padding + 8].copy_from_slice(&bits.to_le_bytes());
+    self.update(&tail[..padding + 8]);
+
+    let mut digest = [0u8; 16];
+    for (bytes, word) in digest.chunks_mut(4).zip(self.state.iter()) {
+      bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+  }
+
+  fn process(&mut self, block: &[u8]) {
+    let mut m = [0u32; 16];
+    for (word, bytes) in m.iter_mut().zip(block.chunks(4)) {
+      *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    let [mut a, mut b, mut c, mut d] = self.state;
+    for i in 0..64 {
+      let (f, g) = match i / 16 {
+        0 => ((b & c) | (!b & d), i),
+        1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+        2 => (b ^ c ^ d, (3 * i + 5) % 16),
+        _ => (c ^ (b | !d), (7 * i) % 16),
+      };
+      let sum = a.wrapping_add(f).wrapping_add(MD5_K[i]).wrapping_add(m[g]);
+      a = d;
+      d = c;
+      c = b;
+      b = b.wrapping_add(sum.rotate_left(MD5_SHIFTS[i / 16 * 4 + i % 4]));
+    }
+    for (s, v) in self.state.iter_mut().zip([a, b, c, d].iter()) {
+      *s = s.wrapping_add(*v);
+    }
+  }
+}
+
 #[inline(always)]
 pub fn msb(x: i32) -> i32 {
   debug_assert!(x > 0);
@@ -186,3 +304,41 @@ pub fn msb(x: i32) -> i32 {
 pub fn round_shift(value: i32, bit: usize) -> i32 {
   (value + (1 << bit >> 1)) >> bit
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn hex(digest: [u8; 16]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+  }
+
+  fn md5_hex(data: &[u8]) -> String {
+    let mut md5 = Md5::new();
+    md5.update(data);
+    hex(md5.finish())
+  }
+
+  #[test]
+  fn md5_digests() {
+    assert_eq!(md5_hex(b""), "d41d8cd98f00b204e9800998ecf8427e");
+    assert_eq!(md5_hex(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+    assert_eq!(
+      md5_hex(b"The quick brown fox jumps over the lazy dog"),
+      "9e107d9d372bb6826bd81d3542a419d6"
+    );
+  }
+
+  #[test]
+  fn md5_chunked() {
+    let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+    // Chunks straddling the blocks and the padding
+    for &size in &[1, 13, 55, 56, 64, 65, 1000] {
+      let mut md5 = Md5::new();
+      for chunk in data.chunks(size) {
+        md5.update(chunk);
+      }
+      assert_eq!(hex(md5.finish()), "4b2f37fc49a134b17c7275fd04a1b7ac");
+    }
+  }
+}